@@ -0,0 +1,461 @@
+use crate::compression::{self, Encoder};
+use crate::config::Compression;
+use crate::rate_limiter::{RateLimiter, ThrottledWriter};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+/// Name of the manifest stored as the last entry of every archive.
+pub const MANIFEST_NAME: &str = ".rusty_bucket-manifest.json";
+
+/// One file in an archive's manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    // Path inside the archive, `/`-separated
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// A file written by `extract`, and what verifying it found.
+#[derive(Debug, Clone)]
+pub struct ExtractedFile {
+    pub path: String,
+    pub destination: PathBuf,
+    pub size: u64,
+    pub sha256: Option<String>,
+    pub problem: Option<String>,
+}
+
+// Hashes everything read through it
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            bytes: 0,
+        }
+    }
+
+    fn finish(self) -> (u64, String) {
+        (self.bytes, format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+}
+
+/// `relative` as a `/`-separated path, as stored in archives and manifests.
+pub fn archive_path(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// How a member of `archive` is named in reports: the archive's path, then
+/// the member's path inside it.
+pub fn member_label(archive: &Path, member: &str) -> String {
+    format!("{}:{}", archive.display(), member)
+}
+
+// What a skipped entry is, for reporting it
+fn entry_kind(file_type: fs::FileType) -> &'static str {
+    match file_type.is_symlink() {
+        true => "symlink",
+        false => "not a regular file",
+    }
+}
+
+/// Packs the tree under `origin` into a tar at `destination`, compressed
+/// if asked, with a manifest of every file's hash as the last entry.
+/// Writes go through `rate_limiter`. Stops at the first file it can't read.
+/// Only regular files and directories are archived; returns the manifest
+/// and a description of every other entry, which was skipped.
+pub fn create(
+    origin: &Path,
+    destination: &Path,
+    compression: Option<Compression>,
+    rate_limiter: &mut RateLimiter,
+) -> anyhow::Result<(Vec<ManifestEntry>, Vec<String>)> {
+    let file = File::create(destination)?;
+    let writer = Encoder::new(ThrottledWriter::new(file, rate_limiter), compression)?;
+    let mut builder = tar::Builder::new(writer);
+    let mut manifest = Vec::new();
+    let mut skipped = Vec::new();
+
+    for entry in WalkDir::new(origin).sort_by_file_name() {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(origin)?;
+        if relative.as_os_str().is_empty() {
+            continue;
+        }
+        let name = archive_path(relative);
+        if name == MANIFEST_NAME {
+            anyhow::bail!(
+                "{} is reserved for the archive manifest",
+                entry.path().display()
+            );
+        }
+        let metadata = entry.metadata()?;
+
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&metadata);
+        if entry.file_type().is_dir() {
+            builder
+                .get_mut()
+                .get_mut()
+                .rate_limiter()
+                .throttle_metadata(1);
+            builder.append_data(&mut header, &name, io::empty())?;
+        } else if entry.file_type().is_file() {
+            builder.get_mut().get_mut().rate_limiter().throttle_file();
+            let source = File::open(entry.path())
+                .with_context(|| format!("cannot read {}", entry.path().display()))?;
+            // Archive exactly the size recorded in the header, even if the
+            // file grows meanwhile
+            let mut reader = HashingReader::new(source.take(metadata.len()));
+            builder
+                .append_data(&mut header, &name, &mut reader)
+                .with_context(|| format!("cannot archive {}", entry.path().display()))?;
+            let (size, sha256) = reader.finish();
+            manifest.push(ManifestEntry {
+                path: name,
+                size,
+                sha256,
+            });
+        } else {
+            skipped.push(format!("{} ({})", name, entry_kind(entry.file_type())));
+        }
+    }
+
+    let listing = serde_json::to_vec_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(listing.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs()),
+    );
+    builder.append_data(&mut header, MANIFEST_NAME, listing.as_slice())?;
+
+    let file = builder.into_inner()?.finish()?.into_inner();
+    file.sync_all()?;
+    Ok((manifest, skipped))
+}
+
+fn open(
+    archive: &Path,
+    compression: Option<Compression>,
+) -> anyhow::Result<tar::Archive<Box<dyn Read>>> {
+    Ok(tar::Archive::new(compression::open_decoded(
+        archive,
+        compression,
+        None,
+    )?))
+}
+
+// Compares what was found in an archive against its manifest. Returns one
+// problem per file, keyed by archive path.
+fn check_manifest(
+    manifest: &[ManifestEntry],
+    found: &HashMap<String, (u64, String)>,
+) -> HashMap<String, String> {
+    let mut problems = HashMap::new();
+    for listed in manifest {
+        match found.get(&listed.path) {
+            None => {
+                problems.insert(listed.path.clone(), "missing from the archive".to_string());
+            }
+            Some((size, sha256)) if *size != listed.size || *sha256 != listed.sha256 => {
+                problems.insert(
+                    listed.path.clone(),
+                    "hash does not match the archive manifest".to_string(),
+                );
+            }
+            Some(_) => {}
+        }
+    }
+    for path in found.keys() {
+        if !manifest.iter().any(|listed| &listed.path == path) {
+            problems.insert(
+                path.clone(),
+                "not listed in the archive manifest".to_string(),
+            );
+        }
+    }
+    problems
+}
+
+/// Reads an archive back and checks every file in it against its manifest.
+/// Returns a description of each problem found.
+pub fn verify(archive: &Path, compression: Option<Compression>) -> anyhow::Result<Vec<String>> {
+    let mut found = HashMap::new();
+    let mut manifest = None;
+    for entry in open(archive, compression)?.entries()? {
+        let mut entry = entry?;
+        let name = archive_path(&entry.path()?);
+        if name == MANIFEST_NAME {
+            manifest = Some(serde_json::from_reader::<_, Vec<ManifestEntry>>(
+                &mut entry,
+            )?);
+        } else if entry.header().entry_type().is_file() {
+            let mut reader = HashingReader::new(&mut entry);
+            io::copy(&mut reader, &mut io::sink())?;
+            found.insert(name, reader.finish());
+        }
+    }
+    let Some(manifest) = manifest else {
+        anyhow::bail!("archive has no manifest");
+    };
+    let mut problems: Vec<String> = check_manifest(&manifest, &found)
+        .into_iter()
+        .map(|(path, problem)| format!("{}: {}", path, problem))
+        .collect();
+    problems.sort();
+    Ok(problems)
+}
+
+/// Where an archive member lands under `destination`. Absolute paths and
+/// `..` components are refused so an archive can't write outside it.
+pub fn member_destination(destination: &Path, member: &Path) -> anyhow::Result<PathBuf> {
+    let mut target = destination.to_path_buf();
+    for component in member.components() {
+        match component {
+            Component::Normal(part) => target.push(part),
+            Component::CurDir => {}
+            _ => anyhow::bail!("refusing unsafe path in archive: {}", member.display()),
+        }
+    }
+    Ok(target)
+}
+
+/// Unpacks `archive` into `destination` through `rate_limiter`, then checks
+/// each file against the manifest. Files that fail the check, including
+/// any the manifest doesn't list, are removed. Only regular files and
+/// directories are unpacked. Returns the files, whether the archive had a
+/// manifest at all and a description of every member skipped.
+pub fn extract(
+    archive: &Path,
+    destination: &Path,
+    compression: Option<Compression>,
+    rate_limiter: &mut RateLimiter,
+) -> anyhow::Result<(Vec<ExtractedFile>, bool, Vec<String>)> {
+    fs::create_dir_all(destination)?;
+    let mut files = Vec::new();
+    let mut found = HashMap::new();
+    let mut manifest = None;
+    let mut skipped = Vec::new();
+
+    for entry in open(archive, compression)?.entries()? {
+        let mut entry = entry?;
+        let member = entry.path()?.into_owned();
+        let name = archive_path(&member);
+        if name == MANIFEST_NAME {
+            manifest = Some(serde_json::from_reader::<_, Vec<ManifestEntry>>(
+                &mut entry,
+            )?);
+            continue;
+        }
+        let target = member_destination(destination, &member)?;
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_dir() {
+            rate_limiter.throttle_metadata(1);
+            fs::create_dir_all(&target)?;
+        } else if entry_type.is_file() {
+            rate_limiter.throttle_file();
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mode = entry.header().mode().ok();
+            let mut reader = HashingReader::new(&mut entry);
+            let mut writer = ThrottledWriter::new(File::create(&target)?, rate_limiter);
+            io::copy(&mut reader, &mut writer)
+                .with_context(|| format!("cannot extract {}", name))?;
+            let file = writer.into_inner();
+            file.sync_all()?;
+            #[cfg(unix)]
+            if let Some(mode) = mode {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&target, fs::Permissions::from_mode(mode))?;
+            }
+            let (size, sha256) = reader.finish();
+            found.insert(name.clone(), (size, sha256.clone()));
+            files.push(ExtractedFile {
+                path: name,
+                destination: target,
+                size,
+                sha256: Some(sha256),
+                problem: None,
+            });
+        } else {
+            skipped.push(format!("{} ({:?} entry)", name, entry_type));
+        }
+    }
+
+    let Some(manifest) = manifest else {
+        return Ok((files, false, skipped));
+    };
+    let mut problems = check_manifest(&manifest, &found);
+    for file in &mut files {
+        file.problem = problems.remove(&file.path);
+        if file.problem.is_some() {
+            rate_limiter.throttle_metadata(1);
+            let _ = fs::remove_file(&file.destination);
+        }
+    }
+    // What's left is listed in the manifest but wasn't in the archive
+    let mut missing: Vec<(String, String)> = problems.into_iter().collect();
+    missing.sort();
+    for (path, problem) in missing {
+        let size = manifest
+            .iter()
+            .find(|listed| listed.path == path)
+            .map_or(0, |listed| listed.size);
+        files.push(ExtractedFile {
+            destination: member_destination(destination, Path::new(&path))?,
+            path,
+            size,
+            sha256: None,
+            problem: Some(problem),
+        });
+    }
+    Ok((files, true, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, unlimited};
+
+    fn listed(path: &str, size: u64, sha256: &str) -> ManifestEntry {
+        ManifestEntry {
+            path: path.to_string(),
+            size,
+            sha256: sha256.to_string(),
+        }
+    }
+
+    #[test]
+    fn check_manifest_reports_each_kind_of_problem() {
+        let manifest = [
+            listed("same.txt", 3, "aaa"),
+            listed("changed.txt", 3, "bbb"),
+            listed("resized.txt", 3, "ccc"),
+            listed("missing.txt", 3, "ddd"),
+        ];
+        let found = HashMap::from([
+            ("same.txt".to_string(), (3, "aaa".to_string())),
+            ("changed.txt".to_string(), (3, "fff".to_string())),
+            ("resized.txt".to_string(), (4, "ccc".to_string())),
+            ("extra.txt".to_string(), (1, "eee".to_string())),
+        ]);
+        let problems = check_manifest(&manifest, &found);
+        assert_eq!(problems.len(), 4);
+        assert!(!problems.contains_key("same.txt"));
+        assert_eq!(
+            problems["changed.txt"],
+            "hash does not match the archive manifest"
+        );
+        assert_eq!(
+            problems["resized.txt"],
+            "hash does not match the archive manifest"
+        );
+        assert_eq!(problems["missing.txt"], "missing from the archive");
+        assert_eq!(problems["extra.txt"], "not listed in the archive manifest");
+    }
+
+    #[test]
+    fn member_destination_stays_inside() {
+        let destination = Path::new("/restore");
+        assert_eq!(
+            member_destination(destination, Path::new("./docs/a.txt")).unwrap(),
+            Path::new("/restore/docs/a.txt")
+        );
+        for unsafe_member in ["../a.txt", "docs/../../a.txt", "/etc/passwd"] {
+            assert!(
+                member_destination(destination, Path::new(unsafe_member)).is_err(),
+                "{} was accepted",
+                unsafe_member
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn create_reports_skipped_symlinks_and_extracts_verified() {
+        let dir = TempDir::new("archive");
+        let origin = dir.join("origin");
+        fs::create_dir_all(origin.join("docs")).unwrap();
+        fs::write(origin.join("docs/a.txt"), "hello").unwrap();
+        std::os::unix::fs::symlink("docs/a.txt", origin.join("link")).unwrap();
+        let tar = dir.join("out.tar");
+
+        let (manifest, skipped) = create(&origin, &tar, None, &mut unlimited()).unwrap();
+        assert_eq!(manifest.len(), 1);
+        assert_eq!(manifest[0].path, "docs/a.txt");
+        assert_eq!(skipped, ["link (symlink)"]);
+        assert!(verify(&tar, None).unwrap().is_empty());
+
+        let restored = dir.join("restored");
+        let (files, has_manifest, skipped) =
+            extract(&tar, &restored, None, &mut unlimited()).unwrap();
+        assert!(has_manifest);
+        assert!(skipped.is_empty());
+        assert_eq!(files.len(), 1);
+        assert!(files[0].problem.is_none());
+        assert_eq!(fs::read(restored.join("docs/a.txt")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn extract_removes_files_the_manifest_does_not_list() {
+        let dir = TempDir::new("archive");
+        let tar = dir.join("out.tar");
+        let mut builder = tar::Builder::new(File::create(&tar).unwrap());
+        let mut append = |name: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, data).unwrap();
+        };
+        append("listed.txt", b"kept");
+        append("extra.txt", b"planted");
+        let manifest = [listed(
+            "listed.txt",
+            4,
+            &format!("{:x}", Sha256::digest(b"kept")),
+        )];
+        append(MANIFEST_NAME, &serde_json::to_vec(&manifest).unwrap());
+        builder.into_inner().unwrap();
+
+        let restored = dir.join("restored");
+        let (files, _, _) = extract(&tar, &restored, None, &mut unlimited()).unwrap();
+        let extra = files.iter().find(|f| f.path == "extra.txt").unwrap();
+        assert_eq!(
+            extra.problem.as_deref(),
+            Some("not listed in the archive manifest")
+        );
+        assert!(!restored.join("extra.txt").exists());
+        assert!(restored.join("listed.txt").exists());
+    }
+}
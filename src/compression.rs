@@ -0,0 +1,302 @@
+use crate::config::Compression;
+use crate::crypto::{DecryptReader, Key};
+use flate2::Compression as GzipLevel;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+impl Compression {
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// The compression a file name's suffix indicates, if any.
+    pub fn detect(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy();
+        [Compression::Gzip, Compression::Zstd]
+            .into_iter()
+            .find(|compression| name.ends_with(compression.suffix()))
+    }
+}
+
+/// Where a copy of a file bound for `destination` is written: the same
+/// path with the compression suffix appended, e.g. `app.log.gz`.
+pub fn compressed_path(destination: &Path, compression: Option<Compression>) -> PathBuf {
+    let Some(compression) = compression else {
+        return destination.to_path_buf();
+    };
+    let mut name = OsString::from(destination.as_os_str());
+    name.push(compression.suffix());
+    PathBuf::from(name)
+}
+
+/// The reverse of `compressed_path`, for destinations read back from a
+/// report. Paths without the suffix are returned unchanged.
+pub fn strip_suffix(destination: &Path, compression: Option<Compression>) -> PathBuf {
+    let Some(compression) = compression else {
+        return destination.to_path_buf();
+    };
+    let text = destination.to_string_lossy();
+    match text.strip_suffix(compression.suffix()) {
+        Some(stripped) => PathBuf::from(stripped),
+        None => destination.to_path_buf(),
+    }
+}
+
+/// Counts the bytes that reach the underlying writer, so rate limiting
+/// can apply to what is actually written rather than to the input.
+pub struct CountingWriter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, written: 0 }
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A writer that compresses into `W`, or passes data through unchanged.
+pub enum Encoder<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(inner: W, compression: Option<Compression>) -> io::Result<Self> {
+        Ok(match compression {
+            None => Encoder::Plain(inner),
+            Some(Compression::Gzip) => Encoder::Gzip(GzEncoder::new(inner, GzipLevel::default())),
+            Some(Compression::Zstd) => Encoder::Zstd(zstd::Encoder::new(inner, 0)?),
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            Encoder::Plain(inner) => inner,
+            Encoder::Gzip(encoder) => encoder.get_mut(),
+            Encoder::Zstd(encoder) => encoder.get_mut(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        match self {
+            Encoder::Plain(inner) => inner,
+            Encoder::Gzip(encoder) => encoder.get_ref(),
+            Encoder::Zstd(encoder) => encoder.get_ref(),
+        }
+    }
+
+    /// Writes out whatever the compressor still holds and returns the
+    /// underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Plain(inner) => Ok(inner),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(inner) => inner.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(inner) => inner.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Opens `path` for reading, decrypting and then decompressing it on the
+/// fly.
+pub fn open_decoded(
+    path: &Path,
+    compression: Option<Compression>,
+    key: Option<&Key>,
+) -> io::Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+    let raw: Box<dyn Read> = match key {
+        Some(key) => Box::new(DecryptReader::new(BufReader::new(file), key)?),
+        None => Box::new(file),
+    };
+    Ok(match compression {
+        None => raw,
+        Some(Compression::Gzip) => Box::new(GzDecoder::new(BufReader::new(raw))),
+        Some(Compression::Zstd) => Box::new(zstd::Decoder::new(raw)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{self, EncryptWriter};
+    use crate::test_util::TempDir;
+    use crate::validation;
+    use std::fs;
+
+    const COMPRESSIONS: [Option<Compression>; 3] =
+        [None, Some(Compression::Gzip), Some(Compression::Zstd)];
+
+    fn sample() -> Vec<u8> {
+        // Compressible, and longer than one encryption chunk
+        (0..200_000u32)
+            .flat_map(|i| (i % 97).to_le_bytes())
+            .collect()
+    }
+
+    fn load_key(dir: &Path) -> Key {
+        let path = dir.join("key");
+        fs::write(&path, "42".repeat(32)).unwrap();
+        Key::load(&path).unwrap()
+    }
+
+    // Writes `data` the way a copy does: compressed, then encrypted
+    fn write_copy(path: &Path, data: &[u8], compression: Option<Compression>, key: Option<&Key>) {
+        let sealed = EncryptWriter::new(File::create(path).unwrap(), key).unwrap();
+        let mut writer = Encoder::new(sealed, compression).unwrap();
+        for piece in data.chunks(7000) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish().unwrap().finish(None).unwrap();
+    }
+
+    fn read_copy(path: &Path, compression: Option<Compression>, key: Option<&Key>) -> Vec<u8> {
+        let mut data = Vec::new();
+        open_decoded(path, compression, key)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn encoder_round_trips_through_open_decoded() {
+        let dir = TempDir::new("compression");
+        let key = load_key(&dir);
+        let data = sample();
+        for compression in COMPRESSIONS {
+            for key in [None, Some(&key)] {
+                let path = dir.join("copy");
+                write_copy(&path, &data, compression, key);
+                let written = fs::read(&path).unwrap();
+                if compression.is_some() && key.is_none() {
+                    assert!(
+                        written.len() < data.len() / 4,
+                        "{compression:?} didn't shrink"
+                    );
+                }
+                if key.is_some() {
+                    assert!(!written.windows(16).any(|w| w == &data[..16]));
+                }
+                assert_eq!(
+                    read_copy(&path, compression, key),
+                    data,
+                    "{compression:?}, encrypted: {}",
+                    key.is_some()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn empty_file_round_trips() {
+        let dir = TempDir::new("compression");
+        let key = load_key(&dir);
+        for compression in COMPRESSIONS {
+            let path = dir.join("empty");
+            write_copy(&path, &[], compression, Some(&key));
+            assert!(read_copy(&path, compression, Some(&key)).is_empty());
+        }
+    }
+
+    #[test]
+    fn suffixes_are_added_detected_and_stripped() {
+        let plain = Path::new("/backup/app.log");
+        assert_eq!(compressed_path(plain, None), plain);
+        for (compression, name) in [
+            (Compression::Gzip, "/backup/app.log.gz"),
+            (Compression::Zstd, "/backup/app.log.zst"),
+        ] {
+            let compressed = compressed_path(plain, Some(compression));
+            assert_eq!(compressed, Path::new(name));
+            assert_eq!(Compression::detect(&compressed), Some(compression));
+            assert_eq!(strip_suffix(&compressed, Some(compression)), plain);
+            // Only the suffix of the compression in use is removed
+            assert_eq!(strip_suffix(plain, Some(compression)), plain);
+        }
+        assert_eq!(
+            strip_suffix(Path::new("/backup/app.log.gz"), Some(Compression::Zstd)),
+            Path::new("/backup/app.log.gz")
+        );
+        assert_eq!(Compression::detect(plain), None);
+        assert_eq!(Compression::detect(Path::new("/backup/zst")), None);
+        // With encryption the compression suffix comes first
+        let sealed = crypto::encrypted_path(&compressed_path(plain, Some(Compression::Zstd)));
+        assert_eq!(sealed, Path::new("/backup/app.log.zst.enc"));
+        let unsealed = crypto::strip_suffix(&sealed).unwrap();
+        assert_eq!(strip_suffix(&unsealed, Some(Compression::Zstd)), plain);
+    }
+
+    #[test]
+    fn compressed_copy_verifies_against_the_source() {
+        let dir = TempDir::new("compression");
+        let source = dir.join("source");
+        let data = sample();
+        fs::write(&source, &data).unwrap();
+        let expected = validation::calculate_sha256(&source).unwrap();
+
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let copy = compressed_path(&dir.join("copy"), Some(compression));
+            write_copy(&copy, &data, Some(compression), None);
+            // The compressed bytes themselves differ from the source
+            assert_ne!(validation::calculate_sha256(&copy).unwrap(), expected);
+            assert_eq!(
+                validation::verify_files_match(&source, &copy, Some(compression), None).unwrap(),
+                Some(expected.clone())
+            );
+
+            // A copy of different content doesn't verify
+            write_copy(&copy, &data[1..], Some(compression), None);
+            assert_eq!(
+                validation::verify_files_match(&source, &copy, Some(compression), None).unwrap(),
+                None
+            );
+        }
+    }
+}
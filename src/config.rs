@@ -1,24 +1,74 @@
+use crate::diagnostics::SourceMap;
+use crate::preflight::Margin;
+use crate::{paths, schema};
+use chrono::{DateTime, Datelike, Local, NaiveTime, Weekday};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Default)]
+#[serde(deny_unknown_fields)]
 pub struct RateLimit {
     pub enabled: bool,
     pub bytes_per_second: Option<u64>,     // Bytes per second
     pub megabytes_per_minute: Option<u64>, // Megabytes per minute
+    // Bytes that may go out at full speed before the rate applies
+    // (64 KiB by default)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst_bytes: Option<u64>,
+    // Files started per second, for trees of many small files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files_per_second: Option<u64>,
+    // Directory and file creations and removals at the destination per
+    // second
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_ops_per_second: Option<u64>,
+    // Time-of-day windows with their own rate; outside every window the
+    // rate above applies (or none, if it is unset)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<RateWindow>,
+    // Slow down while the system is busy (per operation only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adaptive: Option<AdaptiveThrottle>,
 }
 
-impl Default for RateLimit {
-    fn default() -> Self {
-        Self {
-            enabled: false,
-            bytes_per_second: None,
-            megabytes_per_minute: None,
-        }
-    }
+/// Backs the byte rate off while the load average or the destination's
+/// write latency is above a threshold, and brings it back up once they
+/// drop. Set at least one threshold.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AdaptiveThrottle {
+    // One-minute load average
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_load_average: Option<f64>,
+    // Average time to write one chunk to the destination, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_write_latency_ms: Option<u64>,
+    // Slowest rate to back off to, such as `64KB/s` (the default)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_rate: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// A rate that applies between two times of day, optionally only on some
+/// days, e.g. `256KB/s` from `08:00` to `18:00` on `weekdays`. A window
+/// whose end is before its start runs past midnight.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RateWindow {
+    // A rate such as `256KB/s` or `10MB/min`, or `unlimited`
+    pub rate: String,
+    pub from: String,
+    pub to: String,
+    // Day names (`mon`, `tuesday`, ...), `weekdays` or `weekends`; empty
+    // means every day
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Default)]
+#[serde(deny_unknown_fields)]
 pub struct FileOperation {
     pub name: String,
     pub origin: PathBuf,
@@ -26,31 +76,987 @@ pub struct FileOperation {
     pub operation_type: OperationType,
     #[serde(default)]
     pub rate_limit: RateLimit, // NEW: Rate limiting per operation
+    // Share of the global rate limit relative to other operations running
+    // at the same time (1 by default)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Retention>,
+    // Write copies compressed, adding `.gz` or `.zst` to each file name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    // Write copies encrypted (after any compression), adding `.enc`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    // How plain copies are written; without it files are copied the
+    // ordinary way
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub copy_strategy: Option<CopyStrategy>,
+    // How often to retry a file that fails to copy or verify, and the
+    // seconds to wait before the first retry (1 by default, doubling after
+    // each one)
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub retries: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_backoff: Option<f64>,
+    // Cron expression or `every 15m`; only used by daemon mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+    // Paths as written in the config file, paired with what they expanded
+    // to at load time, so saving can keep `~` and `${VAR}` intact.
+    #[serde(skip)]
+    pub raw_origin: Option<(PathBuf, PathBuf)>,
+    #[serde(skip)]
+    pub raw_destination: Option<(PathBuf, PathBuf)>,
+    // Config file the operation was loaded from; saving writes it back there
+    #[serde(skip)]
+    pub source_file: Option<PathBuf>,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+/// How many dated snapshots to keep under a templated destination. Each
+/// `keep_*` rule keeps the newest snapshot in each of its N latest periods;
+/// anything no rule keeps is pruned after a successful run.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Retention {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_daily: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_weekly: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_monthly: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_yearly: Option<usize>,
+}
+
+impl Retention {
+    pub fn has_rules(&self) -> bool {
+        [
+            self.keep_last,
+            self.keep_daily,
+            self.keep_weekly,
+            self.keep_monthly,
+            self.keep_yearly,
+        ]
+        .iter()
+        .any(|n| n.is_some_and(|n| n > 0))
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+/// How a plain copy moves its data. `auto` clones the file where the
+/// filesystem supports reflinks (btrfs, XFS), otherwise keeps the holes of
+/// sparse files and copies the rest with `copy_file_range`.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CopyStrategy {
+    Auto,
+    // Fail rather than copy when the file can't be cloned
+    Reflink,
+    Sparse,
+    CopyFileRange,
+    // Read and write in user space
+    Buffered,
+}
+
+/// AES-256-GCM encryption of copies at rest. The key lives in its own file
+/// so the config can be shared without it.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Encryption {
+    // File holding the key as 32 raw bytes or 64 hex digits
+    pub key_file: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq, Default)]
 pub enum OperationType {
+    #[default]
     Copy,
     Move,
+    // Pack the origin directory into one tar file at the destination
+    Archive,
+    // Unpack an archive made by `Archive` into the destination directory
+    Extract,
+    // Decrypt `.enc` files made by an encrypted copy into the destination
+    Decrypt,
+    // Store the origin directory in a content-addressed repository at the
+    // destination: each distinct file content once, plus a snapshot per run
+    Repository,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    // Schema version the file was written for; see `schema::migrate`
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
     pub operations: Vec<FileOperation>,
     #[serde(default)]
     pub global_rate_limit: RateLimit, // NEW: Global rate limit
+    // Space to leave free on each destination filesystem, as a size such
+    // as `2GiB` or a percentage of the filesystem such as `5%`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub free_space_margin: Option<String>,
+    // Every file that contributed to this config, in load order. The first
+    // one is the primary file passed on the command line.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub files: Vec<ConfigFile>,
+    // Set when the built-in defaults stand in for config files that failed
+    // to load, so saving must not overwrite them
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub fallback: bool,
+}
+
+/// One file that was read while loading a config, kept so each operation
+/// can be saved back where it came from.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    pub variables: BTreeMap<String, String>,
+    pub include: Vec<String>,
+    pub source: Option<String>,
+    // Only the primary file's global_rate_limit applies
+    pub sets_global_rate_limit: bool,
+    // Schema version the file was upgraded from while loading
+    pub migrated_from: Option<u32>,
+}
+
+impl ConfigFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            ..Default::default()
+        }
+    }
+}
+
+// What an included file is saved as: it has no global_rate_limit of its own
+#[derive(Serialize)]
+struct IncludedFile<'a> {
+    version: u32,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    variables: &'a BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    include: &'a Vec<String>,
+    operations: Vec<FileOperation>,
+}
+
+/// On-disk config syntax, chosen by file extension. All three carry the
+/// same schema; anything that isn't `.toml` or `.json` is read as YAML.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    pub fn from_path(path: &Path) -> Self {
+        match path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .as_deref()
+        {
+            Some("toml") => ConfigFormat::Toml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(self, content: &str) -> anyhow::Result<T> {
+        Ok(match self {
+            ConfigFormat::Yaml => serde_yaml::from_str(content)?,
+            ConfigFormat::Toml => toml::from_str(content)?,
+            ConfigFormat::Json => serde_json::from_str(content)?,
+        })
+    }
+
+    pub fn render<T: Serialize>(self, value: &T) -> anyhow::Result<String> {
+        Ok(match self {
+            ConfigFormat::Yaml => serde_yaml::to_string(value)?,
+            ConfigFormat::Toml => toml::to_string_pretty(value)?,
+            ConfigFormat::Json => serde_json::to_string_pretty(value)? + "\n",
+        })
+    }
 }
 
 impl Config {
-    pub fn load_from_file(path: &str) -> anyhow::Result<Self> {
-        let content = std::fs::read_to_string(path)?;
-        let config: Config = serde_yaml::from_str(&content)?;
+    /// Loads one or more config files, following each file's `include:`
+    /// list, and merges all of their operations. The first file supplies
+    /// `global_rate_limit` and `free_space_margin`. A file reached twice is only loaded once.
+    pub fn load_from_files<P: AsRef<Path>>(paths: &[P]) -> anyhow::Result<Self> {
+        let mut config = Config {
+            version: schema::CURRENT_VERSION,
+            variables: BTreeMap::new(),
+            include: Vec::new(),
+            operations: Vec::new(),
+            global_rate_limit: RateLimit::default(),
+            free_space_margin: None,
+            files: Vec::new(),
+            fallback: false,
+        };
+        let mut loaded = HashSet::new();
+        for (idx, path) in paths.iter().enumerate() {
+            let path = path.as_ref();
+            if !path.exists() {
+                anyhow::bail!("{}: file not found", path.display());
+            }
+            config.load_tree(path, &BTreeMap::new(), idx == 0, &mut loaded)?;
+        }
         Ok(config)
     }
 
+    fn load_tree(
+        &mut self,
+        path: &Path,
+        inherited: &BTreeMap<String, String>,
+        primary: bool,
+        loaded: &mut HashSet<PathBuf>,
+    ) -> anyhow::Result<()> {
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        if !loaded.insert(canonical) {
+            return Ok(());
+        }
+
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let format = ConfigFormat::from_path(path);
+        let mut document: serde_json::Value = format
+            .parse(&content)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let migrated_from = schema::migrate(&mut document)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+
+        let source_map = (format == ConfigFormat::Yaml).then(|| SourceMap::parse(&content));
+        let unknown = schema::unknown_fields(&document, source_map.as_ref());
+        if !unknown.is_empty() {
+            let lines: Vec<String> = unknown
+                .iter()
+                .map(|problem| format!("{}: {}", path.display(), problem))
+                .collect();
+            anyhow::bail!("{}", lines.join("\n"));
+        }
+
+        // Parse the original text when possible so type errors keep their
+        // line numbers; a migrated document only exists as a value.
+        let parsed = match migrated_from {
+            Some(_) => serde_json::from_value(document).map_err(anyhow::Error::from),
+            None => format.parse(&content),
+        };
+        let mut file: Config = parsed.map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let scope = file
+            .expand_paths(inherited)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+
+        let sets_global_rate_limit = file.global_rate_limit.enabled
+            || file.global_rate_limit.bytes_per_second.is_some()
+            || file.global_rate_limit.megabytes_per_minute.is_some();
+        if primary {
+            self.variables = file.variables.clone();
+            self.include = file.include.clone();
+            self.global_rate_limit = file.global_rate_limit.clone();
+            self.free_space_margin = file.free_space_margin.clone();
+        }
+        for op in &mut file.operations {
+            op.source_file = Some(path.to_path_buf());
+        }
+        self.operations.append(&mut file.operations);
+        self.files.push(ConfigFile {
+            path: path.to_path_buf(),
+            variables: file.variables,
+            include: file.include.clone(),
+            source: Some(content),
+            sets_global_rate_limit,
+            migrated_from,
+        });
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        for pattern in &file.include {
+            for included in resolve_include(pattern, base, &scope)
+                .map_err(|e| anyhow::anyhow!("{}: include '{}': {}", path.display(), pattern, e))?
+            {
+                self.load_tree(&included, &scope, false, loaded)?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn save_to_file(&self, path: &str) -> anyhow::Result<()> {
-        let content = serde_yaml::to_string(self)?;
+        let content = ConfigFormat::from_path(Path::new(path)).render(&self.unexpanded())?;
         std::fs::write(path, content)?;
         Ok(())
     }
+
+    /// Writes every operation back to the file it was loaded from. Returns
+    /// the files written.
+    pub fn save(&self) -> anyhow::Result<Vec<PathBuf>> {
+        if self.fallback {
+            anyhow::bail!(
+                "the config failed to load and these are the built-in defaults; fix the file and restart instead"
+            );
+        }
+        let mut written = Vec::new();
+        for (path, content) in self.render_files()? {
+            std::fs::write(&path, content)
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+            written.push(path);
+        }
+        Ok(written)
+    }
+
+    /// What each config file would be saved as, in load order.
+    pub fn render_files(&self) -> anyhow::Result<Vec<(PathBuf, String)>> {
+        self.files
+            .iter()
+            .enumerate()
+            .map(|(idx, file)| {
+                let content = self.render_file(idx, ConfigFormat::from_path(&file.path))?;
+                Ok((file.path.clone(), content))
+            })
+            .collect()
+    }
+
+    /// Writes the primary config file to `output` in the format its
+    /// extension names. Includes are carried over as references, not inlined.
+    pub fn convert_to(&self, output: &Path) -> anyhow::Result<()> {
+        let content = self.render_file(0, ConfigFormat::from_path(output))?;
+        std::fs::write(output, content)
+            .map_err(|e| anyhow::anyhow!("{}: {}", output.display(), e))?;
+        Ok(())
+    }
+
+    fn render_file(&self, idx: usize, format: ConfigFormat) -> anyhow::Result<String> {
+        let Some(primary) = self.files.first() else {
+            anyhow::bail!("no config file to save to");
+        };
+        let file = &self.files[idx];
+        let unexpanded = self.unexpanded();
+        let operations: Vec<FileOperation> = unexpanded
+            .operations
+            .iter()
+            .filter(|op| op.source_file.as_ref().unwrap_or(&primary.path) == &file.path)
+            .cloned()
+            .collect();
+
+        if idx == 0 {
+            format.render(&Config {
+                version: schema::CURRENT_VERSION,
+                operations,
+                ..unexpanded
+            })
+        } else {
+            format.render(&IncludedFile {
+                version: schema::CURRENT_VERSION,
+                variables: &file.variables,
+                include: &file.include,
+                operations,
+            })
+        }
+    }
+
+    /// Expands `~` and variables in every operation's origin and destination.
+    /// `inherited` holds the variables of the file that included this one;
+    /// the file's own `variables:` override them. Returns the combined set.
+    pub fn expand_paths(
+        &mut self,
+        inherited: &BTreeMap<String, String>,
+    ) -> anyhow::Result<BTreeMap<String, String>> {
+        let mut variables = inherited.clone();
+        for (name, value) in &self.variables {
+            let value = paths::expand_vars(value, inherited)
+                .map_err(|e| anyhow::anyhow!("variables.{}: {}", name, e))?;
+            variables.insert(name.clone(), value);
+        }
+
+        for op in &mut self.operations {
+            let expand = |field: &str,
+                          path: &PathBuf|
+             -> anyhow::Result<Option<(PathBuf, PathBuf)>> {
+                let raw = path.to_string_lossy();
+                let expanded = paths::expand_vars(&raw, &variables)
+                    .map_err(|e| anyhow::anyhow!("operation '{}': {}: {}", op.name, field, e))?;
+                Ok((expanded != raw).then(|| (path.clone(), PathBuf::from(expanded))))
+            };
+            let raw_origin = expand("origin", &op.origin)?;
+            let raw_destination = expand("destination", &op.destination)?;
+
+            if let Some((_, expanded)) = &raw_origin {
+                op.origin = expanded.clone();
+            }
+            if let Some((_, expanded)) = &raw_destination {
+                op.destination = expanded.clone();
+            }
+            op.raw_origin = raw_origin;
+            op.raw_destination = raw_destination;
+        }
+        Ok(variables)
+    }
+
+    /// The parsed `free_space_margin`; none when it is unset or invalid
+    /// (the config check reports invalid values).
+    pub fn space_margin(&self) -> Margin {
+        Margin::parse(self.free_space_margin.as_deref()).unwrap_or_default()
+    }
+
+    /// Copy of the config with paths put back the way they were written,
+    /// except where they have been edited since loading.
+    pub fn unexpanded(&self) -> Config {
+        let mut config = self.clone();
+        for op in &mut config.operations {
+            if let Some((raw, expanded)) = &op.raw_origin
+                && *expanded == op.origin
+            {
+                op.origin = raw.clone();
+            }
+            if let Some((raw, expanded)) = &op.raw_destination
+                && *expanded == op.destination
+            {
+                op.destination = raw.clone();
+            }
+        }
+        config
+    }
+}
+
+/// Files matched by one `include:` entry. Relative patterns are resolved
+/// against the including file's directory. A plain path must exist; a glob
+/// that matches nothing is allowed.
+fn resolve_include(
+    pattern: &str,
+    base: &Path,
+    variables: &BTreeMap<String, String>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let expanded = PathBuf::from(paths::expand_vars(pattern, variables)?);
+    let full = if expanded.is_absolute() {
+        expanded
+    } else {
+        base.join(expanded)
+    };
+    let full_text = full.to_string_lossy();
+
+    if !full_text.contains(['*', '?', '[']) {
+        if !full.is_file() {
+            anyhow::bail!("{} does not exist", full.display());
+        }
+        return Ok(vec![full]);
+    }
+
+    let mut matches: Vec<PathBuf> = glob::glob(&full_text)?
+        .filter_map(Result::ok)
+        .filter(|p| p.is_file())
+        // Lock files of running configs sit next to them
+        .filter(|p| p.extension().is_none_or(|ext| ext != "lock"))
+        .collect();
+    matches.sort();
+    Ok(matches)
+}
+
+/// Parses a human-readable transfer rate such as `262144`, `256KB/s`,
+/// `10MiB/s` or `600MB/min` into bytes per second.
+///
+/// A bare number is taken to be in `default_unit` per `default_period`
+/// seconds, so the same parser serves both the bytes-per-second and the
+/// megabytes-per-minute fields. Binary multiples are used throughout
+/// (1 KB = 1024 bytes), matching how the rest of the tool reports sizes.
+pub fn parse_rate(input: &str, default_unit: u64, default_period: u64) -> anyhow::Result<u64> {
+    let input = input.trim();
+    if input.is_empty() {
+        anyhow::bail!("rate is empty");
+    }
+
+    let (amount, period) = match input.split_once('/') {
+        Some((amount, period)) => {
+            let seconds = match period.trim().to_lowercase().as_str() {
+                "s" | "sec" | "second" => 1,
+                "m" | "min" | "minute" => 60,
+                "h" | "hr" | "hour" => 3600,
+                other => anyhow::bail!("unknown time unit '{}' (use s, min or h)", other),
+            };
+            (amount.trim(), Some(seconds))
+        }
+        None => (input, None),
+    };
+
+    let split_at = amount
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(amount.len());
+    let (number, unit) = amount.split_at(split_at);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow::anyhow!("'{}' is not a number", input))?;
+
+    let multiplier = match unit.trim().to_lowercase().as_str() {
+        "" => {
+            if period.is_none() {
+                default_unit
+            } else {
+                1
+            }
+        }
+        "b" => 1,
+        "k" | "kb" | "kib" => 1024,
+        "m" | "mb" | "mib" => 1024 * 1024,
+        "g" | "gb" | "gib" => 1024 * 1024 * 1024,
+        other => anyhow::bail!("unknown size unit '{}' (use B, KiB, MiB or GiB)", other),
+    };
+
+    let seconds = period.unwrap_or(default_period);
+    let bytes_per_second = number * multiplier as f64 / seconds as f64;
+    if bytes_per_second < 1.0 {
+        anyhow::bail!("rate '{}' is below 1 byte per second", input);
+    }
+
+    Ok(bytes_per_second as u64)
+}
+
+/// Formats a byte count with the largest fitting binary unit.
+pub fn format_size(bytes: u64) -> String {
+    let value = bytes as f64;
+    if value >= 1024.0 * 1024.0 * 1024.0 {
+        format!("{:.2} GiB", value / (1024.0 * 1024.0 * 1024.0))
+    } else if value >= 1024.0 * 1024.0 {
+        format!("{:.2} MiB", value / (1024.0 * 1024.0))
+    } else if value >= 1024.0 {
+        format!("{:.2} KiB", value / 1024.0)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+// Rounded rather than truncated, and in floating point so large values
+// can't overflow
+fn megabytes_per_minute_to_bps(megabytes_per_minute: u64) -> u64 {
+    (megabytes_per_minute as f64 * 1024.0 * 1024.0 / 60.0).round() as u64
+}
+
+pub fn format_rate(bytes_per_second: u64) -> String {
+    format!("{}/s", format_size(bytes_per_second))
+}
+
+impl RateLimit {
+    /// Effective limit in bytes per second, `None` when unthrottled.
+    pub fn effective_bytes_per_second(&self) -> Option<u64> {
+        if !self.enabled {
+            return None;
+        }
+        self.bytes_per_second
+            .or_else(|| self.megabytes_per_minute.map(megabytes_per_minute_to_bps))
+    }
+
+    /// Limit in bytes per second at `now`: the rate of the first schedule
+    /// window covering `now`, otherwise the fixed rate. `None` when
+    /// unthrottled. Invalid windows are skipped (the config check reports
+    /// them).
+    pub fn rate_at(&self, now: DateTime<Local>) -> Option<u64> {
+        if !self.enabled {
+            return None;
+        }
+        for window in &self.schedule {
+            if window.covers(now).unwrap_or(false)
+                && let Ok(rate) = window.bytes_per_second()
+            {
+                return rate;
+            }
+        }
+        self.effective_bytes_per_second()
+    }
+
+    /// Whether this limit ever throttles anything.
+    pub fn is_active(&self) -> bool {
+        self.limits_bytes()
+            || (self.enabled
+                && (self.files_per_second.is_some() || self.metadata_ops_per_second.is_some()))
+    }
+
+    /// Whether this limit ever throttles the byte rate.
+    pub fn limits_bytes(&self) -> bool {
+        self.enabled
+            && (self.effective_bytes_per_second().is_some()
+                || !self.schedule.is_empty()
+                || self.adaptive.is_some())
+    }
+
+    pub fn describe(&self) -> String {
+        if !self.enabled {
+            return "disabled".to_string();
+        }
+        let mut parts = Vec::new();
+        let base = self.effective_bytes_per_second().map(format_rate);
+        if !self.schedule.is_empty() {
+            let windows: Vec<String> = self.schedule.iter().map(RateWindow::describe).collect();
+            parts.push(format!(
+                "{}; {} otherwise",
+                windows.join("; "),
+                base.unwrap_or_else(|| "unlimited".to_string())
+            ));
+        } else if let Some(base) = base {
+            parts.push(base);
+        }
+        if let Some(files) = self.files_per_second {
+            parts.push(format!("{} files/s", files));
+        }
+        if let Some(ops) = self.metadata_ops_per_second {
+            parts.push(format!("{} metadata ops/s", ops));
+        }
+        if let Some(adaptive) = &self.adaptive {
+            parts.push(format!("adaptive ({})", adaptive.describe()));
+        }
+        if parts.is_empty() {
+            return "enabled (no rate set)".to_string();
+        }
+        parts.join(", ")
+    }
+}
+
+impl RateWindow {
+    /// The window's rate in bytes per second, `None` for `unlimited`.
+    pub fn bytes_per_second(&self) -> anyhow::Result<Option<u64>> {
+        match self.rate.trim().to_lowercase().as_str() {
+            "unlimited" | "none" => Ok(None),
+            _ => parse_rate(&self.rate, 1, 1).map(Some),
+        }
+    }
+
+    /// Whether `now` falls inside the window. For a window that runs past
+    /// midnight, the early-morning part belongs to the previous day's entry
+    /// in `days`.
+    pub fn covers(&self, now: DateTime<Local>) -> anyhow::Result<bool> {
+        let from = parse_time_of_day(&self.from)?;
+        let to = parse_time_of_day(&self.to)?;
+        let days = self.weekdays()?;
+        let on = |day: Weekday| days.is_empty() || days.contains(&day);
+
+        let time = now.time();
+        let today = now.weekday();
+        Ok(if from <= to {
+            on(today) && time >= from && time < to
+        } else {
+            (on(today) && time >= from) || (on(today.pred()) && time < to)
+        })
+    }
+
+    fn weekdays(&self) -> anyhow::Result<Vec<Weekday>> {
+        use Weekday::*;
+        let mut days = Vec::new();
+        for name in &self.days {
+            match name.trim().to_lowercase().as_str() {
+                "weekdays" => days.extend([Mon, Tue, Wed, Thu, Fri]),
+                "weekends" => days.extend([Sat, Sun]),
+                other => days.push(
+                    other
+                        .parse::<Weekday>()
+                        .map_err(|_| anyhow::anyhow!("unknown day '{}'", name))?,
+                ),
+            }
+        }
+        Ok(days)
+    }
+
+    pub fn describe(&self) -> String {
+        let mut text = format!("{} {}-{}", self.rate, self.from, self.to);
+        if !self.days.is_empty() {
+            text.push_str(&format!(" {}", self.days.join(",")));
+        }
+        text
+    }
+}
+
+/// Floor for backing off when `min_rate` is unset.
+pub const DEFAULT_ADAPTIVE_MIN_RATE: u64 = 64 * 1024;
+
+impl AdaptiveThrottle {
+    pub fn min_bytes_per_second(&self) -> anyhow::Result<u64> {
+        match &self.min_rate {
+            Some(rate) => parse_rate(rate, 1, 1),
+            None => Ok(DEFAULT_ADAPTIVE_MIN_RATE),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(load) = self.max_load_average {
+            parts.push(format!("load average above {:.2}", load));
+        }
+        if let Some(latency) = self.max_write_latency_ms {
+            parts.push(format!("write latency above {} ms", latency));
+        }
+        let floor = self
+            .min_bytes_per_second()
+            .map(format_rate)
+            .unwrap_or_else(|_| self.min_rate.clone().unwrap_or_default());
+        parts.push(format!("not below {}", floor));
+        parts.join(", ")
+    }
+}
+
+// `HH:MM`; `24:00` is accepted as the end of the day
+fn parse_time_of_day(input: &str) -> anyhow::Result<NaiveTime> {
+    let input = input.trim();
+    if input == "24:00" {
+        return Ok(NaiveTime::MIN);
+    }
+    NaiveTime::parse_from_str(input, "%H:%M")
+        .map_err(|_| anyhow::anyhow!("invalid time '{}' (use HH:MM)", input))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::fs;
+
+    const PRIMARY: &str = "version: 3
+variables:
+  root: /srv
+include:
+  - conf.d/*.yaml
+operations:
+  - name: docs
+    origin: ${root}/docs
+    destination: /backup/docs
+    operation_type: Copy
+    rate_limit:
+      enabled: true
+      bytes_per_second: 1000
+global_rate_limit:
+  enabled: true
+  megabytes_per_minute: 60
+free_space_margin: 5%
+";
+
+    const INCLUDED: &str = "version: 3
+operations:
+  - name: photos
+    origin: ${root}/photos
+    destination: /backup/photos
+    operation_type: Move
+    retries: 2
+";
+
+    // A primary config that includes one more file through a glob
+    fn write_tree(dir: &Path) -> PathBuf {
+        fs::create_dir(dir.join("conf.d")).unwrap();
+        fs::write(dir.join("conf.d/photos.yaml"), INCLUDED).unwrap();
+        let primary = dir.join("config.yaml");
+        fs::write(&primary, PRIMARY).unwrap();
+        primary
+    }
+
+    fn as_value(config: &Config) -> serde_json::Value {
+        serde_json::to_value(config).unwrap()
+    }
+
+    #[test]
+    fn includes_are_loaded_with_the_including_files_variables() {
+        let dir = TempDir::new("config");
+        let primary = write_tree(&dir);
+        let config = Config::load_from_files(&[&primary]).unwrap();
+
+        let names: Vec<&str> = config
+            .operations
+            .iter()
+            .map(|op| op.name.as_str())
+            .collect();
+        assert_eq!(names, ["docs", "photos"]);
+        assert_eq!(config.operations[1].origin, PathBuf::from("/srv/photos"));
+        assert_eq!(
+            config.operations[1].source_file.as_deref(),
+            Some(dir.join("conf.d/photos.yaml").as_path())
+        );
+        let files: Vec<&Path> = config.files.iter().map(|f| f.path.as_path()).collect();
+        assert_eq!(files, [primary.as_path(), &dir.join("conf.d/photos.yaml")]);
+        assert!(config.files[0].sets_global_rate_limit);
+        assert!(!config.files[1].sets_global_rate_limit);
+        assert_eq!(config.free_space_margin.as_deref(), Some("5%"));
+    }
+
+    #[test]
+    fn a_file_reached_twice_is_loaded_once() {
+        let dir = TempDir::new("config");
+        let primary = write_tree(&dir);
+        let config =
+            Config::load_from_files(&[primary.clone(), dir.join("conf.d/photos.yaml")]).unwrap();
+        assert_eq!(config.operations.len(), 2);
+        assert_eq!(config.files.len(), 2);
+    }
+
+    #[test]
+    fn include_globs_skip_lock_files() {
+        let dir = TempDir::new("config");
+        let primary = write_tree(&dir);
+        let _locks = crate::lock::RunLock::acquire_all([
+            primary.as_path(),
+            dir.join("conf.d/photos.yaml").as_path(),
+        ])
+        .unwrap();
+        fs::write(&primary, PRIMARY.replace("conf.d/*.yaml", "conf.d/*")).unwrap();
+        let config = Config::load_from_files(&[&primary]).unwrap();
+        assert_eq!(config.operations.len(), 2);
+    }
+
+    #[test]
+    fn missing_include_is_an_error() {
+        let dir = TempDir::new("config");
+        let primary = dir.join("config.yaml");
+        fs::write(&primary, "include:\n  - missing.yaml\noperations: []\n").unwrap();
+        let err = Config::load_from_files(&[&primary])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("include 'missing.yaml'"), "{err}");
+        assert!(err.contains("does not exist"), "{err}");
+    }
+
+    #[test]
+    fn duplicate_operation_across_config_files_is_reported() {
+        let dir = TempDir::new("config");
+        let primary = write_tree(&dir);
+        let other = dir.join("other.toml");
+        fs::write(
+            &other,
+            "version = 3\n\n[[operations]]\nname = \"photos\"\norigin = \"/elsewhere\"\n\
+             destination = \"/backup/elsewhere\"\noperation_type = \"Copy\"\n",
+        )
+        .unwrap();
+        let config = Config::load_from_files(&[primary, other.clone()]).unwrap();
+        let errors: Vec<_> = crate::diagnostics::check_config(&config)
+            .into_iter()
+            .filter(|d| d.message.starts_with("duplicate operation name 'photos'"))
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("photos.yaml"));
+        assert_eq!(errors[0].file.as_deref(), Some(other.as_path()));
+    }
+
+    #[test]
+    fn save_is_refused_for_the_fallback_config() {
+        let dir = TempDir::new("config");
+        let path = dir.join("config.yaml");
+        fs::write(&path, "not: [valid").unwrap();
+        assert!(Config::load_from_files(&[&path]).is_err());
+
+        // What main stands in with when the file fails to load
+        let config = Config {
+            version: schema::CURRENT_VERSION,
+            variables: BTreeMap::new(),
+            include: Vec::new(),
+            operations: vec![FileOperation {
+                name: "default".to_string(),
+                ..Default::default()
+            }],
+            global_rate_limit: RateLimit::default(),
+            free_space_margin: None,
+            files: vec![ConfigFile::new(&path)],
+            fallback: true,
+        };
+        let err = config.save().unwrap_err().to_string();
+        assert!(err.contains("built-in defaults"), "{err}");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not: [valid");
+    }
+
+    #[test]
+    fn save_writes_each_operation_back_to_its_file() {
+        let dir = TempDir::new("config");
+        let primary = write_tree(&dir);
+        let mut config = Config::load_from_files(&[&primary]).unwrap();
+        config.operations[1].retries = 5;
+        let written = config.save().unwrap();
+        assert_eq!(written.len(), 2);
+
+        let included = fs::read_to_string(dir.join("conf.d/photos.yaml")).unwrap();
+        assert!(included.contains("retries: 5"));
+        assert!(!included.contains("docs"));
+        // Variables are kept as written
+        assert!(included.contains("${root}/photos"));
+        assert!(!fs::read_to_string(&primary).unwrap().contains("photos"));
+    }
+
+    #[test]
+    fn every_format_round_trips_to_the_same_config() {
+        let dir = TempDir::new("config");
+        let primary = write_tree(&dir);
+        let config = Config::load_from_files(&[&primary]).unwrap();
+
+        for format in [ConfigFormat::Yaml, ConfigFormat::Toml, ConfigFormat::Json] {
+            let rendered = format.render(&config).unwrap();
+            let parsed: Config = format.parse(&rendered).unwrap();
+            assert_eq!(as_value(&parsed), as_value(&config), "{format:?}");
+        }
+    }
+
+    #[test]
+    fn convert_writes_the_primary_file_in_the_output_format() {
+        let dir = TempDir::new("config");
+        let primary = write_tree(&dir);
+        let config = Config::load_from_files(&[&primary]).unwrap();
+
+        for name in ["converted.toml", "converted.json", "converted.yml"] {
+            let output = dir.join(name);
+            config.convert_to(&output).unwrap();
+            let converted = Config::load_from_files(&[&output]).unwrap();
+            // The include is carried over as a reference and still resolves
+            assert_eq!(converted.include, ["conf.d/*.yaml"]);
+            assert_eq!(as_value(&converted), as_value(&config), "{name}");
+        }
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("a/config.TOML")),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("config")),
+            ConfigFormat::Yaml
+        );
+    }
+
+    #[test]
+    fn parse_rate_reads_units_and_periods() {
+        assert_eq!(parse_rate("262144", 1, 1).unwrap(), 262_144);
+        assert_eq!(parse_rate("256KB/s", 1, 1).unwrap(), 256 * 1024);
+        assert_eq!(parse_rate(" 10 MiB / s ", 1, 1).unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_rate("600MB/min", 1, 1).unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_rate("1g/h", 1, 1).unwrap(), 1024 * 1024 * 1024 / 3600);
+        assert_eq!(parse_rate("1.5k", 1, 1).unwrap(), 1536);
+    }
+
+    #[test]
+    fn parse_rate_bare_numbers_use_the_default_unit_and_period() {
+        // As the megabytes-per-minute field reads them
+        assert_eq!(parse_rate("60", 1024 * 1024, 60).unwrap(), 1024 * 1024);
+        // An explicit unit or period overrides the defaults
+        assert_eq!(parse_rate("60KB", 1024 * 1024, 60).unwrap(), 1024);
+        assert_eq!(parse_rate("60/s", 1024 * 1024, 60).unwrap(), 60);
+    }
+
+    #[test]
+    fn parse_rate_rejects_bad_input() {
+        for input in [
+            "",
+            "  ",
+            "fast",
+            "10XB/s",
+            "10MB/fortnight",
+            "1.2.3",
+            "0",
+            "30/min",
+        ] {
+            assert!(parse_rate(input, 1, 1).is_err(), "{input:?} was accepted");
+        }
+    }
 }
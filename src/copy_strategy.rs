@@ -0,0 +1,358 @@
+use crate::config::{CopyStrategy, format_size};
+use crate::rate_limiter::RateLimiter;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+// Bytes between rate limiter checks, as in the buffered copy
+const CHUNK: usize = 64 * 1024;
+// Without a byte limit the kernel is handed bigger pieces
+const UNLIMITED_CHUNK: usize = 8 * 1024 * 1024;
+
+/// How one file was actually copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Reflink,
+    // Only the data regions were copied; the rest stayed holes
+    Sparse { data: u64 },
+    CopyFileRange,
+    Buffered,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Method::Reflink => write!(f, "reflink"),
+            Method::Sparse { data } => write!(f, "sparse, {} of data", format_size(*data)),
+            Method::CopyFileRange => write!(f, "copy_file_range"),
+            Method::Buffered => write!(f, "buffered"),
+        }
+    }
+}
+
+/// Copies `source` to `destination` with `strategy` and returns the bytes
+/// copied and how. `Auto` tries a reflink clone, then keeps the holes of a
+/// sparse file, copying its data with `copy_file_range`; where the kernel
+/// can't do that the data is read and written here. Cloning moves no data,
+/// so only the other methods are paced by `rate_limiter`.
+pub fn copy(
+    source: &Path,
+    destination: &Path,
+    strategy: CopyStrategy,
+    rate_limiter: &mut RateLimiter,
+) -> io::Result<(u64, Method)> {
+    let reader = File::open(source)?;
+    let size = reader.metadata()?.len();
+    let writer = File::create(destination)?;
+    let copied = copy_between(&reader, &writer, size, strategy, rate_limiter);
+    if copied.is_err() {
+        let _ = fs::remove_file(destination);
+    }
+    Ok((size, copied?))
+}
+
+fn copy_between(
+    reader: &File,
+    writer: &File,
+    size: u64,
+    strategy: CopyStrategy,
+    rate_limiter: &mut RateLimiter,
+) -> io::Result<Method> {
+    let mut kernel = cfg!(target_os = "linux");
+    let method = match strategy {
+        CopyStrategy::Reflink => {
+            reflink(reader, writer)
+                .map_err(|e| io::Error::new(e.kind(), format!("cannot clone: {}", e)))?;
+            Method::Reflink
+        }
+        CopyStrategy::Auto if reflink(reader, writer).is_ok() => Method::Reflink,
+        CopyStrategy::Auto | CopyStrategy::Sparse => {
+            copy_sparse(reader, writer, size, &mut kernel, rate_limiter)?
+        }
+        CopyStrategy::CopyFileRange => {
+            copy_range(reader, writer, 0, size, &mut kernel, rate_limiter)?;
+            range_method(kernel)
+        }
+        CopyStrategy::Buffered => {
+            kernel = false;
+            copy_range(reader, writer, 0, size, &mut kernel, rate_limiter)?;
+            Method::Buffered
+        }
+    };
+    writer.sync_all()?;
+    Ok(method)
+}
+
+fn range_method(kernel: bool) -> Method {
+    match kernel {
+        true => Method::CopyFileRange,
+        false => Method::Buffered,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(reader: &File, writer: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: both descriptors stay open for the call
+    let cloned = unsafe { libc::ioctl(writer.as_raw_fd(), libc::FICLONE, reader.as_raw_fd()) };
+    match cloned {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_reader: &File, _writer: &File) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are only supported on Linux",
+    ))
+}
+
+// Copies the data regions of `reader` and leaves the holes between them
+// unwritten. A file without holes is copied as one region.
+fn copy_sparse(
+    reader: &File,
+    writer: &File,
+    size: u64,
+    kernel: &mut bool,
+    rate_limiter: &mut RateLimiter,
+) -> io::Result<Method> {
+    // Starts the copy out as one hole of the full size
+    writer.set_len(size)?;
+    let mut offset = 0;
+    let mut data = 0;
+    while offset < size {
+        let Some((start, end)) = next_data(reader, offset, size)? else {
+            break;
+        };
+        copy_range(reader, writer, start, end - start, kernel, rate_limiter)?;
+        data += end - start;
+        offset = end;
+    }
+    Ok(match data < size {
+        true => Method::Sparse { data },
+        false => range_method(*kernel),
+    })
+}
+
+// The data region at or after `offset`, if there is one
+#[cfg(target_os = "linux")]
+fn next_data(reader: &File, offset: u64, size: u64) -> io::Result<Option<(u64, u64)>> {
+    use std::os::fd::AsRawFd;
+    let fd = reader.as_raw_fd();
+    // SAFETY: seeking an open descriptor; positional copies ignore its offset
+    let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+    if start == -1 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            // No data past `offset`
+            Some(libc::ENXIO) => Ok(None),
+            // No hole support: everything left is data
+            Some(libc::EINVAL) => Ok(Some((offset, size))),
+            _ => Err(e),
+        };
+    }
+    // SAFETY: as above
+    let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+    let end = if end == -1 { size } else { end as u64 };
+    Ok(Some((start as u64, end.min(size))))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn next_data(_reader: &File, offset: u64, size: u64) -> io::Result<Option<(u64, u64)>> {
+    Ok(Some((offset, size)))
+}
+
+// Copies `len` bytes at `start` to the same place in `writer`, chunk by
+// chunk through `rate_limiter`. Uses `copy_file_range` while `kernel` is
+// set and clears it if the kernel can't copy between these files.
+fn copy_range(
+    reader: &File,
+    writer: &File,
+    start: u64,
+    len: u64,
+    kernel: &mut bool,
+    rate_limiter: &mut RateLimiter,
+) -> io::Result<()> {
+    let chunk = match rate_limiter.limits_bytes() {
+        true => CHUNK,
+        false => UNLIMITED_CHUNK,
+    };
+    let mut buffer = Vec::new();
+    let mut done = 0;
+    while done < len {
+        let want = chunk.min((len - done) as usize);
+        let offset = start + done;
+        let write_started = Instant::now();
+        let copied = match *kernel {
+            true => match kernel_copy(reader, writer, offset, want) {
+                Err(e) if is_unsupported(&e) => {
+                    *kernel = false;
+                    continue;
+                }
+                copied => copied?,
+            },
+            false => {
+                buffer.resize(want, 0);
+                buffered_copy(reader, writer, offset, &mut buffer)?
+            }
+        };
+        if copied == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "source shrank while it was being copied",
+            ));
+        }
+        rate_limiter.record_write(write_started.elapsed());
+        rate_limiter.throttle_chunk(copied);
+        if rate_limiter.refresh_schedule() {
+            println!(
+                "  Rate limit changed to {} (schedule)",
+                rate_limiter.limit_text()
+            );
+        }
+        if let Some(adjustment) = rate_limiter.adapt() {
+            println!("  Adaptive throttling: {}", adjustment);
+        }
+        done += copied as u64;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn kernel_copy(reader: &File, writer: &File, offset: u64, len: usize) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+    let mut off_in = offset as libc::loff_t;
+    let mut off_out = offset as libc::loff_t;
+    // SAFETY: both descriptors stay open and the offsets outlive the call
+    let copied = unsafe {
+        libc::copy_file_range(
+            reader.as_raw_fd(),
+            &mut off_in,
+            writer.as_raw_fd(),
+            &mut off_out,
+            len,
+            0,
+        )
+    };
+    match copied {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel_copy(_reader: &File, _writer: &File, _offset: u64, _len: usize) -> io::Result<usize> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+// Errors meaning `copy_file_range` can't be used for these files at all
+fn is_unsupported(e: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    if let Some(code) = e.raw_os_error() {
+        return matches!(
+            code,
+            libc::ENOSYS | libc::EXDEV | libc::EOPNOTSUPP | libc::EINVAL
+        );
+    }
+    e.kind() == io::ErrorKind::Unsupported
+}
+
+#[cfg(unix)]
+fn buffered_copy(
+    reader: &File,
+    writer: &File,
+    offset: u64,
+    buffer: &mut [u8],
+) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    let read = reader.read_at(buffer, offset)?;
+    writer.write_all_at(&buffer[..read], offset)?;
+    Ok(read)
+}
+
+#[cfg(windows)]
+fn buffered_copy(
+    reader: &File,
+    writer: &File,
+    offset: u64,
+    buffer: &mut [u8],
+) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    let read = reader.seek_read(buffer, offset)?;
+    let mut written = 0;
+    while written < read {
+        written += writer.seek_write(&buffer[written..read], offset + written as u64)?;
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, unlimited};
+    use std::io::{Seek, SeekFrom, Write};
+
+    #[cfg(unix)]
+    #[test]
+    fn sparse_copy_keeps_the_holes() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = TempDir::new("copy");
+        let source = dir.join("sparse.img");
+        let size = 64 * 1024 * 1024;
+        let mut file = File::create(&source).unwrap();
+        file.set_len(size).unwrap();
+        file.seek(SeekFrom::Start(48 * 1024 * 1024)).unwrap();
+        file.write_all(&[0xab; 4096]).unwrap();
+        file.sync_all().unwrap();
+        drop(file);
+        // Nothing to show where the filesystem doesn't keep holes
+        if fs::metadata(&source).unwrap().blocks() * 512 >= size {
+            return;
+        }
+
+        let sparse = dir.join("sparse.copy");
+        let (copied, method) =
+            copy(&source, &sparse, CopyStrategy::Sparse, &mut unlimited()).unwrap();
+        assert_eq!(copied, size);
+        let Method::Sparse { data } = method else {
+            panic!("copied with {}", method);
+        };
+        assert!(data >= 4096 && data < size, "{} bytes of data", data);
+        assert!(fs::read(&source).unwrap() == fs::read(&sparse).unwrap());
+
+        let full = dir.join("full.copy");
+        let (_, method) = copy(&source, &full, CopyStrategy::Buffered, &mut unlimited()).unwrap();
+        assert_eq!(method, Method::Buffered);
+        let blocks = |path: &Path| fs::metadata(path).unwrap().blocks();
+        assert!(
+            blocks(&sparse) < blocks(&full),
+            "sparse copy has {} blocks, full copy {}",
+            blocks(&sparse),
+            blocks(&full)
+        );
+    }
+
+    #[test]
+    fn every_strategy_copies_the_contents() {
+        let dir = TempDir::new("copy");
+        let source = dir.join("source");
+        let data: Vec<u8> = (0..3 * CHUNK + 17).map(|i| (i % 253) as u8).collect();
+        fs::write(&source, &data).unwrap();
+        for strategy in [
+            CopyStrategy::Auto,
+            CopyStrategy::Sparse,
+            CopyStrategy::CopyFileRange,
+            CopyStrategy::Buffered,
+        ] {
+            let destination = dir.join(format!("{:?}", strategy));
+            let (copied, _) = copy(&source, &destination, strategy, &mut unlimited()).unwrap();
+            assert_eq!(copied, data.len() as u64);
+            assert_eq!(fs::read(&destination).unwrap(), data, "{:?}", strategy);
+        }
+    }
+}
@@ -0,0 +1,580 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::Context;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Encrypted files start with MAGIC, a random salt, a random nonce prefix
+// and the chunk size. Each file is encrypted under its own subkey, derived
+// from the key and the salt with HKDF-SHA256, so the 7-byte prefixes of
+// different files never share a key. The content follows in chunks sealed
+// with AES-256-GCM, each authenticating the header too. The nonce of a
+// chunk is the prefix, its index and a flag marking the final chunk, so
+// chunks can't be reordered, dropped or the file truncated without
+// decryption failing. The final chunk ends with the SHA-256 of the
+// plaintext the file was made from.
+const MAGIC: &[u8; 6] = b"RBENC\x02";
+const SALT_SIZE: usize = 16;
+const PREFIX_SIZE: usize = 7;
+const PREFIX_START: usize = MAGIC.len() + SALT_SIZE;
+const HEADER_SIZE: usize = PREFIX_START + PREFIX_SIZE + 4;
+// Binds derived subkeys to this file format
+const SUBKEY_INFO: &[u8] = b"rusty_bucket file encryption v2";
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const TRAILER_SIZE: usize = 32;
+// Largest final chunk: a whole chunk of data plus the trailer
+const MAX_FINAL: usize = CHUNK_SIZE + TRAILER_SIZE + TAG_SIZE;
+const MIN_FINAL: usize = TRAILER_SIZE + TAG_SIZE;
+
+/// Added to the name of every encrypted file.
+pub const SUFFIX: &str = ".enc";
+
+/// An AES-256 key loaded from a key file. Neither `Debug` nor any error
+/// message ever includes the key itself.
+#[derive(Clone)]
+pub struct Key {
+    secret: [u8; 32],
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(<redacted>)")
+    }
+}
+
+impl Key {
+    /// Reads a key file holding either 32 raw bytes or 64 hex digits.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data =
+            fs::read(path).with_context(|| format!("cannot read key file {}", path.display()))?;
+        let hex = std::str::from_utf8(&data)
+            .ok()
+            .map(str::trim)
+            .filter(|text| text.len() == 64 && text.chars().all(|c| c.is_ascii_hexdigit()));
+        let bytes: Vec<u8> = match hex {
+            Some(hex) => (0..64)
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or(0))
+                .collect(),
+            None if data.len() == 32 => data,
+            None => anyhow::bail!(
+                "key file {} must hold 32 bytes or 64 hex digits",
+                path.display()
+            ),
+        };
+        let secret = bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("invalid key in {}", path.display()))?;
+        Ok(Self { secret })
+    }
+
+    // The subkey for the file whose header (and so salt) is `header`
+    fn for_file(&self, header: &[u8; HEADER_SIZE]) -> io::Result<FileKey> {
+        let hkdf = Hkdf::<Sha256>::new(Some(&header[MAGIC.len()..PREFIX_START]), &self.secret);
+        let mut subkey = [0u8; 32];
+        let cipher = hkdf
+            .expand(SUBKEY_INFO, &mut subkey)
+            .ok()
+            .and_then(|()| Aes256Gcm::new_from_slice(&subkey).ok());
+        subkey.fill(0);
+        let cipher = cipher.ok_or_else(|| io::Error::other("key derivation failed"))?;
+        Ok(FileKey { cipher })
+    }
+}
+
+// One file's subkey
+#[derive(Clone)]
+struct FileKey {
+    cipher: Aes256Gcm,
+}
+
+impl FileKey {
+    fn seal(&self, header: &[u8], index: u32, last: bool, data: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = nonce(header, index, last);
+        self.cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: data,
+                    aad: header,
+                },
+            )
+            .map_err(|_| io::Error::other("encryption failed"))
+    }
+
+    fn open(&self, header: &[u8], index: u32, last: bool, data: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = nonce(header, index, last);
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: data,
+                    aad: header,
+                },
+            )
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "decryption failed: the file is damaged or the key is wrong",
+                )
+            })
+    }
+}
+
+fn nonce(header: &[u8], index: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..PREFIX_SIZE].copy_from_slice(&header[PREFIX_START..PREFIX_START + PREFIX_SIZE]);
+    nonce[PREFIX_SIZE..PREFIX_SIZE + 4].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = u8::from(last);
+    nonce
+}
+
+/// `path` with the encrypted-file suffix appended.
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(SUFFIX);
+    PathBuf::from(name)
+}
+
+/// `path` without the encrypted-file suffix, if it has one.
+pub fn strip_suffix(path: &Path) -> Option<PathBuf> {
+    path.to_string_lossy()
+        .strip_suffix(SUFFIX)
+        .map(PathBuf::from)
+}
+
+/// Encrypts everything written through it into `W`, or passes it through
+/// unchanged when there is no key.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    key: Option<FileKey>,
+    header: [u8; HEADER_SIZE],
+    header_written: bool,
+    buffer: Vec<u8>,
+    index: u32,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(inner: W, key: Option<&Key>) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_SIZE];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        // Salt and nonce prefix
+        getrandom::getrandom(&mut header[MAGIC.len()..PREFIX_START + PREFIX_SIZE])
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        header[PREFIX_START + PREFIX_SIZE..].copy_from_slice(&(CHUNK_SIZE as u32).to_be_bytes());
+        Ok(Self {
+            inner,
+            key: key.map(|key| key.for_file(&header)).transpose()?,
+            header,
+            header_written: false,
+            buffer: Vec::new(),
+            index: 0,
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    fn write_chunk(&mut self, last: bool) -> io::Result<()> {
+        let Some(key) = &self.key else {
+            return Ok(());
+        };
+        if !self.header_written {
+            self.inner.write_all(&self.header)?;
+            self.header_written = true;
+        }
+        let len = if last { self.buffer.len() } else { CHUNK_SIZE };
+        let sealed = key.seal(&self.header, self.index, last, &self.buffer[..len])?;
+        self.inner.write_all(&sealed)?;
+        self.buffer.drain(..len);
+        self.index = self
+            .index
+            .checked_add(1)
+            .ok_or_else(|| io::Error::other("file too large to encrypt"))?;
+        Ok(())
+    }
+
+    /// Seals the final chunk, recording `plaintext_sha256` (the hash of the
+    /// original content) in it, and returns the underlying writer.
+    pub fn finish(mut self, plaintext_sha256: Option<[u8; 32]>) -> io::Result<W> {
+        if self.key.is_some() {
+            self.buffer
+                .extend_from_slice(&plaintext_sha256.unwrap_or([0; TRAILER_SIZE]));
+            while self.buffer.len() > CHUNK_SIZE + TRAILER_SIZE {
+                self.write_chunk(false)?;
+            }
+            self.write_chunk(true)?;
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.key.is_none() {
+            return self.inner.write(buf);
+        }
+        self.buffer.extend_from_slice(buf);
+        // Keep a whole chunk back: the final one must hold some data
+        while self.buffer.len() > CHUNK_SIZE + TRAILER_SIZE {
+            self.write_chunk(false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn read_header(reader: &mut impl Read) -> io::Result<[u8; HEADER_SIZE]> {
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => {
+            io::Error::new(io::ErrorKind::InvalidData, "not an encrypted file")
+        }
+        _ => e,
+    })?;
+    let chunk_size = u32::from_be_bytes(header[PREFIX_START + PREFIX_SIZE..].try_into().unwrap());
+    if &header[..MAGIC.len()] != MAGIC || chunk_size as usize != CHUNK_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an encrypted file",
+        ));
+    }
+    Ok(header)
+}
+
+/// Decrypts a file written by `EncryptWriter`, failing with `InvalidData`
+/// if any part of it doesn't authenticate.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    key: FileKey,
+    header: [u8; HEADER_SIZE],
+    index: u32,
+    // Ciphertext read ahead, to tell the final chunk apart
+    pending: Vec<u8>,
+    plain: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(mut inner: R, key: &Key) -> io::Result<Self> {
+        let header = read_header(&mut inner)?;
+        Ok(Self {
+            inner,
+            key: key.for_file(&header)?,
+            header,
+            index: 0,
+            pending: Vec::new(),
+            plain: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        // A non-final chunk is always followed by at least a minimal final
+        // one, so anything shorter than that is the final chunk
+        let lookahead = CHUNK_SIZE + TAG_SIZE + MIN_FINAL;
+        let mut eof = false;
+        while self.pending.len() < lookahead {
+            let mut buf = vec![0u8; lookahead - self.pending.len()];
+            let n = self.inner.read(&mut buf)?;
+            if n == 0 {
+                eof = true;
+                break;
+            }
+            self.pending.extend_from_slice(&buf[..n]);
+        }
+
+        let truncated =
+            || io::Error::new(io::ErrorKind::InvalidData, "encrypted file is truncated");
+        if !eof {
+            let sealed: Vec<u8> = self.pending.drain(..CHUNK_SIZE + TAG_SIZE).collect();
+            self.plain = self.key.open(&self.header, self.index, false, &sealed)?;
+            self.index = self.index.checked_add(1).ok_or_else(truncated)?;
+        } else {
+            if self.pending.len() < MIN_FINAL || self.pending.len() > MAX_FINAL {
+                return Err(truncated());
+            }
+            let mut plain = self
+                .key
+                .open(&self.header, self.index, true, &self.pending)?;
+            plain.truncate(plain.len() - TRAILER_SIZE);
+            self.plain = plain;
+            self.pending.clear();
+            self.done = true;
+        }
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.plain.len() {
+                let n = buf.len().min(self.plain.len() - self.pos);
+                buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            if self.done {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+    }
+}
+
+/// The plaintext SHA-256 recorded when `path` was encrypted, read from its
+/// final chunk alone. `None` if none was recorded.
+pub fn recorded_sha256(path: &Path, key: &Key) -> anyhow::Result<Option<String>> {
+    let mut file = File::open(path)?;
+    let header = read_header(&mut file)?;
+    let body = file.metadata()?.len().saturating_sub(HEADER_SIZE as u64);
+    if body < MIN_FINAL as u64 {
+        anyhow::bail!("encrypted file is truncated");
+    }
+    // Every chunk before the final one is full size
+    let full = (CHUNK_SIZE + TAG_SIZE) as u64;
+    let index = (body - MIN_FINAL as u64) / full;
+    file.seek(SeekFrom::Start(HEADER_SIZE as u64 + index * full))?;
+    let mut sealed = Vec::new();
+    file.read_to_end(&mut sealed)?;
+    if sealed.len() > MAX_FINAL {
+        anyhow::bail!("encrypted file is truncated");
+    }
+    let index = u32::try_from(index)?;
+    let plain = key.for_file(&header)?.open(&header, index, true, &sealed)?;
+    let trailer = &plain[plain.len() - TRAILER_SIZE..];
+    if trailer.iter().all(|&b| b == 0) {
+        return Ok(None);
+    }
+    Ok(Some(trailer.iter().map(|b| format!("{:02x}", b)).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use sha2::{Digest, Sha256};
+
+    // Sizes either side of where the final chunk has to change shape
+    const SIZES: [usize; 9] = [
+        0,
+        1,
+        CHUNK_SIZE - 1,
+        CHUNK_SIZE,
+        CHUNK_SIZE + 1,
+        CHUNK_SIZE + TRAILER_SIZE,
+        CHUNK_SIZE + TRAILER_SIZE + 1,
+        2 * CHUNK_SIZE + TRAILER_SIZE,
+        3 * CHUNK_SIZE + 5,
+    ];
+
+    fn key(byte: u8) -> Key {
+        Key { secret: [byte; 32] }
+    }
+
+    fn plaintext(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn encrypt(data: &[u8], key: &Key, sha256: Option<[u8; 32]>) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(Vec::new(), Some(key)).unwrap();
+        // Odd-sized writes, so chunks never line up with them
+        for piece in data.chunks(1000) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish(sha256).unwrap()
+    }
+
+    fn decrypt(sealed: &[u8], key: &Key) -> io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        DecryptReader::new(sealed, key)?.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    fn sealed_size(len: usize) -> usize {
+        // Full chunks go out while more than a chunk plus the trailer is
+        // left, so the final chunk holds 0..=CHUNK_SIZE bytes of data
+        let full = len.saturating_sub(1) / CHUNK_SIZE;
+        let last = len - full * CHUNK_SIZE + TRAILER_SIZE;
+        HEADER_SIZE + full * (CHUNK_SIZE + TAG_SIZE) + last + TAG_SIZE
+    }
+
+    fn assert_invalid(result: io::Result<Vec<u8>>) {
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+    }
+
+    fn temp_file(dir: &Path, name: &str, data: &[u8]) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn round_trips_at_chunk_boundaries() {
+        let key = key(1);
+        for len in SIZES {
+            let data = plaintext(len);
+            let sealed = encrypt(&data, &key, None);
+            assert_eq!(sealed.len(), sealed_size(len), "size for {} bytes", len);
+            assert_eq!(decrypt(&sealed, &key).unwrap(), data, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn final_chunk_holds_data_up_to_a_chunk_plus_the_trailer() {
+        let key = key(1);
+        let full = CHUNK_SIZE + TAG_SIZE;
+        assert_eq!(encrypt(&[], &key, None).len(), HEADER_SIZE + MIN_FINAL);
+        assert_eq!(
+            encrypt(&plaintext(CHUNK_SIZE), &key, None).len(),
+            HEADER_SIZE + CHUNK_SIZE + MIN_FINAL
+        );
+        // Chunk plus trailer of data plus the trailer itself doesn't fit
+        assert_eq!(
+            encrypt(&plaintext(CHUNK_SIZE + TRAILER_SIZE), &key, None).len(),
+            HEADER_SIZE + full + TRAILER_SIZE + MIN_FINAL
+        );
+    }
+
+    #[test]
+    fn recorded_sha256_reads_the_final_chunk() {
+        let dir = TempDir::new("crypto");
+        let key = key(2);
+        for len in SIZES {
+            let data = plaintext(len);
+            let sha256: [u8; 32] = Sha256::digest(&data).into();
+            let path = temp_file(
+                &dir,
+                &format!("recorded_{}", len),
+                &encrypt(&data, &key, Some(sha256)),
+            );
+            let expected: String = sha256.iter().map(|b| format!("{:02x}", b)).collect();
+            assert_eq!(
+                recorded_sha256(&path, &key).unwrap(),
+                Some(expected),
+                "{} bytes",
+                len
+            );
+        }
+        let path = temp_file(&dir, "unrecorded", &encrypt(b"data", &key, None));
+        assert_eq!(recorded_sha256(&path, &key).unwrap(), None);
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let dir = TempDir::new("crypto");
+        let sealed = encrypt(&plaintext(CHUNK_SIZE + 1), &key(3), None);
+        assert_invalid(decrypt(&sealed, &key(4)));
+        let path = temp_file(&dir, "wrong_key", &sealed);
+        assert!(recorded_sha256(&path, &key(4)).is_err());
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let key = key(5);
+        for len in SIZES {
+            let sealed = encrypt(&plaintext(len), &key, None);
+            for at in [
+                MAGIC.len(),
+                HEADER_SIZE - 5,
+                HEADER_SIZE,
+                sealed.len() / 2,
+                sealed.len() - 1,
+            ] {
+                let mut tampered = sealed.clone();
+                tampered[at] ^= 1;
+                assert_invalid(decrypt(&tampered, &key));
+            }
+        }
+    }
+
+    #[test]
+    fn truncation_is_detected() {
+        let dir = TempDir::new("crypto");
+        let key = key(6);
+        let full = CHUNK_SIZE + TAG_SIZE;
+        for len in SIZES {
+            let sealed = encrypt(&plaintext(len), &key, None);
+            let mut cuts = vec![0, HEADER_SIZE - 1, HEADER_SIZE, sealed.len() - 1];
+            // Dropping whole chunks, the final one included
+            cuts.extend(
+                (1..)
+                    .map(|n| HEADER_SIZE + n * full)
+                    .take_while(|&cut| cut < sealed.len()),
+            );
+            for cut in cuts {
+                assert_invalid(decrypt(&sealed[..cut], &key));
+                let path = temp_file(&dir, "truncated", &sealed[..cut]);
+                assert!(recorded_sha256(&path, &key).is_err(), "cut at {}", cut);
+            }
+        }
+    }
+
+    #[test]
+    fn reordered_chunks_are_detected() {
+        let key = key(7);
+        let full = CHUNK_SIZE + TAG_SIZE;
+        let sealed = encrypt(&plaintext(3 * CHUNK_SIZE + 5), &key, None);
+        let first = HEADER_SIZE..HEADER_SIZE + full;
+        let second = HEADER_SIZE + full..HEADER_SIZE + 2 * full;
+        let mut swapped = sealed[..HEADER_SIZE].to_vec();
+        swapped.extend_from_slice(&sealed[second]);
+        swapped.extend_from_slice(&sealed[first]);
+        swapped.extend_from_slice(&sealed[HEADER_SIZE + 2 * full..]);
+        assert_eq!(swapped.len(), sealed.len());
+        assert_invalid(decrypt(&swapped, &key));
+    }
+
+    #[test]
+    fn each_file_gets_its_own_salt_and_subkey() {
+        let key = key(8);
+        let data = plaintext(CHUNK_SIZE + 1);
+        let first = encrypt(&data, &key, None);
+        let second = encrypt(&data, &key, None);
+        let salt = MAGIC.len()..PREFIX_START;
+        assert_ne!(first[salt.clone()], second[salt.clone()]);
+
+        // The same salt and prefix under the same key give the same subkey
+        let header: [u8; HEADER_SIZE] = first[..HEADER_SIZE].try_into().unwrap();
+        let chunk = &data[..CHUNK_SIZE];
+        let sealed = key
+            .for_file(&header)
+            .unwrap()
+            .seal(&header, 0, false, chunk)
+            .unwrap();
+        assert_eq!(
+            sealed,
+            first[HEADER_SIZE..HEADER_SIZE + CHUNK_SIZE + TAG_SIZE]
+        );
+        // while another salt doesn't, even with the prefix unchanged
+        let mut resalted = header;
+        resalted[salt.start] ^= 1;
+        let other = key
+            .for_file(&resalted)
+            .unwrap()
+            .seal(&header, 0, false, chunk)
+            .unwrap();
+        assert_ne!(sealed, other);
+    }
+
+    #[test]
+    fn writer_without_key_passes_data_through() {
+        let mut writer = EncryptWriter::new(Vec::new(), None).unwrap();
+        writer.write_all(b"plain").unwrap();
+        assert_eq!(writer.finish(None).unwrap(), b"plain");
+    }
+}
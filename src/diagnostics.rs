@@ -0,0 +1,1064 @@
+use crate::config::{Config, ConfigFormat, FileOperation, OperationType, RateLimit};
+use crate::crypto::Key;
+use crate::paths;
+use crate::preflight::Margin;
+use crate::retention::SnapshotLayout;
+use crate::{scheduler, schema};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub operation: Option<String>,
+    pub message: String,
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error")?,
+            Severity::Warning => write!(f, "warning")?,
+        }
+        match (&self.file, self.line, self.column) {
+            (Some(file), Some(line), Some(column)) => {
+                write!(f, " ({}, line {}, column {})", file.display(), line, column)?
+            }
+            (Some(file), _, _) => write!(f, " ({})", file.display())?,
+            (None, Some(line), Some(column)) => write!(f, " (line {}, column {})", line, column)?,
+            _ => {}
+        }
+        if let Some(name) = &self.operation {
+            write!(f, " [{}]", name)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Maps YAML key paths such as `operations[1].rate_limit.enabled` to the
+/// 1-based line and column where the key appears.
+///
+/// serde_yaml doesn't keep spans once a document is deserialized, so this
+/// does a light indentation-based scan of the block-style YAML we write.
+/// Keys inside flow collections aren't located; diagnostics for them carry
+/// the position of the key holding the collection.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    positions: HashMap<String, (usize, usize)>,
+}
+
+impl SourceMap {
+    pub fn parse(source: &str) -> Self {
+        let mut positions = HashMap::new();
+        // (indent, path, is_sequence_item)
+        let mut stack: Vec<(usize, String, bool)> = Vec::new();
+        let mut sequence_counters: HashMap<String, usize> = HashMap::new();
+        // Lines that belong to a value rather than holding keys: the body
+        // of a `|` or `>` block scalar (anything indented past its key),
+        // or the rest of a flow collection until its brackets close
+        let mut block_scalar: Option<usize> = None;
+        let mut flow_depth = 0;
+
+        for (line_idx, raw_line) in source.lines().enumerate() {
+            let line_no = line_idx + 1;
+            let trimmed = raw_line.trim_start();
+            if trimmed.is_empty() {
+                continue;
+            }
+            let mut column = raw_line.len() - trimmed.len();
+            if let Some(indent) = block_scalar {
+                if column > indent {
+                    continue;
+                }
+                block_scalar = None;
+            }
+            if flow_depth > 0 {
+                flow_depth += bracket_depth(trimmed);
+                continue;
+            }
+            if trimmed.starts_with('#') || trimmed.starts_with("---") {
+                continue;
+            }
+            let mut content = trimmed;
+
+            if content == "-" || content.starts_with("- ") {
+                while let Some((indent, _, is_item)) = stack.last() {
+                    if *indent > column || (*indent == column && *is_item) {
+                        stack.pop();
+                    } else {
+                        break;
+                    }
+                }
+                let parent = stack.last().map(|(_, p, _)| p.clone()).unwrap_or_default();
+                let counter = sequence_counters.entry(parent.clone()).or_insert(0);
+                let item_path = format!("{}[{}]", parent, counter);
+                *counter += 1;
+                positions.insert(item_path.clone(), (line_no, column + 1));
+                stack.push((column, item_path, true));
+
+                let rest = content[1..].trim_start();
+                column += content.len() - rest.len();
+                content = rest;
+                if content.is_empty() {
+                    continue;
+                }
+            }
+
+            let Some(key) = yaml_key(content) else {
+                flow_depth = bracket_depth(content).max(0);
+                continue;
+            };
+            let value = content[content.find(':').unwrap_or(0) + 1..].trim_start();
+            if value.starts_with('|') || value.starts_with('>') {
+                block_scalar = Some(column);
+            }
+            flow_depth = bracket_depth(value).max(0);
+
+            while let Some((indent, _, _)) = stack.last() {
+                if *indent >= column {
+                    stack.pop();
+                } else {
+                    break;
+                }
+            }
+            let path = match stack.last() {
+                Some((_, parent, _)) => format!("{}.{}", parent, key),
+                None => key.to_string(),
+            };
+            positions.insert(path.clone(), (line_no, column + 1));
+            stack.push((column, path, false));
+        }
+
+        Self { positions }
+    }
+
+    pub fn get(&self, path: &str) -> Option<(usize, usize)> {
+        self.positions.get(path).copied()
+    }
+
+    /// Position of `path`, falling back to each enclosing key in turn.
+    pub fn nearest(&self, path: &str) -> Option<(usize, usize)> {
+        let mut path = path;
+        loop {
+            if let Some(pos) = self.get(path) {
+                return Some(pos);
+            }
+            let cut = path.rfind(['.', '['])?;
+            path = &path[..cut];
+        }
+    }
+}
+
+// How many more flow brackets `text` opens than it closes, ignoring quoted
+// text and comments
+fn bracket_depth(text: &str) -> isize {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut previous = ' ';
+    for c in text.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') if previous == ' ' => break,
+            (None, '[' | '{') => depth += 1,
+            (None, ']' | '}') => depth -= 1,
+            _ => {}
+        }
+        previous = c;
+    }
+    depth
+}
+
+fn yaml_key(content: &str) -> Option<&str> {
+    let colon = content.find(':')?;
+    let after = &content[colon + 1..];
+    if !(after.is_empty() || after.starts_with(' ')) {
+        return None;
+    }
+    let key = content[..colon]
+        .trim()
+        .trim_matches(|c| c == '"' || c == '\'');
+    if key.is_empty() || key.contains(' ') {
+        return None;
+    }
+    Some(key)
+}
+
+struct Checker {
+    maps: HashMap<PathBuf, SourceMap>,
+    // File the checks currently running refer to, and whether to name it
+    // (only worth doing when the config spans several files)
+    file: Option<PathBuf>,
+    show_file: bool,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker {
+    fn push(&mut self, severity: Severity, operation: Option<&str>, path: &str, message: String) {
+        let position = self
+            .file
+            .as_ref()
+            .and_then(|file| self.maps.get(file))
+            .and_then(|map| map.nearest(path));
+        self.diagnostics.push(Diagnostic {
+            severity,
+            operation: operation.map(str::to_string),
+            message,
+            file: self.file.clone().filter(|_| self.show_file),
+            line: position.map(|p| p.0),
+            column: position.map(|p| p.1),
+        });
+    }
+
+    fn check_rate_limit(&mut self, rate_limit: &RateLimit, operation: Option<&str>, path: &str) {
+        if rate_limit.bytes_per_second.is_some() && rate_limit.megabytes_per_minute.is_some() {
+            self.push(
+                Severity::Warning,
+                operation,
+                &format!("{}.megabytes_per_minute", path),
+                "both bytes_per_second and megabytes_per_minute are set; bytes_per_second takes precedence"
+                    .to_string(),
+            );
+        }
+        if rate_limit.bytes_per_second == Some(0) {
+            self.push(
+                Severity::Error,
+                operation,
+                &format!("{}.bytes_per_second", path),
+                "bytes_per_second must be greater than zero".to_string(),
+            );
+        }
+        if rate_limit.megabytes_per_minute == Some(0) {
+            self.push(
+                Severity::Error,
+                operation,
+                &format!("{}.megabytes_per_minute", path),
+                "megabytes_per_minute must be greater than zero".to_string(),
+            );
+        }
+        for (field, value) in [
+            ("files_per_second", rate_limit.files_per_second),
+            (
+                "metadata_ops_per_second",
+                rate_limit.metadata_ops_per_second,
+            ),
+        ] {
+            if value == Some(0) {
+                self.push(
+                    Severity::Error,
+                    operation,
+                    &format!("{}.{}", path, field),
+                    format!("{} must be greater than zero", field),
+                );
+            }
+        }
+        for (idx, window) in rate_limit.schedule.iter().enumerate() {
+            let window_path = format!("{}.schedule[{}]", path, idx);
+            if let Err(e) = window.bytes_per_second() {
+                self.push(
+                    Severity::Error,
+                    operation,
+                    &format!("{}.rate", window_path),
+                    format!("invalid rate in schedule window: {}", e),
+                );
+            }
+            if let Err(e) = window.covers(chrono::Local::now()) {
+                self.push(
+                    Severity::Error,
+                    operation,
+                    &window_path,
+                    format!("invalid schedule window: {}", e),
+                );
+            }
+        }
+
+        if let Some(adaptive) = &rate_limit.adaptive {
+            let adaptive_path = format!("{}.adaptive", path);
+            if operation.is_none() {
+                self.push(
+                    Severity::Warning,
+                    operation,
+                    &adaptive_path,
+                    "adaptive throttling only applies to operations; it is ignored here"
+                        .to_string(),
+                );
+            }
+            if adaptive.max_load_average.is_none() && adaptive.max_write_latency_ms.is_none() {
+                self.push(
+                    Severity::Warning,
+                    operation,
+                    &adaptive_path,
+                    "adaptive throttling has no threshold (set max_load_average or \
+                     max_write_latency_ms); it will never back off"
+                        .to_string(),
+                );
+            }
+            if adaptive.max_load_average.is_some_and(|load| load <= 0.0) {
+                self.push(
+                    Severity::Error,
+                    operation,
+                    &format!("{}.max_load_average", adaptive_path),
+                    "max_load_average must be greater than zero".to_string(),
+                );
+            }
+            if adaptive.max_write_latency_ms == Some(0) {
+                self.push(
+                    Severity::Error,
+                    operation,
+                    &format!("{}.max_write_latency_ms", adaptive_path),
+                    "max_write_latency_ms must be greater than zero".to_string(),
+                );
+            }
+            match adaptive.min_bytes_per_second() {
+                Err(e) => self.push(
+                    Severity::Error,
+                    operation,
+                    &format!("{}.min_rate", adaptive_path),
+                    format!("invalid min_rate: {}", e),
+                ),
+                Ok(0) => self.push(
+                    Severity::Error,
+                    operation,
+                    &format!("{}.min_rate", adaptive_path),
+                    "min_rate must be greater than zero".to_string(),
+                ),
+                Ok(_) => {}
+            }
+        }
+
+        let has_rate = rate_limit.bytes_per_second.is_some()
+            || rate_limit.megabytes_per_minute.is_some()
+            || !rate_limit.schedule.is_empty()
+            || rate_limit.files_per_second.is_some()
+            || rate_limit.metadata_ops_per_second.is_some()
+            || rate_limit.adaptive.is_some();
+        if rate_limit.enabled && !has_rate {
+            self.push(
+                Severity::Warning,
+                operation,
+                &format!("{}.enabled", path),
+                "rate limiting is enabled but no rate is set; transfers will not be throttled"
+                    .to_string(),
+            );
+        } else if !rate_limit.enabled && has_rate {
+            self.push(
+                Severity::Warning,
+                operation,
+                &format!("{}.enabled", path),
+                "a rate is set but enabled is false; the limit will be ignored".to_string(),
+            );
+        }
+    }
+
+    fn check_operation(&mut self, idx: usize, op: &FileOperation) {
+        let path = format!("operations[{}]", idx);
+        let name = Some(op.name.as_str());
+
+        if op.name.trim().is_empty() {
+            self.push(
+                Severity::Error,
+                name,
+                &format!("{}.name", path),
+                "operation name is empty".to_string(),
+            );
+        }
+
+        let template = op.destination.to_string_lossy();
+        if paths::is_template(&template)
+            && let Err(e) = paths::render_template(&template, chrono::Local::now())
+        {
+            self.push(
+                Severity::Error,
+                name,
+                &format!("{}.destination", path),
+                format!(
+                    "invalid destination template: {} (braces that are part of a directory name must be doubled, as {{{{ and }}}})",
+                    e
+                ),
+            );
+        }
+
+        let origin = normalize(&op.origin);
+        let destination = normalize(&op.destination);
+
+        if origin == destination {
+            self.push(
+                Severity::Error,
+                name,
+                &format!("{}.destination", path),
+                format!(
+                    "origin and destination are the same path ({})",
+                    op.origin.display()
+                ),
+            );
+        } else if destination.starts_with(&origin) && !op.origin.is_file() {
+            self.push(
+                Severity::Error,
+                name,
+                &format!("{}.destination", path),
+                format!(
+                    "destination {} is inside origin {}; copying would recurse into its own output",
+                    op.destination.display(),
+                    op.origin.display()
+                ),
+            );
+        }
+
+        if !op.origin.exists() {
+            self.push(
+                Severity::Warning,
+                name,
+                &format!("{}.origin", path),
+                format!("source {} does not exist", op.origin.display()),
+            );
+        } else if op.operation_type == OperationType::Archive && !op.origin.is_dir() {
+            self.push(
+                Severity::Error,
+                name,
+                &format!("{}.origin", path),
+                "an Archive operation packs a directory; origin is not one".to_string(),
+            );
+        } else if op.operation_type == OperationType::Extract && !op.origin.is_file() {
+            self.push(
+                Severity::Error,
+                name,
+                &format!("{}.origin", path),
+                "an Extract operation needs an archive file as its origin".to_string(),
+            );
+        } else if op.operation_type == OperationType::Repository && !op.origin.is_dir() {
+            self.push(
+                Severity::Error,
+                name,
+                &format!("{}.origin", path),
+                "a Repository operation stores a directory; origin is not one".to_string(),
+            );
+        }
+
+        match nearest_existing_ancestor(&op.destination) {
+            Some(ancestor) if !ancestor.is_dir() => self.push(
+                Severity::Error,
+                name,
+                &format!("{}.destination", path),
+                format!(
+                    "destination parent {} exists but is not a directory",
+                    ancestor.display()
+                ),
+            ),
+            Some(ancestor) if !is_writable(&ancestor) => self.push(
+                Severity::Error,
+                name,
+                &format!("{}.destination", path),
+                format!("destination parent {} is not writable", ancestor.display()),
+            ),
+            _ => {}
+        }
+
+        self.check_rate_limit(&op.rate_limit, name, &format!("{}.rate_limit", path));
+
+        if op.compression.is_some() && op.operation_type == OperationType::Move {
+            self.push(
+                Severity::Warning,
+                name,
+                &format!("{}.compression", path),
+                "compression only applies to copies; moved files are left as they are".to_string(),
+            );
+        } else if op.compression.is_some() && op.operation_type == OperationType::Repository {
+            self.push(
+                Severity::Warning,
+                name,
+                &format!("{}.compression", path),
+                "a repository stores content as it is; compression is ignored".to_string(),
+            );
+        }
+
+        if op.copy_strategy.is_some() {
+            let ignored = if op.operation_type != OperationType::Copy {
+                Some("a copy strategy only applies to Copy operations; it is ignored")
+            } else if op.compression.is_some() || op.encryption.is_some() {
+                Some(
+                    "compressed or encrypted copies are written chunk by chunk; the copy strategy is ignored",
+                )
+            } else {
+                None
+            };
+            if let Some(ignored) = ignored {
+                self.push(
+                    Severity::Warning,
+                    name,
+                    &format!("{}.copy_strategy", path),
+                    ignored.to_string(),
+                );
+            }
+        }
+
+        match &op.encryption {
+            Some(encryption) => {
+                if !matches!(
+                    op.operation_type,
+                    OperationType::Copy | OperationType::Decrypt
+                ) {
+                    self.push(
+                        Severity::Warning,
+                        name,
+                        &format!("{}.encryption", path),
+                        format!(
+                            "encryption only applies to Copy and Decrypt; this {:?} ignores it",
+                            op.operation_type
+                        ),
+                    );
+                }
+                // The error names the key file only, never its contents
+                if let Err(e) = Key::load(&encryption.key_file) {
+                    self.push(
+                        Severity::Error,
+                        name,
+                        &format!("{}.encryption.key_file", path),
+                        format!("{:#}", e),
+                    );
+                }
+            }
+            None if op.operation_type == OperationType::Decrypt => self.push(
+                Severity::Error,
+                name,
+                &format!("{}.encryption", path),
+                "a Decrypt operation needs encryption.key_file".to_string(),
+            ),
+            None => {}
+        }
+
+        if op.weight == Some(0) {
+            self.push(
+                Severity::Error,
+                name,
+                &format!("{}.weight", path),
+                "weight must be at least 1".to_string(),
+            );
+        }
+
+        if let Some(backoff) = op.retry_backoff
+            && !(backoff >= 0.0 && backoff.is_finite())
+        {
+            self.push(
+                Severity::Error,
+                name,
+                &format!("{}.retry_backoff", path),
+                "retry_backoff must be a non-negative number of seconds".to_string(),
+            );
+        }
+
+        if let Some(schedule) = &op.schedule
+            && let Err(e) = scheduler::Schedule::parse(schedule)
+        {
+            self.push(
+                Severity::Error,
+                name,
+                &format!("{}.schedule", path),
+                e.to_string(),
+            );
+        }
+
+        if let Some(retention) = &op.retention {
+            if !retention.has_rules() {
+                self.push(
+                    Severity::Error,
+                    name,
+                    &format!("{}.retention", path),
+                    "retention has no keep rules; every earlier snapshot would be pruned"
+                        .to_string(),
+                );
+            }
+            if let Ok(None) = SnapshotLayout::from_template(&op.destination) {
+                self.push(
+                    Severity::Warning,
+                    name,
+                    &format!("{}.retention", path),
+                    "retention is set but the destination has no {date} or {time} placeholder, so nothing will be pruned"
+                        .to_string(),
+                );
+            }
+        }
+    }
+}
+
+/// Runs every config check. Line and column information comes from the
+/// source text of each YAML file, when it is available.
+pub fn check_config(config: &Config) -> Vec<Diagnostic> {
+    let maps = config
+        .files
+        .iter()
+        .filter(|file| ConfigFormat::from_path(&file.path) == ConfigFormat::Yaml)
+        .filter_map(|file| {
+            let source = file.source.as_deref()?;
+            Some((file.path.clone(), SourceMap::parse(source)))
+        })
+        .collect();
+    let primary = config.files.first().map(|file| file.path.clone());
+    let mut checker = Checker {
+        maps,
+        file: None,
+        show_file: config.files.len() > 1,
+        diagnostics: Vec::new(),
+    };
+
+    // Positions are per file, so number each operation within its own file
+    let mut index_in_file: HashMap<PathBuf, usize> = HashMap::new();
+    let mut seen: HashMap<&str, Option<&Path>> = HashMap::new();
+    for op in &config.operations {
+        let file = op.source_file.clone().or_else(|| primary.clone());
+        let counter = index_in_file
+            .entry(file.clone().unwrap_or_default())
+            .or_insert(0);
+        let idx = *counter;
+        *counter += 1;
+        checker.file = file;
+
+        if let Some(first_file) = seen.get(op.name.as_str()) {
+            let message = match (first_file, checker.show_file) {
+                (Some(first_file), true) => format!(
+                    "duplicate operation name '{}' (first defined in {})",
+                    op.name,
+                    first_file.display()
+                ),
+                _ => format!("duplicate operation name '{}'", op.name),
+            };
+            checker.push(
+                Severity::Error,
+                Some(&op.name),
+                &format!("operations[{}].name", idx),
+                message,
+            );
+        } else {
+            seen.insert(op.name.as_str(), op.source_file.as_deref());
+        }
+        checker.check_operation(idx, op);
+    }
+
+    checker.file = primary;
+    checker.check_rate_limit(&config.global_rate_limit, None, "global_rate_limit");
+    if let Err(e) = Margin::parse(config.free_space_margin.as_deref()) {
+        checker.push(
+            Severity::Error,
+            None,
+            "free_space_margin",
+            format!("invalid free_space_margin: {}", e),
+        );
+    }
+
+    for (idx, file) in config.files.iter().enumerate() {
+        checker.file = Some(file.path.clone());
+        if let Some(version) = file.migrated_from {
+            checker.push(
+                Severity::Warning,
+                None,
+                "version",
+                format!(
+                    "file uses config schema version {}; it was upgraded on load and will be saved as version {}",
+                    version,
+                    schema::CURRENT_VERSION
+                ),
+            );
+        }
+        if idx > 0 && file.sets_global_rate_limit {
+            checker.push(
+                Severity::Warning,
+                None,
+                "global_rate_limit",
+                "global_rate_limit is only read from the first config file; this one is ignored"
+                    .to_string(),
+            );
+        }
+    }
+
+    checker.diagnostics
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+/// Absolute, `.`/`..`-free form of `path` for comparisons. Existing paths
+/// are canonicalized so symlinks resolve; the rest is cleaned lexically.
+fn normalize(path: &Path) -> PathBuf {
+    if let Ok(canonical) = path.canonicalize() {
+        return canonical;
+    }
+    // Canonicalize the deepest existing ancestor and append the remainder
+    if let Some(ancestor) = nearest_existing_ancestor(path)
+        && let Ok(canonical) = ancestor.canonicalize()
+        && let Ok(rest) = path.strip_prefix(&ancestor)
+    {
+        return lexical_clean(&canonical.join(rest));
+    }
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()
+            .unwrap_or_else(|_| PathBuf::from("."))
+            .join(path)
+    };
+    lexical_clean(&absolute)
+}
+
+fn lexical_clean(path: &Path) -> PathBuf {
+    let mut cleaned = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                cleaned.pop();
+            }
+            other => cleaned.push(other),
+        }
+    }
+    cleaned
+}
+
+fn nearest_existing_ancestor(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .skip(1)
+        .map(|p| {
+            if p.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                p
+            }
+        })
+        .find(|p| p.exists())
+        .map(Path::to_path_buf)
+}
+
+#[cfg(unix)]
+fn is_writable(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: c_path is a valid NUL-terminated string for the duration of the call
+    unsafe { libc::access(c_path.as_ptr(), libc::W_OK) == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_writable(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .map(|m| !m.permissions().readonly())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::fs;
+
+    // One operation as the YAML lines of a list item, three lines long
+    // before its rate limit
+    fn operation(name: &str, origin: &Path, destination: &Path, rate_limit: &str) -> String {
+        format!(
+            "  - name: {}\n    origin: {}\n    destination: {}\n    operation_type: Copy\n    rate_limit:\n{}",
+            name,
+            origin.display(),
+            destination.display(),
+            rate_limit
+        )
+    }
+
+    const NO_LIMIT: &str = "      enabled: false\n";
+
+    // Loads `operations` as config.yaml in `dir` and checks it
+    fn check(dir: &Path, operations: &[String]) -> Vec<Diagnostic> {
+        let path = dir.join("config.yaml");
+        fs::write(
+            &path,
+            format!("version: 3\noperations:\n{}", operations.concat()),
+        )
+        .unwrap();
+        check_config(&Config::load_from_files(&[path]).unwrap())
+    }
+
+    fn find<'a>(diagnostics: &'a [Diagnostic], needle: &str) -> &'a Diagnostic {
+        diagnostics
+            .iter()
+            .find(|d| d.message.contains(needle))
+            .unwrap_or_else(|| panic!("no diagnostic about {:?} in {:#?}", needle, diagnostics))
+    }
+
+    fn position(diagnostic: &Diagnostic) -> (Severity, Option<&str>, usize, usize) {
+        (
+            diagnostic.severity,
+            diagnostic.operation.as_deref(),
+            diagnostic.line.unwrap(),
+            diagnostic.column.unwrap(),
+        )
+    }
+
+    #[test]
+    fn duplicate_operation_names() {
+        let dir = TempDir::new("diagnostics");
+        let diagnostics = check(
+            &dir,
+            &[
+                operation("docs", &dir.join("a"), &dir.join("b"), NO_LIMIT),
+                operation("docs", &dir.join("c"), &dir.join("d"), NO_LIMIT),
+            ],
+        );
+        let duplicate = find(&diagnostics, "duplicate operation name 'docs'");
+        // The second operation's name, on line 3 + 6
+        assert_eq!(position(duplicate), (Severity::Error, Some("docs"), 9, 5));
+        assert_eq!(duplicate.file, None);
+    }
+
+    #[test]
+    fn duplicate_names_across_files_name_the_first_file() {
+        let dir = TempDir::new("diagnostics");
+        let included = dir.join("more.yaml");
+        fs::write(
+            &included,
+            format!(
+                "operations:\n{}",
+                operation("docs", &dir.join("c"), &dir.join("d"), NO_LIMIT)
+            ),
+        )
+        .unwrap();
+        let path = dir.join("config.yaml");
+        fs::write(
+            &path,
+            format!(
+                "version: 3\ninclude:\n  - more.yaml\noperations:\n{}",
+                operation("docs", &dir.join("a"), &dir.join("b"), NO_LIMIT)
+            ),
+        )
+        .unwrap();
+        let diagnostics = check_config(&Config::load_from_files(&[&path]).unwrap());
+        let duplicate = find(&diagnostics, "duplicate operation name 'docs'");
+        assert!(
+            duplicate
+                .message
+                .contains(&format!("first defined in {}", path.display()))
+        );
+        assert_eq!(duplicate.file.as_deref(), Some(included.as_path()));
+        assert_eq!((duplicate.line, duplicate.column), (Some(2), Some(5)));
+    }
+
+    #[test]
+    fn origin_same_as_destination() {
+        let dir = TempDir::new("diagnostics");
+        let diagnostics = check(&dir, &[operation("docs", &dir, &dir.join("."), NO_LIMIT)]);
+        let same = find(&diagnostics, "origin and destination are the same path");
+        assert_eq!(position(same), (Severity::Error, Some("docs"), 5, 5));
+    }
+
+    #[test]
+    fn destination_inside_origin() {
+        let dir = TempDir::new("diagnostics");
+        let diagnostics = check(
+            &dir,
+            &[operation(
+                "docs",
+                &dir,
+                &dir.join("backup/{date}"),
+                NO_LIMIT,
+            )],
+        );
+        let nested = find(&diagnostics, "is inside origin");
+        assert_eq!(position(nested), (Severity::Error, Some("docs"), 5, 5));
+    }
+
+    #[test]
+    fn destination_parent_that_is_a_file() {
+        let dir = TempDir::new("diagnostics");
+        fs::write(dir.join("file"), "").unwrap();
+        let diagnostics = check(
+            &dir,
+            &[operation(
+                "docs",
+                &dir.join("a"),
+                &dir.join("file/backup"),
+                NO_LIMIT,
+            )],
+        );
+        let parent = find(&diagnostics, "exists but is not a directory");
+        assert_eq!(position(parent), (Severity::Error, Some("docs"), 5, 5));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn destination_parent_not_writable() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("diagnostics");
+        let locked = dir.join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o555)).unwrap();
+        // Permissions don't bind root
+        if is_writable(&locked) {
+            return;
+        }
+        let diagnostics = check(
+            &dir,
+            &[operation(
+                "docs",
+                &dir.join("a"),
+                &locked.join("backup"),
+                NO_LIMIT,
+            )],
+        );
+        let unwritable = find(&diagnostics, "is not writable");
+        assert_eq!(position(unwritable), (Severity::Error, Some("docs"), 5, 5));
+    }
+
+    #[test]
+    fn conflicting_rate_fields() {
+        let dir = TempDir::new("diagnostics");
+        let diagnostics = check(
+            &dir,
+            &[
+                operation(
+                    "both",
+                    &dir.join("a"),
+                    &dir.join("b"),
+                    "      enabled: true\n      bytes_per_second: 1000\n      megabytes_per_minute: 5\n",
+                ),
+                operation(
+                    "disabled",
+                    &dir.join("c"),
+                    &dir.join("d"),
+                    "      enabled: false\n      bytes_per_second: 1000\n",
+                ),
+                operation(
+                    "no_rate",
+                    &dir.join("e"),
+                    &dir.join("f"),
+                    "      enabled: true\n",
+                ),
+            ],
+        );
+        let both = find(
+            &diagnostics,
+            "both bytes_per_second and megabytes_per_minute",
+        );
+        assert_eq!(position(both), (Severity::Warning, Some("both"), 10, 7));
+        let ignored = find(&diagnostics, "enabled is false");
+        assert_eq!(
+            position(ignored),
+            (Severity::Warning, Some("disabled"), 16, 7)
+        );
+        let unthrottled = find(&diagnostics, "no rate is set");
+        assert_eq!(
+            position(unthrottled),
+            (Severity::Warning, Some("no_rate"), 23, 7)
+        );
+    }
+
+    #[test]
+    fn source_map_locates_nested_operation_lists() {
+        let map = SourceMap::parse(
+            "\
+version: 2
+operations:
+  - name: first
+    origin: /a
+    rate_limit:
+      enabled: true
+      schedule:
+        - start: \"09:00\"
+          rate: 1MB/s
+        -   start: \"18:00\"
+            rate: 10MB/s
+  - name: second
+    retention:
+      keep_last: 3
+global_rate_limit:
+  enabled: false
+",
+        );
+        assert_eq!(map.get("version"), Some((1, 1)));
+        assert_eq!(map.get("operations[0]"), Some((3, 3)));
+        assert_eq!(map.get("operations[0].name"), Some((3, 5)));
+        assert_eq!(map.get("operations[0].rate_limit.enabled"), Some((6, 7)));
+        assert_eq!(
+            map.get("operations[0].rate_limit.schedule[0]"),
+            Some((8, 9))
+        );
+        assert_eq!(
+            map.get("operations[0].rate_limit.schedule[0].rate"),
+            Some((9, 11))
+        );
+        assert_eq!(
+            map.get("operations[0].rate_limit.schedule[1].start"),
+            Some((10, 13))
+        );
+        assert_eq!(
+            map.get("operations[0].rate_limit.schedule[1].rate"),
+            Some((11, 13))
+        );
+        assert_eq!(map.get("operations[1].name"), Some((12, 5)));
+        assert_eq!(map.get("operations[1].retention.keep_last"), Some((14, 7)));
+        assert_eq!(map.get("global_rate_limit.enabled"), Some((16, 3)));
+        // Unknown fields fall back to the closest key that exists
+        assert_eq!(
+            map.nearest("operations[1].retention.keep_daily"),
+            Some((13, 5))
+        );
+    }
+
+    #[test]
+    fn source_map_skips_flow_collections() {
+        let map = SourceMap::parse(
+            "\
+operations:
+  - name: \"a: b\"
+    exclude: [\"*.tmp\", '[x]', {deep: [1]}]
+    schedule: [
+      first: 1,
+      second: 2 ]
+    origin: /a
+  - [not, a, mapping]
+  - name: c
+",
+        );
+        assert_eq!(map.get("operations[0].name"), Some((2, 5)));
+        assert_eq!(map.get("operations[0].exclude"), Some((3, 5)));
+        assert_eq!(map.get("operations[0].schedule"), Some((4, 5)));
+        assert_eq!(map.get("operations[0].first"), None);
+        assert_eq!(map.get("operations[0].schedule.first"), None);
+        assert_eq!(map.get("operations[0].origin"), Some((7, 5)));
+        assert_eq!(map.get("operations[1]"), Some((8, 3)));
+        assert_eq!(map.get("operations[2].name"), Some((9, 5)));
+    }
+
+    #[test]
+    fn source_map_skips_block_scalars() {
+        let map = SourceMap::parse(
+            "\
+operations:
+  - name: notes
+    description: |
+      origin: not a key
+      - not an item
+
+      still: text
+    origin: /a
+    comment: >-
+      folded: text
+  - name: next
+",
+        );
+        assert_eq!(map.get("operations[0].description"), Some((3, 5)));
+        assert_eq!(map.get("operations[0].description.origin"), None);
+        assert_eq!(map.get("operations[0].description[0]"), None);
+        assert_eq!(map.get("operations[0].origin"), Some((8, 5)));
+        assert_eq!(map.get("operations[0].comment"), Some((9, 5)));
+        assert_eq!(map.get("operations[1].name"), Some((11, 5)));
+        assert_eq!(map.get("operations[2]"), None);
+    }
+}
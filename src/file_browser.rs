@@ -0,0 +1,448 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+// Directory walks for the size preview stop after this many entries so a
+// huge tree doesn't freeze the UI.
+const PREVIEW_ENTRY_LIMIT: usize = 20_000;
+
+#[derive(Debug, Clone)]
+pub struct BrowserEntry {
+    pub name: String,
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SizePreview {
+    pub files: usize,
+    pub bytes: u64,
+    pub truncated: bool,
+}
+
+pub struct FileBrowser {
+    pub current_dir: PathBuf,
+    pub entries: Vec<BrowserEntry>,
+    pub selected: usize,
+    pub filter: String,
+    pub show_hidden: bool,
+    pub error: Option<String>,
+    size_cache: HashMap<PathBuf, SizePreview>,
+}
+
+impl FileBrowser {
+    /// Opens the browser at `start`. Files open their parent directory with
+    /// the file highlighted; paths that don't exist yet fall back to the
+    /// nearest existing ancestor, then the working directory.
+    pub fn open(start: &str) -> Self {
+        let start_path = if start.trim().is_empty() {
+            std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."))
+        } else {
+            PathBuf::from(start.trim())
+        };
+
+        let mut highlight = None;
+        let mut dir = start_path.clone();
+        if dir.is_file() {
+            highlight = dir.file_name().map(|n| n.to_string_lossy().to_string());
+            dir = dir.parent().map(Path::to_path_buf).unwrap_or_default();
+        }
+        while !dir.is_dir() {
+            match dir.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => dir = parent.to_path_buf(),
+                _ => {
+                    dir = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+                    break;
+                }
+            }
+        }
+
+        let mut browser = Self {
+            current_dir: dir.canonicalize().unwrap_or(dir),
+            entries: Vec::new(),
+            selected: 0,
+            filter: String::new(),
+            show_hidden: false,
+            error: None,
+            size_cache: HashMap::new(),
+        };
+        browser.refresh();
+
+        if let Some(name) = highlight
+            && let Some(idx) = browser
+                .visible_entries()
+                .iter()
+                .position(|e| e.name == name)
+        {
+            browser.selected = idx;
+        }
+        browser
+    }
+
+    pub fn refresh(&mut self) {
+        self.entries.clear();
+        self.error = None;
+
+        // "." lets the user pick the directory they are looking at
+        self.entries.push(BrowserEntry {
+            name: ".".to_string(),
+            path: self.current_dir.clone(),
+            is_dir: true,
+            size: 0,
+        });
+
+        match fs::read_dir(&self.current_dir) {
+            Ok(read_dir) => {
+                let mut entries: Vec<BrowserEntry> = read_dir
+                    .flatten()
+                    .filter_map(|entry| {
+                        let name = entry.file_name().to_string_lossy().to_string();
+                        if !self.show_hidden && name.starts_with('.') {
+                            return None;
+                        }
+                        let path = entry.path();
+                        // Follow symlinks so linked directories can be entered
+                        let metadata = fs::metadata(&path).ok();
+                        Some(BrowserEntry {
+                            name,
+                            is_dir: metadata.as_ref().is_some_and(|m| m.is_dir()),
+                            size: metadata.as_ref().map(|m| m.len()).unwrap_or(0),
+                            path,
+                        })
+                    })
+                    .collect();
+                entries.sort_by(|a, b| {
+                    b.is_dir
+                        .cmp(&a.is_dir)
+                        .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+                });
+                self.entries.extend(entries);
+            }
+            Err(e) => {
+                self.error = Some(format!("Cannot read {}: {}", self.current_dir.display(), e));
+            }
+        }
+
+        self.selected = 0;
+    }
+
+    pub fn visible_entries(&self) -> Vec<&BrowserEntry> {
+        let filter = self.filter.to_lowercase();
+        self.entries
+            .iter()
+            .filter(|e| filter.is_empty() || e.name.to_lowercase().starts_with(&filter))
+            .collect()
+    }
+
+    pub fn selected_entry(&self) -> Option<&BrowserEntry> {
+        self.visible_entries().get(self.selected).copied()
+    }
+
+    pub fn select_next(&mut self) {
+        let count = self.visible_entries().len();
+        if count > 0 {
+            self.selected = (self.selected + 1) % count;
+        }
+    }
+
+    pub fn select_previous(&mut self) {
+        let count = self.visible_entries().len();
+        if count > 0 {
+            self.selected = if self.selected == 0 {
+                count - 1
+            } else {
+                self.selected - 1
+            };
+        }
+    }
+
+    pub fn change_dir(&mut self, dir: PathBuf) {
+        self.current_dir = dir;
+        self.filter.clear();
+        self.refresh();
+    }
+
+    /// Descends into the highlighted directory. Returns `false` for files.
+    pub fn enter_selected(&mut self) -> bool {
+        match self.selected_entry() {
+            Some(entry) if entry.is_dir && entry.name != "." => {
+                let path = entry.path.clone();
+                self.change_dir(path);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn go_parent(&mut self) {
+        let child = self
+            .current_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string());
+        if let Some(parent) = self.current_dir.parent().map(Path::to_path_buf)
+            && !parent.as_os_str().is_empty()
+        {
+            self.change_dir(parent);
+            if let Some(child) = child
+                && let Some(idx) = self.visible_entries().iter().position(|e| e.name == child)
+            {
+                self.selected = idx;
+            }
+        }
+    }
+
+    pub fn toggle_hidden(&mut self) {
+        self.show_hidden = !self.show_hidden;
+        self.refresh();
+    }
+
+    pub fn push_filter(&mut self, c: char) {
+        if c == '/' {
+            // Typing a separator after a full directory name descends into it
+            let target = self.current_dir.join(&self.filter);
+            if !self.filter.is_empty() && target.is_dir() {
+                self.change_dir(target);
+            }
+            return;
+        }
+        self.filter.push(c);
+        self.selected = 0;
+    }
+
+    pub fn pop_filter(&mut self) {
+        if self.filter.pop().is_some() {
+            self.selected = 0;
+        } else {
+            self.go_parent();
+        }
+    }
+
+    /// Shell-style completion of the filter: extends it to the longest
+    /// common prefix of the matching names and enters the directory when
+    /// exactly one directory matches.
+    pub fn complete(&mut self) {
+        let matches: Vec<(String, bool, PathBuf)> = self
+            .visible_entries()
+            .into_iter()
+            .filter(|e| e.name != ".")
+            .map(|e| (e.name.clone(), e.is_dir, e.path.clone()))
+            .collect();
+
+        match matches.as_slice() {
+            [] => {}
+            [(_, true, path)] => {
+                let path = path.clone();
+                self.change_dir(path);
+            }
+            [(name, false, _)] => {
+                self.filter = name.clone();
+                self.selected = 0;
+            }
+            [(first, _, _), rest @ ..] => {
+                let mut prefix_len = first.len();
+                for (name, _, _) in rest {
+                    prefix_len = first
+                        .char_indices()
+                        .zip(name.chars())
+                        .take_while(|((_, a), b)| a.eq_ignore_ascii_case(b))
+                        .last()
+                        .map(|((i, a), _)| i + a.len_utf8())
+                        .unwrap_or(0)
+                        .min(prefix_len);
+                }
+                if prefix_len > self.filter.len() {
+                    self.filter = first[..prefix_len].to_string();
+                }
+                self.selected = 0;
+            }
+        }
+    }
+
+    /// File size for files, or a (bounded) recursive total for directories.
+    pub fn preview(&mut self) -> Option<SizePreview> {
+        let entry = self.selected_entry()?.clone();
+        if !entry.is_dir {
+            return Some(SizePreview {
+                files: 1,
+                bytes: entry.size,
+                truncated: false,
+            });
+        }
+
+        if let Some(cached) = self.size_cache.get(&entry.path) {
+            return Some(*cached);
+        }
+
+        let mut preview = SizePreview {
+            files: 0,
+            bytes: 0,
+            truncated: false,
+        };
+        for (seen, walked) in WalkDir::new(&entry.path).into_iter().flatten().enumerate() {
+            if seen >= PREVIEW_ENTRY_LIMIT {
+                preview.truncated = true;
+                break;
+            }
+            if walked.file_type().is_file() {
+                preview.files += 1;
+                preview.bytes += walked.metadata().map(|m| m.len()).unwrap_or(0);
+            }
+        }
+        self.size_cache.insert(entry.path, preview);
+        Some(preview)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    // docs/ with two reports and a hidden file, photos/ and a top-level file
+    fn tree() -> TempDir {
+        let root = TempDir::new("browser");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join("photos")).unwrap();
+        fs::write(root.join("docs/report_a.txt"), "a").unwrap();
+        fs::write(root.join("docs/report_b.txt"), "bb").unwrap();
+        fs::write(root.join("docs/.hidden"), "").unwrap();
+        fs::write(root.join("notes.txt"), "notes").unwrap();
+        root
+    }
+
+    fn names(browser: &FileBrowser) -> Vec<&str> {
+        browser
+            .visible_entries()
+            .iter()
+            .map(|e| e.name.as_str())
+            .collect()
+    }
+
+    fn select(browser: &mut FileBrowser, name: &str) {
+        browser.selected = browser
+            .visible_entries()
+            .iter()
+            .position(|e| e.name == name)
+            .unwrap();
+    }
+
+    #[test]
+    fn lists_directories_first_then_files() {
+        let root = tree();
+        let browser = FileBrowser::open(&root.to_string_lossy());
+        assert_eq!(names(&browser), [".", "docs", "photos", "notes.txt"]);
+        assert_eq!(browser.selected_entry().unwrap().name, ".");
+    }
+
+    #[test]
+    fn opening_a_file_highlights_it_in_its_directory() {
+        let root = tree();
+        let browser = FileBrowser::open(&root.join("docs/report_b.txt").to_string_lossy());
+        assert_eq!(
+            browser.current_dir,
+            root.join("docs").canonicalize().unwrap()
+        );
+        assert_eq!(browser.selected_entry().unwrap().name, "report_b.txt");
+    }
+
+    #[test]
+    fn opening_a_missing_path_falls_back_to_its_nearest_ancestor() {
+        let root = tree();
+        let browser = FileBrowser::open(&root.join("docs/2026/03").to_string_lossy());
+        assert_eq!(
+            browser.current_dir,
+            root.join("docs").canonicalize().unwrap()
+        );
+    }
+
+    #[test]
+    fn enters_directories_and_goes_back_to_the_parent() {
+        let root = tree();
+        let mut browser = FileBrowser::open(&root.to_string_lossy());
+        select(&mut browser, "notes.txt");
+        assert!(!browser.enter_selected());
+        select(&mut browser, ".");
+        assert!(!browser.enter_selected());
+
+        select(&mut browser, "docs");
+        assert!(browser.enter_selected());
+        assert_eq!(names(&browser), [".", "report_a.txt", "report_b.txt"]);
+        browser.go_parent();
+        assert_eq!(browser.current_dir, root.canonicalize().unwrap());
+        // Back on the directory just left
+        assert_eq!(browser.selected_entry().unwrap().name, "docs");
+    }
+
+    #[test]
+    fn filter_narrows_completes_and_descends() {
+        let root = tree();
+        let mut browser = FileBrowser::open(&root.to_string_lossy());
+        browser.push_filter('D');
+        assert_eq!(names(&browser), ["docs"]);
+        browser.complete();
+        // The only match was a directory, so completion entered it
+        assert_eq!(
+            browser.current_dir,
+            root.join("docs").canonicalize().unwrap()
+        );
+        assert!(browser.filter.is_empty());
+
+        browser.push_filter('r');
+        browser.complete();
+        assert_eq!(browser.filter, "report_");
+        browser.push_filter('b');
+        browser.complete();
+        assert_eq!(browser.filter, "report_b.txt");
+
+        // Backspacing past an empty filter goes up a level
+        browser.filter.clear();
+        browser.pop_filter();
+        assert_eq!(browser.current_dir, root.canonicalize().unwrap());
+
+        for c in "photos/".chars() {
+            browser.push_filter(c);
+        }
+        assert_eq!(
+            browser.current_dir,
+            root.join("photos").canonicalize().unwrap()
+        );
+    }
+
+    #[test]
+    fn hidden_files_show_only_when_toggled() {
+        let root = tree();
+        let mut browser = FileBrowser::open(&root.join("docs").to_string_lossy());
+        assert!(!names(&browser).contains(&".hidden"));
+        browser.toggle_hidden();
+        assert_eq!(
+            names(&browser),
+            [".", ".hidden", "report_a.txt", "report_b.txt"]
+        );
+        browser.toggle_hidden();
+        assert!(!names(&browser).contains(&".hidden"));
+    }
+
+    #[test]
+    fn selection_wraps_around() {
+        let root = tree();
+        let mut browser = FileBrowser::open(&root.to_string_lossy());
+        browser.select_previous();
+        assert_eq!(browser.selected_entry().unwrap().name, "notes.txt");
+        browser.select_next();
+        assert_eq!(browser.selected_entry().unwrap().name, ".");
+    }
+
+    #[test]
+    fn preview_totals_a_directory() {
+        let root = tree();
+        let mut browser = FileBrowser::open(&root.to_string_lossy());
+        select(&mut browser, "docs");
+        let preview = browser.preview().unwrap();
+        assert_eq!((preview.files, preview.bytes), (3, 3));
+        assert!(!preview.truncated);
+        select(&mut browser, "notes.txt");
+        assert_eq!(browser.preview().unwrap().bytes, 5);
+    }
+}
@@ -1,16 +1,51 @@
-use crate::config::{FileOperation, OperationType, RateLimit};
-use crate::rate_limiter::RateLimiter;
+use crate::archive;
+use crate::compression::{self, CountingWriter, Encoder};
+use crate::config::{
+    Compression, CopyStrategy, FileOperation, OperationType, Retention, format_rate, format_size,
+};
+use crate::copy_strategy::{self, Method};
+use crate::crypto::{self, EncryptWriter, Key};
+use crate::paths;
+use crate::preflight::{self, Margin, SpaceCheck};
+use crate::rate_limiter::{GlobalLimiter, RateLimiter, ThrottledWriter};
+use crate::repository::{DedupStats, Repository, SnapshotEntry, SnapshotManifest};
+use crate::restore::{self, Conflict, Existing, Source};
+use crate::retention::{self, SnapshotLayout};
 use crate::validation;
+use chrono::Local;
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 use walkdir::WalkDir;
 
-#[derive(Debug, Clone)]
+// Longest wait between retries of a failed copy
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+// Report file names end in this, so runs finishing within the same second
+// don't overwrite each other's reports
+const REPORT_STAMP_FORMAT: &str = "%Y%m%d_%H%M%S_%3f";
+
+/// The stamp naming one run's report files: the local time to the
+/// millisecond, moved on past the last stamp handed out so no two runs in
+/// this process share one.
+pub fn report_stamp() -> String {
+    static LAST: Mutex<Option<chrono::DateTime<Local>>> = Mutex::new(None);
+    let mut last = LAST.lock().unwrap_or_else(|e| e.into_inner());
+    let mut now = Local::now();
+    if let Some(last) = *last {
+        now = now.max(last + chrono::TimeDelta::milliseconds(1));
+    }
+    *last = Some(now);
+    now.format(REPORT_STAMP_FORMAT).to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileEntry {
     pub source_path: String,
     pub destination_path: String,
@@ -18,9 +53,12 @@ pub struct FileEntry {
     pub hash_verified: bool,
     pub success: bool,
     pub error_message: Option<String>,
+    // Content hash, recorded where the operation keeps a manifest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OperationResult {
     pub operation_name: String,
     pub source: String,
@@ -35,6 +73,78 @@ pub struct OperationResult {
     pub end_time: SystemTime,
     pub details: Vec<String>,
     pub file_list: Vec<FileEntry>,
+    // Set by Repository operations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup: Option<DedupStats>,
+}
+
+// Why one attempt at copying and verifying a file failed
+enum CopyFailure {
+    Copy(io::Error),
+    Mismatch(u64),
+    Verify(u64, anyhow::Error),
+}
+
+impl fmt::Display for CopyFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CopyFailure::Copy(e) => write!(f, "copy failed: {}", e),
+            CopyFailure::Mismatch(_) => write!(f, "hash verification failed"),
+            CopyFailure::Verify(_, e) => write!(f, "verification error: {}", e),
+        }
+    }
+}
+
+/// How copies are written: compressed, then encrypted, each step adding
+/// a suffix to the file name.
+#[derive(Debug, Clone, Default)]
+pub struct OutputFormat {
+    pub compression: Option<Compression>,
+    pub key: Option<Key>,
+    // Only used for plain copies
+    pub strategy: Option<CopyStrategy>,
+}
+
+impl OutputFormat {
+    /// The operation's format, loading its key if it encrypts.
+    pub fn for_operation(operation: &FileOperation) -> anyhow::Result<Self> {
+        let key = match &operation.encryption {
+            Some(encryption) => Some(Key::load(&encryption.key_file)?),
+            None => None,
+        };
+        Ok(Self {
+            compression: operation.compression,
+            key,
+            strategy: operation.copy_strategy,
+        })
+    }
+
+    pub fn is_plain(&self) -> bool {
+        self.compression.is_none() && self.key.is_none()
+    }
+
+    fn plain_strategy(&self) -> Option<CopyStrategy> {
+        self.strategy.filter(|_| self.is_plain())
+    }
+
+    /// Where a file bound for `destination` is written.
+    pub fn path(&self, destination: &Path) -> PathBuf {
+        let path = compression::compressed_path(destination, self.compression);
+        match self.key {
+            Some(_) => crypto::encrypted_path(&path),
+            None => path,
+        }
+    }
+
+    /// The reverse of `path`; `None` for an encrypted copy that lacks the
+    /// suffix.
+    fn plain_path(&self, written: &Path) -> Option<PathBuf> {
+        let path = match self.key {
+            Some(_) => crypto::strip_suffix(written)?,
+            None => written.to_path_buf(),
+        };
+        Some(compression::strip_suffix(&path, self.compression))
+    }
 }
 
 pub struct FileManager;
@@ -42,7 +152,8 @@ pub struct FileManager;
 impl FileManager {
     pub fn execute_operations(
         operations: &[FileOperation],
-        global_rate_limit: &RateLimit,
+        global_limiter: &GlobalLimiter,
+        free_space_margin: &Margin,
         progress_callback: Option<Arc<dyn Fn(String) + Send + Sync>>,
     ) -> Vec<OperationResult> {
         let results = Arc::new(Mutex::new(Vec::new()));
@@ -63,9 +174,19 @@ impl FileManager {
             );
         }
 
-        operations.par_iter().for_each(|op| {
+        // Copies that would fill their destination filesystem fail up front
+        // rather than halfway through
+        let space_checks = preflight::check(operations, free_space_margin, Local::now());
+
+        operations.par_iter().enumerate().for_each(|(idx, op)| {
             let start_time = SystemTime::now();
-            let result = Self::execute_single_operation(op, global_rate_limit, start_time);
+            let short = space_checks
+                .iter()
+                .find(|check| check.is_short() && check.operations.contains(&idx));
+            let result = match short {
+                Some(check) => Self::insufficient_space(op, operations, check, start_time),
+                None => Self::execute_single_operation(op, global_limiter, start_time),
+            };
 
             let mut results_lock = results.lock().unwrap();
             results_lock.push(result);
@@ -86,9 +207,192 @@ impl FileManager {
         Arc::try_unwrap(results).unwrap().into_inner().unwrap()
     }
 
+    /// Runs one operation on its own, outside the parallel batch.
+    pub fn execute_operation(
+        operation: &FileOperation,
+        global_limiter: &GlobalLimiter,
+    ) -> OperationResult {
+        Self::execute_single_operation(operation, global_limiter, SystemTime::now())
+    }
+
+    // Result for an operation that was not started because its destination
+    // filesystem lacks room for everything planned onto it
+    fn insufficient_space(
+        operation: &FileOperation,
+        operations: &[FileOperation],
+        check: &SpaceCheck,
+        start_time: SystemTime,
+    ) -> OperationResult {
+        let mut error_msg = format!("Not enough free space: {}", check.describe());
+        if check.operations.len() > 1 {
+            let names: Vec<&str> = check
+                .operations
+                .iter()
+                .map(|&idx| operations[idx].name.as_str())
+                .collect();
+            error_msg.push_str(&format!(" (shared by: {})", names.join(", ")));
+        }
+        let mut details = vec![
+            format!("Starting operation: {}", operation.name),
+            format!("  Type: {:?}", operation.operation_type),
+            format!("  Source: {}", operation.origin.display()),
+            format!("  Destination: {}", operation.destination.display()),
+        ];
+        // Name the directory the run would have written to, as a started
+        // operation does; the preflight check skipped templates that fail
+        let mut destination = operation.destination.to_string_lossy().to_string();
+        if paths::is_template(&destination)
+            && let Ok(rendered) = paths::render_template(&destination, start_time.into())
+        {
+            details.push(format!("  Resolved destination: {}", rendered));
+            destination = rendered;
+        }
+        details.push(format!("ERROR: {}", error_msg));
+        details.push("  Nothing was copied; free up space or lower free_space_margin".to_string());
+        OperationResult {
+            operation_name: operation.name.clone(),
+            source: operation.origin.to_string_lossy().to_string(),
+            destination,
+            success: false,
+            error_message: Some(error_msg),
+            hash_verified: false,
+            operation_type: operation.operation_type.clone(),
+            files_processed: 0,
+            total_size: 0,
+            start_time,
+            end_time: SystemTime::now(),
+            details,
+            file_list: Vec::new(),
+            dedup: None,
+        }
+    }
+
+    /// Re-runs the failed files of an earlier run, one result per earlier
+    /// operation. Settings such as rate limits and retries come from the
+    /// operation of the same name in `operations`. An operation that failed
+    /// before reaching any file is run again in full.
+    pub fn retry_failed(
+        previous: &[OperationResult],
+        operations: &[FileOperation],
+        global_limiter: &GlobalLimiter,
+    ) -> Vec<OperationResult> {
+        let mut results = Vec::new();
+        for earlier in previous {
+            let failed: Vec<&FileEntry> = earlier.file_list.iter().filter(|f| !f.success).collect();
+            if earlier.success && failed.is_empty() {
+                continue;
+            }
+
+            let mut notes = Vec::new();
+            let operation = match operations
+                .iter()
+                .find(|op| op.name == earlier.operation_name)
+            {
+                Some(op) => op.clone(),
+                None => {
+                    notes.push(format!(
+                        "WARNING: '{}' is no longer in the config; retrying with default settings",
+                        earlier.operation_name
+                    ));
+                    FileOperation {
+                        name: earlier.operation_name.clone(),
+                        origin: earlier.source.clone().into(),
+                        destination: paths::escape_template(&earlier.destination).into(),
+                        operation_type: earlier.operation_type.clone(),
+                        ..Default::default()
+                    }
+                }
+            };
+
+            // An archive is written or extracted as a whole, and a
+            // repository snapshot only lists a complete run
+            let whole = matches!(
+                operation.operation_type,
+                OperationType::Archive | OperationType::Extract | OperationType::Repository
+            );
+            if failed.is_empty() || whole {
+                let mut result = Self::execute_operation(&operation, global_limiter);
+                notes.push(if whole {
+                    format!(
+                        "  {:?} operations can't be retried per file; ran it again in full",
+                        operation.operation_type
+                    )
+                } else {
+                    "  Earlier run failed before copying any file; ran it again in full".to_string()
+                });
+                notes.append(&mut result.details);
+                result.details = notes;
+                results.push(result);
+                continue;
+            }
+
+            let start_time = SystemTime::now();
+            let mut combined = OperationResult {
+                operation_name: earlier.operation_name.clone(),
+                source: earlier.source.clone(),
+                destination: earlier.destination.clone(),
+                success: true,
+                error_message: None,
+                hash_verified: true,
+                operation_type: earlier.operation_type.clone(),
+                files_processed: 0,
+                total_size: 0,
+                start_time,
+                end_time: start_time,
+                details: notes,
+                file_list: Vec::new(),
+                dedup: None,
+            };
+            combined.details.push(format!(
+                "Retrying {} failed file(s) of operation: {}",
+                failed.len(),
+                earlier.operation_name
+            ));
+
+            let mut errors = Vec::new();
+            for entry in failed {
+                // The recorded destination is already resolved, and a copy's
+                // carries any suffixes copy_file will add again. A decrypted
+                // file's is the plaintext path, used as given.
+                let mut destination = PathBuf::from(&entry.destination_path);
+                if operation.operation_type == OperationType::Copy {
+                    if operation.encryption.is_some()
+                        && let Some(stripped) = crypto::strip_suffix(&destination)
+                    {
+                        destination = stripped;
+                    }
+                    destination = compression::strip_suffix(&destination, operation.compression);
+                }
+                let file_op = FileOperation {
+                    origin: entry.source_path.clone().into(),
+                    destination: paths::escape_template(&destination.to_string_lossy()).into(),
+                    retention: None,
+                    schedule: None,
+                    ..operation.clone()
+                };
+                let mut result = Self::execute_operation(&file_op, global_limiter);
+                combined.success &= result.success;
+                combined.hash_verified &= result.hash_verified;
+                combined.files_processed += result.files_processed;
+                combined.total_size += result.total_size;
+                combined.details.append(&mut result.details);
+                combined.file_list.append(&mut result.file_list);
+                if let Some(e) = result.error_message {
+                    errors.push(e);
+                }
+            }
+            if !errors.is_empty() {
+                combined.error_message = Some(errors.join("; "));
+            }
+            combined.end_time = SystemTime::now();
+            results.push(combined);
+        }
+        results
+    }
+
     fn execute_single_operation(
         operation: &FileOperation,
-        global_rate_limit: &RateLimit,
+        global_limiter: &GlobalLimiter,
         start_time: SystemTime,
     ) -> OperationResult {
         let mut details = Vec::new();
@@ -100,6 +404,44 @@ impl FileManager {
             operation.destination.display()
         ));
 
+        // Resolve destination templates once, so every file in the run lands
+        // under the same dated directory even if the run crosses midnight
+        let template = operation.destination.to_string_lossy().to_string();
+        let resolved = if paths::is_template(&template) {
+            match paths::render_template(&template, start_time.into()) {
+                Ok(destination) => {
+                    details.push(format!("  Resolved destination: {}", destination));
+                    FileOperation {
+                        destination: destination.into(),
+                        ..operation.clone()
+                    }
+                }
+                Err(e) => {
+                    let error_msg = format!("Invalid destination template: {}", e);
+                    details.push(format!("ERROR: {}", error_msg));
+                    return OperationResult {
+                        operation_name: operation.name.clone(),
+                        source: operation.origin.to_string_lossy().to_string(),
+                        destination: template,
+                        success: false,
+                        error_message: Some(error_msg),
+                        hash_verified: false,
+                        operation_type: operation.operation_type.clone(),
+                        files_processed: 0,
+                        total_size: 0,
+                        start_time,
+                        end_time: SystemTime::now(),
+                        details,
+                        file_list: Vec::new(),
+                        dedup: None,
+                    };
+                }
+            }
+        } else {
+            operation.clone()
+        };
+        let operation = &resolved;
+
         let mut result = OperationResult {
             operation_name: operation.name.clone(),
             source: operation.origin.to_string_lossy().to_string(),
@@ -114,6 +456,7 @@ impl FileManager {
             end_time: SystemTime::now(),
             details: details.clone(),
             file_list: Vec::new(),
+            dedup: None,
         };
 
         if !operation.origin.exists() {
@@ -145,31 +488,45 @@ impl FileManager {
             if is_dir { "directory" } else { "file" }
         ));
 
-        if let Some(parent) = operation.destination.parent() {
-            if !parent.exists() {
-                details.push(format!("  Creating parent directory: {}", parent.display()));
-                if let Err(e) = fs::create_dir_all(parent) {
-                    let error_msg = format!(
-                        "Failed to create destination directory '{}': {}",
-                        parent.display(),
-                        e
-                    );
-                    details.push(format!("ERROR: {}", error_msg));
-                    result.error_message = Some(error_msg.clone()); // Clone here
-                    result.end_time = SystemTime::now();
-                    result.details = details;
-                    return result;
-                }
-                details.push("  Parent directory created successfully".to_string());
+        if let Some(parent) = operation.destination.parent()
+            && !parent.exists()
+        {
+            details.push(format!("  Creating parent directory: {}", parent.display()));
+            if let Err(e) = fs::create_dir_all(parent) {
+                let error_msg = format!(
+                    "Failed to create destination directory '{}': {}",
+                    parent.display(),
+                    e
+                );
+                details.push(format!("ERROR: {}", error_msg));
+                result.error_message = Some(error_msg.clone()); // Clone here
+                result.end_time = SystemTime::now();
+                result.details = details;
+                return result;
             }
+            details.push("  Parent directory created successfully".to_string());
         }
 
+        // Only the key file's path is ever reported, never the key
+        let format = match OutputFormat::for_operation(operation) {
+            Ok(format) => format,
+            Err(e) => {
+                let error_msg = format!("Cannot load encryption key: {:#}", e);
+                details.push(format!("ERROR: {}", error_msg));
+                result.error_message = Some(error_msg);
+                result.end_time = SystemTime::now();
+                result.details = details;
+                return result;
+            }
+        };
+
         match operation.operation_type {
             OperationType::Copy => {
                 if is_dir {
-                    result = Self::copy_directory(operation, global_rate_limit, details);
+                    result =
+                        Self::copy_directory(operation, &format, None, global_limiter, details);
                 } else {
-                    result = Self::copy_file(operation, global_rate_limit, details);
+                    result = Self::copy_file(operation, &format, None, global_limiter, details);
                 }
             }
             OperationType::Move => {
@@ -179,46 +536,156 @@ impl FileManager {
                     result = Self::move_file(operation, details);
                 }
             }
+            OperationType::Archive if is_dir => {
+                result = Self::archive_directory(operation, global_limiter, details);
+            }
+            OperationType::Extract if is_file => {
+                result = Self::extract_archive(operation, global_limiter, details);
+            }
+            OperationType::Decrypt => match &format.key {
+                Some(key) => {
+                    result = Self::decrypt(operation, key, is_dir, global_limiter, details);
+                }
+                None => {
+                    let error_msg = "Decrypt needs an encryption key_file".to_string();
+                    details.push(format!("ERROR: {}", error_msg));
+                    result.error_message = Some(error_msg);
+                    result.details = details;
+                }
+            },
+            OperationType::Repository if is_dir => {
+                result = Self::store_in_repository(operation, global_limiter, details);
+            }
+            OperationType::Archive | OperationType::Extract | OperationType::Repository => {
+                let error_msg = format!(
+                    "{:?} needs a {} as its source",
+                    operation.operation_type,
+                    if is_dir { "archive file" } else { "directory" }
+                );
+                details.push(format!("ERROR: {}", error_msg));
+                result.error_message = Some(error_msg);
+                result.details = details;
+            }
+        }
+
+        // A policy without rules would keep nothing but the current run
+        if result.success
+            && let Some(policy) = &operation.retention
+            && policy.has_rules()
+        {
+            Self::apply_retention(policy, Path::new(&template), operation, &mut result.details);
         }
 
         result.end_time = SystemTime::now();
         result
     }
 
-    fn compute_effective_bps(op: &RateLimit, global: &RateLimit) -> Option<u64> {
-        // Respect enabled flags and choose defaults/caps
-        // If neither enabled, no throttling
-        if !op.enabled && !global.enabled {
-            return None;
-        }
-        // Helper to convert RateLimit to optional bps
-        let to_bps = |rl: &RateLimit| -> Option<u64> {
-            if !rl.enabled {
-                return None;
+    fn apply_retention(
+        policy: &Retention,
+        destination_template: &Path,
+        operation: &FileOperation,
+        details: &mut Vec<String>,
+    ) {
+        let layout = match SnapshotLayout::from_template(destination_template) {
+            Ok(Some(layout)) => layout,
+            Ok(None) => {
+                details.push(
+                    "  Retention: destination has no date placeholder, nothing to prune"
+                        .to_string(),
+                );
+                return;
             }
-            if let Some(bps) = rl.bytes_per_second {
-                Some(bps)
-            } else if let Some(mb_min) = rl.megabytes_per_minute {
-                Some(mb_min * 1024 * 1024 / 60)
-            } else {
-                None
+            Err(e) => {
+                details.push(format!("WARNING: Retention skipped: {}", e));
+                return;
+            }
+        };
+
+        let snapshots = match layout.list_snapshots() {
+            Ok(snapshots) => snapshots,
+            Err(e) => {
+                details.push(format!(
+                    "WARNING: Retention skipped, cannot list {}: {}",
+                    layout.root.display(),
+                    e
+                ));
+                return;
             }
         };
-        let op_bps = to_bps(op);
-        let global_bps = to_bps(global);
-        match (op_bps, global_bps) {
-            (Some(o), Some(g)) => Some(o.min(g)),
-            (Some(o), None) => Some(o),
-            (None, Some(g)) => Some(g),
-            (None, None) => None,
+
+        let current = layout.snapshot_of(&operation.destination);
+        let decisions = retention::plan(policy, snapshots, current.as_deref());
+        details.push(format!(
+            "  Retention: {} snapshot(s) under {}",
+            decisions.len(),
+            layout.root.display()
+        ));
+        for line in retention::apply(&decisions, false) {
+            details.push(format!("  Retention: {}", line));
+        }
+    }
+
+    // Describes the limits a copy runs under, including any schedules
+    fn push_rate_limiting(
+        details: &mut Vec<String>,
+        rate_limiter: &RateLimiter,
+        operation: &FileOperation,
+        global_limiter: &GlobalLimiter,
+        label: &str,
+    ) {
+        if !rate_limiter.is_enabled() {
+            return;
+        }
+        if rate_limiter.limits_bytes() {
+            match rate_limiter.get_rate_limit() {
+                Some(limit) => details.push(format!(
+                    "  {}: {} bytes/second ({:.2} MB/min)",
+                    label,
+                    limit,
+                    limit as f64 * 60.0 / (1024.0 * 1024.0)
+                )),
+                None => details.push(format!("  {}: currently unlimited", label)),
+            }
+        }
+        let limit = &operation.rate_limit;
+        if limit.enabled {
+            if let Some(adaptive) = &limit.adaptive {
+                details.push(format!("  Adaptive throttling: {}", adaptive.describe()));
+            }
+            if let Some(files) = limit.files_per_second {
+                details.push(format!("  File rate limiting: {} files/second", files));
+            }
+            if let Some(ops) = limit.metadata_ops_per_second {
+                details.push(format!(
+                    "  Metadata rate limiting: {} operations/second",
+                    ops
+                ));
+            }
+        }
+        if limit.is_active() && !limit.schedule.is_empty() {
+            details.push(format!("  Rate schedule (operation): {}", limit.describe()));
+        }
+        if let Some(global) = global_limiter.describe() {
+            details.push(format!(
+                "  Shared global limit: {} (weight {})",
+                global,
+                operation.weight.unwrap_or(1)
+            ));
         }
     }
 
-    fn copy_file(operation: &FileOperation, global_rate_limit: &RateLimit, mut details: Vec<String>) -> OperationResult {
+    fn copy_file(
+        operation: &FileOperation,
+        format: &OutputFormat,
+        on_conflict: Option<Conflict>,
+        global_limiter: &GlobalLimiter,
+        mut details: Vec<String>,
+    ) -> OperationResult {
+        let destination = format.path(&operation.destination);
         let mut result = OperationResult {
             operation_name: operation.name.clone(),
             source: operation.origin.to_string_lossy().to_string(),
-            destination: operation.destination.to_string_lossy().to_string(),
+            destination: destination.to_string_lossy().to_string(),
             success: false,
             error_message: None,
             hash_verified: false,
@@ -229,6 +696,7 @@ impl FileManager {
             end_time: SystemTime::now(),
             details: details.clone(),
             file_list: Vec::new(),
+            dedup: None,
         };
 
         let file_size = if let Ok(metadata) = std::fs::metadata(&operation.origin) {
@@ -239,114 +707,146 @@ impl FileManager {
         result.total_size = file_size;
         details.push(format!("  File size: {} bytes", result.total_size));
 
-        // Compute effective rate limit combining per-op and global (cap by min)
-        let effective_bps = Self::compute_effective_bps(&operation.rate_limit, global_rate_limit);
-        let mut rate_limiter = RateLimiter::new(effective_bps, None);
-
-        if rate_limiter.is_enabled() {
-            if let Some(limit) = rate_limiter.get_rate_limit() {
-                details.push(format!(
-                    "  Rate limiting: {} bytes/second ({:.2} MB/min)",
-                    limit,
-                    limit as f64 * 60.0 / (1024.0 * 1024.0)
-                ));
+        // The tighter of the per-op and global limits applies
+        let mut rate_limiter = RateLimiter::for_operation(
+            &operation.rate_limit,
+            global_limiter,
+            operation.weight.unwrap_or(1),
+        );
+        Self::push_rate_limiting(
+            &mut details,
+            &rate_limiter,
+            operation,
+            global_limiter,
+            "Rate limiting",
+        );
+
+        if let Some(compression) = format.compression {
+            details.push(format!(
+                "  Compression: {:?}, writing {}",
+                compression,
+                destination.display()
+            ));
+        }
+        Self::push_encryption(&mut details, operation);
+        Self::push_strategy(&mut details, format);
+        if let Some(conflict) = on_conflict
+            && destination.exists()
+        {
+            match Self::check_existing(&operation.origin, &destination, conflict) {
+                Ok((Existing::Identical, sha256)) => {
+                    details.push("  Already up to date".to_string());
+                    result.success = true;
+                    result.hash_verified = true;
+                    result.file_list.push(FileEntry {
+                        source_path: operation.origin.to_string_lossy().to_string(),
+                        destination_path: destination.to_string_lossy().to_string(),
+                        size: file_size,
+                        hash_verified: true,
+                        success: true,
+                        error_message: None,
+                        sha256: Some(sha256),
+                    });
+                    result.details = details;
+                    return result;
+                }
+                Ok((Existing::Kept, _)) => {
+                    details.push(format!(
+                        "WARNING: Kept {}: it differs from the copy being restored",
+                        destination.display()
+                    ));
+                    result.success = true;
+                    result.details = details;
+                    return result;
+                }
+                Ok((Existing::MovedTo(aside), _)) => details.push(format!(
+                    "  Moved the existing file aside to {}",
+                    aside.display()
+                )),
+                Ok(_) => {}
+                Err(e) => {
+                    let error_msg =
+                        format!("Cannot restore over {}: {:#}", destination.display(), e);
+                    details.push(format!("ERROR: {}", error_msg));
+                    result.error_message = Some(error_msg);
+                    result.details = details;
+                    return result;
+                }
             }
         }
-
         details.push("  Starting file copy...".to_string());
 
-        // Use a custom copy function with rate limiting
-        let copy_result: io::Result<u64> = if rate_limiter.is_enabled() {
-            Self::copy_file_with_rate_limit(
-                &operation.origin,
-                &operation.destination,
-                &mut rate_limiter,
-            )
-        } else {
-            fs::copy(&operation.origin, &operation.destination)
-        };
-
-        match copy_result {
-            Ok(bytes_copied) => {
+        match Self::copy_with_retries(
+            operation,
+            &operation.origin,
+            &destination,
+            format,
+            &mut rate_limiter,
+            &mut details,
+            "  ",
+        ) {
+            Ok((bytes_copied, sha256)) => {
                 details.push(format!("  Copy completed: {} bytes copied", bytes_copied));
                 result.total_size = bytes_copied;
+                details.push("  Verification successful: Files match".to_string());
+                result.success = true;
+                result.hash_verified = true;
 
-                details.push("  Verifying file integrity...".to_string());
-                match validation::verify_files_match(&operation.origin, &operation.destination) {
-                    Ok(true) => {
-                        details.push("  Verification successful: Files match".to_string());
-                        result.success = true;
-                        result.hash_verified = true;
-
-                        result.file_list.push(FileEntry {
-                            source_path: operation.origin.to_string_lossy().to_string(),
-                            destination_path: operation.destination.to_string_lossy().to_string(),
-                            size: bytes_copied,
-                            hash_verified: true,
-                            success: true,
-                            error_message: None,
-                        });
-                    }
-                    Ok(false) => {
-                        let error_msg =
-                            "Hash verification failed - files are different".to_string();
-                        details.push(format!("ERROR: {}", error_msg));
-                        result.error_message = Some(error_msg.clone());
-                        let _ = fs::remove_file(&operation.destination);
-                        details.push("  Cleaned up failed copy".to_string());
-
-                        result.file_list.push(FileEntry {
-                            source_path: operation.origin.to_string_lossy().to_string(),
-                            destination_path: operation.destination.to_string_lossy().to_string(),
-                            size: bytes_copied,
-                            hash_verified: false,
-                            success: false,
-                            error_message: Some(error_msg),
-                        });
-                    }
-                    Err(e) => {
-                        let error_msg = format!("Verification error: {}", e);
-                        details.push(format!("ERROR: {}", error_msg));
-                        result.error_message = Some(error_msg.clone());
-                        let _ = fs::remove_file(&operation.destination);
-                        details.push("  Cleaned up failed copy".to_string());
-
-                        result.file_list.push(FileEntry {
-                            source_path: operation.origin.to_string_lossy().to_string(),
-                            destination_path: operation.destination.to_string_lossy().to_string(),
-                            size: bytes_copied,
-                            hash_verified: false,
-                            success: false,
-                            error_message: Some(error_msg),
-                        });
-                    }
-                }
+                result.file_list.push(FileEntry {
+                    source_path: operation.origin.to_string_lossy().to_string(),
+                    destination_path: destination.to_string_lossy().to_string(),
+                    size: bytes_copied,
+                    hash_verified: true,
+                    success: true,
+                    error_message: None,
+                    sha256: Some(sha256),
+                });
             }
-            Err(e) => {
-                let error_msg = format!(
-                    "Copy failed: {} (from {} to {})",
-                    e,
-                    operation.origin.display(),
-                    operation.destination.display()
-                );
+            Err(failure) => {
+                let (size, error_msg) = match &failure {
+                    CopyFailure::Copy(e) => (
+                        file_size,
+                        format!(
+                            "Copy failed: {} (from {} to {})",
+                            e,
+                            operation.origin.display(),
+                            destination.display()
+                        ),
+                    ),
+                    CopyFailure::Mismatch(bytes) => (
+                        *bytes,
+                        "Hash verification failed - files are different".to_string(),
+                    ),
+                    CopyFailure::Verify(bytes, e) => (*bytes, format!("Verification error: {}", e)),
+                };
+                if !matches!(failure, CopyFailure::Copy(_)) {
+                    details.push(format!("  Copy completed: {} bytes copied", size));
+                }
                 details.push(format!("ERROR: {}", error_msg));
                 result.error_message = Some(error_msg.clone());
 
                 result.file_list.push(FileEntry {
                     source_path: operation.origin.to_string_lossy().to_string(),
-                    destination_path: operation.destination.to_string_lossy().to_string(),
-                    size: file_size,
+                    destination_path: destination.to_string_lossy().to_string(),
+                    size,
                     hash_verified: false,
                     success: false,
                     error_message: Some(error_msg),
+                    sha256: None,
                 });
 
-                if e.kind() == io::ErrorKind::PermissionDenied {
-                    details.push("  Permission denied - check file permissions".to_string());
-                } else if e.kind() == io::ErrorKind::NotFound {
-                    details.push("  File not found - check path".to_string());
-                } else if e.kind() == io::ErrorKind::AlreadyExists {
-                    details.push("  Destination already exists".to_string());
+                match failure {
+                    CopyFailure::Copy(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                        details.push("  Permission denied - check file permissions".to_string());
+                    }
+                    CopyFailure::Copy(e) if e.kind() == io::ErrorKind::NotFound => {
+                        details.push("  File not found - check path".to_string());
+                    }
+                    CopyFailure::Copy(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                        details.push("  Destination already exists".to_string());
+                    }
+                    CopyFailure::Copy(_) => {}
+                    _ => details.push("  Cleaned up failed copy".to_string()),
                 }
             }
         }
@@ -355,24 +855,90 @@ impl FileManager {
         result
     }
 
-    // NEW: Copy file with rate limiting
+    // Whether `name` is a report `save_operation_reports_to_destinations`
+    // or `save_file_list_reports` wrote next to a copy of `operation_name`
+    fn is_destination_report(name: &Path, operation_name: &str) -> bool {
+        let name = name.to_string_lossy();
+        let Some(stem) = name.strip_suffix(".txt") else {
+            return false;
+        };
+        let slug = operation_name.replace(" ", "_").to_lowercase();
+        // Reports written before stamps had milliseconds end in whole seconds
+        [REPORT_STAMP_FORMAT, "%Y%m%d_%H%M%S"].iter().any(|format| {
+            let Some((prefix, stamp)) = stem
+                .rsplitn(format.matches('_').count() + 2, '_')
+                .last()
+                .map(|prefix| (prefix, &stem[prefix.len()..]))
+            else {
+                return false;
+            };
+            let named = prefix == format!("file_list_{}", slug)
+                || (prefix.starts_with("operation_") && prefix.ends_with(&format!("_{}", slug)));
+            named && chrono::NaiveDateTime::parse_from_str(stamp, &format!("_{}", format)).is_ok()
+        })
+    }
+
+    fn push_strategy(details: &mut Vec<String>, format: &OutputFormat) {
+        if let Some(strategy) = format.plain_strategy() {
+            details.push(format!("  Copy strategy: {:?}", strategy));
+        }
+    }
+
+    // What restoring `source` over the existing `destination` should do,
+    // with the source's hash
+    fn check_existing(
+        source: &Path,
+        destination: &Path,
+        conflict: Conflict,
+    ) -> anyhow::Result<(Existing, String)> {
+        let sha256 = validation::calculate_sha256(source)?;
+        let existing = restore::prepare(destination, &sha256, conflict)?;
+        Ok((existing, sha256))
+    }
+
+    // Names the key file only; the key itself never reaches the details
+    fn push_encryption(details: &mut Vec<String>, operation: &FileOperation) {
+        if let Some(encryption) = &operation.encryption {
+            details.push(format!(
+                "  Encryption: AES-256-GCM (key from {}), adding {}",
+                encryption.key_file.display(),
+                crypto::SUFFIX
+            ));
+        }
+    }
+
+    // NEW: Copy file with rate limiting. With compression, the limit applies
+    // to the compressed bytes written, not to the source bytes read.
     fn copy_file_with_rate_limit(
         source: &Path,
         destination: &Path,
         rate_limiter: &mut RateLimiter,
+        format: &OutputFormat,
     ) -> io::Result<u64> {
+        use sha2::{Digest, Sha256};
         use std::io::{Read, Write};
 
         let mut source_file = fs::File::open(source)?;
-        let mut dest_file = fs::File::create(destination)?;
+        let dest_file = CountingWriter::new(fs::File::create(destination)?);
+        let sealed = EncryptWriter::new(dest_file, format.key.as_ref())?;
+        let mut writer = Encoder::new(sealed, format.compression)?;
+        // An encrypted copy records the plaintext hash in its last chunk
+        let mut hasher = format.key.as_ref().map(|_| Sha256::new());
 
         let metadata = source_file.metadata()?;
         let total_size = metadata.len();
         let mut total_copied = 0;
+        let mut total_written = 0;
+        // Compressed or encrypted copies without a byte limit come through
+        // here too
+        let show_progress = rate_limiter.limits_bytes();
 
         // Emit initial progress at 0%
-        if total_size > 0 {
-            println!("  Progress: 0% (0.00 KB/s)");
+        if show_progress && total_size > 0 {
+            println!(
+                "  Progress: 0% (0.00 KB/s, limit {})",
+                rate_limiter.limit_text()
+            );
         }
 
         // Use a buffer for chunked copying
@@ -385,232 +951,1538 @@ impl FileManager {
                 break;
             }
 
-            dest_file.write_all(&buffer[..bytes_read])?;
-            total_copied += bytes_read as u64;
-
-            // Apply rate limiting for this chunk
-            rate_limiter.throttle_chunk(bytes_read, total_size);
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&buffer[..bytes_read]);
+            }
+            let write_started = Instant::now();
+            writer.write_all(&buffer[..bytes_read])?;
+            rate_limiter.record_write(write_started.elapsed());
+            total_copied += bytes_read as u64;
+
+            // Apply rate limiting for this chunk
+            let written = writer.get_ref().get_ref().written();
+            rate_limiter.throttle_chunk((written - total_written) as usize);
+            total_written = written;
+            if rate_limiter.refresh_schedule() {
+                println!(
+                    "  Rate limit changed to {} (schedule)",
+                    rate_limiter.limit_text()
+                );
+            }
+            if let Some(adjustment) = rate_limiter.adapt() {
+                println!("  Adaptive throttling: {}", adjustment);
+            }
+
+            // Report progress every 10% or for files under 10MB
+            let before =
+                (total_copied.saturating_sub(bytes_read as u64) * 100).checked_div(total_size);
+            let after = (total_copied * 100).checked_div(total_size);
+            if show_progress && let (Some(before), Some(after)) = (before, after) {
+                let after = after.min(99); // avoid 100% inside loop
+                if after > before || total_size < 10 * 1024 * 1024 {
+                    let rate = rate_limiter.get_current_rate();
+                    println!(
+                        "  Progress: {}% ({:.2} KB/s, limit {})",
+                        after,
+                        rate / 1024.0,
+                        rate_limiter.limit_text()
+                    );
+                }
+            }
+        }
+
+        let plaintext_sha256 = hasher.map(|hasher| hasher.finalize().into());
+        let dest_file = writer.finish()?.finish(plaintext_sha256)?;
+        rate_limiter.throttle_chunk((dest_file.written() - total_written) as usize);
+
+        // Finalize at 100%
+        if show_progress && total_size > 0 {
+            let rate = rate_limiter.get_current_rate();
+            println!(
+                "  Progress: 100% ({:.2} KB/s, limit {})",
+                rate / 1024.0,
+                rate_limiter.limit_text()
+            );
+        }
+
+        dest_file.into_inner().sync_all()?;
+        Ok(total_copied)
+    }
+
+    // One copy of `source` to `destination` followed by a hash comparison
+    // against what the copy decodes to. Returns the bytes copied, the
+    // source's hash and, with a copy strategy, how the file was copied. A
+    // copy that doesn't verify is removed.
+    fn copy_and_verify(
+        source: &Path,
+        destination: &Path,
+        rate_limiter: &mut RateLimiter,
+        format: &OutputFormat,
+    ) -> Result<(u64, String, Option<Method>), CopyFailure> {
+        rate_limiter.throttle_file();
+        let (bytes_copied, method) = if let Some(strategy) = format.plain_strategy() {
+            copy_strategy::copy(source, destination, strategy, rate_limiter)
+                .map(|(bytes, method)| (bytes, Some(method)))
+        } else if rate_limiter.limits_bytes() || !format.is_plain() {
+            Self::copy_file_with_rate_limit(source, destination, rate_limiter, format)
+                .map(|bytes| (bytes, None))
+        } else {
+            fs::copy(source, destination).map(|bytes| (bytes, None))
+        }
+        .map_err(CopyFailure::Copy)?;
+
+        let verified = validation::verify_files_match(
+            source,
+            destination,
+            format.compression,
+            format.key.as_ref(),
+        );
+        match verified {
+            Ok(Some(sha256)) => Ok((bytes_copied, sha256, method)),
+            Ok(None) => {
+                rate_limiter.throttle_metadata(1);
+                let _ = fs::remove_file(destination);
+                Err(CopyFailure::Mismatch(bytes_copied))
+            }
+            Err(e) => {
+                rate_limiter.throttle_metadata(1);
+                let _ = fs::remove_file(destination);
+                Err(CopyFailure::Verify(bytes_copied, e))
+            }
+        }
+    }
+
+    // `copy_and_verify`, retried up to `operation.retries` times. The wait
+    // starts at `retry_backoff` seconds and doubles after each failure, up
+    // to `MAX_RETRY_BACKOFF`.
+    fn copy_with_retries(
+        operation: &FileOperation,
+        source: &Path,
+        destination: &Path,
+        format: &OutputFormat,
+        rate_limiter: &mut RateLimiter,
+        details: &mut Vec<String>,
+        indent: &str,
+    ) -> Result<(u64, String), CopyFailure> {
+        let mut backoff =
+            Duration::try_from_secs_f64(operation.retry_backoff.unwrap_or(1.0).max(0.0))
+                .unwrap_or(MAX_RETRY_BACKOFF)
+                .min(MAX_RETRY_BACKOFF);
+        let mut attempt = 0;
+        loop {
+            let outcome = Self::copy_and_verify(source, destination, rate_limiter, format).map(
+                |(bytes, sha256, method)| {
+                    if let Some(method) = method {
+                        details.push(format!("{}Copied with {}", indent, method));
+                    }
+                    (bytes, sha256)
+                },
+            );
+            for adjustment in rate_limiter.take_adjustments() {
+                details.push(format!("{}Adaptive throttling: {}", indent, adjustment));
+            }
+            if let (Ok((bytes, _)), Some(compression)) = (&outcome, format.compression) {
+                let written = fs::metadata(destination).map(|m| m.len()).unwrap_or(0);
+                details.push(format!(
+                    "{}Compressed {} to {} bytes ({:?}, {:.1}x)",
+                    indent,
+                    bytes,
+                    written,
+                    compression,
+                    *bytes as f64 / written.max(1) as f64
+                ));
+            }
+            match outcome {
+                Err(failure) if attempt < operation.retries => {
+                    attempt += 1;
+                    details.push(format!(
+                        "{}WARNING: {} ({}); retry {}/{} in {:.1}s",
+                        indent,
+                        failure,
+                        source.display(),
+                        attempt,
+                        operation.retries,
+                        backoff.as_secs_f64()
+                    ));
+                    std::thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2).min(MAX_RETRY_BACKOFF);
+                }
+                outcome => {
+                    if attempt > 0 && outcome.is_ok() {
+                        details.push(format!(
+                            "{}Succeeded after {} retr{}",
+                            indent,
+                            attempt,
+                            if attempt == 1 { "y" } else { "ies" }
+                        ));
+                    }
+                    return outcome;
+                }
+            }
+        }
+    }
+
+    /// Bytes a copy of `origin` to `destination` will add, found with the
+    /// same walk `copy_directory` makes. Files already at the destination
+    /// are overwritten in place, so only growth over their size counts.
+    pub fn planned_copy_bytes(origin: &Path, destination: &Path) -> u64 {
+        let existing = |path: &Path| fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        if origin.is_file() {
+            let size = fs::metadata(origin).map(|m| m.len()).unwrap_or(0);
+            return size.saturating_sub(existing(destination));
+        }
+
+        let mut total = 0;
+        for entry in WalkDir::new(origin).into_iter().flatten() {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let Ok(relative_path) = entry.path().strip_prefix(origin) else {
+                continue;
+            };
+            let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+            total += size.saturating_sub(existing(&destination.join(relative_path)));
+        }
+        total
+    }
+
+    fn copy_directory(
+        operation: &FileOperation,
+        format: &OutputFormat,
+        on_conflict: Option<Conflict>,
+        global_limiter: &GlobalLimiter,
+        mut details: Vec<String>,
+    ) -> OperationResult {
+        let mut result = OperationResult {
+            operation_name: operation.name.clone(),
+            source: operation.origin.to_string_lossy().to_string(),
+            destination: operation.destination.to_string_lossy().to_string(),
+            success: false,
+            error_message: None,
+            hash_verified: true,
+            operation_type: OperationType::Copy,
+            files_processed: 0,
+            total_size: 0,
+            start_time: SystemTime::now(),
+            end_time: SystemTime::now(),
+            details: details.clone(),
+            file_list: Vec::new(),
+            dedup: None,
+        };
+
+        let mut all_successful = true;
+        let mut error_messages = Vec::new();
+        let mut kept = 0;
+
+        details.push("  Starting directory copy...".to_string());
+
+        // Prepare a shared rate limiter for the whole directory copy
+        let mut dir_rate_limiter = RateLimiter::for_operation(
+            &operation.rate_limit,
+            global_limiter,
+            operation.weight.unwrap_or(1),
+        );
+        Self::push_rate_limiting(
+            &mut details,
+            &dir_rate_limiter,
+            operation,
+            global_limiter,
+            "Directory rate limiting",
+        );
+        if let Some(compression) = format.compression {
+            details.push(format!(
+                "  Compression: {:?}, adding {} to each file name",
+                compression,
+                compression.suffix()
+            ));
+        }
+        Self::push_encryption(&mut details, operation);
+        Self::push_strategy(&mut details, format);
+
+        if let Err(e) = fs::create_dir_all(&operation.destination) {
+            let error_msg = format!("Failed to create destination directory: {}", e);
+            details.push(format!("ERROR: {}", error_msg));
+            result.error_message = Some(error_msg.clone()); // Clone here
+            result.details = details;
+            return result;
+        }
+        details.push("  Destination directory created".to_string());
+
+        for entry in WalkDir::new(&operation.origin) {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    error_messages.push(format!("Error reading directory entry: {}", e));
+                    details.push(format!("WARNING: Error reading entry: {}", e));
+                    all_successful = false;
+                    continue;
+                }
+            };
+
+            let source_path = entry.path();
+
+            if source_path == operation.origin {
+                continue;
+            }
+
+            let relative_path = match source_path.strip_prefix(&operation.origin) {
+                Ok(p) => p,
+                Err(_) => {
+                    let msg = format!("Failed to get relative path for: {}", source_path.display());
+                    error_messages.push(msg.clone());
+                    details.push(format!("WARNING: {}", msg));
+                    continue;
+                }
+            };
+
+            let dest_path = operation.destination.join(relative_path);
+
+            // A restore leaves out the reports the backup run saved
+            if on_conflict.is_some()
+                && relative_path.parent() == Some(Path::new(""))
+                && Self::is_destination_report(relative_path, &operation.name)
+            {
+                details.push(format!("  Skipping report {}", source_path.display()));
+                continue;
+            }
+
+            if entry.file_type().is_dir() {
+                dir_rate_limiter.throttle_metadata(1);
+                if let Err(e) = fs::create_dir_all(&dest_path) {
+                    let msg = format!("Failed to create directory {}: {}", dest_path.display(), e);
+                    error_messages.push(msg.clone());
+                    details.push(format!("ERROR creating directory: {}", msg));
+                    all_successful = false;
+                } else {
+                    details.push(format!("  Created directory: {}", dest_path.display()));
+                }
+            } else if entry.file_type().is_file() {
+                result.files_processed += 1;
+                let dest_path = format.path(&dest_path);
+
+                let file_size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                result.total_size += file_size;
+
+                details.push(format!(
+                    "  Copying file {}/{}: {}",
+                    result.files_processed,
+                    "?",
+                    source_path.display()
+                ));
+
+                if let Some(conflict) = on_conflict
+                    && dest_path.exists()
+                {
+                    match Self::check_existing(source_path, &dest_path, conflict) {
+                        Ok((Existing::Identical, sha256)) => {
+                            details.push("    Already up to date".to_string());
+                            result.file_list.push(FileEntry {
+                                source_path: source_path.to_string_lossy().to_string(),
+                                destination_path: dest_path.to_string_lossy().to_string(),
+                                size: file_size,
+                                hash_verified: true,
+                                success: true,
+                                error_message: None,
+                                sha256: Some(sha256),
+                            });
+                            continue;
+                        }
+                        Ok((Existing::Kept, _)) => {
+                            details.push(format!(
+                                "WARNING: Kept {}: it differs from the copy being restored",
+                                dest_path.display()
+                            ));
+                            kept += 1;
+                            continue;
+                        }
+                        Ok((Existing::MovedTo(aside), _)) => details.push(format!(
+                            "    Moved the existing file aside to {}",
+                            aside.display()
+                        )),
+                        Ok(_) => {}
+                        Err(e) => {
+                            let msg =
+                                format!("Cannot restore over {}: {:#}", dest_path.display(), e);
+                            error_messages.push(msg.clone());
+                            details.push(format!("ERROR: {}", msg));
+                            all_successful = false;
+                            continue;
+                        }
+                    }
+                }
+
+                match Self::copy_with_retries(
+                    operation,
+                    source_path,
+                    &dest_path,
+                    format,
+                    &mut dir_rate_limiter,
+                    &mut details,
+                    "    ",
+                ) {
+                    Ok((bytes_copied, sha256)) => {
+                        details.push(format!("    Copied {} bytes", bytes_copied));
+                        details.push("    Verification successful".to_string());
+
+                        result.file_list.push(FileEntry {
+                            source_path: source_path.to_string_lossy().to_string(),
+                            destination_path: dest_path.to_string_lossy().to_string(),
+                            size: bytes_copied,
+                            hash_verified: true,
+                            success: true,
+                            error_message: None,
+                            sha256: Some(sha256),
+                        });
+                    }
+                    Err(failure) => {
+                        let (size, msg, entry_error) = match &failure {
+                            CopyFailure::Copy(e) => {
+                                let msg = format!(
+                                    "Failed to copy {} to {}: {}",
+                                    source_path.display(),
+                                    dest_path.display(),
+                                    e
+                                );
+                                (file_size, msg.clone(), msg)
+                            }
+                            CopyFailure::Mismatch(bytes) => (
+                                *bytes,
+                                format!("Hash verification failed for: {}", source_path.display()),
+                                "Hash verification failed".to_string(),
+                            ),
+                            CopyFailure::Verify(bytes, e) => (
+                                *bytes,
+                                format!("Verification error for {}: {}", source_path.display(), e),
+                                format!("Verification error: {}", e),
+                            ),
+                        };
+                        // Verification failures got as far as writing the copy
+                        let copied = !matches!(failure, CopyFailure::Copy(_));
+                        if copied {
+                            details.push(format!("    Copied {} bytes", size));
+                        }
+                        error_messages.push(msg.clone());
+                        details.push(format!("ERROR: {}", msg));
+                        all_successful = false;
+                        if copied {
+                            result.hash_verified = false;
+                            details.push("    Cleaned up failed copy".to_string());
+                        }
+
+                        result.file_list.push(FileEntry {
+                            source_path: source_path.to_string_lossy().to_string(),
+                            destination_path: dest_path.to_string_lossy().to_string(),
+                            size,
+                            hash_verified: false,
+                            success: false,
+                            error_message: Some(entry_error),
+                            sha256: None,
+                        });
+                    }
+                }
+            }
+        }
+
+        details.push(format!(
+            "  Total files processed: {}",
+            result.files_processed
+        ));
+        details.push(format!("  Total size: {} bytes", result.total_size));
+        if kept > 0 {
+            details.push(format!("  Left {} conflicting file(s) as they were", kept));
+        }
+
+        result.success = all_successful;
+        if !error_messages.is_empty() {
+            result.error_message = Some(error_messages.join("; "));
+        }
+        result.details = details;
+
+        result
+    }
+
+    fn archive_directory(
+        operation: &FileOperation,
+        global_limiter: &GlobalLimiter,
+        mut details: Vec<String>,
+    ) -> OperationResult {
+        let destination =
+            compression::compressed_path(&operation.destination, operation.compression);
+        let mut result = OperationResult {
+            operation_name: operation.name.clone(),
+            source: operation.origin.to_string_lossy().to_string(),
+            destination: destination.to_string_lossy().to_string(),
+            success: false,
+            error_message: None,
+            hash_verified: false,
+            operation_type: OperationType::Archive,
+            files_processed: 0,
+            total_size: 0,
+            start_time: SystemTime::now(),
+            end_time: SystemTime::now(),
+            details: details.clone(),
+            file_list: Vec::new(),
+            dedup: None,
+        };
+
+        let mut rate_limiter = RateLimiter::for_operation(
+            &operation.rate_limit,
+            global_limiter,
+            operation.weight.unwrap_or(1),
+        );
+        Self::push_rate_limiting(
+            &mut details,
+            &rate_limiter,
+            operation,
+            global_limiter,
+            "Archive rate limiting",
+        );
+        details.push(format!(
+            "  Writing archive: {} (tar{})",
+            destination.display(),
+            operation
+                .compression
+                .map(|c| format!(", {:?}", c))
+                .unwrap_or_default()
+        ));
+
+        let created = archive::create(
+            &operation.origin,
+            &destination,
+            operation.compression,
+            &mut rate_limiter,
+        );
+        for adjustment in rate_limiter.take_adjustments() {
+            details.push(format!("  Adaptive throttling: {}", adjustment));
+        }
+        let (manifest, skipped) = match created {
+            Ok(created) => created,
+            Err(e) => {
+                let error_msg = format!("Failed to write archive: {:#}", e);
+                details.push(format!("ERROR: {}", error_msg));
+                let _ = fs::remove_file(&destination);
+                details.push("  Removed incomplete archive".to_string());
+                result.error_message = Some(error_msg);
+                result.details = details;
+                return result;
+            }
+        };
+
+        for entry in &skipped {
+            details.push(format!(
+                "WARNING: Skipped {}: only regular files and directories are archived",
+                entry
+            ));
+        }
+        result.files_processed = manifest.len();
+        result.total_size = manifest.iter().map(|entry| entry.size).sum();
+        let archive_size = fs::metadata(&destination).map(|m| m.len()).unwrap_or(0);
+        details.push(format!(
+            "  Archived {} files, {} bytes; archive is {} bytes",
+            result.files_processed, result.total_size, archive_size
+        ));
+
+        // Read the archive back to make sure what landed on disk is intact
+        let problems = match archive::verify(&destination, operation.compression) {
+            Ok(problems) => problems,
+            Err(e) => vec![format!("cannot read archive back: {:#}", e)],
+        };
+        result.hash_verified = problems.is_empty();
+        result.success = problems.is_empty();
+        if problems.is_empty() {
+            details.push("  Verification successful: archive matches its manifest".to_string());
+        } else {
+            for problem in &problems {
+                details.push(format!("ERROR: Archive verification: {}", problem));
+            }
+            result.error_message = Some(format!(
+                "Archive verification failed: {}",
+                problems.join("; ")
+            ));
+        }
+
+        for entry in manifest {
+            result.file_list.push(FileEntry {
+                source_path: operation
+                    .origin
+                    .join(&entry.path)
+                    .to_string_lossy()
+                    .to_string(),
+                destination_path: archive::member_label(&destination, &entry.path),
+                size: entry.size,
+                hash_verified: result.hash_verified,
+                success: result.success,
+                error_message: None,
+                sha256: Some(entry.sha256),
+            });
+        }
+
+        result.details = details;
+        result
+    }
+
+    fn extract_archive(
+        operation: &FileOperation,
+        global_limiter: &GlobalLimiter,
+        mut details: Vec<String>,
+    ) -> OperationResult {
+        let mut result = OperationResult {
+            operation_name: operation.name.clone(),
+            source: operation.origin.to_string_lossy().to_string(),
+            destination: operation.destination.to_string_lossy().to_string(),
+            success: false,
+            error_message: None,
+            hash_verified: false,
+            operation_type: OperationType::Extract,
+            files_processed: 0,
+            total_size: 0,
+            start_time: SystemTime::now(),
+            end_time: SystemTime::now(),
+            details: details.clone(),
+            file_list: Vec::new(),
+            dedup: None,
+        };
+
+        let compression = operation
+            .compression
+            .or_else(|| Compression::detect(&operation.origin));
+        let mut rate_limiter = RateLimiter::for_operation(
+            &operation.rate_limit,
+            global_limiter,
+            operation.weight.unwrap_or(1),
+        );
+        Self::push_rate_limiting(
+            &mut details,
+            &rate_limiter,
+            operation,
+            global_limiter,
+            "Extract rate limiting",
+        );
+        details.push(format!(
+            "  Extracting archive (tar{}) into {}",
+            compression
+                .map(|c| format!(", {:?}", c))
+                .unwrap_or_default(),
+            operation.destination.display()
+        ));
+
+        let extracted = archive::extract(
+            &operation.origin,
+            &operation.destination,
+            compression,
+            &mut rate_limiter,
+        );
+        for adjustment in rate_limiter.take_adjustments() {
+            details.push(format!("  Adaptive throttling: {}", adjustment));
+        }
+        let (files, has_manifest, skipped) = match extracted {
+            Ok(extracted) => extracted,
+            Err(e) => {
+                let error_msg = format!("Failed to extract archive: {:#}", e);
+                details.push(format!("ERROR: {}", error_msg));
+                result.error_message = Some(error_msg);
+                result.details = details;
+                return result;
+            }
+        };
+
+        for member in &skipped {
+            details.push(format!(
+                "WARNING: Skipped {}: only regular files and directories are extracted",
+                member
+            ));
+        }
+        let mut error_messages = Vec::new();
+        for file in files {
+            result.files_processed += 1;
+            result.total_size += file.size;
+            if let Some(problem) = &file.problem {
+                let msg = format!("{}: {}", file.path, problem);
+                details.push(format!("ERROR: {}", msg));
+                error_messages.push(msg);
+            }
+            result.file_list.push(FileEntry {
+                source_path: archive::member_label(&operation.origin, &file.path),
+                destination_path: file.destination.to_string_lossy().to_string(),
+                size: file.size,
+                hash_verified: has_manifest && file.problem.is_none(),
+                success: file.problem.is_none(),
+                error_message: file.problem,
+                sha256: file.sha256,
+            });
+        }
+        details.push(format!(
+            "  Extracted {} files, {} bytes",
+            result.files_processed, result.total_size
+        ));
+
+        result.success = error_messages.is_empty();
+        if !has_manifest {
+            details.push(
+                "WARNING: Archive has no manifest; extracted files were not verified".to_string(),
+            );
+        } else if result.success {
+            result.hash_verified = true;
+            details.push("  Verification successful: all files match the manifest".to_string());
+        } else {
+            details.push("  Removed files that failed verification".to_string());
+            result.error_message = Some(error_messages.join("; "));
+        }
+
+        result.details = details;
+        result
+    }
+
+    fn decrypt(
+        operation: &FileOperation,
+        key: &Key,
+        is_dir: bool,
+        global_limiter: &GlobalLimiter,
+        mut details: Vec<String>,
+    ) -> OperationResult {
+        let mut result = OperationResult {
+            operation_name: operation.name.clone(),
+            source: operation.origin.to_string_lossy().to_string(),
+            destination: operation.destination.to_string_lossy().to_string(),
+            success: false,
+            error_message: None,
+            hash_verified: false,
+            operation_type: OperationType::Decrypt,
+            files_processed: 0,
+            total_size: 0,
+            start_time: SystemTime::now(),
+            end_time: SystemTime::now(),
+            details: details.clone(),
+            file_list: Vec::new(),
+            dedup: None,
+        };
+
+        let mut rate_limiter = RateLimiter::for_operation(
+            &operation.rate_limit,
+            global_limiter,
+            operation.weight.unwrap_or(1),
+        );
+        Self::push_rate_limiting(
+            &mut details,
+            &rate_limiter,
+            operation,
+            global_limiter,
+            "Decrypt rate limiting",
+        );
+        if let Some(encryption) = &operation.encryption {
+            details.push(format!(
+                "  Decrypting with AES-256-GCM (key from {})",
+                encryption.key_file.display()
+            ));
+        }
+
+        // Encrypted files paired with where their plaintext goes. Inside a
+        // directory the suffixes the copy added are taken off again.
+        let mut pending = Vec::new();
+        let mut error_messages = Vec::new();
+        if is_dir {
+            for entry in WalkDir::new(&operation.origin).sort_by_file_name() {
+                let entry = match entry {
+                    Ok(e) => e,
+                    Err(e) => {
+                        error_messages.push(format!("Error reading directory entry: {}", e));
+                        details.push(format!("WARNING: Error reading entry: {}", e));
+                        continue;
+                    }
+                };
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                let Ok(relative_path) = entry.path().strip_prefix(&operation.origin) else {
+                    continue;
+                };
+                let Some(plain) = crypto::strip_suffix(relative_path) else {
+                    details.push(format!(
+                        "WARNING: Skipping {}: no {} suffix",
+                        entry.path().display(),
+                        crypto::SUFFIX
+                    ));
+                    continue;
+                };
+                let compression = operation
+                    .compression
+                    .or_else(|| Compression::detect(&plain));
+                let destination =
+                    compression::strip_suffix(&operation.destination.join(&plain), compression);
+                pending.push((entry.path().to_path_buf(), destination, compression));
+            }
+        } else {
+            let compression = operation.compression.or_else(|| {
+                crypto::strip_suffix(&operation.origin)
+                    .and_then(|plain| Compression::detect(&plain))
+            });
+            pending.push((
+                operation.origin.clone(),
+                operation.destination.clone(),
+                compression,
+            ));
+        }
+
+        for (source, destination, compression) in pending {
+            result.files_processed += 1;
+            match Self::decrypt_file(&source, &destination, key, compression, &mut rate_limiter) {
+                Ok((size, sha256)) => {
+                    result.total_size += size;
+                    details.push(format!(
+                        "  Decrypted {} to {} ({} bytes)",
+                        source.display(),
+                        destination.display(),
+                        size
+                    ));
+                    result.file_list.push(FileEntry {
+                        source_path: source.to_string_lossy().to_string(),
+                        destination_path: destination.to_string_lossy().to_string(),
+                        size,
+                        hash_verified: true,
+                        success: true,
+                        error_message: None,
+                        sha256: Some(sha256),
+                    });
+                }
+                Err(e) => {
+                    let msg = format!("Failed to decrypt {}: {:#}", source.display(), e);
+                    details.push(format!("ERROR: {}", msg));
+                    error_messages.push(msg);
+                    result.file_list.push(FileEntry {
+                        source_path: source.to_string_lossy().to_string(),
+                        destination_path: destination.to_string_lossy().to_string(),
+                        size: 0,
+                        hash_verified: false,
+                        success: false,
+                        error_message: Some(format!("{:#}", e)),
+                        sha256: None,
+                    });
+                }
+            }
+        }
+        for adjustment in rate_limiter.take_adjustments() {
+            details.push(format!("  Adaptive throttling: {}", adjustment));
+        }
+        details.push(format!(
+            "  Decrypted {} files, {} bytes",
+            result.files_processed, result.total_size
+        ));
+
+        result.success = error_messages.is_empty();
+        result.hash_verified = result.success;
+        if result.success {
+            details.push(
+                "  Verification successful: all files match the hashes recorded at copy time"
+                    .to_string(),
+            );
+        } else {
+            result.error_message = Some(error_messages.join("; "));
+        }
+
+        result.details = details;
+        result
+    }
+
+    // Decrypts and decompresses `source` into `destination`, then checks the
+    // output against the hash recorded when it was encrypted. Returns the
+    // size and hash written. Output that fails is removed.
+    fn decrypt_file(
+        source: &Path,
+        destination: &Path,
+        key: &Key,
+        compression: Option<Compression>,
+        rate_limiter: &mut RateLimiter,
+    ) -> anyhow::Result<(u64, String)> {
+        rate_limiter.throttle_file();
+        let expected = Self::decoded_sha256(source, Some(key), compression)?;
+        let size = Self::decode_file(
+            source,
+            destination,
+            &expected,
+            Some(key),
+            compression,
+            rate_limiter,
+        )?;
+        Ok((size, expected))
+    }
+
+    // The hash of what `source` decodes to: the one recorded at copy time
+    // for an encrypted file, otherwise that of the decompressed content
+    fn decoded_sha256(
+        source: &Path,
+        key: Option<&Key>,
+        compression: Option<Compression>,
+    ) -> anyhow::Result<String> {
+        match key {
+            Some(key) => crypto::recorded_sha256(source, key)?
+                .ok_or_else(|| anyhow::anyhow!("no hash was recorded when it was encrypted")),
+            None => {
+                validation::sha256_of_reader(compression::open_decoded(source, compression, None)?)
+            }
+        }
+    }
+
+    // Decodes `source` into `destination` and checks the output hashes to
+    // `expected`. Returns the size written. Output that fails is removed.
+    fn decode_file(
+        source: &Path,
+        destination: &Path,
+        expected: &str,
+        key: Option<&Key>,
+        compression: Option<Compression>,
+        rate_limiter: &mut RateLimiter,
+    ) -> anyhow::Result<u64> {
+        let mut reader = compression::open_decoded(source, compression, key)?;
+        if let Some(parent) = destination.parent()
+            && !parent.exists()
+        {
+            rate_limiter.throttle_metadata(1);
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut writer = ThrottledWriter::new(fs::File::create(destination)?, rate_limiter);
+        let written = io::copy(&mut reader, &mut writer).map_err(anyhow::Error::from);
+        let file = writer.into_inner();
+        let verified = written.and_then(|size| {
+            file.sync_all()?;
+            if !validation::verify_file_integrity(destination, expected)? {
+                match key {
+                    Some(_) => anyhow::bail!("hash does not match the one recorded at copy time"),
+                    None => anyhow::bail!("hash does not match the decompressed copy"),
+                }
+            }
+            Ok(size)
+        });
+        match verified {
+            Ok(size) => Ok(size),
+            Err(e) => {
+                rate_limiter.throttle_metadata(1);
+                let _ = fs::remove_file(destination);
+                Err(e)
+            }
+        }
+    }
+
+    fn store_in_repository(
+        operation: &FileOperation,
+        global_limiter: &GlobalLimiter,
+        mut details: Vec<String>,
+    ) -> OperationResult {
+        let mut result = OperationResult {
+            operation_name: operation.name.clone(),
+            source: operation.origin.to_string_lossy().to_string(),
+            destination: operation.destination.to_string_lossy().to_string(),
+            success: false,
+            error_message: None,
+            hash_verified: false,
+            operation_type: OperationType::Repository,
+            files_processed: 0,
+            total_size: 0,
+            start_time: SystemTime::now(),
+            end_time: SystemTime::now(),
+            details: details.clone(),
+            file_list: Vec::new(),
+            dedup: None,
+        };
+
+        let mut rate_limiter = RateLimiter::for_operation(
+            &operation.rate_limit,
+            global_limiter,
+            operation.weight.unwrap_or(1),
+        );
+        Self::push_rate_limiting(
+            &mut details,
+            &rate_limiter,
+            operation,
+            global_limiter,
+            "Repository rate limiting",
+        );
+        let repository = match Repository::init(&operation.destination) {
+            Ok(repository) => repository,
+            Err(e) => {
+                let error_msg = format!("Cannot open repository: {:#}", e);
+                details.push(format!("ERROR: {}", error_msg));
+                result.error_message = Some(error_msg);
+                result.details = details;
+                return result;
+            }
+        };
+        details.push(format!(
+            "  Storing into repository {}",
+            repository.root.display()
+        ));
+
+        let mut stats = DedupStats::default();
+        let mut snapshot = Vec::new();
+        let mut error_messages = Vec::new();
+        for entry in WalkDir::new(&operation.origin).sort_by_file_name() {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    error_messages.push(format!("Error reading directory entry: {}", e));
+                    details.push(format!("WARNING: Error reading entry: {}", e));
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let Ok(relative_path) = entry.path().strip_prefix(&operation.origin) else {
+                continue;
+            };
+            let path = archive::archive_path(relative_path);
+            result.files_processed += 1;
+
+            match repository.store(entry.path(), &mut rate_limiter) {
+                Ok(stored) => {
+                    result.total_size += stored.size;
+                    if stored.new {
+                        stats.new_objects += 1;
+                        stats.new_bytes += stored.size;
+                        details.push(format!("    Stored {} ({} bytes)", path, stored.size));
+                    } else {
+                        stats.reused_bytes += stored.size;
+                        details.push(format!("    {}: content already stored", path));
+                    }
+                    result.file_list.push(FileEntry {
+                        source_path: entry.path().to_string_lossy().to_string(),
+                        destination_path: stored.object.to_string_lossy().to_string(),
+                        size: stored.size,
+                        hash_verified: true,
+                        success: true,
+                        error_message: None,
+                        sha256: Some(stored.sha256.clone()),
+                    });
+                    #[cfg(unix)]
+                    let mode = {
+                        use std::os::unix::fs::PermissionsExt;
+                        entry.metadata().ok().map(|m| m.permissions().mode())
+                    };
+                    #[cfg(not(unix))]
+                    let mode = None;
+                    snapshot.push(SnapshotEntry {
+                        path,
+                        size: stored.size,
+                        sha256: stored.sha256,
+                        mode,
+                    });
+                }
+                Err(e) => {
+                    let msg = format!("Failed to store {}: {:#}", entry.path().display(), e);
+                    details.push(format!("ERROR: {}", msg));
+                    error_messages.push(msg);
+                    result.file_list.push(FileEntry {
+                        source_path: entry.path().to_string_lossy().to_string(),
+                        destination_path: repository.root.to_string_lossy().to_string(),
+                        size: entry.metadata().map(|m| m.len()).unwrap_or(0),
+                        hash_verified: false,
+                        success: false,
+                        error_message: Some(format!("{:#}", e)),
+                        sha256: None,
+                    });
+                }
+            }
+        }
+        for adjustment in rate_limiter.take_adjustments() {
+            details.push(format!("  Adaptive throttling: {}", adjustment));
+        }
+
+        // A snapshot only ever lists a complete run; what was stored before
+        // a failure is picked up again by the next one
+        if !error_messages.is_empty() {
+            details.push("  No snapshot written because some files failed".to_string());
+            result.error_message = Some(error_messages.join("; "));
+            result.details = details;
+            return result;
+        }
+        let manifest = SnapshotManifest {
+            created: Local::now().to_rfc3339(),
+            origin: operation.origin.clone(),
+            files: snapshot,
+        };
+        stats.files = manifest.files.len();
+        match repository.write_snapshot(&manifest) {
+            Ok(name) => {
+                details.push(format!(
+                    "  Wrote snapshot {}: {} files, {} new ({} bytes), {} bytes already stored",
+                    name, stats.files, stats.new_objects, stats.new_bytes, stats.reused_bytes
+                ));
+                stats.snapshot = name;
+            }
+            Err(e) => {
+                let error_msg = format!("Failed to write snapshot: {:#}", e);
+                details.push(format!("ERROR: {}", error_msg));
+                result.error_message = Some(error_msg);
+                result.details = details;
+                return result;
+            }
+        }
+        match repository.stats(stats.clone()) {
+            Ok(stats) => {
+                details.push(format!(
+                    "  Repository holds {} snapshots referencing {} bytes in {} bytes of content ({:.1}x)",
+                    stats.snapshots,
+                    stats.referenced_bytes,
+                    stats.stored_bytes,
+                    stats.ratio()
+                ));
+                result.dedup = Some(stats);
+            }
+            Err(e) => {
+                details.push(format!("WARNING: Cannot total repository usage: {:#}", e));
+                result.dedup = Some(stats);
+            }
+        }
+
+        result.success = true;
+        result.hash_verified = true;
+        details.push("  Verification successful: all new content matches its hash".to_string());
+        result.details = details;
+        result
+    }
+
+    /// Copies a backup made by `operation` back to `target`, or to the
+    /// operation's origin. `selector` picks a snapshot by name or time;
+    /// without one the latest is used. Files already at the target are
+    /// handled as `conflict` says, and every restored file is verified.
+    pub fn restore(
+        operation: &FileOperation,
+        selector: Option<&str>,
+        target: Option<&Path>,
+        conflict: Conflict,
+        global_limiter: &GlobalLimiter,
+    ) -> OperationResult {
+        let target = target.map_or_else(|| operation.origin.clone(), Path::to_path_buf);
+        let mut details = vec![
+            format!("Starting restore of operation: {}", operation.name),
+            format!("  Restoring to: {}", target.display()),
+            format!("  Existing files that differ: {:?}", conflict),
+        ];
+        let source = match restore::locate(operation, selector) {
+            Ok(source) => source,
+            Err(e) => {
+                let error_msg = format!("Cannot restore: {:#}", e);
+                details.push(format!("ERROR: {}", error_msg));
+                return OperationResult {
+                    operation_name: operation.name.clone(),
+                    source: operation.destination.to_string_lossy().to_string(),
+                    destination: target.to_string_lossy().to_string(),
+                    success: false,
+                    error_message: Some(error_msg),
+                    hash_verified: false,
+                    operation_type: operation.operation_type.clone(),
+                    files_processed: 0,
+                    total_size: 0,
+                    start_time: SystemTime::now(),
+                    end_time: SystemTime::now(),
+                    details,
+                    file_list: Vec::new(),
+                    dedup: None,
+                };
+            }
+        };
+
+        let mut result = match source {
+            Source::Repository {
+                repository,
+                snapshot,
+            } => Self::restore_from_repository(
+                operation,
+                &repository,
+                &snapshot,
+                &target,
+                conflict,
+                global_limiter,
+                details,
+            ),
+            Source::Tree { path, snapshot } => {
+                match snapshot {
+                    Some(snapshot) => {
+                        details.push(format!("  Snapshot: {} ({})", snapshot, path.display()))
+                    }
+                    None => details.push(format!("  Copy: {}", path.display())),
+                }
+                if operation.compression.is_some() || operation.encryption.is_some() {
+                    let mut result = Self::restore_encoded(
+                        operation,
+                        &path,
+                        &target,
+                        conflict,
+                        global_limiter,
+                        details,
+                    );
+                    result.end_time = SystemTime::now();
+                    return result;
+                }
+                // The same copy as the operation's, run in reverse
+                let reversed = FileOperation {
+                    name: operation.name.clone(),
+                    origin: path.clone(),
+                    destination: target.clone(),
+                    operation_type: OperationType::Copy,
+                    rate_limit: operation.rate_limit.clone(),
+                    weight: operation.weight,
+                    retries: operation.retries,
+                    retry_backoff: operation.retry_backoff,
+                    copy_strategy: operation.copy_strategy,
+                    ..Default::default()
+                };
+                let format = OutputFormat {
+                    strategy: operation.copy_strategy,
+                    ..Default::default()
+                };
+                if path.is_dir() {
+                    Self::copy_directory(
+                        &reversed,
+                        &format,
+                        Some(conflict),
+                        global_limiter,
+                        details,
+                    )
+                } else {
+                    if let Some(parent) = target.parent()
+                        && let Err(e) = fs::create_dir_all(parent)
+                    {
+                        details.push(format!(
+                            "WARNING: Cannot create {}: {}",
+                            parent.display(),
+                            e
+                        ));
+                    }
+                    Self::copy_file(&reversed, &format, Some(conflict), global_limiter, details)
+                }
+            }
+        };
+        result.end_time = SystemTime::now();
+        result
+    }
+
+    // Restores a compressed or encrypted copy at `source` (one file or a
+    // tree of them) by decoding each file into `target`. Each file is
+    // checked against the hash recorded when it was encrypted, or against
+    // its decompressed content.
+    fn restore_encoded(
+        operation: &FileOperation,
+        source: &Path,
+        target: &Path,
+        conflict: Conflict,
+        global_limiter: &GlobalLimiter,
+        mut details: Vec<String>,
+    ) -> OperationResult {
+        let mut result = OperationResult {
+            operation_name: operation.name.clone(),
+            source: source.to_string_lossy().to_string(),
+            destination: target.to_string_lossy().to_string(),
+            success: false,
+            error_message: None,
+            hash_verified: false,
+            operation_type: operation.operation_type.clone(),
+            files_processed: 0,
+            total_size: 0,
+            start_time: SystemTime::now(),
+            end_time: SystemTime::now(),
+            details: Vec::new(),
+            file_list: Vec::new(),
+            dedup: None,
+        };
+        let format = match OutputFormat::for_operation(operation) {
+            Ok(format) => format,
+            Err(e) => {
+                let error_msg = format!("Cannot restore: {:#}", e);
+                details.push(format!("ERROR: {}", error_msg));
+                result.error_message = Some(error_msg);
+                result.details = details;
+                return result;
+            }
+        };
+        if let Some(compression) = format.compression {
+            details.push(format!("  Decompressing {:?} copies", compression));
+        }
+        Self::push_encryption(&mut details, operation);
+
+        let mut rate_limiter = RateLimiter::for_operation(
+            &operation.rate_limit,
+            global_limiter,
+            operation.weight.unwrap_or(1),
+        );
+        Self::push_rate_limiting(
+            &mut details,
+            &rate_limiter,
+            operation,
+            global_limiter,
+            "Restore rate limiting",
+        );
+
+        // Copies paired with where their decoded content goes
+        let mut pending = Vec::new();
+        let mut error_messages = Vec::new();
+        if source.is_dir() {
+            for entry in WalkDir::new(source).sort_by_file_name() {
+                let entry = match entry {
+                    Ok(e) => e,
+                    Err(e) => {
+                        error_messages.push(format!("Error reading directory entry: {}", e));
+                        details.push(format!("WARNING: Error reading entry: {}", e));
+                        continue;
+                    }
+                };
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                let Ok(relative_path) = entry.path().strip_prefix(source) else {
+                    continue;
+                };
+                if relative_path.parent() == Some(Path::new(""))
+                    && Self::is_destination_report(relative_path, &operation.name)
+                {
+                    details.push(format!("  Skipping report {}", entry.path().display()));
+                    continue;
+                }
+                let Some(plain) = format.plain_path(relative_path) else {
+                    details.push(format!(
+                        "WARNING: Skipping {}: no {} suffix",
+                        entry.path().display(),
+                        crypto::SUFFIX
+                    ));
+                    continue;
+                };
+                pending.push((entry.path().to_path_buf(), target.join(plain)));
+            }
+        } else {
+            pending.push((source.to_path_buf(), target.to_path_buf()));
+        }
+
+        let mut kept = 0;
+        for (source, destination) in pending {
+            rate_limiter.throttle_file();
+            let restored = Self::decoded_sha256(&source, format.key.as_ref(), format.compression)
+                .and_then(|sha256| {
+                    let existing = restore::prepare(&destination, &sha256, conflict)?;
+                    if !existing.write() {
+                        let size = fs::metadata(&destination).map(|m| m.len()).unwrap_or(0);
+                        return Ok((existing, size, sha256));
+                    }
+                    if let Some(parent) = destination.parent()
+                        && !parent.exists()
+                    {
+                        rate_limiter.throttle_metadata(1);
+                        fs::create_dir_all(parent)?;
+                    }
+                    let size = Self::decode_file(
+                        &source,
+                        &destination,
+                        &sha256,
+                        format.key.as_ref(),
+                        format.compression,
+                        &mut rate_limiter,
+                    )?;
+                    Ok((existing, size, sha256))
+                });
 
-            // Report progress every 10% or for files under 10MB
-            if total_size > 0 {
-                let before = (total_copied.saturating_sub(bytes_read as u64)) * 100 / total_size;
-                let after = (total_copied * 100 / total_size).min(99); // avoid 100% inside loop
-                if after > before || total_size < 10 * 1024 * 1024 {
-                    let rate = rate_limiter.get_current_rate();
-                    println!("  Progress: {}% ({:.2} KB/s)", after, rate / 1024.0);
+            match restored {
+                Ok((Existing::Kept, _, _)) => {
+                    details.push(format!(
+                        "WARNING: Kept {}: it differs from the copy",
+                        destination.display()
+                    ));
+                    kept += 1;
+                    continue;
+                }
+                Ok((existing, size, sha256)) => {
+                    match existing {
+                        Existing::Identical => details
+                            .push(format!("    {}: already up to date", destination.display())),
+                        Existing::MovedTo(aside) => details.push(format!(
+                            "    {}: moved the existing file aside to {}",
+                            destination.display(),
+                            aside.display()
+                        )),
+                        _ => details.push(format!(
+                            "  Restored {} to {} ({} bytes)",
+                            source.display(),
+                            destination.display(),
+                            size
+                        )),
+                    }
+                    result.files_processed += 1;
+                    result.total_size += size;
+                    result.file_list.push(FileEntry {
+                        source_path: source.to_string_lossy().to_string(),
+                        destination_path: destination.to_string_lossy().to_string(),
+                        size,
+                        hash_verified: true,
+                        success: true,
+                        error_message: None,
+                        sha256: Some(sha256),
+                    });
+                }
+                Err(e) => {
+                    result.files_processed += 1;
+                    let msg = format!("Failed to restore {}: {:#}", source.display(), e);
+                    details.push(format!("ERROR: {}", msg));
+                    error_messages.push(msg);
+                    result.file_list.push(FileEntry {
+                        source_path: source.to_string_lossy().to_string(),
+                        destination_path: destination.to_string_lossy().to_string(),
+                        size: 0,
+                        hash_verified: false,
+                        success: false,
+                        error_message: Some(format!("{:#}", e)),
+                        sha256: None,
+                    });
                 }
             }
         }
-
-        // Finalize at 100%
-        if total_size > 0 {
-            let rate = rate_limiter.get_current_rate();
-            println!("  Progress: 100% ({:.2} KB/s)", rate / 1024.0);
+        for adjustment in rate_limiter.take_adjustments() {
+            details.push(format!("  Adaptive throttling: {}", adjustment));
+        }
+        details.push(format!(
+            "  Restored {} files, {} bytes",
+            result.files_processed, result.total_size
+        ));
+        if kept > 0 {
+            details.push(format!("  Left {} conflicting file(s) as they were", kept));
         }
 
-        dest_file.sync_all()?;
-        Ok(total_copied)
+        result.success = error_messages.is_empty();
+        result.hash_verified = result.success;
+        if result.success {
+            details.push("  Verification successful: all files match the copy".to_string());
+        } else {
+            result.error_message = Some(error_messages.join("; "));
+        }
+        result.details = details;
+        result
     }
 
-    fn copy_directory(operation: &FileOperation, global_rate_limit: &RateLimit, mut details: Vec<String>) -> OperationResult {
+    fn restore_from_repository(
+        operation: &FileOperation,
+        repository: &Repository,
+        snapshot: &str,
+        target: &Path,
+        conflict: Conflict,
+        global_limiter: &GlobalLimiter,
+        mut details: Vec<String>,
+    ) -> OperationResult {
         let mut result = OperationResult {
             operation_name: operation.name.clone(),
-            source: operation.origin.to_string_lossy().to_string(),
-            destination: operation.destination.to_string_lossy().to_string(),
+            source: repository.root.to_string_lossy().to_string(),
+            destination: target.to_string_lossy().to_string(),
             success: false,
             error_message: None,
-            hash_verified: true,
-            operation_type: OperationType::Copy,
+            hash_verified: false,
+            operation_type: OperationType::Repository,
             files_processed: 0,
             total_size: 0,
             start_time: SystemTime::now(),
             end_time: SystemTime::now(),
-            details: details.clone(),
+            details: Vec::new(),
             file_list: Vec::new(),
+            dedup: None,
         };
-
-        let mut all_successful = true;
-        let mut error_messages = Vec::new();
-
-        details.push("  Starting directory copy...".to_string());
-
-        // Prepare a shared rate limiter for the whole directory copy
-        let effective_bps = Self::compute_effective_bps(&operation.rate_limit, global_rate_limit);
-        let mut dir_rate_limiter = RateLimiter::new(effective_bps, None);
-        if dir_rate_limiter.is_enabled() {
-            if let Some(limit) = dir_rate_limiter.get_rate_limit() {
-                details.push(format!(
-                    "  Directory rate limiting: {} bytes/second ({:.2} MB/min)",
-                    limit,
-                    limit as f64 * 60.0 / (1024.0 * 1024.0)
-                ));
+        let manifest = match repository.load_snapshot(snapshot) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                let error_msg = format!("Cannot restore: {:#}", e);
+                details.push(format!("ERROR: {}", error_msg));
+                result.error_message = Some(error_msg);
+                result.details = details;
+                return result;
             }
-        }
+        };
+        details.push(format!(
+            "  Snapshot: {} ({} files, taken {})",
+            snapshot,
+            manifest.files.len(),
+            manifest.created
+        ));
 
-        if let Err(e) = fs::create_dir_all(&operation.destination) {
-            let error_msg = format!("Failed to create destination directory: {}", e);
-            details.push(format!("ERROR: {}", error_msg));
-            result.error_message = Some(error_msg.clone()); // Clone here
-            result.details = details;
-            return result;
+        let mut rate_limiter = RateLimiter::for_operation(
+            &operation.rate_limit,
+            global_limiter,
+            operation.weight.unwrap_or(1),
+        );
+        Self::push_rate_limiting(
+            &mut details,
+            &rate_limiter,
+            operation,
+            global_limiter,
+            "Restore rate limiting",
+        );
+        let restored = repository.restore(&manifest, target, conflict, &mut rate_limiter);
+        for adjustment in rate_limiter.take_adjustments() {
+            details.push(format!("  Adaptive throttling: {}", adjustment));
         }
-        details.push("  Destination directory created".to_string());
-
-        for entry in WalkDir::new(&operation.origin) {
-            let entry = match entry {
-                Ok(e) => e,
-                Err(e) => {
-                    error_messages.push(format!("Error reading directory entry: {}", e));
-                    details.push(format!("WARNING: Error reading entry: {}", e));
-                    all_successful = false;
-                    continue;
-                }
-            };
-
-            let source_path = entry.path();
-
-            if source_path == operation.origin {
-                continue;
+        let files = match restored {
+            Ok(files) => files,
+            Err(e) => {
+                let error_msg = format!("Failed to restore snapshot: {:#}", e);
+                details.push(format!("ERROR: {}", error_msg));
+                result.error_message = Some(error_msg);
+                result.details = details;
+                return result;
             }
+        };
 
-            let relative_path = match source_path.strip_prefix(&operation.origin) {
-                Ok(p) => p,
-                Err(_) => {
-                    let msg = format!("Failed to get relative path for: {}", source_path.display());
-                    error_messages.push(msg.clone());
-                    details.push(format!("WARNING: {}", msg));
+        let mut error_messages = Vec::new();
+        let mut kept = 0;
+        for file in files {
+            match &file.existing {
+                Existing::Kept if file.problem.is_none() => {
+                    details.push(format!(
+                        "WARNING: Kept {}: it differs from the snapshot",
+                        file.destination.display()
+                    ));
+                    kept += 1;
                     continue;
                 }
-            };
-
-            let dest_path = operation.destination.join(relative_path);
-
-            if entry.file_type().is_dir() {
-                if let Err(e) = fs::create_dir_all(&dest_path) {
-                    let msg = format!("Failed to create directory {}: {}", dest_path.display(), e);
-                    error_messages.push(msg.clone());
-                    details.push(format!("ERROR creating directory: {}", msg));
-                    all_successful = false;
-                } else {
-                    details.push(format!("  Created directory: {}", dest_path.display()));
-                }
-            } else if entry.file_type().is_file() {
-                result.files_processed += 1;
-
-                let file_size = entry.metadata().map(|m| m.len()).unwrap_or(0);
-                result.total_size += file_size;
-
-                details.push(format!(
-                    "  Copying file {}/{}: {}",
-                    result.files_processed,
-                    "?",
-                    source_path.display()
-                ));
-
-                let copy_res: io::Result<u64> = if dir_rate_limiter.is_enabled() {
-                    Self::copy_file_with_rate_limit(source_path, &dest_path, &mut dir_rate_limiter)
-                } else {
-                    fs::copy(source_path, &dest_path)
-                };
-
-                match copy_res {
-                    Ok(bytes_copied) => {
-                        details.push(format!("    Copied {} bytes", bytes_copied));
-
-                        match validation::verify_files_match(source_path, &dest_path) {
-                            Ok(true) => {
-                                details.push("    Verification successful".to_string());
-
-                                result.file_list.push(FileEntry {
-                                    source_path: source_path.to_string_lossy().to_string(),
-                                    destination_path: dest_path.to_string_lossy().to_string(),
-                                    size: bytes_copied,
-                                    hash_verified: true,
-                                    success: true,
-                                    error_message: None,
-                                });
-                            }
-                            Ok(false) => {
-                                let msg = format!(
-                                    "Hash verification failed for: {}",
-                                    source_path.display()
-                                );
-                                error_messages.push(msg.clone());
-                                details.push(format!("ERROR: {}", msg));
-                                all_successful = false;
-                                result.hash_verified = false;
-                                let _ = fs::remove_file(&dest_path);
-                                details.push("    Cleaned up failed copy".to_string());
-
-                                result.file_list.push(FileEntry {
-                                    source_path: source_path.to_string_lossy().to_string(),
-                                    destination_path: dest_path.to_string_lossy().to_string(),
-                                    size: bytes_copied,
-                                    hash_verified: false,
-                                    success: false,
-                                    error_message: Some("Hash verification failed".to_string()),
-                                });
-                            }
-                            Err(e) => {
-                                let msg = format!(
-                                    "Verification error for {}: {}",
-                                    source_path.display(),
-                                    e
-                                );
-                                error_messages.push(msg.clone());
-                                details.push(format!("ERROR: {}", msg));
-                                all_successful = false;
-                                result.hash_verified = false;
-                                let _ = fs::remove_file(&dest_path);
-                                details.push("    Cleaned up failed copy".to_string());
-
-                                result.file_list.push(FileEntry {
-                                    source_path: source_path.to_string_lossy().to_string(),
-                                    destination_path: dest_path.to_string_lossy().to_string(),
-                                    size: bytes_copied,
-                                    hash_verified: false,
-                                    success: false,
-                                    error_message: Some(format!("Verification error: {}", e)),
-                                });
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        let msg = format!(
-                            "Failed to copy {} to {}: {}",
-                            source_path.display(),
-                            dest_path.display(),
-                            e
-                        );
-                        error_messages.push(msg.clone());
-                        details.push(format!("ERROR: {}", msg));
-                        all_successful = false;
-
-                        result.file_list.push(FileEntry {
-                            source_path: source_path.to_string_lossy().to_string(),
-                            destination_path: dest_path.to_string_lossy().to_string(),
-                            size: file_size,
-                            hash_verified: false,
-                            success: false,
-                            error_message: Some(msg),
-                        });
-                    }
+                Existing::Identical => {
+                    details.push(format!("    {}: already up to date", file.path));
                 }
+                Existing::MovedTo(aside) => details.push(format!(
+                    "    {}: moved the existing file aside to {}",
+                    file.path,
+                    aside.display()
+                )),
+                _ => {}
+            }
+            result.files_processed += 1;
+            result.total_size += file.size;
+            if let Some(problem) = &file.problem {
+                let msg = format!("{}: {}", file.path, problem);
+                details.push(format!("ERROR: {}", msg));
+                error_messages.push(msg);
             }
+            result.file_list.push(FileEntry {
+                source_path: repository
+                    .object_path(&file.sha256)
+                    .to_string_lossy()
+                    .to_string(),
+                destination_path: file.destination.to_string_lossy().to_string(),
+                size: file.size,
+                hash_verified: file.problem.is_none(),
+                success: file.problem.is_none(),
+                error_message: file.problem,
+                sha256: Some(file.sha256),
+            });
         }
-
         details.push(format!(
-            "  Total files processed: {}",
-            result.files_processed
+            "  Restored {} files, {} bytes",
+            result.files_processed, result.total_size
         ));
-        details.push(format!("  Total size: {} bytes", result.total_size));
+        if kept > 0 {
+            details.push(format!("  Left {} conflicting file(s) as they were", kept));
+        }
 
-        result.success = all_successful;
-        if !error_messages.is_empty() {
+        result.success = error_messages.is_empty();
+        result.hash_verified = result.success;
+        if result.success {
+            details.push("  Verification successful: all files match the snapshot".to_string());
+        } else {
+            details.push("  Removed files that failed verification".to_string());
             result.error_message = Some(error_messages.join("; "));
         }
         result.details = details;
-
         result
     }
 
@@ -629,6 +2501,7 @@ impl FileManager {
             end_time: SystemTime::now(),
             details: details.clone(),
             file_list: Vec::new(),
+            dedup: None,
         };
 
         let file_size = if let Ok(metadata) = std::fs::metadata(&operation.origin) {
@@ -675,6 +2548,7 @@ impl FileManager {
                         hash_verified: true,
                         success: true,
                         error_message: None,
+                        sha256: None,
                     });
                 } else {
                     let error_msg = "Destination file doesn't exist after move".to_string();
@@ -699,6 +2573,7 @@ impl FileManager {
                     hash_verified: false,
                     success: false,
                     error_message: Some(error_msg), // Use the original
+                    sha256: None,
                 });
 
                 if e.kind() == io::ErrorKind::PermissionDenied {
@@ -730,6 +2605,7 @@ impl FileManager {
             end_time: SystemTime::now(),
             details: details.clone(),
             file_list: Vec::new(),
+            dedup: None,
         };
 
         details.push("  Starting directory move...".to_string());
@@ -769,32 +2645,31 @@ impl FileManager {
                 if result.success {
                     details.push("  Verification: Destination exists".to_string());
                     for entry in WalkDir::new(&operation.destination) {
-                        if let Ok(entry) = entry {
-                            if entry.file_type().is_file() {
-                                result.files_processed += 1;
-                                if let Ok(metadata) = entry.metadata() {
-                                    result.total_size += metadata.len();
-
-                                    let source_path = entry.path();
-                                    let relative_path = source_path
-                                        .strip_prefix(&operation.destination)
-                                        .ok()
-                                        .map(|p| p.to_string_lossy().to_string())
-                                        .unwrap_or_else(|| {
-                                            source_path.to_string_lossy().to_string()
-                                        });
-
-                                    let original_source = operation.origin.join(&relative_path);
-
-                                    result.file_list.push(FileEntry {
-                                        source_path: original_source.to_string_lossy().to_string(),
-                                        destination_path: source_path.to_string_lossy().to_string(),
-                                        size: metadata.len(),
-                                        hash_verified: true,
-                                        success: true,
-                                        error_message: None,
-                                    });
-                                }
+                        if let Ok(entry) = entry
+                            && entry.file_type().is_file()
+                        {
+                            result.files_processed += 1;
+                            if let Ok(metadata) = entry.metadata() {
+                                result.total_size += metadata.len();
+
+                                let source_path = entry.path();
+                                let relative_path = source_path
+                                    .strip_prefix(&operation.destination)
+                                    .ok()
+                                    .map(|p| p.to_string_lossy().to_string())
+                                    .unwrap_or_else(|| source_path.to_string_lossy().to_string());
+
+                                let original_source = operation.origin.join(&relative_path);
+
+                                result.file_list.push(FileEntry {
+                                    source_path: original_source.to_string_lossy().to_string(),
+                                    destination_path: source_path.to_string_lossy().to_string(),
+                                    size: metadata.len(),
+                                    hash_verified: true,
+                                    success: true,
+                                    error_message: None,
+                                    sha256: None,
+                                });
                             }
                         }
                     }
@@ -835,6 +2710,20 @@ impl FileManager {
         result
     }
 
+    /// Bytes per second across all `results` together, from the first start
+    /// to the last finish, and that span in seconds. Parallel operations
+    /// overlap, so this is what a shared global limit actually allowed.
+    pub fn aggregate_rate(results: &[OperationResult]) -> Option<(u64, f64)> {
+        let start = results.iter().map(|r| r.start_time).min()?;
+        let end = results.iter().map(|r| r.end_time).max()?;
+        let elapsed = end.duration_since(start).ok()?.as_secs_f64();
+        if elapsed <= 0.0 {
+            return None;
+        }
+        let total_size: u64 = results.iter().map(|r| r.total_size).sum();
+        Some(((total_size as f64 / elapsed) as u64, elapsed))
+    }
+
     pub fn generate_report(results: &[OperationResult]) -> String {
         let mut report = String::new();
         report.push_str("File Operation Report\n");
@@ -856,6 +2745,40 @@ impl FileManager {
             total_size,
             total_size as f64 / (1024.0 * 1024.0)
         ));
+        if let Some((rate, elapsed)) = Self::aggregate_rate(results) {
+            report.push_str(&format!(
+                "Aggregate Throughput: {} over {:.1}s\n\n",
+                format_rate(rate),
+                elapsed
+            ));
+        }
+
+        let repositories: Vec<_> = results
+            .iter()
+            .filter_map(|r| r.dedup.as_ref().map(|stats| (r, stats)))
+            .collect();
+        if !repositories.is_empty() {
+            report.push_str("Deduplication:\n");
+            for (result, stats) in repositories {
+                report.push_str(&format!(
+                    "  {}: snapshot {}, {} files, {} new ({}), {} already stored\n",
+                    result.operation_name,
+                    stats.snapshot,
+                    stats.files,
+                    stats.new_objects,
+                    format_size(stats.new_bytes),
+                    format_size(stats.reused_bytes)
+                ));
+                report.push_str(&format!(
+                    "    Across {} snapshots: {} referenced, {} stored ({:.1}x)\n",
+                    stats.snapshots,
+                    format_size(stats.referenced_bytes),
+                    format_size(stats.stored_bytes),
+                    stats.ratio()
+                ));
+            }
+            report.push('\n');
+        }
 
         if !successful.is_empty() {
             report.push_str("Successful Operations:\n");
@@ -871,7 +2794,7 @@ impl FileManager {
                     if result.hash_verified { "✓" } else { "✗" }
                 ));
             }
-            report.push_str("\n");
+            report.push('\n');
         }
 
         if !failed.is_empty() {
@@ -908,6 +2831,7 @@ impl FileManager {
     pub fn generate_detailed_report(
         results: &[OperationResult],
         destination_dir: &Path,
+        stamp: &str,
     ) -> anyhow::Result<String> {
         use chrono::{DateTime, Local};
         use std::env;
@@ -980,6 +2904,13 @@ impl FileManager {
             total_size as f64 / (1024.0 * 1024.0)
         ));
         report.push_str(&format!("Total Duration: {} ms\n", total_duration));
+        if let Some((rate, elapsed)) = Self::aggregate_rate(results) {
+            report.push_str(&format!(
+                "Aggregate Throughput: {} over {:.1}s wall clock\n",
+                format_rate(rate),
+                elapsed
+            ));
+        }
         report.push('\n');
 
         report.push_str("DETAILED RESULTS\n");
@@ -1040,9 +2971,7 @@ impl FileManager {
             report.push('\n');
         }
 
-        let timestamp = now.format("%Y%m%d_%H%M%S");
-        let report_filename =
-            destination_dir.join(format!("file_operations_report_{}.txt", timestamp));
+        let report_filename = destination_dir.join(format!("file_operations_report_{}.txt", stamp));
 
         match std::fs::write(&report_filename, &report) {
             Ok(_) => {
@@ -1065,6 +2994,7 @@ impl FileManager {
 
     pub fn save_operation_reports_to_destinations(
         results: &[OperationResult],
+        stamp: &str,
     ) -> anyhow::Result<Vec<String>> {
         use chrono::{DateTime, Local};
         use std::fs;
@@ -1083,15 +3013,15 @@ impl FileManager {
                     .unwrap_or_else(|| Path::new(".").to_path_buf())
             };
 
-            if !report_dir.exists() {
-                if let Err(e) = fs::create_dir_all(&report_dir) {
-                    saved_paths.push(format!(
-                        "✗ Could not create directory for operation {}: {}",
-                        i + 1,
-                        e
-                    ));
-                    continue;
-                }
+            if !report_dir.exists()
+                && let Err(e) = fs::create_dir_all(&report_dir)
+            {
+                saved_paths.push(format!(
+                    "✗ Could not create directory for operation {}: {}",
+                    i + 1,
+                    e
+                ));
+                continue;
             }
 
             let mut operation_report = String::new();
@@ -1176,12 +3106,11 @@ impl FileManager {
                 operation_report.push_str(&format!("{}\n", detail));
             }
 
-            let timestamp = now.format("%Y%m%d_%H%M%S");
             let report_filename = report_dir.join(format!(
                 "operation_{}_{}_{}.txt",
                 i + 1,
                 result.operation_name.replace(" ", "_").to_lowercase(),
-                timestamp
+                stamp
             ));
 
             match fs::write(&report_filename, &operation_report) {
@@ -1200,6 +3129,24 @@ impl FileManager {
         Ok(saved_paths)
     }
 
+    /// Saves `results` as JSON so a later `--retry-failed` can read them.
+    pub fn save_json_report(
+        results: &[OperationResult],
+        destination_dir: &Path,
+        stamp: &str,
+    ) -> anyhow::Result<std::path::PathBuf> {
+        let path = destination_dir.join(format!("file_operations_report_{}.json", stamp));
+        fs::write(&path, serde_json::to_string_pretty(results)?)?;
+        Ok(path)
+    }
+
+    pub fn load_json_report(path: &Path) -> anyhow::Result<Vec<OperationResult>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("cannot read report {}: {}", path.display(), e))?;
+        serde_json::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("{} is not a JSON report: {}", path.display(), e))
+    }
+
     pub fn generate_file_list_report(results: &[OperationResult]) -> String {
         let mut report = String::new();
         report.push_str("FILE LIST REPORT\n");
@@ -1264,16 +3211,16 @@ impl FileManager {
         report
     }
 
-    pub fn save_file_list_reports(results: &[OperationResult]) -> anyhow::Result<Vec<String>> {
-        use chrono::{DateTime, Local};
+    pub fn save_file_list_reports(
+        results: &[OperationResult],
+        stamp: &str,
+    ) -> anyhow::Result<Vec<String>> {
         use std::fs;
 
-        let now: DateTime<Local> = Local::now();
         let mut saved_paths = Vec::new();
 
         let overall_report = Self::generate_file_list_report(results);
-        let timestamp = now.format("%Y%m%d_%H%M%S");
-        let overall_filename = format!("file_list_report_{}.txt", timestamp);
+        let overall_filename = format!("file_list_report_{}.txt", stamp);
 
         if let Err(e) = fs::write(&overall_filename, &overall_report) {
             saved_paths.push(format!("✗ Failed to save overall file list report: {}", e));
@@ -1296,15 +3243,15 @@ impl FileManager {
                         .unwrap_or_else(|| Path::new(".").to_path_buf())
                 };
 
-                if !report_dir.exists() {
-                    if let Err(e) = fs::create_dir_all(&report_dir) {
-                        saved_paths.push(format!(
-                            "✗ Could not create directory for operation {}: {}",
-                            i + 1,
-                            e
-                        ));
-                        continue;
-                    }
+                if !report_dir.exists()
+                    && let Err(e) = fs::create_dir_all(&report_dir)
+                {
+                    saved_paths.push(format!(
+                        "✗ Could not create directory for operation {}: {}",
+                        i + 1,
+                        e
+                    ));
+                    continue;
                 }
 
                 let mut operation_file_report = String::new();
@@ -1360,7 +3307,7 @@ impl FileManager {
                 let operation_filename = report_dir.join(format!(
                     "file_list_{}_{}.txt",
                     result.operation_name.replace(" ", "_").to_lowercase(),
-                    timestamp
+                    stamp
                 ));
 
                 match fs::write(&operation_filename, &operation_file_report) {
@@ -1384,3 +3331,156 @@ impl FileManager {
         Ok(saved_paths)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_stamps_are_never_repeated() {
+        let stamps: Vec<String> = (0..50).map(|_| report_stamp()).collect();
+        let mut unique = stamps.clone();
+        unique.dedup();
+        assert_eq!(unique, stamps);
+        assert!(stamps.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn destination_reports_are_recognised_with_either_stamp() {
+        let stamp = report_stamp();
+        let is_report =
+            |name: &str| FileManager::is_destination_report(Path::new(name), "Daily Docs");
+        assert!(is_report(&format!("file_list_daily_docs_{}.txt", stamp)));
+        assert!(is_report(&format!("operation_2_daily_docs_{}.txt", stamp)));
+        assert!(is_report("file_list_daily_docs_20240101_120000.txt"));
+        assert!(!is_report(&format!("file_list_other_{}.txt", stamp)));
+        assert!(!is_report("file_list_daily_docs_notes.txt"));
+        assert!(!is_report("daily_docs_20240101_120000.txt"));
+    }
+
+    #[test]
+    fn short_destination_is_reported_at_its_rendered_path() {
+        let dir = crate::test_util::TempDir::new("file_ops");
+        let origin = dir.join("origin");
+        fs::create_dir(&origin).unwrap();
+        fs::write(origin.join("a.txt"), "hello").unwrap();
+        let operations = [FileOperation {
+            name: "dated".to_string(),
+            origin,
+            destination: dir.join("backup/{date}"),
+            operation_type: OperationType::Copy,
+            ..Default::default()
+        }];
+        // A margin no real filesystem has room for
+        let results = FileManager::execute_operations(
+            &operations,
+            &GlobalLimiter::new(&crate::config::RateLimit::default()),
+            &Margin::Percent(99.999),
+            None,
+        );
+
+        let result = &results[0];
+        assert!(!result.success);
+        assert!(
+            result
+                .error_message
+                .as_ref()
+                .unwrap()
+                .contains("Not enough free space")
+        );
+        let expected = dir.join(format!("backup/{}", Local::now().format("%Y-%m-%d")));
+        assert_eq!(result.destination, expected.to_string_lossy());
+        assert!(!dir.join("backup").exists());
+    }
+
+    #[test]
+    fn encoded_copies_are_restored_and_verified() {
+        let dir = crate::test_util::TempDir::new("file_ops");
+        let origin = dir.join("origin");
+        fs::create_dir_all(origin.join("sub")).unwrap();
+        fs::write(origin.join("a.txt"), "alpha ".repeat(1000)).unwrap();
+        fs::write(origin.join("sub/b.txt"), "beta").unwrap();
+        let key_file = dir.join("key");
+        fs::write(&key_file, "ab".repeat(32)).unwrap();
+        let global = GlobalLimiter::new(&crate::config::RateLimit::default());
+
+        for (name, compression, encrypted) in [
+            ("gzip_encrypted", Some(Compression::Gzip), true),
+            ("zstd", Some(Compression::Zstd), false),
+        ] {
+            let operation = FileOperation {
+                name: name.to_string(),
+                origin: origin.clone(),
+                destination: dir.join(name),
+                operation_type: OperationType::Copy,
+                compression,
+                encryption: encrypted.then(|| crate::config::Encryption {
+                    key_file: key_file.clone(),
+                }),
+                ..Default::default()
+            };
+            let backup = FileManager::execute_operation(&operation, &global);
+            assert!(backup.success, "{:?}", backup.error_message);
+
+            let target = dir.join(format!("{}_restored", name));
+            fs::create_dir(&target).unwrap();
+            // One file already matches, one differs
+            fs::write(target.join("a.txt"), "alpha ".repeat(1000)).unwrap();
+            fs::create_dir(target.join("sub")).unwrap();
+            fs::write(target.join("sub/b.txt"), "changed").unwrap();
+
+            let restored =
+                FileManager::restore(&operation, None, Some(&target), Conflict::Rename, &global);
+            assert!(restored.success, "{:?}", restored.error_message);
+            assert!(restored.hash_verified);
+            assert_eq!(restored.files_processed, 2);
+            assert_eq!(
+                fs::read_to_string(target.join("a.txt")).unwrap(),
+                "alpha ".repeat(1000)
+            );
+            assert_eq!(
+                fs::read_to_string(target.join("sub/b.txt")).unwrap(),
+                "beta"
+            );
+            assert_eq!(
+                fs::read_to_string(target.join("sub/b.txt.orig")).unwrap(),
+                "changed"
+            );
+            assert!(
+                restored
+                    .details
+                    .iter()
+                    .any(|d| d.contains("already up to date"))
+            );
+            // Backup reports at the destination stay out of the restore
+            assert_eq!(fs::read_dir(&target).unwrap().count(), 2);
+        }
+    }
+
+    #[test]
+    fn damaged_compressed_copy_fails_to_restore() {
+        let dir = crate::test_util::TempDir::new("file_ops");
+        let origin = dir.join("notes.txt");
+        fs::write(&origin, "some notes").unwrap();
+        let operation = FileOperation {
+            name: "notes".to_string(),
+            origin: origin.clone(),
+            destination: dir.join("backup/notes.txt"),
+            operation_type: OperationType::Copy,
+            compression: Some(Compression::Gzip),
+            ..Default::default()
+        };
+        let global = GlobalLimiter::new(&crate::config::RateLimit::default());
+        assert!(FileManager::execute_operation(&operation, &global).success);
+        let copy = dir.join("backup/notes.txt.gz");
+        let mut data = fs::read(&copy).unwrap();
+        data.truncate(data.len() - 4);
+        fs::write(&copy, data).unwrap();
+
+        let target = dir.join("restored.txt");
+        let restored =
+            FileManager::restore(&operation, None, Some(&target), Conflict::Skip, &global);
+        assert!(!restored.success);
+        assert!(!target.exists());
+    }
+}
@@ -0,0 +1,215 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+// Exit status when another process holds the lock (EX_TEMPFAIL), so cron
+// wrappers can tell "skipped" apart from "failed"
+pub const ALREADY_RUNNING_EXIT_CODE: i32 = 75;
+
+/// Another live process is already running operations from this config.
+#[derive(Debug)]
+pub struct AlreadyRunning {
+    // Unknown while the owner is still writing it, or where locked files
+    // can't be read
+    pub pid: Option<u32>,
+    pub lock_path: PathBuf,
+}
+
+impl fmt::Display for AlreadyRunning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "already running: process {} holds ", pid)?,
+            None => write!(f, "already running: another process holds ")?,
+        }
+        write!(f, "{}", self.lock_path.display())
+    }
+}
+
+impl std::error::Error for AlreadyRunning {}
+
+/// Lock file next to a config, holding the owner's PID and locked by it
+/// while it runs. The operating system releases the lock when the owner
+/// exits, so a file left behind by a crashed run is simply locked again.
+/// Removed when dropped.
+#[derive(Debug)]
+pub struct RunLock {
+    path: PathBuf,
+    file: File,
+    // PID found in the file when it was locked
+    stale_owner: Option<u32>,
+}
+
+impl RunLock {
+    /// Locks every file of a config, so runs whose configs share an
+    /// included file exclude each other. Nothing stays locked on failure.
+    pub fn acquire_all<'a>(
+        config_paths: impl IntoIterator<Item = &'a Path>,
+    ) -> anyhow::Result<Vec<Self>> {
+        let mut paths: Vec<&Path> = config_paths.into_iter().collect();
+        // The same order everywhere, so two runs report the same conflict
+        paths.sort();
+        paths.dedup();
+        paths.into_iter().map(Self::acquire).collect()
+    }
+
+    pub fn acquire(config_path: &Path) -> anyhow::Result<Self> {
+        let mut lock_name = config_path
+            .file_name()
+            .map(|name| name.to_os_string())
+            .unwrap_or_else(|| "config".into());
+        lock_name.push(".lock");
+        let path = config_path.with_file_name(lock_name);
+
+        loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .map_err(|e| {
+                    anyhow::anyhow!("cannot create lock file {}: {}", path.display(), e)
+                })?;
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => {
+                    return Err(AlreadyRunning {
+                        pid: read_pid(&mut file),
+                        lock_path: path,
+                    }
+                    .into());
+                }
+                Err(TryLockError::Error(e)) => {
+                    anyhow::bail!("cannot lock {}: {}", path.display(), e);
+                }
+            }
+            // The last owner removes the file before unlocking it; if that
+            // happened after we opened it, the lock we hold excludes nobody
+            if !still_at(&file, &path) {
+                continue;
+            }
+            let stale_owner = read_pid(&mut file);
+            file.set_len(0)?;
+            file.rewind()?;
+            writeln!(file, "{}", std::process::id())?;
+            return Ok(Self {
+                path,
+                file,
+                stale_owner,
+            });
+        }
+    }
+
+    /// Note for the user when the lock file still held the PID of an
+    /// earlier owner that exited without removing it.
+    pub fn takeover_note(&self) -> Option<String> {
+        self.stale_owner.map(|pid| {
+            format!(
+                "Took over stale lock {} (owner {} is not running)",
+                self.path.display(),
+                pid
+            )
+        })
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        // Removed while still locked, so nobody can have taken it over yet
+        #[cfg(unix)]
+        let _ = fs::remove_file(&self.path);
+        // Elsewhere an open file may not be removable, so it is only emptied
+        #[cfg(not(unix))]
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut text = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut text).ok()?;
+    text.trim().parse().ok()
+}
+
+// Whether `file` is still the one at `path`
+#[cfg(unix)]
+fn still_at(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(open), Ok(named)) => open.dev() == named.dev() && open.ino() == named.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn still_at(_file: &File, _path: &Path) -> bool {
+    // Lock files aren't removed there
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn second_acquire_reports_the_owner() {
+        let dir = TempDir::new("lock");
+        let config = dir.join("config.yaml");
+        let lock = RunLock::acquire(&config).unwrap();
+        assert_eq!(lock.stale_owner, None);
+        let err = RunLock::acquire(&config).unwrap_err();
+        let running = err.downcast_ref::<AlreadyRunning>().unwrap();
+        assert_eq!(running.pid, Some(std::process::id()));
+        assert_eq!(running.lock_path, config.with_file_name("config.yaml.lock"));
+        drop(lock);
+        assert!(!running.lock_path.exists());
+        RunLock::acquire(&config).unwrap();
+    }
+
+    #[test]
+    fn leftover_lock_file_is_taken_over() {
+        let dir = TempDir::new("lock");
+        let config = dir.join("config.yaml");
+        let lock_path = config.with_file_name("config.yaml.lock");
+        // A longer PID than ours, so a partial overwrite would show
+        fs::write(&lock_path, "4294967295\n").unwrap();
+        let lock = RunLock::acquire(&config).unwrap();
+        assert_eq!(lock.stale_owner, Some(4294967295));
+        assert!(lock.takeover_note().unwrap().contains("owner 4294967295"));
+        assert_eq!(
+            fs::read_to_string(&lock_path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(lock);
+        assert!(!lock_path.exists());
+    }
+
+    #[test]
+    fn acquire_all_locks_every_file_or_none() {
+        let dir = TempDir::new("lock");
+        let primary = dir.join("config.yaml");
+        let included = dir.join("shared.yaml");
+        let other = dir.join("other.yaml");
+
+        let locks = RunLock::acquire_all([primary.as_path(), included.as_path()]).unwrap();
+        assert_eq!(locks.len(), 2);
+        assert!(included.with_file_name("shared.yaml.lock").exists());
+
+        // Another config that includes the same file can't run alongside
+        let err = RunLock::acquire_all([other.as_path(), included.as_path()]).unwrap_err();
+        assert!(err.downcast_ref::<AlreadyRunning>().is_some());
+        // and released the lock it got before failing
+        assert!(!other.with_file_name("other.yaml.lock").exists());
+
+        drop(locks);
+        assert_eq!(
+            RunLock::acquire_all([other.as_path(), included.as_path(), other.as_path()])
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+}
@@ -1,14 +1,43 @@
+mod archive;
+mod compression;
 mod config;
+mod copy_strategy;
+mod crypto;
+mod diagnostics;
+mod file_browser;
 mod file_ops;
+mod lock;
+mod paths;
+mod preflight;
 mod rate_limiter;
+mod repository;
+mod restore;
+mod retention;
+mod scheduler;
+mod schema;
+#[cfg(test)]
+mod test_util;
 mod ui;
 mod validation;
+#[cfg(target_os = "linux")]
+mod watcher;
 
 use clap::{Arg, Command};
 use std::fs;
 use std::path::PathBuf;
 
 fn main() -> anyhow::Result<()> {
+    let result = run();
+    if let Err(e) = &result
+        && let Some(running) = e.downcast_ref::<lock::AlreadyRunning>()
+    {
+        eprintln!("Error: {}", running);
+        std::process::exit(lock::ALREADY_RUNNING_EXIT_CODE);
+    }
+    result
+}
+
+fn run() -> anyhow::Result<()> {
     let matches = Command::new("File Manager")
         .version("1.0")
         .author("Your Name")
@@ -18,7 +47,8 @@ fn main() -> anyhow::Result<()> {
                 .short('c')
                 .long("config")
                 .value_name("FILE")
-                .help("Sets a custom config file")
+                .help("Sets a custom config file (may be given more than once)")
+                .action(clap::ArgAction::Append)
                 .default_value("config.yaml"),
         )
         .arg(
@@ -28,6 +58,28 @@ fn main() -> anyhow::Result<()> {
                 .help("Run in batch mode (no UI)")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("daemon")
+                .short('d')
+                .long("daemon")
+                .help("Stay running and execute operations on their schedules")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("watch")
+                .short('w')
+                .long("watch")
+                .help("Watch each operation's origin and transfer files as they are written")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("debounce")
+                .long("debounce")
+                .value_name("SECONDS")
+                .help("In watch mode, how long a file must sit unchanged before it is transferred")
+                .value_parser(clap::value_parser!(f64))
+                .default_value("2"),
+        )
         .arg(
             Arg::new("verbose")
                 .short('v')
@@ -35,6 +87,59 @@ fn main() -> anyhow::Result<()> {
                 .help("Show verbose output")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("prune-dry-run")
+                .long("prune-dry-run")
+                .help("List which snapshots the retention policies would keep or prune, then exit")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("convert")
+                .long("convert")
+                .value_name("FILE")
+                .help("Write the config to FILE as YAML, TOML or JSON (by extension), then exit"),
+        )
+        .arg(
+            Arg::new("retry-failed")
+                .long("retry-failed")
+                .value_name("REPORT_JSON")
+                .help("Re-run only the files that failed in a previous run's JSON report"),
+        )
+        .arg(
+            Arg::new("restore")
+                .long("restore")
+                .value_name("OPERATION")
+                .help("Copy an operation's backup back to its origin, then exit"),
+        )
+        .arg(
+            Arg::new("snapshot")
+                .long("snapshot")
+                .value_name("NAME_OR_TIME")
+                .help("With --restore, the snapshot to restore, by name or as the latest taken at or before a time (the latest by default)")
+                .requires("restore"),
+        )
+        .arg(
+            Arg::new("restore-to")
+                .long("restore-to")
+                .value_name("PATH")
+                .help("With --restore, restore here instead of to the origin")
+                .requires("restore"),
+        )
+        .arg(
+            Arg::new("on-conflict")
+                .long("on-conflict")
+                .value_name("ACTION")
+                .help("With --restore, what to do with existing files that differ from the backup")
+                .value_parser(["skip", "overwrite", "rename"])
+                .default_value("skip")
+                .requires("restore"),
+        )
+        .arg(
+            Arg::new("print-schema")
+                .long("print-schema")
+                .help("Print a JSON Schema for config files, then exit")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("report-dir")
                 .short('r')
@@ -45,39 +150,63 @@ fn main() -> anyhow::Result<()> {
         )
         .get_matches();
 
-    let config_path = matches.get_one::<String>("config").unwrap();
+    if matches.get_flag("print-schema") {
+        println!("{}", schema::json_schema()?);
+        return Ok(());
+    }
+
+    let config_paths: Vec<&String> = matches.get_many::<String>("config").unwrap().collect();
+    let config_path = config_paths[0];
     let batch_mode = matches.get_flag("batch");
     let verbose = matches.get_flag("verbose");
     let report_dir = matches.get_one::<String>("report-dir").unwrap();
 
-    let config = if std::path::Path::new(config_path).exists() {
-        match config::Config::load_from_file(config_path) {
+    let config = if config_paths.len() > 1 || std::path::Path::new(config_path).exists() {
+        match config::Config::load_from_files(&config_paths) {
             Ok(cfg) => cfg,
             Err(e) => {
-                if verbose {
-                    println!("Failed to parse config '{}': {}", config_path, e);
-                }
-                println!("Using in-memory default configuration (existing file was left untouched). Please fix your YAML or press 's' in the UI to save the current config.");
-                create_default_config()
+                println!("Failed to parse config: {}", e);
+                println!(
+                    "Using in-memory default configuration (existing file was left untouched). Saving is disabled until the file is fixed."
+                );
+                let mut default_config = create_default_config();
+                default_config
+                    .files
+                    .push(config::ConfigFile::new(config_path));
+                default_config.fallback = true;
+                default_config
             }
         }
     } else {
-        println!("Config file not found. Creating a default config at '{}'...", config_path);
-        let default_config = create_default_config();
+        println!(
+            "Config file not found. Creating a default config at '{}'...",
+            config_path
+        );
+        let mut default_config = create_default_config();
         if let Err(e) = default_config.save_to_file(config_path) {
             println!("Warning: Could not save default config: {}", e);
         } else {
             println!("Default config created at '{}'", config_path);
         }
         default_config
+            .files
+            .push(config::ConfigFile::new(config_path));
+        default_config
     };
 
     if verbose {
         println!("Loaded configuration:");
-        println!("  Config file: {}", config_path);
+        for file in &config.files {
+            println!("  Config file: {}", file.path.display());
+        }
         println!("  Operations configured: {}", config.operations.len());
         for (i, op) in config.operations.iter().enumerate() {
             println!("  Operation {}: {}", i + 1, op.name);
+            if config.files.len() > 1
+                && let Some(file) = &op.source_file
+            {
+                println!("    File: {}", file.display());
+            }
             println!("    From: {}", op.origin.display());
             println!("    To: {}", op.destination.display());
             println!("    Type: {:?}", op.operation_type);
@@ -85,14 +214,68 @@ fn main() -> anyhow::Result<()> {
         println!();
     }
 
-    if batch_mode {
+    if let Some(output) = matches.get_one::<String>("convert") {
+        return run_convert(&config, output);
+    }
+
+    if matches.get_flag("prune-dry-run") {
+        return run_prune_dry_run(&config);
+    }
+
+    if let Some(name) = matches.get_one::<String>("restore") {
+        let snapshot = matches.get_one::<String>("snapshot").map(String::as_str);
+        let target = matches.get_one::<String>("restore-to").map(String::as_str);
+        let conflict = matches
+            .get_one::<String>("on-conflict")
+            .and_then(|action| restore::Conflict::parse(action))
+            .unwrap_or_default();
+        return run_restore_mode(
+            &config, name, snapshot, target, conflict, verbose, report_dir,
+        );
+    }
+
+    if let Some(report_file) = matches.get_one::<String>("retry-failed") {
+        run_retry_mode(&config, report_file, verbose, report_dir)
+    } else if matches.get_flag("watch") {
+        let debounce = *matches.get_one::<f64>("debounce").unwrap();
+        run_watch_mode(&config, debounce)
+    } else if matches.get_flag("daemon") {
+        run_daemon_mode(&config, report_dir)
+    } else if batch_mode {
         run_batch_mode(&config, verbose, report_dir)
     } else {
         run_ui_mode(&config, report_dir)
     }
 }
 
+// Held for as long as operations from this config may be running
+fn lock_config(config: &config::Config) -> anyhow::Result<Vec<lock::RunLock>> {
+    let locks = lock::RunLock::acquire_all(config.files.iter().map(|f| f.path.as_path()))?;
+    for note in locks.iter().filter_map(|lock| lock.takeover_note()) {
+        println!("{}", note);
+    }
+    Ok(locks)
+}
+
+fn check_config_before_run(config: &config::Config) -> anyhow::Result<()> {
+    let diagnostics = diagnostics::check_config(config);
+    if !diagnostics.is_empty() {
+        println!("Configuration check:");
+        for diagnostic in &diagnostics {
+            println!("  {}", diagnostic);
+        }
+        println!();
+    }
+    if diagnostics::has_errors(&diagnostics) {
+        anyhow::bail!("configuration has errors; no operations were run");
+    }
+    Ok(())
+}
+
 fn run_batch_mode(config: &config::Config, verbose: bool, report_dir: &str) -> anyhow::Result<()> {
+    check_config_before_run(config)?;
+    let _lock = lock_config(config)?;
+
     println!("Starting batch operations...");
 
     if verbose {
@@ -107,6 +290,10 @@ fn run_batch_mode(config: &config::Config, verbose: bool, report_dir: &str) -> a
                 match op.operation_type {
                     config::OperationType::Copy => "Copy",
                     config::OperationType::Move => "Move",
+                    config::OperationType::Archive => "Archive",
+                    config::OperationType::Extract => "Extract",
+                    config::OperationType::Decrypt => "Decrypt",
+                    config::OperationType::Repository => "Repository",
                 }
             );
 
@@ -117,11 +304,11 @@ fn run_batch_mode(config: &config::Config, verbose: bool, report_dir: &str) -> a
                     let mut total_size = 0;
                     if let Ok(entries) = std::fs::read_dir(&op.origin) {
                         for entry in entries.flatten() {
-                            if let Ok(metadata) = entry.metadata() {
-                                if metadata.is_file() {
-                                    file_count += 1;
-                                    total_size += metadata.len();
-                                }
+                            if let Ok(metadata) = entry.metadata()
+                                && metadata.is_file()
+                            {
+                                file_count += 1;
+                                total_size += metadata.len();
                             }
                         }
                     }
@@ -162,24 +349,307 @@ fn run_batch_mode(config: &config::Config, verbose: bool, report_dir: &str) -> a
         println!();
     }
 
-    let results = file_ops::FileManager::execute_operations(&config.operations, &config.global_rate_limit, None);
+    let space_checks = preflight::check(
+        &config.operations,
+        &config.space_margin(),
+        chrono::Local::now(),
+    );
+    let short = space_checks.iter().filter(|c| c.is_short()).count();
+    if verbose || short > 0 {
+        println!("Free space on destination filesystems:");
+        for check in &space_checks {
+            let names: Vec<&str> = check
+                .operations
+                .iter()
+                .map(|&idx| config.operations[idx].name.as_str())
+                .collect();
+            if check.is_short() {
+                println!(
+                    "  ERROR: not enough space: {} ({})",
+                    check.describe(),
+                    names.join(", ")
+                );
+            } else if verbose {
+                println!("  OK: {} ({})", check.describe(), names.join(", "));
+            }
+        }
+        if short > 0 {
+            println!("  Operations on these filesystems will not be started.");
+        }
+        println!();
+    }
 
-    let summary_report = file_ops::FileManager::generate_report(&results);
-    println!("{}", summary_report);
+    let results = file_ops::FileManager::execute_operations(
+        &config.operations,
+        &rate_limiter::GlobalLimiter::new(&config.global_rate_limit),
+        &config.space_margin(),
+        None,
+    );
 
-    let report_path = PathBuf::from(report_dir);
-    if !report_path.exists() {
-        if let Err(e) = fs::create_dir_all(&report_path) {
-            println!(
-                "Warning: Could not create report directory '{}': {}",
-                report_dir, e
-            );
-            println!("Saving report to current directory instead.");
+    save_reports(&results, report_dir, true, true);
+    print_outcome(&results, verbose);
+
+    Ok(())
+}
+
+fn print_outcome(results: &[file_ops::OperationResult], verbose: bool) {
+    let successful = results.iter().filter(|r| r.success).count();
+    let total = results.len();
+
+    if successful == total {
+        println!("\n✓ All operations completed successfully!");
+    } else {
+        println!("\n⚠ {}/{} operations failed.", total - successful, total);
+        println!("Check the reports for detailed error information.");
+
+        println!("\nDetailed error information:");
+        for (i, result) in results.iter().enumerate().filter(|(_, r)| !r.success) {
+            println!("  Operation {}: {}", i + 1, result.operation_name);
+            if let Some(err) = &result.error_message {
+                println!("    Error: {}", err);
+
+                if verbose {
+                    println!("    Operation details:");
+                    for detail in &result.details {
+                        println!("      {}", detail);
+                    }
+                }
+            }
         }
     }
+}
+
+fn run_retry_mode(
+    config: &config::Config,
+    report_file: &str,
+    verbose: bool,
+    report_dir: &str,
+) -> anyhow::Result<()> {
+    check_config_before_run(config)?;
+    let _lock = lock_config(config)?;
 
-    match file_ops::FileManager::generate_detailed_report(&results, &report_path) {
-        Ok(detailed_report) => {
+    let previous = file_ops::FileManager::load_json_report(std::path::Path::new(report_file))?;
+    let failed_files: usize = previous
+        .iter()
+        .map(|r| r.file_list.iter().filter(|f| !f.success).count())
+        .sum();
+    let failed_operations = previous.iter().filter(|r| !r.success).count();
+    if failed_operations == 0 && failed_files == 0 {
+        println!("Nothing failed in {}; nothing to retry.", report_file);
+        return Ok(());
+    }
+    println!(
+        "Retrying {} failed file(s) from {} operation(s) in {}...",
+        failed_files, failed_operations, report_file
+    );
+
+    let results = file_ops::FileManager::retry_failed(
+        &previous,
+        &config.operations,
+        &rate_limiter::GlobalLimiter::new(&config.global_rate_limit),
+    );
+    save_reports(&results, report_dir, true, true);
+    print_outcome(&results, verbose);
+    Ok(())
+}
+
+fn run_restore_mode(
+    config: &config::Config,
+    name: &str,
+    snapshot: Option<&str>,
+    target: Option<&str>,
+    conflict: restore::Conflict,
+    verbose: bool,
+    report_dir: &str,
+) -> anyhow::Result<()> {
+    check_config_before_run(config)?;
+    let _lock = lock_config(config)?;
+    let Some(operation) = config.operations.iter().find(|op| op.name == name) else {
+        let names: Vec<&str> = config
+            .operations
+            .iter()
+            .map(|op| op.name.as_str())
+            .collect();
+        anyhow::bail!(
+            "no operation named '{}'; configured: {}",
+            name,
+            names.join(", ")
+        );
+    };
+    let target = target.map(std::path::Path::new);
+    println!(
+        "Restoring '{}' to {}...",
+        name,
+        target.unwrap_or(&operation.origin).display()
+    );
+
+    let result = file_ops::FileManager::restore(
+        operation,
+        snapshot,
+        target,
+        conflict,
+        &rate_limiter::GlobalLimiter::new(&config.global_rate_limit),
+    );
+    let results = [result];
+    // Reports stay out of the restored tree
+    save_reports(&results, report_dir, true, false);
+    print_outcome(&results, verbose);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn run_watch_mode(config: &config::Config, debounce: f64) -> anyhow::Result<()> {
+    check_config_before_run(config)?;
+    let _lock = lock_config(config)?;
+    let debounce = std::time::Duration::try_from_secs_f64(debounce)
+        .map_err(|_| anyhow::anyhow!("invalid --debounce value {}", debounce))?;
+    watcher::watch(
+        &config.operations,
+        rate_limiter::GlobalLimiter::new(&config.global_rate_limit),
+        debounce,
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run_watch_mode(_config: &config::Config, _debounce: f64) -> anyhow::Result<()> {
+    anyhow::bail!("watch mode uses inotify and is only available on Linux")
+}
+
+fn run_daemon_mode(config: &config::Config, report_dir: &str) -> anyhow::Result<()> {
+    use chrono::Local;
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    check_config_before_run(config)?;
+    let _lock = lock_config(config)?;
+
+    let mut jobs = Vec::new();
+    for op in &config.operations {
+        match &op.schedule {
+            Some(spec) => jobs.push((op.clone(), scheduler::Schedule::parse(spec)?)),
+            None => println!("Skipping '{}': it has no schedule", op.name),
+        }
+    }
+    if jobs.is_empty() {
+        anyhow::bail!("no operations have a schedule; nothing for the daemon to do");
+    }
+
+    let now = Local::now();
+    let mut next_runs: Vec<Option<chrono::DateTime<Local>>> = jobs
+        .iter()
+        .map(|(_, schedule)| schedule.next_run(None, now))
+        .collect();
+    println!("Daemon started with {} scheduled operations:", jobs.len());
+    for ((op, _), next) in jobs.iter().zip(&next_runs) {
+        match next {
+            Some(next) => println!(
+                "  {} ({}): next run {}",
+                op.name,
+                op.schedule.as_deref().unwrap_or_default(),
+                next.format("%Y-%m-%d %H:%M:%S")
+            ),
+            None => println!("  {}: schedule never fires", op.name),
+        }
+    }
+
+    // One bucket for every run, so overlapping operations share the limit
+    let global_limiter = rate_limiter::GlobalLimiter::new(&config.global_rate_limit);
+
+    // Names of operations with a run in progress; a due operation that is
+    // still running from last time is skipped rather than started twice.
+    let running: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+
+    loop {
+        let now = Local::now();
+        for ((op, schedule), next) in jobs.iter().zip(next_runs.iter_mut()) {
+            if !next.is_some_and(|next| next <= now) {
+                continue;
+            }
+            *next = schedule.next_run(Some(now), now);
+
+            let stamp = now.format("%Y-%m-%d %H:%M:%S");
+            if !running.lock().unwrap().insert(op.name.clone()) {
+                println!(
+                    "[{}] '{}' is still running; skipping this run",
+                    stamp, op.name
+                );
+                continue;
+            }
+            println!("[{}] Starting '{}'", stamp, op.name);
+
+            let op = op.clone();
+            let global_limiter = global_limiter.clone();
+            let free_space_margin = config.space_margin();
+            let report_dir = report_dir.to_string();
+            let running = Arc::clone(&running);
+            std::thread::spawn(move || {
+                let results = file_ops::FileManager::execute_operations(
+                    std::slice::from_ref(&op),
+                    &global_limiter,
+                    &free_space_margin,
+                    None,
+                );
+                let outcome = match results.first() {
+                    Some(result) if result.success => "succeeded".to_string(),
+                    Some(result) => format!(
+                        "failed: {}",
+                        result.error_message.as_deref().unwrap_or("unknown error")
+                    ),
+                    None => "produced no result".to_string(),
+                };
+                println!(
+                    "[{}] '{}' {}",
+                    Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    op.name,
+                    outcome
+                );
+                save_reports(&results, &report_dir, false, true);
+                running.lock().unwrap().remove(&op.name);
+            });
+        }
+
+        // Wake for the earliest due operation, but at least once a minute
+        let wait = next_runs
+            .iter()
+            .flatten()
+            .min()
+            .map(|next| (*next - Local::now()).to_std().unwrap_or(Duration::ZERO))
+            .unwrap_or(Duration::from_secs(60))
+            .clamp(Duration::from_millis(200), Duration::from_secs(60));
+        std::thread::sleep(wait);
+    }
+}
+
+// Writes the summary, detailed, per-destination and file list reports for
+// one run. `echo` also prints the summary and the start of the detailed
+// report, as batch mode does. Without `to_destinations` nothing is written
+// next to the operations' destinations.
+fn save_reports(
+    results: &[file_ops::OperationResult],
+    report_dir: &str,
+    echo: bool,
+    to_destinations: bool,
+) {
+    let summary_report = file_ops::FileManager::generate_report(results);
+    let stamp = file_ops::report_stamp();
+    if echo {
+        println!("{}", summary_report);
+    }
+
+    let report_path = PathBuf::from(report_dir);
+    if !report_path.exists()
+        && let Err(e) = fs::create_dir_all(&report_path)
+    {
+        println!(
+            "Warning: Could not create report directory '{}': {}",
+            report_dir, e
+        );
+        println!("Saving report to current directory instead.");
+    }
+
+    match file_ops::FileManager::generate_detailed_report(results, &report_path, &stamp) {
+        Ok(detailed_report) if echo => {
             let lines: Vec<&str> = detailed_report.lines().collect();
             let display_lines = lines.len().min(50);
             for line in lines.iter().take(display_lines) {
@@ -189,33 +659,41 @@ fn run_batch_mode(config: &config::Config, verbose: bool, report_dir: &str) -> a
                 println!("... (full report saved to file)");
             }
         }
+        Ok(_) => {}
         Err(e) => {
             println!("Warning: Could not generate detailed report: {}", e);
         }
     }
 
-    println!("\nSaving operation reports to destination folders:");
-    match file_ops::FileManager::save_operation_reports_to_destinations(&results) {
-        Ok(saved_paths) => {
-            for path in saved_paths {
-                println!("  {}", path);
+    if to_destinations {
+        println!("\nSaving operation reports to destination folders:");
+        match file_ops::FileManager::save_operation_reports_to_destinations(results, &stamp) {
+            Ok(saved_paths) => {
+                for path in saved_paths {
+                    println!("  {}", path);
+                }
+            }
+            Err(e) => {
+                println!("Warning: Could not save operation reports: {}", e);
             }
         }
-        Err(e) => {
-            println!("Warning: Could not save operation reports: {}", e);
-        }
-    }
 
-    println!("\nSaving file list reports:");
-    match file_ops::FileManager::save_file_list_reports(&results) {
-        Ok(saved_paths) => {
-            for path in saved_paths {
-                println!("  {}", path);
+        println!("\nSaving file list reports:");
+        match file_ops::FileManager::save_file_list_reports(results, &stamp) {
+            Ok(saved_paths) => {
+                for path in saved_paths {
+                    println!("  {}", path);
+                }
+            }
+            Err(e) => {
+                println!("Warning: Could not save file list reports: {}", e);
             }
         }
-        Err(e) => {
-            println!("Warning: Could not save file list reports: {}", e);
-        }
+    }
+
+    match file_ops::FileManager::save_json_report(results, &report_path, &stamp) {
+        Ok(path) => println!("\nJSON report saved to {}", path.display()),
+        Err(e) => println!("Warning: Could not save JSON report: {}", e),
     }
 
     let summary_filename = report_path.join("operation_summary.txt");
@@ -228,32 +706,70 @@ fn run_batch_mode(config: &config::Config, verbose: bool, report_dir: &str) -> a
     } else {
         println!("\nSummary report saved to {}", summary_filename.display());
     }
+}
 
-    let successful = results.iter().filter(|r| r.success).count();
-    let total = results.len();
-
-    if successful == total {
-        println!("\n✓ All operations completed successfully!");
-    } else {
-        println!("\n⚠ {}/{} operations failed.", total - successful, total);
-        println!("Check the reports for detailed error information.");
+fn run_convert(config: &config::Config, output: &str) -> anyhow::Result<()> {
+    let output = std::path::Path::new(output);
+    // Don't convert the built-in default that replaces an unreadable config
+    if config
+        .files
+        .first()
+        .is_none_or(|file| file.source.is_none())
+    {
+        anyhow::bail!("no config file was loaded, so there is nothing to convert");
+    }
+    if output.exists() {
+        anyhow::bail!(
+            "{} already exists; refusing to overwrite it",
+            output.display()
+        );
+    }
+    config.convert_to(output)?;
+    println!(
+        "Wrote {} as {:?}",
+        output.display(),
+        config::ConfigFormat::from_path(output)
+    );
+    if !config.include.is_empty() {
+        println!("Included files were left as they are; convert them separately if needed.");
+    }
+    Ok(())
+}
 
-        println!("\nDetailed error information:");
-        for (i, result) in results.iter().enumerate().filter(|(_, r)| !r.success) {
-            println!("  Operation {}: {}", i + 1, result.operation_name);
-            if let Some(err) = &result.error_message {
-                println!("    Error: {}", err);
+fn run_prune_dry_run(config: &config::Config) -> anyhow::Result<()> {
+    let mut any_policy = false;
+    for op in &config.operations {
+        let Some(policy) = &op.retention else {
+            continue;
+        };
+        any_policy = true;
+        println!("{}:", op.name);
+        if !policy.has_rules() {
+            println!("  retention has no keep rules, nothing to prune");
+            continue;
+        }
 
-                if verbose {
-                    println!("    Operation details:");
-                    for detail in &result.details {
-                        println!("      {}", detail);
-                    }
-                }
+        let layout = match retention::SnapshotLayout::from_template(&op.destination)? {
+            Some(layout) => layout,
+            None => {
+                println!("  destination has no date placeholder, nothing to prune");
+                continue;
             }
+        };
+        let snapshots = layout.list_snapshots()?;
+        if snapshots.is_empty() {
+            println!("  no snapshots under {}", layout.root.display());
+            continue;
+        }
+        let decisions = retention::plan(policy, snapshots, None);
+        for line in retention::apply(&decisions, true) {
+            println!("  {}", line);
         }
     }
 
+    if !any_policy {
+        println!("No operations have a retention policy.");
+    }
     Ok(())
 }
 
@@ -265,6 +781,9 @@ fn create_default_config() -> config::Config {
     let current_dir = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
 
     config::Config {
+        version: schema::CURRENT_VERSION,
+        variables: Default::default(),
+        include: Vec::new(),
         operations: vec![
             config::FileOperation {
                 name: "Example Copy".to_string(),
@@ -272,6 +791,7 @@ fn create_default_config() -> config::Config {
                 destination: current_dir.join("example_destination.txt"),
                 operation_type: config::OperationType::Copy,
                 rate_limit: config::RateLimit::default(),
+                ..Default::default()
             },
             config::FileOperation {
                 name: "Example Move".to_string(),
@@ -279,6 +799,7 @@ fn create_default_config() -> config::Config {
                 destination: current_dir.join("archive/example_moved.txt"),
                 operation_type: config::OperationType::Move,
                 rate_limit: config::RateLimit::default(),
+                ..Default::default()
             },
             config::FileOperation {
                 name: "Backup Documents".to_string(),
@@ -286,8 +807,48 @@ fn create_default_config() -> config::Config {
                 destination: current_dir.join("backup/documents"),
                 operation_type: config::OperationType::Copy,
                 rate_limit: config::RateLimit::default(),
+                ..Default::default()
             },
         ],
         global_rate_limit: config::RateLimit::default(),
+        free_space_margin: None,
+        files: Vec::new(),
+        fallback: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::fs;
+
+    #[test]
+    fn convert_refuses_the_fallback_config_and_existing_output() {
+        let dir = TempDir::new("convert");
+        let mut fallback = create_default_config();
+        fallback
+            .files
+            .push(config::ConfigFile::new(dir.join("broken.yaml")));
+        fallback.fallback = true;
+        let output = dir.join("out.toml");
+        let err = run_convert(&fallback, output.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("nothing to convert"), "{err}");
+        assert!(!output.exists());
+
+        let source = dir.join("config.yaml");
+        create_default_config()
+            .save_to_file(source.to_str().unwrap())
+            .unwrap();
+        let config = config::Config::load_from_files(&[&source]).unwrap();
+        run_convert(&config, output.to_str().unwrap()).unwrap();
+        assert!(
+            fs::read_to_string(&output)
+                .unwrap()
+                .contains("[[operations]]")
+        );
+
+        let err = run_convert(&config, output.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("refusing to overwrite"), "{err}");
     }
 }
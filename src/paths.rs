@@ -0,0 +1,366 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+use std::collections::BTreeMap;
+
+/// Expands `~`, `$VAR`, `${VAR}` and `${VAR:-default}` in a config path.
+///
+/// Names are looked up in the config's `variables:` map first and then in
+/// the process environment. `$$` produces a literal `$`. Referencing an
+/// undefined name without a default is an error, so a typo can't silently
+/// turn `/backups/${HOST}` into `/backups/`.
+pub fn expand_vars(input: &str, variables: &BTreeMap<String, String>) -> anyhow::Result<String> {
+    let input = expand_home(input)?;
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((_, c)) = chars.next() {
+        if c != '$' {
+            output.push(c);
+            continue;
+        }
+
+        match chars.peek().map(|(_, c)| *c) {
+            Some('$') => {
+                chars.next();
+                output.push('$');
+            }
+            Some('{') => {
+                chars.next();
+                let mut body = String::new();
+                let mut depth = 1;
+                for (_, c) in chars.by_ref() {
+                    match c {
+                        '{' => depth += 1,
+                        '}' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                    body.push(c);
+                }
+                if depth != 0 {
+                    anyhow::bail!("unterminated '${{' in '{}'", input);
+                }
+                let (name, default) = match body.split_once(":-") {
+                    Some((name, default)) => (name, Some(default)),
+                    None => (body.as_str(), None),
+                };
+                match (lookup(name, variables), default) {
+                    (Some(value), _) => output.push_str(&value),
+                    (None, Some(default)) => output.push_str(&expand_vars(default, variables)?),
+                    (None, None) => anyhow::bail!("undefined variable '{}' in '{}'", name, input),
+                }
+            }
+            Some(c) if c == '_' || c.is_ascii_alphabetic() => {
+                let mut name = String::new();
+                while let Some((_, c)) = chars.peek() {
+                    if *c == '_' || c.is_ascii_alphanumeric() {
+                        name.push(*c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                match lookup(&name, variables) {
+                    Some(value) => output.push_str(&value),
+                    None => anyhow::bail!("undefined variable '{}' in '{}'", name, input),
+                }
+            }
+            _ => output.push('$'),
+        }
+    }
+
+    Ok(output)
+}
+
+fn lookup(name: &str, variables: &BTreeMap<String, String>) -> Option<String> {
+    variables
+        .get(name)
+        .cloned()
+        .or_else(|| std::env::var(name).ok())
+}
+
+fn expand_home(input: &str) -> anyhow::Result<String> {
+    if input == "~" || input.starts_with("~/") {
+        let home =
+            home_dir().ok_or_else(|| anyhow::anyhow!("cannot expand '~': HOME is not set"))?;
+        Ok(format!("{}{}", home, &input[1..]))
+    } else {
+        Ok(input.to_string())
+    }
+}
+
+fn home_dir() -> Option<String> {
+    std::env::var("HOME")
+        .ok()
+        .or_else(|| std::env::var("USERPROFILE").ok())
+        .filter(|h| !h.is_empty())
+}
+
+/// Fills in destination templates at the moment an operation starts:
+/// `{date}` / `{date:FORMAT}`, `{time}` / `{time:FORMAT}` (chrono strftime
+/// syntax), `{hostname}` and `{user}`. `{{` and `}}` are literal braces.
+pub fn render_template(input: &str, now: DateTime<Local>) -> anyhow::Result<String> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(pos) = rest.find(['{', '}']) {
+        output.push_str(&rest[..pos]);
+        let brace = &rest[pos..];
+
+        if brace.starts_with("{{") || brace.starts_with("}}") {
+            output.push_str(&brace[..1]);
+            rest = &brace[2..];
+            continue;
+        }
+        if brace.starts_with('}') {
+            anyhow::bail!("unmatched '}}' in '{}'", input);
+        }
+
+        let end = brace
+            .find('}')
+            .ok_or_else(|| anyhow::anyhow!("unterminated '{{' in '{}'", input))?;
+        let placeholder = &brace[1..end];
+        let (name, format) = match placeholder.split_once(':') {
+            Some((name, format)) => (name, Some(format)),
+            None => (placeholder, None),
+        };
+
+        let value = match name {
+            "date" => format_time(now, format.unwrap_or("%Y-%m-%d"))?,
+            "time" => format_time(now, format.unwrap_or("%H-%M-%S"))?,
+            "hostname" => whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string()),
+            "user" => whoami::username(),
+            other => anyhow::bail!("unknown placeholder '{{{}}}' in '{}'", other, input),
+        };
+        output.push_str(&value);
+        rest = &brace[end + 1..];
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Doubles braces so a literal path survives `render_template` unchanged.
+pub fn escape_template(input: &str) -> String {
+    input.replace('{', "{{").replace('}', "}}")
+}
+
+/// Whether `input` needs `render_template`. Any brace counts, so a literal
+/// brace in a directory name must be written doubled.
+pub fn is_template(input: &str) -> bool {
+    input.contains('{') || input.contains('}')
+}
+
+fn format_time(now: DateTime<Local>, format: &str) -> anyhow::Result<String> {
+    let items = format_items(format)?;
+    Ok(now.format_with_items(items.into_iter()).to_string())
+}
+
+fn format_items(format: &str) -> anyhow::Result<Vec<Item<'_>>> {
+    // An invalid specifier would make chrono's Display impl panic
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        anyhow::bail!("invalid date format '{}'", format);
+    }
+    Ok(items)
+}
+
+/// Turns one path component of a destination template into a strftime
+/// pattern that matches the names it renders to, e.g. `db-{date:%Y%m%d}`
+/// becomes `db-%Y%m%d`. Host and user placeholders become their current
+/// literal values. Returns `None` when the component has no date or time.
+pub fn template_to_strftime(component: &str) -> anyhow::Result<Option<String>> {
+    let mut pattern = String::new();
+    let mut has_time = false;
+    let mut rest = component;
+
+    while let Some(pos) = rest.find(['{', '}']) {
+        pattern.push_str(&rest[..pos].replace('%', "%%"));
+        let brace = &rest[pos..];
+        if brace.starts_with("{{") || brace.starts_with("}}") {
+            pattern.push_str(&brace[..1]);
+            rest = &brace[2..];
+            continue;
+        }
+        if brace.starts_with('}') {
+            anyhow::bail!("unmatched '}}' in '{}'", component);
+        }
+        let end = brace
+            .find('}')
+            .ok_or_else(|| anyhow::anyhow!("unterminated '{{' in '{}'", component))?;
+        let placeholder = &brace[1..end];
+        let (name, format) = match placeholder.split_once(':') {
+            Some((name, format)) => (name, Some(format)),
+            None => (placeholder, None),
+        };
+        match name {
+            "date" | "time" => {
+                let default = if name == "date" {
+                    "%Y-%m-%d"
+                } else {
+                    "%H-%M-%S"
+                };
+                let format = format.unwrap_or(default);
+                format_items(format)?;
+                has_time = true;
+                pattern.push_str(format);
+            }
+            "hostname" | "user" => {
+                let value = render_template(&format!("{{{}}}", name), Local::now())?;
+                pattern.push_str(&value.replace('%', "%%"));
+            }
+            other => anyhow::bail!("unknown placeholder '{{{}}}' in '{}'", other, component),
+        }
+        rest = &brace[end + 1..];
+    }
+    pattern.push_str(&rest.replace('%', "%%"));
+
+    Ok(has_time.then_some(pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn expand_vars_substitutes_both_forms() {
+        let variables = vars(&[("ROOT", "/srv"), ("HOST", "db1")]);
+        assert_eq!(
+            expand_vars("$ROOT/${HOST}_x/$HOST.d", &variables).unwrap(),
+            "/srv/db1_x/db1.d"
+        );
+        // A name ends at the first character that can't be part of one
+        assert_eq!(expand_vars("$ROOT-1", &variables).unwrap(), "/srv-1");
+    }
+
+    #[test]
+    fn expand_vars_keeps_escaped_and_stray_dollars() {
+        let variables = vars(&[("A", "x")]);
+        assert_eq!(expand_vars("cost$$A", &variables).unwrap(), "cost$A");
+        assert_eq!(expand_vars("$$$A", &variables).unwrap(), "$x");
+        assert_eq!(expand_vars("$5 and $", &variables).unwrap(), "$5 and $");
+    }
+
+    #[test]
+    fn expand_vars_defaults_nest() {
+        let variables = vars(&[("B", "b")]);
+        assert_eq!(expand_vars("${A:-${B}}", &variables).unwrap(), "b");
+        assert_eq!(expand_vars("${A:-${C:-c}/d}", &variables).unwrap(), "c/d");
+        assert_eq!(expand_vars("${B:-${C}}", &variables).unwrap(), "b");
+        assert_eq!(expand_vars("${A:-}", &variables).unwrap(), "");
+    }
+
+    #[test]
+    fn expand_vars_rejects_undefined_and_unterminated() {
+        let variables = vars(&[]);
+        for input in [
+            "$RUSTY_BUCKET_UNDEFINED",
+            "${RUSTY_BUCKET_UNDEFINED}",
+            "${A:-${RUSTY_BUCKET_UNDEFINED}}",
+            "${A",
+            "${A:-${B}",
+        ] {
+            assert!(
+                expand_vars(input, &variables).is_err(),
+                "{input:?} was accepted"
+            );
+        }
+    }
+
+    fn noon() -> DateTime<Local> {
+        use chrono::TimeZone;
+        Local
+            .with_ymd_and_hms(2026, 3, 1, 12, 30, 5)
+            .single()
+            .unwrap()
+    }
+
+    #[test]
+    fn render_template_fills_in_dates_and_times() {
+        assert_eq!(
+            render_template("/b/{date}/{time}", noon()).unwrap(),
+            "/b/2026-03-01/12-30-05"
+        );
+        assert_eq!(
+            render_template("/b/{date:%Y%m%d}-{time:%H%M}", noon()).unwrap(),
+            "/b/20260301-1230"
+        );
+        assert_eq!(
+            render_template("/b/{user}", noon()).unwrap(),
+            format!("/b/{}", whoami::username())
+        );
+    }
+
+    #[test]
+    fn render_template_doubled_braces_are_literal() {
+        assert_eq!(
+            render_template("/b/{{x}}/{{{date:%Y}}}", noon()).unwrap(),
+            "/b/{x}/{2026}"
+        );
+        let literal = "/b/{weird}}name";
+        assert!(is_template(literal));
+        assert_eq!(
+            render_template(&escape_template(literal), noon()).unwrap(),
+            literal
+        );
+    }
+
+    #[test]
+    fn render_template_rejects_bad_placeholders() {
+        for input in [
+            "/b/{host}",
+            "/b/{date",
+            "/b/date}",
+            "/b/{date:%Q}",
+            "/b/{time:%}",
+        ] {
+            assert!(
+                render_template(input, noon()).is_err(),
+                "{input:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn template_to_strftime_matches_what_render_writes() {
+        assert_eq!(template_to_strftime("plain").unwrap(), None);
+        assert_eq!(
+            template_to_strftime("db-{date:%Y%m%d}").unwrap().as_deref(),
+            Some("db-%Y%m%d")
+        );
+        assert_eq!(
+            template_to_strftime("{date}_{time}").unwrap().as_deref(),
+            Some("%Y-%m-%d_%H-%M-%S")
+        );
+        // Literal text is escaped for strftime
+        assert_eq!(
+            template_to_strftime("100%-{{{date:%Y}}}")
+                .unwrap()
+                .as_deref(),
+            Some("100%%-{%Y}")
+        );
+        for component in ["{date:%Q}", "x}", "{date", "{nope}"] {
+            assert!(
+                template_to_strftime(component).is_err(),
+                "{component:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn config_variables_win_over_the_environment() {
+        let variables = vars(&[("PATH", "/mine")]);
+        assert_eq!(expand_vars("$PATH", &variables).unwrap(), "/mine");
+    }
+}
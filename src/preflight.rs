@@ -0,0 +1,283 @@
+use crate::config::{FileOperation, OperationType, format_size, parse_rate};
+use crate::file_ops::FileManager;
+use crate::paths;
+use chrono::{DateTime, Local};
+use std::path::{Path, PathBuf};
+
+/// Space to leave free on a destination filesystem after a run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Margin {
+    Bytes(u64),
+    // Percentage of the filesystem's total size
+    Percent(f64),
+}
+
+impl Default for Margin {
+    fn default() -> Self {
+        Margin::Bytes(0)
+    }
+}
+
+impl Margin {
+    /// Accepts a size such as `512MiB` or `2GB`, or a percentage such as
+    /// `5%`. No value means no margin.
+    pub fn parse(input: Option<&str>) -> anyhow::Result<Self> {
+        let Some(input) = input.map(str::trim) else {
+            return Ok(Margin::default());
+        };
+        if let Some(percent) = input.strip_suffix('%') {
+            let percent: f64 = percent
+                .trim()
+                .parse()
+                .map_err(|_| anyhow::anyhow!("'{}' is not a percentage", input))?;
+            if !(0.0..100.0).contains(&percent) {
+                anyhow::bail!("free space margin '{}' must be between 0% and 100%", input);
+            }
+            return Ok(Margin::Percent(percent));
+        }
+        if input.parse::<f64>() == Ok(0.0) {
+            return Ok(Margin::Bytes(0));
+        }
+        // A size is a rate over one second as far as parse_rate is concerned
+        let bytes = parse_rate(input, 1, 1)
+            .map_err(|_| anyhow::anyhow!("'{}' is not a size (e.g. 2GiB) or percentage", input))?;
+        Ok(Margin::Bytes(bytes))
+    }
+
+    fn bytes(&self, filesystem_size: u64) -> u64 {
+        match self {
+            Margin::Bytes(bytes) => *bytes,
+            Margin::Percent(percent) => (filesystem_size as f64 * percent / 100.0) as u64,
+        }
+    }
+}
+
+/// Planned copies that land on one destination filesystem.
+#[derive(Debug, Clone)]
+pub struct SpaceCheck {
+    // Existing directory on the filesystem that was measured
+    pub path: PathBuf,
+    // Indices into the checked operations
+    pub operations: Vec<usize>,
+    pub required: u64,
+    pub reserve: u64,
+    pub available: u64,
+}
+
+impl SpaceCheck {
+    pub fn is_short(&self) -> bool {
+        self.required
+            .checked_add(self.reserve)
+            .is_none_or(|needed| needed > self.available)
+    }
+
+    pub fn describe(&self) -> String {
+        let mut text = format!(
+            "{} needs {}",
+            self.path.display(),
+            format_size(self.required)
+        );
+        if self.reserve > 0 {
+            text.push_str(&format!(" plus a {} margin", format_size(self.reserve)));
+        }
+        text.push_str(&format!(", {} available", format_size(self.available)));
+        text
+    }
+}
+
+/// Sums the bytes every copy in `operations` will write, per destination
+/// filesystem, and measures the space left on each. An archive counts at
+/// its uncompressed size. Moves are renames and need no extra space, so
+/// they are left out, as are extracts. Filesystems that can't be
+/// measured are left out too.
+pub fn check(
+    operations: &[FileOperation],
+    margin: &Margin,
+    now: DateTime<Local>,
+) -> Vec<SpaceCheck> {
+    let mut checks: Vec<(u64, SpaceCheck)> = Vec::new();
+    for (idx, op) in operations.iter().enumerate() {
+        if !matches!(
+            op.operation_type,
+            OperationType::Copy | OperationType::Archive
+        ) {
+            continue;
+        }
+        let template = op.destination.to_string_lossy();
+        let destination = if paths::is_template(&template) {
+            match paths::render_template(&template, now) {
+                Ok(destination) => PathBuf::from(destination),
+                Err(_) => continue,
+            }
+        } else {
+            op.destination.clone()
+        };
+        let Some(probe) = nearest_existing(&destination) else {
+            continue;
+        };
+        let Some((device, available, total)) = filesystem_space(&probe) else {
+            continue;
+        };
+
+        let bytes = FileManager::planned_copy_bytes(&op.origin, &destination);
+        match checks.iter_mut().find(|(dev, _)| *dev == device) {
+            Some((_, check)) => {
+                check.operations.push(idx);
+                check.required += bytes;
+            }
+            None => checks.push((
+                device,
+                SpaceCheck {
+                    path: probe,
+                    operations: vec![idx],
+                    required: bytes,
+                    reserve: margin.bytes(total),
+                    available,
+                },
+            )),
+        }
+    }
+    checks.into_iter().map(|(_, check)| check).collect()
+}
+
+fn nearest_existing(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .map(|p| {
+            if p.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                p
+            }
+        })
+        .find(|p| p.exists())
+        .map(Path::to_path_buf)
+}
+
+// Device id, bytes available to unprivileged users, and total size
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // statvfs field widths differ between platforms
+fn filesystem_space(path: &Path) -> Option<(u64, u64, u64)> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let device = std::fs::metadata(path).ok()?.dev();
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: statvfs is plain old data, and c_path is a valid NUL-terminated
+    // string for the duration of the call
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    let block = stats.f_frsize as u64;
+    Some((
+        device,
+        (stats.f_bavail as u64).saturating_mul(block),
+        (stats.f_blocks as u64).saturating_mul(block),
+    ))
+}
+
+#[cfg(not(unix))]
+fn filesystem_space(_path: &Path) -> Option<(u64, u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use chrono::TimeZone;
+    use std::fs;
+
+    fn check_of(required: u64, reserve: u64, available: u64) -> SpaceCheck {
+        SpaceCheck {
+            path: PathBuf::from("/backup"),
+            operations: vec![0],
+            required,
+            reserve,
+            available,
+        }
+    }
+
+    fn copy(name: &str, origin: &Path, destination: &Path) -> FileOperation {
+        FileOperation {
+            name: name.to_string(),
+            origin: origin.to_path_buf(),
+            destination: destination.to_path_buf(),
+            operation_type: OperationType::Copy,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn margin_reads_sizes_and_percentages() {
+        assert_eq!(Margin::parse(None).unwrap(), Margin::Bytes(0));
+        assert_eq!(Margin::parse(Some("0")).unwrap(), Margin::Bytes(0));
+        assert_eq!(Margin::parse(Some("2GiB")).unwrap(), Margin::Bytes(2 << 30));
+        assert_eq!(Margin::parse(Some(" 5 %")).unwrap(), Margin::Percent(5.0));
+        for bad in ["100%", "-1%", "lots%", "plenty"] {
+            assert!(Margin::parse(Some(bad)).is_err(), "{bad:?} was accepted");
+        }
+        assert_eq!(Margin::Percent(5.0).bytes(1000), 50);
+        assert_eq!(Margin::Bytes(10).bytes(1000), 10);
+    }
+
+    #[test]
+    fn short_when_the_copy_and_margin_exceed_free_space() {
+        assert!(!check_of(100, 0, 100).is_short());
+        assert!(check_of(101, 0, 100).is_short());
+        assert!(!check_of(60, 40, 100).is_short());
+        assert!(check_of(60, 41, 100).is_short());
+        assert!(check_of(u64::MAX, u64::MAX, u64::MAX).is_short());
+        assert_eq!(
+            check_of(2048, 1024, 4096).describe(),
+            format!(
+                "/backup needs {} plus a {} margin, {} available",
+                format_size(2048),
+                format_size(1024),
+                format_size(4096)
+            )
+        );
+    }
+
+    #[test]
+    fn copies_to_one_filesystem_are_summed() {
+        let dir = TempDir::new("preflight");
+        let origin = dir.join("origin");
+        fs::create_dir(&origin).unwrap();
+        fs::write(origin.join("a.txt"), vec![0u8; 1000]).unwrap();
+        fs::write(origin.join("b.txt"), vec![0u8; 500]).unwrap();
+        let single = dir.join("single.txt");
+        fs::write(&single, vec![0u8; 300]).unwrap();
+        // Half of b.txt is already at the second destination
+        let partial = dir.join("partial");
+        fs::create_dir(&partial).unwrap();
+        fs::write(partial.join("b.txt"), vec![0u8; 250]).unwrap();
+
+        let operations = [
+            copy("tree", &origin, &dir.join("backup/{date}")),
+            FileOperation {
+                operation_type: OperationType::Move,
+                ..copy("moved", &origin, &dir.join("moved"))
+            },
+            copy("partial", &origin, &partial),
+            copy("single", &single, &dir.join("single_copy.txt")),
+        ];
+        let now = Local.with_ymd_and_hms(2026, 3, 10, 12, 0, 0).unwrap();
+        let checks = check(&operations, &Margin::Bytes(123), now);
+
+        assert_eq!(checks.len(), 1);
+        let check = &checks[0];
+        assert_eq!(check.operations, [0, 2, 3]);
+        assert_eq!(check.required, 1500 + 1250 + 300);
+        assert_eq!(check.reserve, 123);
+        // Measured at the nearest directory that exists
+        assert_eq!(check.path, dir.to_path_buf());
+    }
+
+    #[test]
+    fn unrenderable_templates_are_left_out() {
+        let dir = TempDir::new("preflight");
+        let operations = [copy("bad", &dir, &dir.join("backup/{nonsense}"))];
+        assert!(check(&operations, &Margin::default(), Local::now()).is_empty());
+    }
+}
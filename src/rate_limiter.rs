@@ -1,105 +1,987 @@
+use crate::config::{AdaptiveThrottle, RateLimit, format_rate};
+use chrono::{DateTime, Local};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
+// How often a scheduled limiter checks whether a new window has started
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Burst size when a limit doesn't set `burst_bytes`: one copy chunk
+const DEFAULT_BURST: u64 = 64 * 1024;
+
+// Bytes an operation of weight 1 reserves from the global bucket at a time.
+// Concurrent operations take turns, so an operation of weight 3 reserving
+// three times as much gets three times the share.
+const GLOBAL_QUANTUM: u64 = 64 * 1024;
+
+// How often adaptive throttling samples the load and may adjust the rate
+const ADAPTIVE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Where limiters get the time from, so tests can drive it by hand.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    // Wall-clock time, for time-of-day schedules
+    fn local_time(&self) -> DateTime<Local>;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn local_time(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Token bucket: tokens (bytes) accrue at `rate` per second up to `burst`.
+/// Sending takes tokens and may run the bucket into debt, which the sender
+/// pays off by waiting, so a chunk larger than the burst still goes through
+/// and the long-run rate stays exact. Over any window of length T at most
+/// `burst + rate * T` bytes pass.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    // Bytes per second; 0 means unlimited
+    rate: u64,
+    burst: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A bucket that starts full.
+    pub fn new(rate: u64, burst: u64, now: Instant) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst as f64,
+            updated: now,
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.updated = self.updated.max(now);
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+    }
+
+    /// Changes the rate from `now` on. Tokens earned so far are kept.
+    pub fn set_rate(&mut self, rate: u64, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+    }
+
+    /// Takes `bytes` and returns how long to wait before sending them.
+    pub fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+fn burst_of(limit: &RateLimit) -> u64 {
+    limit.burst_bytes.unwrap_or(DEFAULT_BURST)
+}
+
+// Buckets for the per-file and metadata limits. A burst of one spaces the
+// operations evenly instead of letting a batch through at once.
+fn count_buckets(limit: &RateLimit, now: Instant) -> (TokenBucket, TokenBucket) {
+    let bucket = |rate: Option<u64>| TokenBucket::new(rate.unwrap_or(0), 1, now);
+    (
+        bucket(limit.files_per_second),
+        bucket(limit.metadata_ops_per_second),
+    )
+}
+
+/// One-minute load average, `None` where it can't be read.
+#[cfg(unix)]
+pub fn load_average() -> Option<f64> {
+    let mut loads = [0.0f64; 1];
+    // SAFETY: the buffer holds the one sample asked for
+    let read = unsafe { libc::getloadavg(loads.as_mut_ptr(), 1) };
+    (read == 1).then_some(loads[0])
+}
+
+#[cfg(not(unix))]
+pub fn load_average() -> Option<f64> {
+    None
+}
+
+// Adaptive throttling state. While the load or write latency is over its
+// threshold the rate halves at each check, down to the floor; once both
+// are back under it grows by a quarter per check until it reaches the
+// ceiling again, at which point the cap is lifted.
+struct Adaptive {
+    settings: AdaptiveThrottle,
+    floor: u64,
+    // Cap in force while backed off
+    rate: Option<u64>,
+    // Rate to return to: the configured one, or the throughput measured
+    // when backing off from unlimited
+    ceiling: u64,
+    checked: Instant,
+    bytes_at_check: u64,
+    write_time: Duration,
+    writes: u32,
+    load_average: fn() -> Option<f64>,
+}
+
+impl Adaptive {
+    fn new(settings: &AdaptiveThrottle, now: Instant) -> Self {
+        Self {
+            floor: settings.min_bytes_per_second().unwrap_or(0).max(1),
+            settings: settings.clone(),
+            rate: None,
+            ceiling: 0,
+            checked: now,
+            bytes_at_check: 0,
+            write_time: Duration::ZERO,
+            writes: 0,
+            load_average,
+        }
+    }
+
+    // Why the system counts as busy right now, if it does
+    fn overload(&self, load: Option<f64>, latency: Option<Duration>) -> Option<String> {
+        if let (Some(max), Some(load)) = (self.settings.max_load_average, load)
+            && load > max
+        {
+            return Some(format!("load average {:.2} above {:.2}", load, max));
+        }
+        if let (Some(max), Some(latency)) = (self.settings.max_write_latency_ms, latency)
+            && latency > Duration::from_millis(max)
+        {
+            return Some(format!(
+                "write latency {} ms above {} ms",
+                latency.as_millis(),
+                max
+            ));
+        }
+        None
+    }
+
+    // Samples the load once per interval and returns a note when the cap
+    // changes. `configured` is the rate the limit sets right now (0 when
+    // unlimited) and `total_bytes` what the operation has sent so far.
+    fn check(&mut self, now: Instant, configured: u64, total_bytes: u64) -> Option<String> {
+        let elapsed = now.saturating_duration_since(self.checked);
+        if elapsed < ADAPTIVE_CHECK_INTERVAL {
+            return None;
+        }
+        let sent = total_bytes - self.bytes_at_check;
+        let latency = (self.writes > 0).then(|| self.write_time / self.writes);
+        let load = (self.load_average)();
+        self.checked = now;
+        self.bytes_at_check = total_bytes;
+        self.write_time = Duration::ZERO;
+        self.writes = 0;
+
+        if let Some(reason) = self.overload(load, latency) {
+            let current = match self.rate {
+                Some(rate) => rate,
+                None if configured > 0 => configured,
+                None => (sent as f64 / elapsed.as_secs_f64()) as u64,
+            };
+            if self.rate.is_none() {
+                self.ceiling = if configured > 0 { configured } else { current };
+            }
+            let lowered = (current / 2).max(self.floor);
+            if self.rate.is_some_and(|rate| rate <= lowered) {
+                return None;
+            }
+            self.rate = Some(lowered);
+            return Some(format!(
+                "backed off to {} ({})",
+                format_rate(lowered),
+                reason
+            ));
+        }
+
+        let rate = self.rate?;
+        if configured > 0 {
+            self.ceiling = configured;
+        }
+        let raised = rate + rate.div_ceil(4);
+        if raised >= self.ceiling {
+            self.rate = None;
+            let restored = if configured > 0 {
+                format_rate(configured)
+            } else {
+                "unlimited".to_string()
+            };
+            return Some(format!("load back to normal, restored {}", restored));
+        }
+        self.rate = Some(raised);
+        Some(format!("sped up to {}", format_rate(raised)))
+    }
+}
+
+/// The global rate limit as one token bucket that every running copy draws
+/// from, so parallel operations together stay within it. Clones share the
+/// same bucket.
+#[derive(Clone, Default)]
+pub struct GlobalLimiter {
+    bucket: Option<Arc<Mutex<SharedBucket>>>,
+}
+
+struct SharedBucket {
+    limit: RateLimit,
+    bucket: TokenBucket,
+    files: TokenBucket,
+    metadata: TokenBucket,
+    clock: Arc<dyn Clock>,
+    schedule_checked: Instant,
+}
+
+impl SharedBucket {
+    fn refresh(&mut self) {
+        let now = self.clock.now();
+        if now.saturating_duration_since(self.schedule_checked) >= SCHEDULE_CHECK_INTERVAL {
+            self.schedule_checked = now;
+            let rate = self.limit.rate_at(self.clock.local_time()).unwrap_or(0);
+            self.bucket.set_rate(rate, now);
+        }
+    }
+}
+
+impl GlobalLimiter {
+    pub fn new(limit: &RateLimit) -> Self {
+        Self::with_clock(limit, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(limit: &RateLimit, clock: Arc<dyn Clock>) -> Self {
+        if !limit.is_active() {
+            return Self::default();
+        }
+        let now = clock.now();
+        let rate = limit.rate_at(clock.local_time()).unwrap_or(0);
+        let (files, metadata) = count_buckets(limit, now);
+        let bucket = SharedBucket {
+            limit: limit.clone(),
+            bucket: TokenBucket::new(rate, burst_of(limit), now),
+            files,
+            metadata,
+            clock,
+            schedule_checked: now,
+        };
+        Self {
+            bucket: Some(Arc::new(Mutex::new(bucket))),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.bucket.is_some()
+    }
+
+    /// The global limit in force right now, `None` when unthrottled.
+    pub fn current_limit(&self) -> Option<u64> {
+        let mut shared = self.bucket.as_ref()?.lock().unwrap();
+        shared.refresh();
+        Some(shared.bucket.rate()).filter(|&bps| bps > 0)
+    }
+
+    pub fn describe(&self) -> Option<String> {
+        let shared = self.bucket.as_ref()?.lock().unwrap();
+        Some(shared.limit.describe())
+    }
+
+    // Takes `bytes` from the shared bucket and returns how long the caller
+    // must wait before sending them. Each reservation queues up behind the
+    // debt earlier ones left, which is what keeps the total at the limit.
+    fn reserve(&self, bytes: u64) -> Duration {
+        let Some(shared) = &self.bucket else {
+            return Duration::ZERO;
+        };
+        let mut shared = shared.lock().unwrap();
+        shared.refresh();
+        let now = shared.clock.now();
+        shared.bucket.take(bytes, now)
+    }
+
+    // Like `reserve`, for files started and metadata operations
+    fn reserve_operations(&self, files: u64, metadata: u64) -> Duration {
+        let Some(shared) = &self.bucket else {
+            return Duration::ZERO;
+        };
+        let mut shared = shared.lock().unwrap();
+        let now = shared.clock.now();
+        let files = shared.files.take(files, now);
+        files.max(shared.metadata.take(metadata, now))
+    }
+
+    fn limits_bytes(&self) -> bool {
+        self.bucket
+            .as_ref()
+            .is_some_and(|shared| shared.lock().unwrap().limit.limits_bytes())
+    }
+}
+
+/// Paces one operation's copies: its own limit (following any schedule)
+/// and a weighted share of the global bucket, whichever is tighter.
 pub struct RateLimiter {
-    enabled: bool,
-    bytes_per_second: u64,
-    window_start: Instant,
-    bytes_transferred: u64,
+    clock: Arc<dyn Clock>,
+    // The operation's own limit, when it has one
+    limit: Option<RateLimit>,
+    bucket: TokenBucket,
+    files: TokenBucket,
+    metadata: TokenBucket,
+    schedule_checked: Instant,
+    global: GlobalLimiter,
+    weight: u32,
+    adaptive: Option<Adaptive>,
+    // Adaptive adjustments not yet collected by `take_adjustments`
+    adjustments: Vec<String>,
+    // Bytes already reserved from the global bucket but not yet sent
+    global_credit: u64,
+    // Limit last shown in progress output
+    reported_limit: Option<u64>,
+    started: Option<Instant>,
     total_bytes_transferred: u64,
 }
 
 impl RateLimiter {
-    pub fn new(bytes_per_second: Option<u64>, megabytes_per_minute: Option<u64>) -> Self {
-        let (enabled, bytes_per_second) = match (bytes_per_second, megabytes_per_minute) {
-            (Some(bps), _) => (true, bps),
-            (_, Some(mb_per_min)) => (true, mb_per_min * 1024 * 1024 / 60),
-            (None, None) => (false, 0),
-        };
+    pub fn for_operation(limit: &RateLimit, global: &GlobalLimiter, weight: u32) -> Self {
+        Self::with_clock(limit, global, weight, Arc::new(SystemClock))
+    }
 
-        Self {
-            enabled,
-            bytes_per_second,
-            window_start: Instant::now(),
-            bytes_transferred: 0,
+    pub fn with_clock(
+        limit: &RateLimit,
+        global: &GlobalLimiter,
+        weight: u32,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let now = clock.now();
+        let limit = limit.is_active().then(|| limit.clone());
+        let rate = limit
+            .as_ref()
+            .and_then(|limit| limit.rate_at(clock.local_time()))
+            .unwrap_or(0);
+        let burst = limit.as_ref().map_or(DEFAULT_BURST, burst_of);
+        let adaptive = limit
+            .as_ref()
+            .and_then(|limit| limit.adaptive.as_ref())
+            .map(|settings| Adaptive::new(settings, now));
+        let (files, metadata) = count_buckets(limit.as_ref().unwrap_or(&RateLimit::default()), now);
+        let mut limiter = Self {
+            bucket: TokenBucket::new(rate, burst, now),
+            files,
+            metadata,
+            clock,
+            limit,
+            schedule_checked: now,
+            global: global.clone(),
+            weight: weight.max(1),
+            adaptive,
+            adjustments: Vec::new(),
+            global_credit: 0,
+            reported_limit: None,
+            started: None,
             total_bytes_transferred: 0,
+        };
+        limiter.reported_limit = limiter.get_rate_limit();
+        limiter
+    }
+
+    /// Re-reads the schedules at most once per check interval. Returns true
+    /// when the limit in force changed.
+    pub fn refresh_schedule(&mut self) -> bool {
+        let now = self.clock.now();
+        if now.saturating_duration_since(self.schedule_checked) < SCHEDULE_CHECK_INTERVAL {
+            return false;
         }
+        self.schedule_checked = now;
+        self.apply_own_rate(now);
+        let limit = self.get_rate_limit();
+        let changed = limit != self.reported_limit;
+        self.reported_limit = limit;
+        changed
+    }
+
+    // The operation's configured rate right now, 0 when unlimited
+    fn configured_rate(&self) -> u64 {
+        self.limit
+            .as_ref()
+            .and_then(|limit| limit.rate_at(self.clock.local_time()))
+            .unwrap_or(0)
     }
 
+    // Sets the bucket to the configured rate, capped by adaptive throttling
+    fn apply_own_rate(&mut self, now: Instant) {
+        if self.limit.is_none() {
+            return;
+        }
+        let configured = self.configured_rate();
+        let rate = match self.adaptive.as_ref().and_then(|adaptive| adaptive.rate) {
+            Some(cap) if configured > 0 => cap.min(configured),
+            Some(cap) => cap,
+            None => configured,
+        };
+        self.bucket.set_rate(rate, now);
+    }
+
+    /// Records how long writing one chunk to the destination took, for
+    /// adaptive throttling.
+    pub fn record_write(&mut self, elapsed: Duration) {
+        if let Some(adaptive) = &mut self.adaptive {
+            adaptive.write_time += elapsed;
+            adaptive.writes += 1;
+        }
+    }
+
+    /// Lets adaptive throttling adjust the rate, at most once per check
+    /// interval. Returns a note describing the change, which is also kept
+    /// for `take_adjustments`.
+    pub fn adapt(&mut self) -> Option<String> {
+        let now = self.clock.now();
+        let configured = self.configured_rate();
+        let note = self
+            .adaptive
+            .as_mut()?
+            .check(now, configured, self.total_bytes_transferred)?;
+        self.apply_own_rate(now);
+        self.reported_limit = self.get_rate_limit();
+        self.adjustments.push(note.clone());
+        Some(note)
+    }
+
+    /// Adaptive adjustments made since the last call.
+    pub fn take_adjustments(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.adjustments)
+    }
+
+    /// Whether copies should be paced at all. A scheduled limit counts even
+    /// while its current window is unlimited, since the next one may not be.
     pub fn is_enabled(&self) -> bool {
-        self.enabled
+        self.limit.is_some() || self.global.is_enabled()
     }
 
+    /// Whether the byte rate is limited, so copies must go chunk by chunk.
+    pub fn limits_bytes(&self) -> bool {
+        self.limit.as_ref().is_some_and(RateLimit::limits_bytes) || self.global.limits_bytes()
+    }
+
+    /// The tighter of this operation's limit and the global one, right now.
     pub fn get_rate_limit(&self) -> Option<u64> {
-        if self.enabled {
-            Some(self.bytes_per_second)
-        } else {
-            None
+        let own = Some(self.bucket.rate()).filter(|&bps| bps > 0);
+        match (own, self.global.current_limit()) {
+            (Some(own), Some(global)) => Some(own.min(global)),
+            (own, global) => own.or(global),
         }
     }
 
+    /// The limit in force, for progress messages.
+    pub fn limit_text(&self) -> String {
+        self.get_rate_limit()
+            .map(format_rate)
+            .unwrap_or_else(|| "unlimited".to_string())
+    }
+
+    /// Average rate since the first chunk, in bytes per second.
     pub fn get_current_rate(&self) -> f64 {
-        let elapsed = self.window_start.elapsed();
-        if elapsed.as_secs_f64() > 0.0 {
-            self.bytes_transferred as f64 / elapsed.as_secs_f64()
+        let Some(started) = self.started else {
+            return 0.0;
+        };
+        let elapsed = self
+            .clock
+            .now()
+            .saturating_duration_since(started)
+            .as_secs_f64();
+        if elapsed > 0.0 {
+            self.total_bytes_transferred as f64 / elapsed
         } else {
             0.0
         }
     }
 
+    #[allow(dead_code)]
     pub fn get_total_transferred(&self) -> u64 {
         self.total_bytes_transferred
     }
 
-    pub fn record_transfer(&mut self, bytes: u64) {
-        self.bytes_transferred += bytes;
+    // Wait owed to the global bucket for `bytes`, reserving a weighted
+    // quantum at a time
+    fn draw_from_global(&mut self, bytes: u64) -> Duration {
+        if !self.global.is_enabled() {
+            return Duration::ZERO;
+        }
+        let mut wait = Duration::ZERO;
+        if self.global_credit < bytes {
+            let quantum = bytes.max(GLOBAL_QUANTUM * u64::from(self.weight));
+            wait = self.global.reserve(quantum);
+            self.global_credit += quantum;
+        }
+        self.global_credit -= bytes;
+        wait
+    }
+
+    /// Call before starting each file. Counts one file and the metadata
+    /// operation of creating it, and waits for both limits.
+    pub fn throttle_file(&mut self) {
+        let now = self.clock.now();
+        let own = self.files.take(1, now).max(self.metadata.take(1, now));
+        let wait = own.max(self.global.reserve_operations(1, 1));
+        if !wait.is_zero() {
+            self.clock.sleep(wait);
+        }
+    }
+
+    /// Call before `ops` destination metadata operations other than file
+    /// creation, such as making a directory or removing a failed copy.
+    pub fn throttle_metadata(&mut self, ops: u64) {
+        let now = self.clock.now();
+        let own = self.metadata.take(ops, now);
+        let wait = own.max(self.global.reserve_operations(0, ops));
+        if !wait.is_zero() {
+            self.clock.sleep(wait);
+        }
+    }
+
+    /// Accounts for `chunk_size` bytes just sent and sleeps for as long as
+    /// the tighter limit requires.
+    pub fn throttle_chunk(&mut self, chunk_size: usize) {
+        let bytes = chunk_size as u64;
+        let now = self.clock.now();
+        self.started.get_or_insert(now);
         self.total_bytes_transferred += bytes;
 
-        // Reset window if we've been tracking for more than 1 second
-        if self.window_start.elapsed() >= Duration::from_secs(1) {
-            self.window_start = Instant::now();
-            self.bytes_transferred = 0;
+        let own_wait = self.bucket.take(bytes, now);
+        let global_wait = self.draw_from_global(bytes);
+        let wait = own_wait.max(global_wait);
+        if !wait.is_zero() {
+            self.clock.sleep(wait);
         }
     }
+}
 
-    pub fn throttle(&mut self) {
-        if !self.enabled || self.bytes_per_second == 0 {
-            return;
+/// Paces everything written through it with a `RateLimiter`, for writers
+/// such as archive builders that own their output.
+pub struct ThrottledWriter<'a, W> {
+    inner: W,
+    rate_limiter: &'a mut RateLimiter,
+}
+
+impl<'a, W: Write> ThrottledWriter<'a, W> {
+    pub fn new(inner: W, rate_limiter: &'a mut RateLimiter) -> Self {
+        Self {
+            inner,
+            rate_limiter,
         }
+    }
 
-        let target_duration =
-            Duration::from_secs_f64(self.bytes_transferred as f64 / self.bytes_per_second as f64);
+    pub fn rate_limiter(&mut self) -> &mut RateLimiter {
+        self.rate_limiter
+    }
 
-        let elapsed = self.window_start.elapsed();
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
 
-        if elapsed < target_duration {
-            // We're ahead of schedule, need to slow down
-            let sleep_duration = target_duration - elapsed;
-            thread::sleep(sleep_duration);
+impl<W: Write> Write for ThrottledWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        let n = self.inner.write(buf)?;
+        self.rate_limiter.record_write(started.elapsed());
+        self.rate_limiter.throttle_chunk(n);
+        self.rate_limiter.refresh_schedule();
+        self.rate_limiter.adapt();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AdaptiveThrottle, RateWindow};
+
+    // Time only moves when a limiter sleeps
+    struct ManualClock {
+        start: Instant,
+        local_start: DateTime<Local>,
+        elapsed: Mutex<Duration>,
+    }
 
-            // Reset tracking after sleeping
-            self.window_start = Instant::now();
-            self.bytes_transferred = 0;
+    impl ManualClock {
+        fn at(local_start: DateTime<Local>) -> Arc<Self> {
+            Arc::new(Self {
+                start: Instant::now(),
+                local_start,
+                elapsed: Mutex::new(Duration::ZERO),
+            })
+        }
+
+        fn elapsed(&self) -> Duration {
+            *self.elapsed.lock().unwrap()
         }
     }
 
-    pub fn throttle_chunk(&mut self, chunk_size: usize, total_size: u64) {
-        if !self.enabled {
-            return;
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            self.start + self.elapsed()
         }
 
-        self.record_transfer(chunk_size as u64);
-        self.throttle();
+        fn local_time(&self) -> DateTime<Local> {
+            self.local_start + chrono::Duration::from_std(self.elapsed()).unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            *self.elapsed.lock().unwrap() += duration;
+        }
+    }
 
-        // Also do progressive throttling for large files
-        if total_size > self.bytes_per_second * 10 {
-            // For files larger than 10 seconds worth of data at max speed,
-            // do more frequent throttling
-            let progress = self.total_bytes_transferred as f64 / total_size as f64;
-            if progress % 0.1 < 0.01 {
-                // Every 10% progress
-                self.throttle();
+    fn limit(bytes_per_second: u64, burst_bytes: u64) -> RateLimit {
+        RateLimit {
+            enabled: true,
+            bytes_per_second: Some(bytes_per_second),
+            burst_bytes: Some(burst_bytes),
+            ..Default::default()
+        }
+    }
+
+    fn monday_at(hour: u32, minute: u32) -> DateTime<Local> {
+        use chrono::TimeZone;
+        // 2024-01-01 was a Monday
+        Local
+            .with_ymd_and_hms(2024, 1, 1, hour, minute, 0)
+            .single()
+            .unwrap()
+    }
+
+    #[test]
+    fn holds_rate_over_long_transfer() {
+        let clock = ManualClock::at(monday_at(12, 0));
+        let mut limiter = RateLimiter::with_clock(
+            &limit(100_000, 10_000),
+            &GlobalLimiter::default(),
+            1,
+            clock.clone(),
+        );
+        let mut sent = 0;
+        while sent < 5_000_000 {
+            limiter.throttle_chunk(64 * 1024);
+            sent += 64 * 1024;
+        }
+        // The last chunk's wait is paid before the loop ends, so the clock
+        // shows exactly what the rate allows beyond the initial burst
+        let expected = (sent - 10_000) as f64 / 100_000.0;
+        let elapsed = clock.elapsed().as_secs_f64();
+        assert!((elapsed - expected).abs() < 1e-6, "{elapsed} vs {expected}");
+        let rate = limiter.get_current_rate();
+        assert!((rate - 100_000.0).abs() / 100_000.0 < 0.01, "rate {rate}");
+    }
+
+    #[test]
+    fn every_window_stays_within_rate_plus_burst() {
+        let clock = ManualClock::at(monday_at(12, 0));
+        let mut limiter = RateLimiter::with_clock(
+            &limit(50_000, 20_000),
+            &GlobalLimiter::default(),
+            1,
+            clock.clone(),
+        );
+        // (time the chunk was sent, bytes sent before it)
+        let mut sends = Vec::new();
+        let mut sent = 0u64;
+        for _ in 0..200 {
+            sends.push((clock.elapsed().as_secs_f64(), sent));
+            limiter.throttle_chunk(8 * 1024);
+            sent += 8 * 1024;
+        }
+        for (i, &(t0, b0)) in sends.iter().enumerate() {
+            for &(t1, b1) in &sends[i..] {
+                // Bytes sent in [t0, t1], counting the chunk sent at t1
+                let bytes = (b1 - b0 + 8 * 1024) as f64;
+                let allowed = 20_000.0 + 50_000.0 * (t1 - t0) + 8.0 * 1024.0;
+                assert!(bytes <= allowed + 1.0, "{bytes} > {allowed}");
             }
         }
     }
+
+    #[test]
+    fn burst_passes_immediately_and_idle_time_is_capped() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(1_000, 5_000, now);
+        assert_eq!(bucket.take(5_000, now), Duration::ZERO);
+        assert_eq!(bucket.take(1_000, now), Duration::from_secs(1));
+
+        // A long idle spell refills to the burst size and no further
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(bucket.take(5_000, later), Duration::ZERO);
+        assert_eq!(bucket.take(500, later), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn chunk_larger_than_burst_waits_for_the_excess() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10_000, 1_000, now);
+        assert_eq!(bucket.take(21_000, now), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn zero_rate_means_unlimited() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(0, 0, now);
+        assert_eq!(bucket.take(u64::MAX / 2, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn megabytes_per_minute_is_not_truncated() {
+        let limit = RateLimit {
+            enabled: true,
+            megabytes_per_minute: Some(1),
+            ..Default::default()
+        };
+        // 1048576 / 60 = 17476.27
+        assert_eq!(limit.effective_bytes_per_second(), Some(17_476));
+        let limit = RateLimit {
+            megabytes_per_minute: Some(7),
+            ..limit
+        };
+        // 7340032 / 60 = 122333.87, rounded up rather than down
+        assert_eq!(limit.effective_bytes_per_second(), Some(122_334));
+    }
+
+    #[test]
+    fn follows_schedule_window_changes_mid_transfer() {
+        let clock = ManualClock::at(monday_at(17, 59));
+        let scheduled = RateLimit {
+            enabled: true,
+            burst_bytes: Some(0),
+            schedule: vec![RateWindow {
+                rate: "10KB/s".to_string(),
+                from: "08:00".to_string(),
+                to: "18:00".to_string(),
+                days: vec!["weekdays".to_string()],
+            }],
+            ..Default::default()
+        };
+        let mut limiter =
+            RateLimiter::with_clock(&scheduled, &GlobalLimiter::default(), 1, clock.clone());
+        assert!(limiter.is_enabled());
+        assert_eq!(limiter.get_rate_limit(), Some(10 * 1024));
+
+        // One minute at 10 KiB/s brings the clock to 18:00
+        let mut changed = false;
+        for _ in 0..60 {
+            limiter.throttle_chunk(10 * 1024);
+            changed |= limiter.refresh_schedule();
+        }
+        assert_eq!(clock.elapsed(), Duration::from_secs(60));
+        assert!(changed);
+        assert_eq!(limiter.get_rate_limit(), None);
+
+        // Outside the window nothing waits
+        limiter.throttle_chunk(1024 * 1024);
+        assert_eq!(clock.elapsed(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn files_per_second_spaces_out_small_files() {
+        let clock = ManualClock::at(monday_at(12, 0));
+        let files_only = RateLimit {
+            enabled: true,
+            files_per_second: Some(20),
+            metadata_ops_per_second: Some(10),
+            ..Default::default()
+        };
+        let mut limiter =
+            RateLimiter::with_clock(&files_only, &GlobalLimiter::default(), 1, clock.clone());
+        assert!(limiter.is_enabled());
+        assert!(!limiter.limits_bytes());
+
+        // 100 files, each also a metadata op, plus 50 directories: the
+        // metadata cap of 10/s is the tighter one. The first op is free.
+        for i in 0..100 {
+            if i % 2 == 0 {
+                limiter.throttle_metadata(1);
+            }
+            limiter.throttle_file();
+            limiter.throttle_chunk(100);
+        }
+        assert!((clock.elapsed().as_secs_f64() - 14.9).abs() < 1e-6);
+    }
+
+    static FAKE_LOAD: Mutex<f64> = Mutex::new(0.0);
+
+    fn fake_load() -> Option<f64> {
+        Some(*FAKE_LOAD.lock().unwrap())
+    }
+
+    #[test]
+    fn adaptive_backs_off_under_load_and_recovers() {
+        let clock = ManualClock::at(monday_at(12, 0));
+        let adaptive = RateLimit {
+            adaptive: Some(AdaptiveThrottle {
+                max_load_average: Some(2.0),
+                min_rate: Some("256KiB/s".to_string()),
+                ..Default::default()
+            }),
+            ..limit(1024 * 1024, 64 * 1024)
+        };
+        let mut limiter =
+            RateLimiter::with_clock(&adaptive, &GlobalLimiter::default(), 1, clock.clone());
+        limiter.adaptive.as_mut().unwrap().load_average = fake_load;
+
+        let run = |limiter: &mut RateLimiter, seconds: u64| {
+            let until = clock.elapsed() + Duration::from_secs(seconds);
+            while clock.elapsed() < until {
+                limiter.throttle_chunk(64 * 1024);
+                limiter.adapt();
+            }
+        };
+
+        *FAKE_LOAD.lock().unwrap() = 4.0;
+        run(&mut limiter, 5);
+        assert_eq!(limiter.get_rate_limit(), Some(256 * 1024));
+        let notes = limiter.take_adjustments();
+        assert_eq!(notes.len(), 2, "{:?}", notes);
+        assert!(notes[0].starts_with("backed off to 512.00 KiB/s (load average 4.00 above 2.00)"));
+
+        // At the floor, further checks under load change nothing
+        run(&mut limiter, 4);
+        assert!(limiter.take_adjustments().is_empty());
+
+        *FAKE_LOAD.lock().unwrap() = 0.5;
+        run(&mut limiter, 30);
+        assert_eq!(limiter.get_rate_limit(), Some(1024 * 1024));
+        let notes = limiter.take_adjustments();
+        assert!(
+            notes[0].starts_with("sped up to 320.00 KiB/s"),
+            "{:?}",
+            notes
+        );
+        assert_eq!(
+            notes.last().unwrap(),
+            "load back to normal, restored 1.00 MiB/s"
+        );
+    }
+
+    #[test]
+    fn global_bucket_is_shared_between_operations() {
+        let clock = ManualClock::at(monday_at(12, 0));
+        let global = GlobalLimiter::with_clock(&limit(100_000, 0), clock.clone());
+        let unlimited = RateLimit::default();
+        let mut first = RateLimiter::with_clock(&unlimited, &global, 1, clock.clone());
+        let mut second = RateLimiter::with_clock(&unlimited, &global, 1, clock.clone());
+
+        for _ in 0..50 {
+            first.throttle_chunk(GLOBAL_QUANTUM as usize);
+            second.throttle_chunk(GLOBAL_QUANTUM as usize);
+        }
+        // Both drew from the same 100 kB/s, so together they took as long
+        // as one operation sending everything would have
+        let total = 100.0 * GLOBAL_QUANTUM as f64;
+        let elapsed = clock.elapsed().as_secs_f64();
+        assert!((elapsed - total / 100_000.0).abs() < 1e-6, "{elapsed}");
+    }
+
+    #[test]
+    fn weight_scales_global_reservations() {
+        let clock = ManualClock::at(monday_at(12, 0));
+        let global = GlobalLimiter::with_clock(&limit(100_000, 0), clock.clone());
+        let unlimited = RateLimit::default();
+        let mut heavy = RateLimiter::with_clock(&unlimited, &global, 3, clock.clone());
+
+        // One reservation covers three chunks, so only the first one waits
+        heavy.throttle_chunk(GLOBAL_QUANTUM as usize);
+        let after_first = clock.elapsed();
+        heavy.throttle_chunk(GLOBAL_QUANTUM as usize);
+        heavy.throttle_chunk(GLOBAL_QUANTUM as usize);
+        assert_eq!(clock.elapsed(), after_first);
+        let expected = 3.0 * GLOBAL_QUANTUM as f64 / 100_000.0;
+        assert!((after_first.as_secs_f64() - expected).abs() < 1e-6);
+    }
+
+    // The clock of one of several operations running side by side. They
+    // share `current`, the time of whichever operation is acting, and each
+    // records when its own sleep would end.
+    struct TurnClock {
+        start: Instant,
+        current: Arc<Mutex<Duration>>,
+        wakes_at: Mutex<Duration>,
+    }
+
+    impl Clock for TurnClock {
+        fn now(&self) -> Instant {
+            self.start + *self.current.lock().unwrap()
+        }
+
+        fn local_time(&self) -> DateTime<Local> {
+            monday_at(12, 0) + chrono::Duration::from_std(*self.current.lock().unwrap()).unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            *self.wakes_at.lock().unwrap() = *self.current.lock().unwrap() + duration;
+        }
+    }
+
+    #[test]
+    fn weights_split_the_global_budget() {
+        let start = Instant::now();
+        let current = Arc::new(Mutex::new(Duration::ZERO));
+        let clock = |current: &Arc<Mutex<Duration>>| {
+            Arc::new(TurnClock {
+                start,
+                current: current.clone(),
+                wakes_at: Mutex::new(Duration::ZERO),
+            })
+        };
+        let global_clock = clock(&current);
+        let global = GlobalLimiter::with_clock(&limit(400_000, 0), global_clock);
+        let unlimited = RateLimit::default();
+        let clocks = [clock(&current), clock(&current)];
+        let mut limiters = [
+            RateLimiter::with_clock(&unlimited, &global, 3, clocks[0].clone()),
+            RateLimiter::with_clock(&unlimited, &global, 1, clocks[1].clone()),
+        ];
+
+        // Whichever operation is ready first sends its next chunk, as two
+        // threads would, until a minute has passed
+        let minute = Duration::from_secs(60);
+        loop {
+            let (idx, clock) = clocks
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, clock)| *clock.wakes_at.lock().unwrap())
+                .unwrap();
+            let ready = *clock.wakes_at.lock().unwrap();
+            if ready >= minute {
+                break;
+            }
+            *current.lock().unwrap() = ready;
+            limiters[idx].throttle_chunk(GLOBAL_QUANTUM as usize);
+        }
+
+        let heavy = limiters[0].get_total_transferred() as f64;
+        let light = limiters[1].get_total_transferred() as f64;
+        assert!((heavy / light - 3.0).abs() < 0.05, "{heavy} vs {light}");
+        // Together they used the global budget, give or take one round
+        let budget = 400_000.0 * minute.as_secs_f64();
+        let round = 4.0 * GLOBAL_QUANTUM as f64;
+        assert!((heavy + light - budget).abs() <= round, "{}", heavy + light);
+    }
 }
@@ -0,0 +1,411 @@
+use crate::archive;
+use crate::rate_limiter::{RateLimiter, ThrottledWriter};
+use crate::restore::{self, Conflict, Existing};
+use crate::validation;
+use anyhow::Context;
+use chrono::{Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use walkdir::WalkDir;
+
+// File contents live under objects/, each stored once under its SHA-256
+// and fanned out by the first two hex digits. Every run adds one manifest
+// under snapshots/ mapping the origin's paths to those hashes.
+const OBJECTS_DIR: &str = "objects";
+const SNAPSHOTS_DIR: &str = "snapshots";
+// Snapshot names, so they sort by time
+const SNAPSHOT_FORMAT: &str = "%Y%m%d_%H%M%S";
+
+/// One file in a snapshot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    // Path under the origin, `/`-separated
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+}
+
+/// What one run stored: every file of the origin and its hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub created: String,
+    pub origin: PathBuf,
+    pub files: Vec<SnapshotEntry>,
+}
+
+/// A file stored by `Repository::store`.
+#[derive(Debug, Clone)]
+pub struct StoredFile {
+    pub sha256: String,
+    pub size: u64,
+    pub object: PathBuf,
+    // False when the repository already had this content
+    pub new: bool,
+}
+
+/// A file written by `Repository::restore`, and what verifying it found.
+#[derive(Debug, Clone)]
+pub struct RestoredFile {
+    pub path: String,
+    pub destination: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    // What was at the destination beforehand
+    pub existing: Existing,
+    pub problem: Option<String>,
+}
+
+/// What one run added to a repository, and the repository's totals after
+/// it, for the summary report.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DedupStats {
+    pub snapshot: String,
+    pub files: usize,
+    pub new_objects: usize,
+    pub new_bytes: u64,
+    // Bytes of files whose content was already stored
+    pub reused_bytes: u64,
+    pub snapshots: usize,
+    pub objects: usize,
+    pub stored_bytes: u64,
+    // Sum of the file sizes of every snapshot: what plain copies would take
+    pub referenced_bytes: u64,
+}
+
+impl DedupStats {
+    /// How many times over the stored content is referenced.
+    pub fn ratio(&self) -> f64 {
+        self.referenced_bytes as f64 / self.stored_bytes.max(1) as f64
+    }
+}
+
+/// A content-addressed store of file contents plus snapshot manifests.
+#[derive(Debug, Clone)]
+pub struct Repository {
+    pub root: PathBuf,
+}
+
+impl Repository {
+    /// Opens the repository at `root`, creating it if it doesn't exist yet.
+    pub fn init(root: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(root.join(OBJECTS_DIR))?;
+        fs::create_dir_all(root.join(SNAPSHOTS_DIR))?;
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Opens an existing repository.
+    pub fn open(root: &Path) -> anyhow::Result<Self> {
+        if !root.join(SNAPSHOTS_DIR).is_dir() || !root.join(OBJECTS_DIR).is_dir() {
+            anyhow::bail!("{} is not a repository", root.display());
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    pub fn object_path(&self, sha256: &str) -> PathBuf {
+        let (fan, rest) = sha256.split_at(2.min(sha256.len()));
+        self.root.join(OBJECTS_DIR).join(fan).join(rest)
+    }
+
+    /// Adds the content of `source` unless the repository already has it.
+    /// New content is written through `rate_limiter` and read back to check
+    /// its hash before it takes its place.
+    pub fn store(
+        &self,
+        source: &Path,
+        rate_limiter: &mut RateLimiter,
+    ) -> anyhow::Result<StoredFile> {
+        rate_limiter.throttle_file();
+        let sha256 = validation::calculate_sha256(source)?;
+        let size = fs::metadata(source)?.len();
+        let object = self.object_path(&sha256);
+        // A damaged object of the wrong size is replaced
+        if fs::metadata(&object).is_ok_and(|m| m.len() == size) {
+            return Ok(StoredFile {
+                sha256,
+                size,
+                object,
+                new: false,
+            });
+        }
+
+        let parent = object.parent().unwrap_or(&self.root);
+        if !parent.exists() {
+            rate_limiter.throttle_metadata(1);
+            fs::create_dir_all(parent)?;
+        }
+        let incoming = incoming_path(parent, &sha256);
+        let written = (|| {
+            let mut reader = File::open(source)?;
+            let mut writer = ThrottledWriter::new(File::create(&incoming)?, rate_limiter);
+            io::copy(&mut reader, &mut writer)?;
+            writer.into_inner().sync_all()?;
+            // The source may have changed since it was hashed
+            if !validation::verify_file_integrity(&incoming, &sha256)? {
+                anyhow::bail!("{} changed while it was being stored", source.display());
+            }
+            rate_limiter.throttle_metadata(1);
+            fs::rename(&incoming, &object)?;
+            Ok(())
+        })();
+        if let Err(e) = written {
+            let _ = fs::remove_file(&incoming);
+            return Err(e);
+        }
+        Ok(StoredFile {
+            sha256,
+            size,
+            object,
+            new: true,
+        })
+    }
+
+    /// Writes `manifest` as a new snapshot and returns its name.
+    pub fn write_snapshot(&self, manifest: &SnapshotManifest) -> anyhow::Result<String> {
+        let incoming = incoming_path(&self.root.join(SNAPSHOTS_DIR), "snapshot");
+        fs::write(&incoming, serde_json::to_vec_pretty(manifest)?)?;
+        // Linking fails when the name is taken, so runs finishing in the
+        // same second each get their own snapshot
+        let stamp = Local::now().format(SNAPSHOT_FORMAT).to_string();
+        let mut name = stamp.clone();
+        let mut n = 1;
+        let linked = loop {
+            match fs::hard_link(&incoming, self.snapshot_path(&name)) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    n += 1;
+                    name = format!("{}_{}", stamp, n);
+                }
+                linked => break linked,
+            }
+        };
+        let _ = fs::remove_file(&incoming);
+        linked?;
+        Ok(name)
+    }
+
+    fn snapshot_path(&self, name: &str) -> PathBuf {
+        self.root.join(SNAPSHOTS_DIR).join(format!("{}.json", name))
+    }
+
+    /// Names of every snapshot, oldest first.
+    pub fn snapshots(&self) -> anyhow::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(self.root.join(SNAPSHOTS_DIR))?.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if let Some(name) = file_name.strip_suffix(".json") {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn load_snapshot(&self, name: &str) -> anyhow::Result<SnapshotManifest> {
+        let path = self.snapshot_path(name);
+        let data = fs::read(&path).with_context(|| format!("no snapshot named {}", name))?;
+        serde_json::from_slice(&data).with_context(|| format!("cannot read {}", path.display()))
+    }
+
+    /// Totals across every snapshot and object, with `run`'s own numbers
+    /// already filled in.
+    pub fn stats(&self, mut run: DedupStats) -> anyhow::Result<DedupStats> {
+        for name in self.snapshots()? {
+            let manifest = self.load_snapshot(&name)?;
+            run.snapshots += 1;
+            run.referenced_bytes += manifest.files.iter().map(|f| f.size).sum::<u64>();
+        }
+        for entry in WalkDir::new(self.root.join(OBJECTS_DIR)) {
+            let entry = entry?;
+            if entry.file_type().is_file() && !entry.file_name().to_string_lossy().starts_with('.')
+            {
+                run.objects += 1;
+                run.stored_bytes += entry.metadata()?.len();
+            }
+        }
+        Ok(run)
+    }
+
+    /// Rebuilds the snapshot `manifest` under `destination` through
+    /// `rate_limiter`, checking each file against its recorded hash. Files
+    /// already there are handled as `conflict` says. Files that fail the
+    /// check are removed.
+    pub fn restore(
+        &self,
+        manifest: &SnapshotManifest,
+        destination: &Path,
+        conflict: Conflict,
+        rate_limiter: &mut RateLimiter,
+    ) -> anyhow::Result<Vec<RestoredFile>> {
+        fs::create_dir_all(destination)?;
+        let mut files = Vec::new();
+        for entry in &manifest.files {
+            let target = archive::member_destination(destination, Path::new(&entry.path))?;
+            let (existing, problem) = match restore::prepare(&target, &entry.sha256, conflict) {
+                Ok(existing) if existing.write() => {
+                    let problem = self
+                        .restore_file(entry, &target, rate_limiter)
+                        .err()
+                        .map(|e| format!("{:#}", e));
+                    (existing, problem)
+                }
+                Ok(existing) => (existing, None),
+                Err(e) => (Existing::Kept, Some(format!("{:#}", e))),
+            };
+            files.push(RestoredFile {
+                path: entry.path.clone(),
+                destination: target,
+                size: entry.size,
+                sha256: entry.sha256.clone(),
+                existing,
+                problem,
+            });
+        }
+        Ok(files)
+    }
+
+    fn restore_file(
+        &self,
+        entry: &SnapshotEntry,
+        target: &Path,
+        rate_limiter: &mut RateLimiter,
+    ) -> anyhow::Result<()> {
+        rate_limiter.throttle_file();
+        let object = self.object_path(&entry.sha256);
+        let mut reader = File::open(&object).map_err(|_| {
+            anyhow::anyhow!("content {} is missing from the repository", entry.sha256)
+        })?;
+        if let Some(parent) = target.parent()
+            && !parent.exists()
+        {
+            rate_limiter.throttle_metadata(1);
+            fs::create_dir_all(parent)?;
+        }
+        let mut writer = ThrottledWriter::new(File::create(target)?, rate_limiter);
+        let copied = io::copy(&mut reader, &mut writer);
+        let file = writer.into_inner();
+        let verified = copied.map_err(anyhow::Error::from).and_then(|_| {
+            file.sync_all()?;
+            if !validation::verify_file_integrity(target, &entry.sha256)? {
+                anyhow::bail!("hash does not match the snapshot");
+            }
+            #[cfg(unix)]
+            if let Some(mode) = entry.mode {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(target, fs::Permissions::from_mode(mode))?;
+            }
+            Ok(())
+        });
+        if verified.is_err() {
+            rate_limiter.throttle_metadata(1);
+            let _ = fs::remove_file(target);
+        }
+        verified
+    }
+}
+
+// A temporary file in `dir` for writing `name`, unique to this writer so
+// parallel writers of the same content don't share it. The leading dot
+// keeps it out of the object counts.
+fn incoming_path(dir: &Path, name: &str) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    dir.join(format!(".incoming-{}-{}-{}", name, std::process::id(), n))
+}
+
+/// When a snapshot was taken, from its name.
+pub fn snapshot_time(name: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(name.get(..15)?, SNAPSHOT_FORMAT).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, unlimited};
+
+    fn temp_repository() -> (TempDir, Repository) {
+        let dir = TempDir::new("repository");
+        let repository = Repository::init(&dir.join("repo")).unwrap();
+        (dir, repository)
+    }
+
+    #[test]
+    fn parallel_stores_of_the_same_content_all_succeed() {
+        let (dir, repository) = temp_repository();
+        let data = vec![7u8; 256 * 1024];
+        let sources: Vec<PathBuf> = (0..8)
+            .map(|i| {
+                let source = dir.join(format!("source_{}", i));
+                fs::write(&source, &data).unwrap();
+                source
+            })
+            .collect();
+
+        let stored: Vec<StoredFile> = std::thread::scope(|scope| {
+            let handles: Vec<_> = sources
+                .iter()
+                .map(|source| scope.spawn(|| repository.store(source, &mut unlimited())))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap().unwrap())
+                .collect()
+        });
+
+        let object = &stored[0].object;
+        assert!(stored.iter().all(|file| &file.object == object));
+        assert_eq!(fs::read(object).unwrap(), data);
+        let leftovers: Vec<_> = fs::read_dir(object.parent().unwrap())
+            .unwrap()
+            .flatten()
+            .filter(|entry| entry.path() != *object)
+            .collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+    }
+
+    #[test]
+    fn snapshots_written_together_keep_their_own_names() {
+        let (_dir, repository) = temp_repository();
+        let manifest = |origin: &str| SnapshotManifest {
+            created: String::new(),
+            origin: PathBuf::from(origin),
+            files: Vec::new(),
+        };
+
+        let names: Vec<String> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|i| {
+                    let manifest = manifest(&format!("/origin/{}", i));
+                    let repository = &repository;
+                    scope.spawn(move || repository.write_snapshot(&manifest).unwrap())
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let mut listed = repository.snapshots().unwrap();
+        assert_eq!(listed.len(), 4);
+        let mut written = names.clone();
+        written.sort();
+        listed.sort();
+        assert_eq!(listed, written);
+        let mut origins: Vec<PathBuf> = names
+            .iter()
+            .map(|name| repository.load_snapshot(name).unwrap().origin)
+            .collect();
+        origins.sort();
+        assert_eq!(
+            origins,
+            (0..4)
+                .map(|i| PathBuf::from(format!("/origin/{}", i)))
+                .collect::<Vec<_>>()
+        );
+    }
+}
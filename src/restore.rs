@@ -0,0 +1,484 @@
+use crate::config::{FileOperation, OperationType};
+use crate::repository::{self, Repository};
+use crate::retention::SnapshotLayout;
+use crate::validation;
+use crate::{compression, crypto, paths};
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What to do when a file being restored already exists with different
+/// content. Files that already match are always left alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Conflict {
+    // Keep the existing file
+    #[default]
+    Skip,
+    Overwrite,
+    // Move the existing file aside to NAME.orig (numbered if taken)
+    Rename,
+}
+
+impl Conflict {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "skip" => Some(Conflict::Skip),
+            "overwrite" => Some(Conflict::Overwrite),
+            "rename" => Some(Conflict::Rename),
+            _ => None,
+        }
+    }
+}
+
+/// What `prepare` found at a restore target.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Existing {
+    Absent,
+    Identical,
+    Kept,
+    MovedTo(PathBuf),
+    Replaced,
+}
+
+impl Existing {
+    /// Whether the restored file should be written.
+    pub fn write(&self) -> bool {
+        !matches!(self, Existing::Identical | Existing::Kept)
+    }
+}
+
+/// Deals with whatever is at `target` before content hashing to `sha256`
+/// is restored there.
+pub fn prepare(target: &Path, sha256: &str, conflict: Conflict) -> anyhow::Result<Existing> {
+    if !target.exists() {
+        return Ok(Existing::Absent);
+    }
+    if target.is_file() && validation::verify_file_integrity(target, sha256)? {
+        return Ok(Existing::Identical);
+    }
+    match conflict {
+        Conflict::Skip => Ok(Existing::Kept),
+        Conflict::Overwrite if target.is_dir() => {
+            anyhow::bail!("{} is a directory", target.display())
+        }
+        Conflict::Overwrite => Ok(Existing::Replaced),
+        Conflict::Rename => {
+            let mut aside = PathBuf::from(format!("{}.orig", target.display()));
+            let mut n = 1;
+            while aside.exists() {
+                n += 1;
+                aside = PathBuf::from(format!("{}.orig{}", target.display(), n));
+            }
+            fs::rename(target, &aside)?;
+            Ok(Existing::MovedTo(aside))
+        }
+    }
+}
+
+/// Where a restore reads from.
+#[derive(Debug)]
+pub enum Source {
+    // A plain copy of the origin, or one dated snapshot of it
+    Tree {
+        path: PathBuf,
+        snapshot: Option<String>,
+    },
+    Repository {
+        repository: Repository,
+        snapshot: String,
+    },
+}
+
+/// Finds what to restore for `operation`. `selector` is a snapshot name or
+/// a time, which picks the latest snapshot taken at or before it; without
+/// one the latest snapshot is used.
+pub fn locate(operation: &FileOperation, selector: Option<&str>) -> anyhow::Result<Source> {
+    match operation.operation_type {
+        OperationType::Copy => {}
+        OperationType::Repository => return locate_in_repository(operation, selector),
+        _ => anyhow::bail!(
+            "'{}' is a {:?} operation; only Copy and Repository operations can be restored",
+            operation.name,
+            operation.operation_type
+        ),
+    }
+    let template = operation.destination.to_string_lossy().to_string();
+    let Some(layout) = SnapshotLayout::from_template(&operation.destination)? else {
+        if let Some(selector) = selector {
+            anyhow::bail!(
+                "'{}' keeps a single copy with no snapshots, so '{}' can't be chosen",
+                operation.name,
+                selector
+            );
+        }
+        let path = match paths::is_template(&template) {
+            true => PathBuf::from(paths::render_template(&template, Local::now())?),
+            false => operation.destination.clone(),
+        };
+        return Ok(Source::Tree {
+            path: on_disk(operation, path),
+            snapshot: None,
+        });
+    };
+
+    let snapshots = layout.list_snapshots()?;
+    let names: Vec<String> = snapshots.iter().map(|s| s.name.clone()).collect();
+    let snapshot = match selector {
+        None => snapshots.first(),
+        Some(selector) => match snapshots.iter().find(|s| s.name == selector) {
+            Some(snapshot) => Some(snapshot),
+            None => {
+                let time = parse_time(selector)?;
+                snapshots.iter().find(|s| s.time <= time)
+            }
+        },
+    };
+    let Some(snapshot) = snapshot else {
+        anyhow::bail!(no_snapshot(&layout.root, selector, &names));
+    };
+    // The snapshot's time fills in the rest of the template, as it did
+    // when the snapshot was written
+    let when = Local
+        .from_local_datetime(&snapshot.time)
+        .earliest()
+        .ok_or_else(|| anyhow::anyhow!("snapshot {} has an invalid time", snapshot.name))?;
+    let path = on_disk(
+        operation,
+        PathBuf::from(paths::render_template(&template, when)?),
+    );
+    if !path.starts_with(&snapshot.path) || !path.exists() {
+        anyhow::bail!(
+            "snapshot {} has nothing at {}",
+            snapshot.path.display(),
+            path.display()
+        );
+    }
+    Ok(Source::Tree {
+        path,
+        snapshot: Some(snapshot.name.clone()),
+    })
+}
+
+// Where the copy bound for `path` was written. A directory keeps its name,
+// while a single file carries the suffixes compression and encryption add.
+fn on_disk(operation: &FileOperation, path: PathBuf) -> PathBuf {
+    if path.exists() {
+        return path;
+    }
+    let written = compression::compressed_path(&path, operation.compression);
+    match operation.encryption {
+        Some(_) => crypto::encrypted_path(&written),
+        None => written,
+    }
+}
+
+fn locate_in_repository(
+    operation: &FileOperation,
+    selector: Option<&str>,
+) -> anyhow::Result<Source> {
+    let repository = Repository::open(&operation.destination)?;
+    // Oldest first
+    let names = repository.snapshots()?;
+    let snapshot = match selector {
+        None => names.last(),
+        Some(selector) => match names.iter().find(|name| *name == selector) {
+            Some(name) => Some(name),
+            None => {
+                let time = parse_time(selector)?;
+                names
+                    .iter()
+                    .rev()
+                    .find(|name| repository::snapshot_time(name).is_some_and(|t| t <= time))
+            }
+        },
+    };
+    match snapshot {
+        Some(snapshot) => Ok(Source::Repository {
+            snapshot: snapshot.clone(),
+            repository,
+        }),
+        None => anyhow::bail!(no_snapshot(&operation.destination, selector, &names)),
+    }
+}
+
+fn no_snapshot(root: &Path, selector: Option<&str>, names: &[String]) -> String {
+    match selector {
+        _ if names.is_empty() => format!("no snapshots under {}", root.display()),
+        Some(selector) => format!(
+            "no snapshot matches '{}'; available: {}",
+            selector,
+            names.join(", ")
+        ),
+        None => format!("no snapshots under {}", root.display()),
+    }
+}
+
+// `2026-03-01`, `2026-03-01 14:30` or `2026-03-01T14:30:00`. A date alone
+// means the end of that day.
+fn parse_time(text: &str) -> anyhow::Result<NaiveDateTime> {
+    let text = text.trim();
+    for format in [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M",
+    ] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(text, format) {
+            return Ok(time);
+        }
+    }
+    NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(23, 59, 59))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "'{}' is neither a snapshot name nor a time like 2026-03-01 or 2026-03-01 14:30",
+                text
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Compression;
+    use crate::repository::SnapshotManifest;
+    use crate::test_util::TempDir;
+
+    fn copy_to(destination: PathBuf) -> FileOperation {
+        FileOperation {
+            name: "docs".to_string(),
+            origin: PathBuf::from("/srv/docs"),
+            destination,
+            operation_type: OperationType::Copy,
+            ..Default::default()
+        }
+    }
+
+    // Dated snapshots of a `backup/{date}/docs` destination
+    fn dated_backups(dir: &Path, dates: &[&str]) -> FileOperation {
+        for date in dates {
+            let docs = dir.join("backup").join(date).join("docs");
+            fs::create_dir_all(&docs).unwrap();
+            fs::write(docs.join("a.txt"), date).unwrap();
+        }
+        copy_to(dir.join("backup/{date}/docs"))
+    }
+
+    fn tree(source: Source) -> (PathBuf, Option<String>) {
+        match source {
+            Source::Tree { path, snapshot } => (path, snapshot),
+            other => panic!("expected a tree, got {:?}", other),
+        }
+    }
+
+    fn sha256_of(text: &str) -> String {
+        validation::sha256_of_reader(text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn newest_snapshot_is_the_default() {
+        let dir = TempDir::new("restore");
+        let operation = dated_backups(&dir, &["2026-03-01", "2026-03-05", "2026-02-20"]);
+        let (path, snapshot) = tree(locate(&operation, None).unwrap());
+        assert_eq!(snapshot.as_deref(), Some("2026-03-05"));
+        assert_eq!(path, dir.join("backup/2026-03-05/docs"));
+    }
+
+    #[test]
+    fn selector_picks_a_snapshot_by_name_or_time() {
+        let dir = TempDir::new("restore");
+        let operation = dated_backups(&dir, &["2026-03-01", "2026-03-05"]);
+        let snapshot = |selector| tree(locate(&operation, Some(selector)).unwrap()).1;
+        assert_eq!(snapshot("2026-03-01").as_deref(), Some("2026-03-01"));
+        // The latest taken at or before the time
+        assert_eq!(snapshot("2026-03-04 12:00").as_deref(), Some("2026-03-01"));
+        assert_eq!(
+            snapshot("2026-03-05T00:00:00").as_deref(),
+            Some("2026-03-05")
+        );
+        assert_eq!(snapshot("2027-01-01").as_deref(), Some("2026-03-05"));
+    }
+
+    #[test]
+    fn unknown_selector_lists_the_snapshots() {
+        let dir = TempDir::new("restore");
+        let operation = dated_backups(&dir, &["2026-03-01", "2026-03-05"]);
+        let err = locate(&operation, Some("2026-02-01"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("no snapshot matches '2026-02-01'"), "{err}");
+        assert!(err.contains("2026-03-05, 2026-03-01"), "{err}");
+        let err = locate(&operation, Some("last tuesday"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("neither a snapshot name nor a time"), "{err}");
+
+        let empty = copy_to(dir.join("nothing/{date}"));
+        let err = locate(&empty, None).unwrap_err().to_string();
+        assert!(err.contains("no snapshots under"), "{err}");
+    }
+
+    #[test]
+    fn single_copy_takes_no_selector() {
+        let dir = TempDir::new("restore");
+        let operation = copy_to(dir.join("backup"));
+        assert_eq!(
+            tree(locate(&operation, None).unwrap()),
+            (dir.join("backup"), None)
+        );
+        let err = locate(&operation, Some("2026-03-01"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("keeps a single copy"), "{err}");
+    }
+
+    #[test]
+    fn encoded_single_file_copy_is_found_by_its_suffixes() {
+        let dir = TempDir::new("restore");
+        let operation = FileOperation {
+            compression: Some(Compression::Zstd),
+            encryption: Some(crate::config::Encryption {
+                key_file: dir.join("key"),
+            }),
+            ..copy_to(dir.join("notes.txt"))
+        };
+        let (path, _) = tree(locate(&operation, None).unwrap());
+        assert_eq!(path, dir.join("notes.txt.zst.enc"));
+        // A directory copy keeps its own name
+        fs::create_dir(dir.join("notes.txt")).unwrap();
+        let (path, _) = tree(locate(&operation, None).unwrap());
+        assert_eq!(path, dir.join("notes.txt"));
+    }
+
+    #[test]
+    fn only_copies_and_repositories_can_be_restored() {
+        let operation = FileOperation {
+            operation_type: OperationType::Move,
+            ..copy_to(PathBuf::from("/backup"))
+        };
+        let err = locate(&operation, None).unwrap_err().to_string();
+        assert!(err.contains("is a Move operation"), "{err}");
+    }
+
+    #[test]
+    fn repository_snapshots_are_selected_by_name_or_time() {
+        let dir = TempDir::new("restore");
+        let repository = Repository::init(&dir.join("repo")).unwrap();
+        let manifest = SnapshotManifest {
+            created: String::new(),
+            origin: PathBuf::from("/srv/docs"),
+            files: Vec::new(),
+        };
+        let first = repository.write_snapshot(&manifest).unwrap();
+        let second = repository.write_snapshot(&manifest).unwrap();
+        let operation = FileOperation {
+            operation_type: OperationType::Repository,
+            ..copy_to(dir.join("repo"))
+        };
+        let snapshot = |selector| match locate(&operation, selector).unwrap() {
+            Source::Repository { snapshot, .. } => snapshot,
+            other => panic!("expected a repository, got {:?}", other),
+        };
+
+        assert_eq!(snapshot(None), second);
+        assert_eq!(snapshot(Some(first.as_str())), first);
+        assert_eq!(snapshot(Some("2999-01-01")), second);
+        let err = locate(&operation, Some("2000-01-01"))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(&format!("available: {}, {}", first, second)),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn parse_time_reads_dates_and_times() {
+        let at = |text| parse_time(text).unwrap().to_string();
+        assert_eq!(at("2026-03-01"), "2026-03-01 23:59:59");
+        assert_eq!(at(" 2026-03-01 14:30 "), "2026-03-01 14:30:00");
+        assert_eq!(at("2026-03-01T14:30"), "2026-03-01 14:30:00");
+        assert_eq!(at("2026-03-01 14:30:15"), "2026-03-01 14:30:15");
+        assert_eq!(at("2026-03-01T14:30:15"), "2026-03-01 14:30:15");
+        for bad in [
+            "",
+            "yesterday",
+            "2026-13-01",
+            "2026-03-01 25:00",
+            "01/03/2026",
+        ] {
+            assert!(parse_time(bad).is_err(), "{bad:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn prepare_leaves_absent_and_identical_targets_alone() {
+        let dir = TempDir::new("restore");
+        let target = dir.join("a.txt");
+        for conflict in [Conflict::Skip, Conflict::Overwrite, Conflict::Rename] {
+            assert_eq!(
+                prepare(&target, &sha256_of("new"), conflict).unwrap(),
+                Existing::Absent
+            );
+        }
+        fs::write(&target, "same").unwrap();
+        for conflict in [Conflict::Skip, Conflict::Overwrite, Conflict::Rename] {
+            let existing = prepare(&target, &sha256_of("same"), conflict).unwrap();
+            assert_eq!(existing, Existing::Identical);
+            assert!(!existing.write());
+        }
+        assert_eq!(fs::read_to_string(&target).unwrap(), "same");
+    }
+
+    #[test]
+    fn prepare_skips_or_overwrites_a_differing_file() {
+        let dir = TempDir::new("restore");
+        let target = dir.join("a.txt");
+        fs::write(&target, "old").unwrap();
+        let skipped = prepare(&target, &sha256_of("new"), Conflict::Skip).unwrap();
+        assert_eq!(skipped, Existing::Kept);
+        assert!(!skipped.write());
+        let replaced = prepare(&target, &sha256_of("new"), Conflict::Overwrite).unwrap();
+        assert_eq!(replaced, Existing::Replaced);
+        assert!(replaced.write());
+        // Overwriting is left to the caller
+        assert_eq!(fs::read_to_string(&target).unwrap(), "old");
+    }
+
+    #[test]
+    fn prepare_renames_aside_with_a_free_name() {
+        let dir = TempDir::new("restore");
+        let target = dir.join("a.txt");
+        fs::write(dir.join("a.txt.orig"), "older").unwrap();
+        fs::write(&target, "old").unwrap();
+        let existing = prepare(&target, &sha256_of("new"), Conflict::Rename).unwrap();
+        assert_eq!(existing, Existing::MovedTo(dir.join("a.txt.orig2")));
+        assert!(existing.write());
+        assert!(!target.exists());
+        assert_eq!(fs::read_to_string(dir.join("a.txt.orig2")).unwrap(), "old");
+        assert_eq!(fs::read_to_string(dir.join("a.txt.orig")).unwrap(), "older");
+    }
+
+    #[test]
+    fn prepare_refuses_to_overwrite_a_directory() {
+        let dir = TempDir::new("restore");
+        let target = dir.join("a.txt");
+        fs::create_dir(&target).unwrap();
+        let err = prepare(&target, &sha256_of("new"), Conflict::Overwrite).unwrap_err();
+        assert!(err.to_string().contains("is a directory"), "{err}");
+        assert!(target.is_dir());
+        assert_eq!(
+            prepare(&target, &sha256_of("new"), Conflict::Skip).unwrap(),
+            Existing::Kept
+        );
+    }
+
+    #[test]
+    fn conflict_names_parse() {
+        assert_eq!(Conflict::parse("skip"), Some(Conflict::Skip));
+        assert_eq!(Conflict::parse("overwrite"), Some(Conflict::Overwrite));
+        assert_eq!(Conflict::parse("rename"), Some(Conflict::Rename));
+        assert_eq!(Conflict::parse("Rename"), None);
+    }
+}
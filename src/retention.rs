@@ -0,0 +1,406 @@
+use crate::config::Retention;
+use crate::paths;
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub name: String,
+    pub time: NaiveDateTime,
+    // What this operation wrote under `path`. Other operations may share
+    // the dated directory, so only these are ever pruned.
+    pub contents: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetentionDecision {
+    pub snapshot: Snapshot,
+    pub keep: bool,
+    pub reasons: Vec<String>,
+}
+
+/// Where snapshots of a templated destination live: the directory holding
+/// the first dated path component, and the strftime pattern for it.
+#[derive(Debug, Clone)]
+pub struct SnapshotLayout {
+    pub root: PathBuf,
+    pub pattern: String,
+    // Template components below the dated one
+    rest: Vec<String>,
+}
+
+impl SnapshotLayout {
+    /// Finds the first component of `destination_template` containing a
+    /// `{date}` or `{time}` placeholder. `Ok(None)` means the destination
+    /// isn't dated, so there are no snapshots to rotate.
+    pub fn from_template(destination_template: &Path) -> anyhow::Result<Option<Self>> {
+        let mut root = PathBuf::new();
+        let mut components = destination_template.components();
+        while let Some(component) = components.next() {
+            let text = component.as_os_str().to_string_lossy();
+            if let Some(pattern) = paths::template_to_strftime(&text)? {
+                let rest = components
+                    .map(|c| c.as_os_str().to_string_lossy().to_string())
+                    .collect();
+                return Ok(Some(Self {
+                    root,
+                    pattern,
+                    rest,
+                }));
+            }
+            root.push(component);
+        }
+        Ok(None)
+    }
+
+    /// Snapshot path for a resolved destination, i.e. `resolved` cut back
+    /// to the dated component.
+    pub fn snapshot_of(&self, resolved: &Path) -> Option<PathBuf> {
+        let relative = resolved.strip_prefix(&self.root).ok()?;
+        let first = relative.components().next()?;
+        Some(self.root.join(first))
+    }
+
+    /// Entries under `root` whose names parse with the pattern and that
+    /// hold something of this operation's, newest first. Anything else in
+    /// the directory is left alone.
+    pub fn list_snapshots(&self) -> anyhow::Result<Vec<Snapshot>> {
+        let mut snapshots = Vec::new();
+        if !self.root.is_dir() {
+            return Ok(snapshots);
+        }
+        for entry in fs::read_dir(&self.root)?.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(time) = parse_snapshot_time(&name, &self.pattern) {
+                let contents = self.contents_of(&entry.path())?;
+                if contents.is_empty() {
+                    continue;
+                }
+                snapshots.push(Snapshot {
+                    path: entry.path(),
+                    name,
+                    time,
+                    contents,
+                });
+            }
+        }
+        snapshots.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| b.name.cmp(&a.name)));
+        Ok(snapshots)
+    }
+
+    // The paths under one dated directory that the rest of the template
+    // renders to. A further dated component matches every name its
+    // pattern parses; anything else renders as it does for a run.
+    fn contents_of(&self, snapshot: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let mut found = vec![snapshot.to_path_buf()];
+        for component in &self.rest {
+            let mut next = Vec::new();
+            match paths::template_to_strftime(component)? {
+                Some(pattern) => {
+                    for dir in &found {
+                        for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+                            let name = entry.file_name().to_string_lossy().to_string();
+                            if matches_pattern(&name, &pattern) {
+                                next.push(entry.path());
+                            }
+                        }
+                    }
+                }
+                None => {
+                    let name = paths::render_template(component, Local::now())?;
+                    for dir in &found {
+                        let path = dir.join(&name);
+                        if fs::symlink_metadata(&path).is_ok() {
+                            next.push(path);
+                        }
+                    }
+                }
+            }
+            found = next;
+        }
+        Ok(found)
+    }
+}
+
+fn parse_snapshot_time(name: &str, pattern: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(name, pattern)
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(name, pattern)
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        })
+}
+
+// Whether `name` is something `pattern` formats to, even one naming no
+// whole date, such as `%H%M`
+fn matches_pattern(name: &str, pattern: &str) -> bool {
+    let mut parsed = chrono::format::Parsed::new();
+    chrono::format::parse(
+        &mut parsed,
+        name,
+        chrono::format::StrftimeItems::new(pattern),
+    )
+    .is_ok()
+}
+
+// Identifies the calendar period (day, ISO week, month, year) of a snapshot
+type PeriodKey = fn(&NaiveDateTime) -> (i32, u32);
+
+/// Applies keep-last / daily / weekly / monthly / yearly rules to
+/// `snapshots` (newest first). Each rule keeps the newest snapshot in each
+/// of its N most recent periods. `current` is always kept so a run never
+/// prunes what it just wrote.
+pub fn plan(
+    policy: &Retention,
+    snapshots: Vec<Snapshot>,
+    current: Option<&Path>,
+) -> Vec<RetentionDecision> {
+    let mut decisions: Vec<RetentionDecision> = snapshots
+        .into_iter()
+        .map(|snapshot| RetentionDecision {
+            snapshot,
+            keep: false,
+            reasons: Vec::new(),
+        })
+        .collect();
+
+    if let Some(n) = policy.keep_last {
+        for decision in decisions.iter_mut().take(n) {
+            decision.keep = true;
+            decision.reasons.push("last".to_string());
+        }
+    }
+
+    let rules: [(&str, Option<usize>, PeriodKey); 4] = [
+        ("daily", policy.keep_daily, |t| (t.year(), t.ordinal())),
+        ("weekly", policy.keep_weekly, |t| {
+            let week = t.iso_week();
+            (week.year(), week.week())
+        }),
+        ("monthly", policy.keep_monthly, |t| (t.year(), t.month())),
+        ("yearly", policy.keep_yearly, |t| (t.year(), 0)),
+    ];
+
+    for (label, count, period_of) in rules {
+        let Some(count) = count else {
+            continue;
+        };
+        let mut seen = HashSet::new();
+        for decision in decisions.iter_mut() {
+            if seen.len() >= count {
+                break;
+            }
+            if seen.insert(period_of(&decision.snapshot.time)) {
+                decision.keep = true;
+                decision.reasons.push(label.to_string());
+            }
+        }
+    }
+
+    if let Some(current) = current {
+        for decision in decisions.iter_mut() {
+            if decision.snapshot.path == current {
+                decision.keep = true;
+                decision.reasons.push("current run".to_string());
+            }
+        }
+    }
+
+    decisions
+}
+
+/// Deletes this operation's part of each snapshot `plan` marked for
+/// pruning, then any directories that leaves empty up to the dated one,
+/// and describes every decision for the operation log.
+pub fn apply(decisions: &[RetentionDecision], dry_run: bool) -> Vec<String> {
+    let mut lines = Vec::new();
+    for decision in decisions {
+        let snapshot = &decision.snapshot;
+        if decision.keep {
+            lines.push(format!(
+                "keep   {} ({})",
+                snapshot.path.display(),
+                decision.reasons.join(", ")
+            ));
+            continue;
+        }
+        for path in &snapshot.contents {
+            if dry_run {
+                lines.push(format!("prune  {} (dry run)", path.display()));
+                continue;
+            }
+            let removed = if path.is_dir() && !path.is_symlink() {
+                fs::remove_dir_all(path)
+            } else {
+                fs::remove_file(path)
+            };
+            match removed {
+                Ok(()) => lines.push(format!("pruned {}", path.display())),
+                Err(e) => {
+                    lines.push(format!("FAILED to prune {}: {}", path.display(), e));
+                    continue;
+                }
+            }
+            // Parents shared with other operations stay while they hold
+            // anything
+            let mut parent = path.parent();
+            while let Some(dir) = parent
+                && dir.starts_with(&snapshot.path)
+                && fs::remove_dir(dir).is_ok()
+            {
+                parent = dir.parent();
+            }
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn at(day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2026, 3, day)
+            .and_then(|d| d.and_hms_opt(hour, 0, 0))
+            .unwrap()
+    }
+
+    fn snapshot(time: NaiveDateTime) -> Snapshot {
+        let path = PathBuf::from(time.format("/b/%Y%m%d_%H").to_string());
+        Snapshot {
+            name: time.format("%Y%m%d_%H").to_string(),
+            contents: vec![path.join("docs")],
+            path,
+            time,
+        }
+    }
+
+    fn kept(decisions: &[RetentionDecision]) -> Vec<String> {
+        decisions
+            .iter()
+            .filter(|d| d.keep)
+            .map(|d| d.snapshot.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn plan_keeps_newest_per_period() {
+        // Newest first: two a day over four days
+        let snapshots: Vec<Snapshot> = [4, 3, 2, 1]
+            .into_iter()
+            .flat_map(|day| [at(day, 18), at(day, 6)])
+            .map(snapshot)
+            .collect();
+        let policy = Retention {
+            keep_last: Some(1),
+            keep_daily: Some(3),
+            ..Default::default()
+        };
+        let decisions = plan(&policy, snapshots, None);
+        assert_eq!(
+            kept(&decisions),
+            ["20260304_18", "20260303_18", "20260302_18"]
+        );
+        assert_eq!(decisions[0].reasons, ["last", "daily"]);
+    }
+
+    #[test]
+    fn plan_always_keeps_the_current_run() {
+        let snapshots: Vec<Snapshot> = [at(3, 0), at(2, 0), at(1, 0)]
+            .into_iter()
+            .map(snapshot)
+            .collect();
+        let current = snapshots[2].path.clone();
+        let policy = Retention {
+            keep_last: Some(1),
+            ..Default::default()
+        };
+        let decisions = plan(&policy, snapshots, Some(&current));
+        assert_eq!(kept(&decisions), ["20260303_00", "20260301_00"]);
+        assert_eq!(decisions[2].reasons, ["current run"]);
+    }
+
+    #[test]
+    fn apply_prunes_only_this_operations_part() {
+        let root = TempDir::new("retention");
+        for date in ["20260301", "20260302"] {
+            for part in ["hostA/docs", "hostA/photos", "hostB/docs"] {
+                let dir = root.join(date).join(part);
+                fs::create_dir_all(&dir).unwrap();
+                fs::write(dir.join("file"), date).unwrap();
+            }
+        }
+        // Only hostA/docs was ever written on the 3rd
+        fs::create_dir_all(root.join("20260303/hostA/docs")).unwrap();
+        fs::create_dir_all(root.join("20260304/hostB/docs")).unwrap();
+
+        let template = root.join("{date:%Y%m%d}/hostA/docs");
+        let layout = SnapshotLayout::from_template(&template).unwrap().unwrap();
+        let snapshots = layout.list_snapshots().unwrap();
+        let names: Vec<&str> = snapshots.iter().map(|s| s.name.as_str()).collect();
+        // The 4th holds nothing of this operation's
+        assert_eq!(names, ["20260303", "20260302", "20260301"]);
+
+        let policy = Retention {
+            keep_last: Some(1),
+            ..Default::default()
+        };
+        let decisions = plan(&policy, snapshots, None);
+        let dry = apply(&decisions, true);
+        assert_eq!(dry.len(), 3);
+        assert!(root.join("20260301/hostA/docs").exists());
+
+        apply(&decisions, false);
+        assert!(root.join("20260303/hostA/docs").exists());
+        for date in ["20260301", "20260302"] {
+            assert!(!root.join(date).join("hostA/docs").exists());
+            assert!(root.join(date).join("hostA/photos/file").exists());
+            assert!(root.join(date).join("hostB/docs/file").exists());
+        }
+        assert!(root.join("20260304/hostB/docs").exists());
+    }
+
+    #[test]
+    fn apply_removes_parents_left_empty() {
+        let root = TempDir::new("retention");
+        for date in ["20260301", "20260302"] {
+            let dir = root.join(date).join("hostA/docs");
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("file"), date).unwrap();
+        }
+        let template = root.join("{date:%Y%m%d}/hostA/docs");
+        let layout = SnapshotLayout::from_template(&template).unwrap().unwrap();
+        let policy = Retention {
+            keep_last: Some(1),
+            ..Default::default()
+        };
+        let decisions = plan(&policy, layout.list_snapshots().unwrap(), None);
+        let lines = apply(&decisions, false);
+        assert!(lines.iter().any(|l| l.starts_with("pruned ")), "{lines:?}");
+        assert!(!root.join("20260301").exists());
+        assert!(root.join("20260302/hostA/docs/file").exists());
+    }
+
+    #[test]
+    fn snapshots_with_timed_subdirectories() {
+        let root = TempDir::new("retention");
+        for run in ["0600", "1800"] {
+            fs::create_dir_all(root.join("20260301").join(format!("db-{}", run))).unwrap();
+        }
+        fs::create_dir_all(root.join("20260301/other")).unwrap();
+        let template = root.join("{date:%Y%m%d}/db-{time:%H%M}");
+        let layout = SnapshotLayout::from_template(&template).unwrap().unwrap();
+        let snapshots = layout.list_snapshots().unwrap();
+        let mut contents = snapshots[0].contents.clone();
+        contents.sort();
+        assert_eq!(
+            contents,
+            [root.join("20260301/db-0600"), root.join("20260301/db-1800")]
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use chrono::{DateTime, Local};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// When a scheduled operation runs: a cron expression, or a fixed interval
+/// such as `every 15m`.
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    Cron(Box<cron::Schedule>),
+    Every(Duration),
+}
+
+impl Schedule {
+    /// Accepts `every <N><s|m|h|d>` (or `@every ...`), the `@hourly` style
+    /// shortcuts, standard five-field cron expressions, and the six/seven
+    /// field form with seconds (and years).
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let spec = spec.trim();
+        if let Some(interval) = spec
+            .strip_prefix("@every")
+            .or_else(|| spec.strip_prefix("every"))
+        {
+            return Ok(Schedule::Every(parse_interval(interval.trim())?));
+        }
+
+        let expression = match spec.split_whitespace().count() {
+            // Classic crontab has no seconds field
+            5 => format!("0 {}", spec),
+            _ => spec.to_string(),
+        };
+        let schedule = cron::Schedule::from_str(&expression)
+            .map_err(|e| anyhow::anyhow!("invalid schedule '{}': {}", spec, e))?;
+        Ok(Schedule::Cron(Box::new(schedule)))
+    }
+
+    /// Next time the operation is due. Intervals count from the last start
+    /// and are due straight away if the operation hasn't run yet.
+    pub fn next_run(
+        &self,
+        last_start: Option<DateTime<Local>>,
+        now: DateTime<Local>,
+    ) -> Option<DateTime<Local>> {
+        match self {
+            Schedule::Cron(schedule) => schedule.after(&now).next(),
+            Schedule::Every(interval) => match last_start {
+                Some(last) => last.checked_add_signed(chrono::Duration::from_std(*interval).ok()?),
+                None => Some(now),
+            },
+        }
+    }
+}
+
+fn parse_interval(input: &str) -> anyhow::Result<Duration> {
+    let split_at = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split_at);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid interval '{}'", input))?;
+    let seconds = match unit.trim() {
+        "s" | "sec" | "second" | "seconds" => 1,
+        "" | "m" | "min" | "minute" | "minutes" => 60,
+        "h" | "hour" | "hours" => 3600,
+        "d" | "day" | "days" => 86400,
+        other => anyhow::bail!("unknown interval unit '{}' (use s, m, h or d)", other),
+    };
+    if number == 0 {
+        anyhow::bail!("interval must be greater than zero");
+    }
+    let total = number
+        .checked_mul(seconds)
+        .ok_or_else(|| anyhow::anyhow!("interval '{}' is too long", input))?;
+    Ok(Duration::from_secs(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Datelike, TimeZone, Timelike};
+
+    fn at(day: u32, hour: u32, minute: u32, second: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2026, 3, day, hour, minute, second)
+            .unwrap()
+    }
+
+    #[test]
+    fn five_field_cron_runs_on_the_minute() {
+        let schedule = Schedule::parse("*/15 * * * *").unwrap();
+        let next = schedule.next_run(None, at(10, 9, 7, 30)).unwrap();
+        assert_eq!(next, at(10, 9, 15, 0));
+    }
+
+    #[test]
+    fn six_field_cron_has_seconds() {
+        let schedule = Schedule::parse("30 5 * * * *").unwrap();
+        let next = schedule.next_run(None, at(10, 9, 7, 0)).unwrap();
+        assert_eq!(next, at(10, 10, 5, 30));
+        assert!(Schedule::parse("@hourly").is_ok());
+    }
+
+    #[test]
+    fn every_reads_each_unit() {
+        for (spec, seconds) in [
+            ("every 45s", 45),
+            ("every 15m", 900),
+            ("every 15", 900),
+            ("@every 2h", 7200),
+            ("every 3 days", 3 * 86400),
+            ("every 1d", 86400),
+        ] {
+            match Schedule::parse(spec).unwrap() {
+                Schedule::Every(interval) => {
+                    assert_eq!(interval, Duration::from_secs(seconds), "{spec}")
+                }
+                other => panic!("{spec} parsed as {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn bad_schedules_are_rejected() {
+        for spec in [
+            "",
+            "every",
+            "every 0m",
+            "every -5m",
+            "every 5w",
+            "every m",
+            "every 18446744073709551615d",
+            "every 99999999999999999999s",
+            "* * *",
+            "61 * * * *",
+            "sometimes",
+        ] {
+            assert!(Schedule::parse(spec).is_err(), "{spec:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn interval_counts_from_the_last_start() {
+        let schedule = Schedule::parse("every 1h").unwrap();
+        let now = at(10, 12, 0, 0);
+        assert_eq!(schedule.next_run(None, now), Some(now));
+        assert_eq!(
+            schedule.next_run(Some(at(10, 11, 30, 0)), now),
+            Some(at(10, 12, 30, 0))
+        );
+        // An interval chrono can't represent never comes due
+        let forever = Schedule::Every(Duration::from_secs(u64::MAX / 2));
+        assert_eq!(forever.next_run(Some(now), now), None);
+    }
+
+    #[test]
+    fn next_run_crosses_midnight() {
+        let cron = Schedule::parse("30 0 * * *").unwrap();
+        assert_eq!(
+            cron.next_run(None, at(10, 23, 50, 0)).unwrap(),
+            at(11, 0, 30, 0)
+        );
+
+        let every = Schedule::parse("every 2h").unwrap();
+        let next = every
+            .next_run(Some(at(10, 23, 15, 0)), at(10, 23, 50, 0))
+            .unwrap();
+        assert_eq!((next.day(), next.hour(), next.minute()), (11, 1, 15));
+    }
+}
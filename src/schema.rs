@@ -0,0 +1,210 @@
+use crate::config::Config;
+use crate::diagnostics::SourceMap;
+use serde_json::{Map, Value};
+
+// Bump when the config layout changes, and add a step to `migrate` that
+// upgrades files written for the previous version. Adding an optional field
+// counts: unknown fields are rejected, so without a bump an older build
+// would fail on the new key instead of naming the version it needs.
+//
+// 1: no `version` key. Unknown keys were ignored and `rate_limit` /
+//    `global_rate_limit` could be left out.
+// 2: `version`, unknown keys rejected, both rate limits written out.
+// 3: adds operation `schedule`, `retries`, `retry_backoff`, `compression`,
+//    `encryption` and `copy_strategy`; rate limit `schedule`,
+//    `burst_bytes`, `files_per_second`, `metadata_ops_per_second` and
+//    `adaptive`; top-level `free_space_margin`; the Archive, Extract,
+//    Decrypt and Repository operation types. All optional, so version 2
+//    files need no changes.
+pub const CURRENT_VERSION: u32 = 3;
+
+/// JSON Schema for config files, for editors that validate YAML/TOML/JSON.
+pub fn json_schema() -> anyhow::Result<String> {
+    let schema = schemars::schema_for!(Config);
+    Ok(serde_json::to_string_pretty(&schema)?)
+}
+
+/// Upgrades a parsed config document to `CURRENT_VERSION` in place.
+/// Returns the version the document was written for when it had to be
+/// migrated. Files without a `version` key predate versioning (version 1).
+pub fn migrate(document: &mut Value) -> anyhow::Result<Option<u32>> {
+    let Some(root) = document.as_object_mut() else {
+        anyhow::bail!("expected a mapping at the top level");
+    };
+    let version = match root.get("version") {
+        None => 1,
+        Some(v) => v
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| anyhow::anyhow!("version must be a whole number, got {}", v))?,
+    };
+    if version > CURRENT_VERSION {
+        anyhow::bail!(
+            "config is for schema version {}, but this build only understands up to version {}",
+            version,
+            CURRENT_VERSION
+        );
+    }
+    if version == CURRENT_VERSION {
+        return Ok(None);
+    }
+
+    if version < 2 {
+        // Version 1 files could leave either rate limit out, taking the
+        // disabled default. Spell it out so the upgraded file shows where
+        // the limits go.
+        if let Some(Value::Array(operations)) = root.get_mut("operations") {
+            for op in operations.iter_mut().filter_map(Value::as_object_mut) {
+                op.entry("rate_limit").or_insert_with(disabled_rate_limit);
+            }
+        }
+        root.entry("global_rate_limit")
+            .or_insert_with(disabled_rate_limit);
+    }
+
+    root.insert("version".to_string(), Value::from(CURRENT_VERSION));
+    Ok(Some(version))
+}
+
+fn disabled_rate_limit() -> Value {
+    let mut limit = Map::new();
+    limit.insert("enabled".to_string(), Value::Bool(false));
+    Value::Object(limit)
+}
+
+/// Describes every key in `document` that the schema doesn't know, with a
+/// "did you mean" hint when a known key is close. `source` adds YAML
+/// line/column positions.
+pub fn unknown_fields(document: &Value, source: Option<&SourceMap>) -> Vec<String> {
+    let schema = schemars::schema_for!(Config);
+    let root = schema.as_value();
+    let mut problems = Vec::new();
+    check_object(root, root, document, "", source, &mut problems);
+    problems
+}
+
+fn check_object(
+    root: &Value,
+    schema: &Value,
+    value: &Value,
+    path: &str,
+    source: Option<&SourceMap>,
+    problems: &mut Vec<String>,
+) {
+    let candidates = object_schemas(root, schema);
+
+    match value {
+        Value::Object(map) => {
+            // Maps such as `variables` accept any key
+            let is_map = candidates
+                .iter()
+                .any(|s| s.get("additionalProperties").is_some_and(Value::is_object));
+            let properties: Vec<(&String, &Value)> = candidates
+                .iter()
+                .filter_map(|s| s.get("properties").and_then(Value::as_object))
+                .flat_map(|p| p.iter())
+                .collect();
+
+            for (key, child) in map {
+                let child_path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                match properties.iter().find(|(name, _)| *name == key) {
+                    Some((_, child_schema)) => {
+                        check_object(root, child_schema, child, &child_path, source, problems)
+                    }
+                    None if is_map || properties.is_empty() => {}
+                    None => {
+                        let mut problem = format!("unknown field '{}'", child_path);
+                        if let Some((line, column)) = source.and_then(|s| s.get(&child_path)) {
+                            problem.push_str(&format!(" (line {}, column {})", line, column));
+                        }
+                        if let Some(suggestion) =
+                            closest(key, properties.iter().map(|(name, _)| name.as_str()))
+                        {
+                            problem.push_str(&format!("; did you mean '{}'?", suggestion));
+                        }
+                        problems.push(problem);
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            for (idx, item) in items.iter().enumerate() {
+                let item_path = format!("{}[{}]", path, idx);
+                for item_schema in candidates.iter().filter_map(|s| s.get("items")) {
+                    check_object(root, item_schema, item, &item_path, source, problems);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// The concrete schemas a node stands for, following `$ref` and the
+// `anyOf`/`allOf`/`oneOf` wrappers schemars uses for Option and defaults.
+fn object_schemas<'a>(root: &'a Value, schema: &'a Value) -> Vec<&'a Value> {
+    let mut found = vec![schema];
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str)
+        && let Some(target) = reference
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+    {
+        found.extend(object_schemas(root, target));
+    }
+    for keyword in ["anyOf", "allOf", "oneOf"] {
+        if let Some(variants) = schema.get(keyword).and_then(Value::as_array) {
+            for variant in variants {
+                found.extend(object_schemas(root, variant));
+            }
+        }
+    }
+    found
+}
+
+fn closest<'a>(key: &str, known: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = (key.len() / 3).max(2);
+    known
+        .map(|name| (strsim::levenshtein(key, name), name))
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn migrate_spells_out_version_1_rate_limits() {
+        let mut document = json!({"operations": [{"name": "a"}]});
+        assert_eq!(migrate(&mut document).unwrap(), Some(1));
+        assert_eq!(
+            document,
+            json!({
+                "version": CURRENT_VERSION,
+                "operations": [{"name": "a", "rate_limit": {"enabled": false}}],
+                "global_rate_limit": {"enabled": false},
+            })
+        );
+    }
+
+    #[test]
+    fn migrate_only_restamps_version_2() {
+        let operations = json!([{"name": "a", "rate_limit": {"enabled": true}}]);
+        let mut document = json!({"version": 2, "operations": operations});
+        assert_eq!(migrate(&mut document).unwrap(), Some(2));
+        assert_eq!(
+            document,
+            json!({"version": CURRENT_VERSION, "operations": operations})
+        );
+
+        let mut current = json!({"version": CURRENT_VERSION});
+        assert_eq!(migrate(&mut current).unwrap(), None);
+        let mut newer = json!({"version": CURRENT_VERSION + 1});
+        assert!(migrate(&mut newer).is_err());
+    }
+}
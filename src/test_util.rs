@@ -0,0 +1,49 @@
+// Helpers shared by the unit tests
+use crate::config::RateLimit;
+use crate::rate_limiter::{GlobalLimiter, RateLimiter};
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A fresh directory under the system temp dir, removed with everything in
+/// it when dropped, failed assertions included.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// `name` only makes leftovers of a killed test run recognisable.
+    pub fn new(name: &str) -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "rusty_bucket_{}_{}_{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A rate limiter that never waits.
+pub fn unlimited() -> RateLimiter {
+    let limit = RateLimit::default();
+    RateLimiter::for_operation(&limit, &GlobalLimiter::new(&limit), 1)
+}
@@ -1,5 +1,11 @@
-use crate::config::{Config, OperationType};
-use crate::file_ops::{FileManager, OperationResult};
+use crate::config::{Config, FileOperation, OperationType, RateLimit, format_size, parse_rate};
+use crate::diagnostics::{self, Diagnostic, Severity};
+use crate::file_browser::FileBrowser;
+use crate::file_ops::{self, FileManager, OperationResult};
+use crate::lock::RunLock;
+use crate::rate_limiter::GlobalLimiter;
+use crate::scheduler::Schedule;
+use chrono::{DateTime, Local};
 use crossterm::{
     event::{DisableMouseCapture, EnableMouseCapture},
     execute,
@@ -14,16 +20,21 @@ use ratatui::{
     text::{Line, Span},
     widgets::{Block, Borders, Gauge, List, ListItem, ListState, Paragraph, Row, Table, Tabs},
 };
+use std::collections::HashMap;
 use std::io;
 use std::path::PathBuf;
 use std::sync::Arc;
 
+#[derive(Clone, Copy, PartialEq)]
 pub enum InputMode {
     Normal,
     EditingOperation,
     EditingSource,
     EditingDestination,
     EditingType,
+    EditingRateLimitEnabled,
+    EditingBytesPerSecond,
+    EditingMegabytesPerMinute,
 }
 
 pub struct App {
@@ -31,11 +42,9 @@ pub struct App {
     pub current_tab: usize,
     pub operations_state: ListState,
     pub input_mode: InputMode,
-    pub operation_fields: Vec<(String, String, OperationType)>,
     pub current_field: usize,
     pub results: Vec<OperationResult>,
     pub show_results: bool,
-    pub new_operation: (String, String, OperationType),
     pub editing_operation: (String, String, String, OperationType), // Fixed: 4-element tuple
     pub message: String,
     pub message_timer: usize,
@@ -45,37 +54,39 @@ pub struct App {
     pub details_scroll: u16,
     pub edit_buffer: String,
     pub edit_cursor_position: usize,
+    pub edit_error: Option<String>,
+    pub editing_global_limit: bool,
+    pub file_browser: Option<FileBrowser>,
+    pub config_diagnostics: Vec<Diagnostic>,
+    // The operations' schedules by spec, parsed when the config changes
+    // rather than on every frame; `None` for specs that don't parse
+    schedules: HashMap<String, Option<Schedule>>,
+}
+
+fn parse_schedules(config: &Config) -> HashMap<String, Option<Schedule>> {
+    config
+        .operations
+        .iter()
+        .filter_map(|op| op.schedule.clone())
+        .map(|spec| {
+            let schedule = Schedule::parse(&spec).ok();
+            (spec, schedule)
+        })
+        .collect()
 }
 
 impl App {
     pub fn new(config: Config, report_dir: &str) -> Self {
-        let operation_fields = config
-            .operations
-            .iter()
-            .map(|op| {
-                (
-                    op.name.clone(),
-                    op.origin.to_string_lossy().to_string(),
-                    op.destination.to_string_lossy().to_string(),
-                    op.operation_type.clone(),
-                )
-            })
-            .fold(Vec::new(), |mut acc, (name, src, dst, op_type)| {
-                acc.push((name.clone(), src.clone(), op_type.clone()));
-                acc.push((name, dst, op_type));
-                acc
-            });
-
+        let config_diagnostics = diagnostics::check_config(&config);
+        let schedules = parse_schedules(&config);
         Self {
             config,
             current_tab: 0,
             operations_state: ListState::default(),
             input_mode: InputMode::Normal,
-            operation_fields,
             current_field: 0,
             results: Vec::new(),
             show_results: false,
-            new_operation: (String::new(), String::new(), OperationType::Copy),
             editing_operation: (
                 String::new(),
                 String::new(),
@@ -90,6 +101,39 @@ impl App {
             details_scroll: 0,
             edit_buffer: String::new(),
             edit_cursor_position: 0,
+            edit_error: None,
+            editing_global_limit: false,
+            file_browser: None,
+            config_diagnostics,
+            schedules,
+        }
+    }
+
+    pub fn refresh_diagnostics(&mut self) {
+        self.config_diagnostics = diagnostics::check_config(&self.config);
+        self.schedules = parse_schedules(&self.config);
+    }
+
+    /// When daemon mode would next run `op`, counting from its last run in
+    /// this session.
+    pub fn next_run_text(&self, op: &FileOperation) -> String {
+        let Some(spec) = &op.schedule else {
+            return "not scheduled".to_string();
+        };
+        let Some(Some(schedule)) = self.schedules.get(spec) else {
+            return "invalid schedule".to_string();
+        };
+        let last_start = self
+            .results
+            .iter()
+            .filter(|r| r.operation_name == op.name)
+            .map(|r| DateTime::<Local>::from(r.start_time))
+            .max();
+        let now = Local::now();
+        match schedule.next_run(last_start, now) {
+            Some(next) if next <= now => "due now".to_string(),
+            Some(next) => format!("next {}", next.format("%Y-%m-%d %H:%M")),
+            None => "never".to_string(),
         }
     }
 
@@ -139,22 +183,40 @@ impl App {
     }
 
     pub fn execute_operations(&mut self) {
+        let locks = match RunLock::acquire_all(self.config.files.iter().map(|f| f.path.as_path())) {
+            Ok(locks) => locks,
+            Err(e) => {
+                self.show_message(format!("Not running: {}", e));
+                return;
+            }
+        };
+
         let callback: Arc<dyn Fn(String) + Send + Sync> = Arc::new(|msg| {
             println!("Progress: {}", msg);
         });
 
-        let results = FileManager::execute_operations(&self.config.operations, &self.config.global_rate_limit, Some(callback));
+        let results = FileManager::execute_operations(
+            &self.config.operations,
+            &GlobalLimiter::new(&self.config.global_rate_limit),
+            &self.config.space_margin(),
+            Some(callback),
+        );
 
         self.results = results;
         self.show_results = true;
 
         self.generate_reports();
 
-        self.show_message("Operations completed! Reports saved.".to_string());
+        let mut message = "Operations completed! Reports saved.".to_string();
+        for note in locks.iter().filter_map(|lock| lock.takeover_note()) {
+            message.push_str(&format!("\n{}", note));
+        }
+        self.show_message(message);
     }
 
     fn generate_reports(&mut self) {
         let summary_report = FileManager::generate_report(&self.results);
+        let stamp = file_ops::report_stamp();
 
         let summary_path = self.report_dir.join("operation_summary.txt");
         if let Err(e) = std::fs::write(&summary_path, &summary_report) {
@@ -166,14 +228,16 @@ impl App {
             ));
         }
 
-        if let Err(e) = FileManager::generate_detailed_report(&self.results, &self.report_dir) {
+        if let Err(e) =
+            FileManager::generate_detailed_report(&self.results, &self.report_dir, &stamp)
+        {
             self.show_message(format!(
                 "Warning: Could not generate detailed report: {}",
                 e
             ));
         }
 
-        match FileManager::save_operation_reports_to_destinations(&self.results) {
+        match FileManager::save_operation_reports_to_destinations(&self.results, &stamp) {
             Ok(saved_paths) => {
                 for path in saved_paths {
                     println!("{}", path);
@@ -188,7 +252,11 @@ impl App {
             }
         }
 
-        match FileManager::save_file_list_reports(&self.results) {
+        if let Err(e) = FileManager::save_json_report(&self.results, &self.report_dir, &stamp) {
+            self.show_message(format!("Warning: Could not save JSON report: {}", e));
+        }
+
+        match FileManager::save_file_list_reports(&self.results, &stamp) {
             Ok(saved_paths) => {
                 for path in saved_paths {
                     println!("{}", path);
@@ -259,22 +327,111 @@ impl App {
     }
 
     pub fn start_editing(&mut self) {
-        if let Some(selected_idx) = self.operations_state.selected() {
-            if selected_idx < self.config.operations.len() {
-                let op = &self.config.operations[selected_idx];
-                self.editing_operation = (
-                    op.name.clone(),
-                    op.origin.to_string_lossy().to_string(),
-                    op.destination.to_string_lossy().to_string(),
-                    op.operation_type.clone(),
-                );
-                self.input_mode = InputMode::EditingOperation;
-                self.edit_buffer = op.name.clone();
-                self.edit_cursor_position = self.edit_buffer.len();
-            }
+        if let Some(selected_idx) = self.operations_state.selected()
+            && selected_idx < self.config.operations.len()
+        {
+            let op = &self.config.operations[selected_idx];
+            self.editing_operation = (
+                op.name.clone(),
+                op.origin.to_string_lossy().to_string(),
+                op.destination.to_string_lossy().to_string(),
+                op.operation_type.clone(),
+            );
+            self.editing_global_limit = false;
+            self.enter_edit_field(InputMode::EditingOperation);
         }
     }
 
+    pub fn start_editing_global_limit(&mut self) {
+        self.editing_global_limit = true;
+        self.enter_edit_field(InputMode::EditingRateLimitEnabled);
+    }
+
+    fn edit_fields(&self) -> &'static [InputMode] {
+        if self.editing_global_limit {
+            &[
+                InputMode::EditingRateLimitEnabled,
+                InputMode::EditingBytesPerSecond,
+                InputMode::EditingMegabytesPerMinute,
+            ]
+        } else {
+            &[
+                InputMode::EditingOperation,
+                InputMode::EditingSource,
+                InputMode::EditingDestination,
+                InputMode::EditingType,
+                InputMode::EditingRateLimitEnabled,
+                InputMode::EditingBytesPerSecond,
+                InputMode::EditingMegabytesPerMinute,
+            ]
+        }
+    }
+
+    fn editing_rate_limit(&self) -> Option<&RateLimit> {
+        if self.editing_global_limit {
+            return Some(&self.config.global_rate_limit);
+        }
+        self.operations_state
+            .selected()
+            .and_then(|idx| self.config.operations.get(idx))
+            .map(|op| &op.rate_limit)
+    }
+
+    fn editing_rate_limit_mut(&mut self) -> Option<&mut RateLimit> {
+        if self.editing_global_limit {
+            return Some(&mut self.config.global_rate_limit);
+        }
+        self.operations_state
+            .selected()
+            .and_then(|idx| self.config.operations.get_mut(idx))
+            .map(|op| &mut op.rate_limit)
+    }
+
+    pub fn field_value(&self, mode: InputMode) -> String {
+        let rate_limit = self.editing_rate_limit();
+        match mode {
+            InputMode::EditingOperation => self.editing_operation.0.clone(),
+            InputMode::EditingSource => self.editing_operation.1.clone(),
+            InputMode::EditingDestination => self.editing_operation.2.clone(),
+            InputMode::EditingType => match self.editing_operation.3 {
+                OperationType::Copy => "copy".to_string(),
+                OperationType::Move => "move".to_string(),
+                OperationType::Archive => "archive".to_string(),
+                OperationType::Extract => "extract".to_string(),
+                OperationType::Decrypt => "decrypt".to_string(),
+                OperationType::Repository => "repository".to_string(),
+            },
+            InputMode::EditingRateLimitEnabled => match rate_limit {
+                Some(rl) if rl.enabled => "yes".to_string(),
+                _ => "no".to_string(),
+            },
+            InputMode::EditingBytesPerSecond => rate_limit
+                .and_then(|rl| rl.bytes_per_second)
+                .map(|bps| bps.to_string())
+                .unwrap_or_default(),
+            InputMode::EditingMegabytesPerMinute => rate_limit
+                .and_then(|rl| rl.megabytes_per_minute)
+                .map(|mb_min| mb_min.to_string())
+                .unwrap_or_default(),
+            InputMode::Normal => String::new(),
+        }
+    }
+
+    fn enter_edit_field(&mut self, mode: InputMode) {
+        self.edit_buffer = self.field_value(mode);
+        self.edit_cursor_position = self.edit_buffer.len();
+        self.edit_error = None;
+        self.input_mode = mode;
+    }
+
+    pub fn cancel_edit(&mut self) {
+        self.input_mode = InputMode::Normal;
+        self.edit_buffer.clear();
+        self.edit_cursor_position = 0;
+        self.edit_error = None;
+        self.show_message("Edit cancelled".to_string());
+    }
+
     pub fn handle_edit_input(&mut self, c: char) {
         if self.edit_cursor_position <= self.edit_buffer.len() {
             self.edit_buffer.insert(self.edit_cursor_position, c);
@@ -315,100 +472,169 @@ impl App {
         self.edit_cursor_position = self.edit_buffer.len();
     }
 
-    pub fn save_edit(&mut self) {
-        if let Some(selected_idx) = self.operations_state.selected() {
-            if selected_idx < self.config.operations.len() {
-                match self.input_mode {
-                    InputMode::EditingOperation => {
-                        self.editing_operation.0 = self.edit_buffer.clone();
-                        self.config.operations[selected_idx].name = self.edit_buffer.clone();
+    pub fn open_file_browser(&mut self) {
+        if matches!(
+            self.input_mode,
+            InputMode::EditingSource | InputMode::EditingDestination
+        ) {
+            self.file_browser = Some(FileBrowser::open(&self.edit_buffer));
+        }
+    }
+
+    /// Fills the edit buffer with the highlighted browser entry; the value is
+    /// applied to the operation when the field is saved as usual.
+    pub fn choose_browser_entry(&mut self) {
+        if let Some(browser) = self.file_browser.take()
+            && let Some(entry) = browser.selected_entry()
+        {
+            self.edit_buffer = entry.path.to_string_lossy().to_string();
+            self.edit_cursor_position = self.edit_buffer.len();
+            self.edit_error = None;
+        }
+    }
+
+    /// Applies the edit buffer to the field being edited. Returns `false`
+    /// and leaves the popup open with `edit_error` set if the value is invalid.
+    pub fn save_edit(&mut self) -> bool {
+        let value = self.edit_buffer.trim().to_string();
+
+        match self.input_mode {
+            InputMode::EditingRateLimitEnabled => {
+                let enabled = match value.to_lowercase().as_str() {
+                    "yes" | "y" | "true" | "on" | "1" => true,
+                    "no" | "n" | "false" | "off" | "0" => false,
+                    _ => {
+                        self.edit_error = Some("Enter yes or no".to_string());
+                        return false;
                     }
-                    InputMode::EditingSource => {
-                        self.editing_operation.1 = self.edit_buffer.clone();
-                        self.config.operations[selected_idx].origin =
-                            PathBuf::from(&self.edit_buffer);
+                };
+                if let Some(rl) = self.editing_rate_limit_mut() {
+                    rl.enabled = enabled;
+                }
+            }
+            InputMode::EditingBytesPerSecond | InputMode::EditingMegabytesPerMinute => {
+                let per_second = matches!(self.input_mode, InputMode::EditingBytesPerSecond);
+                let parsed = if value.is_empty() {
+                    None
+                } else {
+                    let (unit, period) = if per_second {
+                        (1, 1)
+                    } else {
+                        (1024 * 1024, 60)
+                    };
+                    match parse_rate(&value, unit, period) {
+                        Ok(bps) if per_second => Some(bps),
+                        Ok(bps) => {
+                            let mb_min = bps * 60 / (1024 * 1024);
+                            if mb_min == 0 {
+                                self.edit_error =
+                                    Some("Rate is below 1 MB/min; use bytes/second".to_string());
+                                return false;
+                            }
+                            Some(mb_min)
+                        }
+                        Err(e) => {
+                            self.edit_error = Some(e.to_string());
+                            return false;
+                        }
                     }
-                    InputMode::EditingDestination => {
-                        self.editing_operation.2 = self.edit_buffer.clone();
-                        self.config.operations[selected_idx].destination =
-                            PathBuf::from(&self.edit_buffer);
+                };
+                if let Some(rl) = self.editing_rate_limit_mut() {
+                    if per_second {
+                        rl.bytes_per_second = parsed;
+                    } else {
+                        rl.megabytes_per_minute = parsed;
                     }
-                    InputMode::EditingType => {
-                        if self.edit_buffer.to_lowercase() == "copy" {
-                            self.editing_operation.3 = OperationType::Copy;
-                            self.config.operations[selected_idx].operation_type =
-                                OperationType::Copy;
-                        } else if self.edit_buffer.to_lowercase() == "move" {
-                            self.editing_operation.3 = OperationType::Move;
-                            self.config.operations[selected_idx].operation_type =
-                                OperationType::Move;
+                }
+            }
+            _ => {
+                if let Some(selected_idx) = self.operations_state.selected()
+                    && selected_idx < self.config.operations.len()
+                {
+                    match self.input_mode {
+                        InputMode::EditingOperation => {
+                            if value.is_empty() {
+                                self.edit_error = Some("Name cannot be empty".to_string());
+                                return false;
+                            }
+                            self.editing_operation.0 = self.edit_buffer.clone();
+                            self.config.operations[selected_idx].name = self.edit_buffer.clone();
+                        }
+                        InputMode::EditingSource => {
+                            self.editing_operation.1 = self.edit_buffer.clone();
+                            self.config.operations[selected_idx].origin =
+                                PathBuf::from(&self.edit_buffer);
+                        }
+                        InputMode::EditingDestination => {
+                            self.editing_operation.2 = self.edit_buffer.clone();
+                            self.config.operations[selected_idx].destination =
+                                PathBuf::from(&self.edit_buffer);
+                        }
+                        InputMode::EditingType => {
+                            let operation_type = match value.to_lowercase().as_str() {
+                                "copy" => OperationType::Copy,
+                                "move" => OperationType::Move,
+                                "archive" => OperationType::Archive,
+                                "extract" => OperationType::Extract,
+                                "decrypt" => OperationType::Decrypt,
+                                "repository" => OperationType::Repository,
+                                _ => {
+                                    self.edit_error = Some(
+                                        "Enter copy, move, archive, extract, decrypt or repository"
+                                            .to_string(),
+                                    );
+                                    return false;
+                                }
+                            };
+                            self.editing_operation.3 = operation_type.clone();
+                            self.config.operations[selected_idx].operation_type = operation_type;
                         }
+                        _ => {}
                     }
-                    InputMode::Normal => {}
                 }
-
-                self.input_mode = InputMode::Normal;
-                self.edit_buffer.clear();
-                self.edit_cursor_position = 0;
-                self.show_message("Operation updated".to_string());
             }
         }
+
+        self.input_mode = InputMode::Normal;
+        self.edit_buffer.clear();
+        self.edit_cursor_position = 0;
+        self.edit_error = None;
+        self.refresh_diagnostics();
+        if self.editing_global_limit {
+            self.show_message("Global rate limit updated".to_string());
+        } else {
+            self.show_message("Operation updated".to_string());
+        }
+        true
     }
 
     pub fn next_edit_field(&mut self) {
-        match self.input_mode {
-            InputMode::EditingOperation => {
-                self.save_edit();
-                self.input_mode = InputMode::EditingSource;
-                self.edit_buffer = self.editing_operation.1.clone();
-                self.edit_cursor_position = self.edit_buffer.len();
-            }
-            InputMode::EditingSource => {
-                self.save_edit();
-                self.input_mode = InputMode::EditingDestination;
-                self.edit_buffer = self.editing_operation.2.clone();
-                self.edit_cursor_position = self.edit_buffer.len();
-            }
-            InputMode::EditingDestination => {
-                self.save_edit();
-                self.input_mode = InputMode::EditingType;
-                self.edit_buffer = match self.editing_operation.3 {
-                    OperationType::Copy => "copy".to_string(),
-                    OperationType::Move => "move".to_string(),
-                };
-                self.edit_cursor_position = self.edit_buffer.len();
-            }
-            InputMode::EditingType => {
-                self.save_edit();
-            }
-            InputMode::Normal => {}
+        let mode = self.input_mode;
+        let fields = self.edit_fields();
+        let next = fields
+            .iter()
+            .position(|m| *m == mode)
+            .and_then(|idx| fields.get(idx + 1))
+            .copied();
+        if self.save_edit()
+            && let Some(next) = next
+        {
+            self.enter_edit_field(next);
         }
     }
 
     pub fn previous_edit_field(&mut self) {
-        match self.input_mode {
-            InputMode::EditingType => {
-                self.save_edit();
-                self.input_mode = InputMode::EditingDestination;
-                self.edit_buffer = self.editing_operation.2.clone();
-                self.edit_cursor_position = self.edit_buffer.len();
-            }
-            InputMode::EditingDestination => {
-                self.save_edit();
-                self.input_mode = InputMode::EditingSource;
-                self.edit_buffer = self.editing_operation.1.clone();
-                self.edit_cursor_position = self.edit_buffer.len();
-            }
-            InputMode::EditingSource => {
-                self.save_edit();
-                self.input_mode = InputMode::EditingOperation;
-                self.edit_buffer = self.editing_operation.0.clone();
-                self.edit_cursor_position = self.edit_buffer.len();
-            }
-            InputMode::EditingOperation => {
-                self.save_edit();
-            }
-            InputMode::Normal => {}
+        let mode = self.input_mode;
+        let fields = self.edit_fields();
+        let previous = fields
+            .iter()
+            .position(|m| *m == mode)
+            .and_then(|idx| idx.checked_sub(1))
+            .map(|idx| fields[idx]);
+        if self.save_edit()
+            && let Some(previous) = previous
+        {
+            self.enter_edit_field(previous);
         }
     }
 }
@@ -448,6 +674,7 @@ fn run_app_internal<B: Backend>(terminal: &mut Terminal<B>, app: &mut App) -> io
             }
 
             match app.input_mode {
+                _ if app.file_browser.is_some() => handle_file_browser_key(app, key),
                 InputMode::Normal => match key.code {
                     ratatui::crossterm::event::KeyCode::Char('q') => return Ok(()),
                     ratatui::crossterm::event::KeyCode::Tab => app.next_tab(),
@@ -466,43 +693,43 @@ fn run_app_internal<B: Backend>(terminal: &mut Terminal<B>, app: &mut App) -> io
                         3 => app.scroll_details_up(),
                         _ => {}
                     },
-                    ratatui::crossterm::event::KeyCode::Char('e') => {
-                        app.start_editing();
-                    }
+                    ratatui::crossterm::event::KeyCode::Char('e') => match app.current_tab {
+                        0 => app.start_editing(),
+                        1 => app.start_editing_global_limit(),
+                        _ => {}
+                    },
                     ratatui::crossterm::event::KeyCode::Char('r') => {
                         app.execute_operations();
                     }
-                    ratatui::crossterm::event::KeyCode::Char('s') => {
-                        if let Err(e) = app.config.save_to_file("config.yaml") {
-                            app.show_message(format!("Save failed: {}", e));
-                        } else {
-                            app.show_message("Configuration saved!".to_string());
-                        }
-                    }
-                    ratatui::crossterm::event::KeyCode::Char('d') => {
-                        if app.current_tab == 2 && !app.results.is_empty() {
-                            app.toggle_details();
-                        }
+                    ratatui::crossterm::event::KeyCode::Char('s') => match app.config.save() {
+                        Ok(written) if written.len() == 1 => app.show_message(format!(
+                            "Configuration saved to {}",
+                            written[0].display()
+                        )),
+                        Ok(written) => app.show_message(format!(
+                            "Configuration saved to {} files",
+                            written.len()
+                        )),
+                        Err(e) => app.show_message(format!("Save failed: {}", e)),
+                    },
+                    ratatui::crossterm::event::KeyCode::Char('d')
+                        if app.current_tab == 2 && !app.results.is_empty() =>
+                    {
+                        app.toggle_details();
                     }
-                    ratatui::crossterm::event::KeyCode::Enter => {
-                        if app.current_tab == 2 && !app.results.is_empty() {
-                            app.toggle_details();
-                        }
+                    ratatui::crossterm::event::KeyCode::Enter
+                        if app.current_tab == 2 && !app.results.is_empty() =>
+                    {
+                        app.toggle_details();
                     }
                     ratatui::crossterm::event::KeyCode::Char('p') => {
                         app.show_message(format!("Report directory: {}", app.report_dir.display()));
                     }
                     _ => {}
                 },
-                InputMode::EditingOperation
-                | InputMode::EditingSource
-                | InputMode::EditingDestination
-                | InputMode::EditingType => match key.code {
+                _ => match key.code {
                     ratatui::crossterm::event::KeyCode::Esc => {
-                        app.input_mode = InputMode::Normal;
-                        app.edit_buffer.clear();
-                        app.edit_cursor_position = 0;
-                        app.show_message("Edit cancelled".to_string());
+                        app.cancel_edit();
                     }
                     ratatui::crossterm::event::KeyCode::Enter => {
                         app.save_edit();
@@ -531,6 +758,13 @@ fn run_app_internal<B: Backend>(terminal: &mut Terminal<B>, app: &mut App) -> io
                     ratatui::crossterm::event::KeyCode::Delete => {
                         app.handle_delete();
                     }
+                    ratatui::crossterm::event::KeyCode::Char('o')
+                        if key
+                            .modifiers
+                            .contains(ratatui::crossterm::event::KeyModifiers::CONTROL) =>
+                    {
+                        app.open_file_browser();
+                    }
                     ratatui::crossterm::event::KeyCode::Char(c) => {
                         app.handle_edit_input(c);
                     }
@@ -548,6 +782,36 @@ fn run_app_internal<B: Backend>(terminal: &mut Terminal<B>, app: &mut App) -> io
     }
 }
 
+fn handle_file_browser_key(app: &mut App, key: ratatui::crossterm::event::KeyEvent) {
+    use ratatui::crossterm::event::{KeyCode, KeyModifiers};
+
+    let Some(browser) = app.file_browser.as_mut() else {
+        return;
+    };
+
+    match key.code {
+        KeyCode::Esc => {
+            app.file_browser = None;
+        }
+        KeyCode::Enter => {
+            app.choose_browser_entry();
+        }
+        KeyCode::Down => browser.select_next(),
+        KeyCode::Up => browser.select_previous(),
+        KeyCode::Right => {
+            browser.enter_selected();
+        }
+        KeyCode::Left => browser.go_parent(),
+        KeyCode::Tab => browser.complete(),
+        KeyCode::Backspace => browser.pop_filter(),
+        KeyCode::Char('t') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            browser.toggle_hidden();
+        }
+        KeyCode::Char(c) => browser.push_filter(c),
+        _ => {}
+    }
+}
+
 fn ui(f: &mut Frame, app: &mut App) {
     let size = f.area();
 
@@ -595,19 +859,16 @@ fn ui(f: &mut Frame, app: &mut App) {
     let help_text = match app.input_mode {
         InputMode::Normal => match app.current_tab {
             0 => "Help: ↑/↓/j/k=Select, e=Edit, Tab=Switch tabs, r=Run, s=Save, q=Quit",
-            1 => "Help: Tab=Switch tabs, r=Run operations, s=Save config, q=Quit",
+            1 => {
+                "Help: e=Edit global rate limit, Tab=Switch tabs, r=Run operations, s=Save config, q=Quit"
+            }
             2 => {
                 "Help: ↑/↓/j/k=Select, Enter/d=Details, Tab=Switch tabs, p=Show report path, q=Quit"
             }
             3 => "Help: ↑/↓=Scroll, Tab=Switch tabs, q=Quit",
             _ => "Help: Tab=Switch tabs, q=Quit",
         },
-        InputMode::EditingOperation
-        | InputMode::EditingSource
-        | InputMode::EditingDestination
-        | InputMode::EditingType => {
-            "EDIT MODE: ↑/↓/Tab=Navigate fields, Enter=Save, Esc=Cancel, Type to edit"
-        }
+        _ => "EDIT MODE: ↑/↓/Tab=Navigate fields, Enter=Save, Esc=Cancel, Type to edit",
     };
 
     let help_widget = Paragraph::new(help_text)
@@ -616,13 +877,13 @@ fn ui(f: &mut Frame, app: &mut App) {
     f.render_widget(help_widget, main_chunk[2]);
 
     // Render edit popup if in edit mode
-    if let InputMode::EditingOperation
-    | InputMode::EditingSource
-    | InputMode::EditingDestination
-    | InputMode::EditingType = app.input_mode
-    {
+    if app.input_mode != InputMode::Normal {
         render_edit_popup(f, app, size);
     }
+
+    if app.file_browser.is_some() {
+        render_file_browser(f, app, size);
+    }
 }
 
 fn render_operations_tab(f: &mut Frame, app: &mut App, area: Rect) {
@@ -646,7 +907,7 @@ fn render_operations_tab(f: &mut Frame, app: &mut App, area: Rect) {
                 Style::default().fg(Color::Yellow)
             };
 
-            let lines = vec![
+            let mut lines = vec![
                 Line::from(vec![
                     Span::raw("Name: "),
                     Span::styled(&op.name, name_style),
@@ -671,11 +932,28 @@ fn render_operations_tab(f: &mut Frame, app: &mut App, area: Rect) {
                         match op.operation_type {
                             OperationType::Copy => "Copy",
                             OperationType::Move => "Move",
+                            OperationType::Archive => "Archive",
+                            OperationType::Extract => "Extract",
+                            OperationType::Decrypt => "Decrypt",
+                            OperationType::Repository => "Repository",
                         },
                         Style::default().fg(Color::Magenta),
                     ),
                 ]),
+                Line::from(vec![
+                    Span::raw("Limit: "),
+                    Span::styled(op.rate_limit.describe(), Style::default().fg(Color::Blue)),
+                ]),
             ];
+            if let Some(schedule) = &op.schedule {
+                lines.push(Line::from(vec![
+                    Span::raw("Schedule: "),
+                    Span::styled(
+                        format!("{} ({})", schedule, app.next_run_text(op)),
+                        Style::default().fg(Color::LightGreen),
+                    ),
+                ]));
+            }
             ListItem::new(lines)
         })
         .collect();
@@ -720,8 +998,17 @@ fn render_config_tab(f: &mut Frame, app: &mut App, area: Rect) {
         .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
         .split(area);
 
-    let config_text = serde_yaml::to_string(&app.config)
-        .unwrap_or_else(|_| "Failed to serialize config".to_string());
+    let config_text = match app.config.render_files() {
+        // One section per file, showing what will be saved there
+        Ok(files) if files.len() > 1 => files
+            .iter()
+            .map(|(path, content)| format!("# {}\n{}", path.display(), content))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Ok(files) if files.len() == 1 => files[0].1.clone(),
+        _ => serde_yaml::to_string(&app.config.unexpanded())
+            .unwrap_or_else(|_| "Failed to serialize config".to_string()),
+    };
 
     let config_widget = Paragraph::new(config_text)
         .block(
@@ -733,7 +1020,7 @@ fn render_config_tab(f: &mut Frame, app: &mut App, area: Rect) {
 
     f.render_widget(config_widget, chunks[0]);
 
-    let stats_text = vec![
+    let mut stats_text = vec![
         Line::from("Statistics:"),
         Line::from(format!(
             "  Total operations: {}",
@@ -756,6 +1043,12 @@ fn render_config_tab(f: &mut Frame, app: &mut App, area: Rect) {
                 .count()
         )),
         Line::from(""),
+        Line::from("Global Rate Limit (e to edit):"),
+        Line::from(Span::styled(
+            format!("  {}", app.config.global_rate_limit.describe()),
+            Style::default().fg(Color::Magenta),
+        )),
+        Line::from(""),
         Line::from("Report Directory:"),
         Line::from(Span::styled(
             app.report_dir.to_string_lossy(),
@@ -763,11 +1056,65 @@ fn render_config_tab(f: &mut Frame, app: &mut App, area: Rect) {
         )),
     ];
 
+    if !app.config.files.is_empty() {
+        stats_text.push(Line::from(""));
+        stats_text.push(Line::from("Operation Sources:"));
+        let primary = &app.config.files[0].path;
+        for op in &app.config.operations {
+            let file = op.source_file.as_ref().unwrap_or(primary);
+            stats_text.push(Line::from(vec![
+                Span::raw(format!("  {} ", op.name)),
+                Span::styled(
+                    format!("← {}", file.display()),
+                    Style::default().fg(Color::Cyan),
+                ),
+            ]));
+        }
+    }
+
     let stats_widget = Paragraph::new(stats_text)
         .block(Block::default().borders(Borders::ALL).title("Stats"))
         .alignment(Alignment::Left);
 
-    f.render_widget(stats_widget, chunks[1]);
+    let side_chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(chunks[1]);
+
+    f.render_widget(stats_widget, side_chunks[0]);
+
+    let error_count = app
+        .config_diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    let warning_count = app.config_diagnostics.len() - error_count;
+
+    let diagnostic_items: Vec<ListItem> = if app.config_diagnostics.is_empty() {
+        vec![ListItem::new(Span::styled(
+            "✓ No problems found",
+            Style::default().fg(Color::Green),
+        ))]
+    } else {
+        app.config_diagnostics
+            .iter()
+            .map(|d| {
+                let style = match d.severity {
+                    Severity::Error => Style::default().fg(Color::Red),
+                    Severity::Warning => Style::default().fg(Color::Yellow),
+                };
+                ListItem::new(Line::from(Span::styled(d.to_string(), style)))
+            })
+            .collect()
+    };
+
+    let diagnostics_widget =
+        List::new(diagnostic_items).block(Block::default().borders(Borders::ALL).title(format!(
+            "Validation ({} errors, {} warnings)",
+            error_count, warning_count
+        )));
+
+    f.render_widget(diagnostics_widget, side_chunks[1]);
 }
 
 fn render_results_tab(f: &mut Frame, app: &mut App, area: Rect) {
@@ -915,15 +1262,13 @@ fn render_details_tab(f: &mut Frame, app: &mut App, area: Rect) {
                 let mut remaining = error.as_str();
                 while !remaining.is_empty() {
                     let end = if remaining.len() > max_line_len {
-                        let break_pos =
-                            remaining[..max_line_len].rfind(' ').unwrap_or(max_line_len);
-                        break_pos
+                        remaining[..max_line_len].rfind(' ').unwrap_or(max_line_len)
                     } else {
                         remaining.len()
                     };
 
                     details_text.push(Line::from(format!("  {}", &remaining[..end])));
-                    remaining = &remaining[end..].trim_start();
+                    remaining = remaining[end..].trim_start();
                 }
             }
 
@@ -1023,8 +1368,8 @@ fn render_details_tab(f: &mut Frame, app: &mut App, area: Rect) {
 }
 
 fn render_edit_popup(f: &mut Frame, app: &mut App, size: Rect) {
-    let popup_width = 60;
-    let popup_height = 12;
+    let popup_width = 60.min(size.width);
+    let popup_height = 14.min(size.height);
     let popup_x = (size.width - popup_width) / 2;
     let popup_y = (size.height - popup_height) / 2;
 
@@ -1033,34 +1378,37 @@ fn render_edit_popup(f: &mut Frame, app: &mut App, size: Rect) {
     let popup_block = Block::default()
         .borders(Borders::ALL)
         .style(Style::default().bg(Color::DarkGray))
-        .title("Edit Operation");
+        .title(if app.editing_global_limit {
+            "Edit Global Rate Limit"
+        } else {
+            "Edit Operation"
+        });
 
     f.render_widget(popup_block, popup_area);
 
     let inner_area = Rect::new(
         popup_area.x + 2,
         popup_area.y + 2,
-        popup_area.width - 4,
-        popup_area.height - 4,
+        popup_area.width.saturating_sub(4),
+        popup_area.height.saturating_sub(4),
     );
 
     let field_name = match app.input_mode {
         InputMode::EditingOperation => "Operation Name",
         InputMode::EditingSource => "Source Path",
         InputMode::EditingDestination => "Destination Path",
-        InputMode::EditingType => "Operation Type (copy/move)",
+        InputMode::EditingType => "Operation Type (copy/move/archive/extract/decrypt/repository)",
+        InputMode::EditingRateLimitEnabled => "Rate Limit Enabled (yes/no)",
+        InputMode::EditingBytesPerSecond => "Bytes per Second (e.g. 262144, 10MiB/s)",
+        InputMode::EditingMegabytesPerMinute => "Megabytes per Minute (e.g. 600, 10MiB/s)",
         InputMode::Normal => "",
     };
 
-    let current_value = match app.input_mode {
-        InputMode::EditingOperation => &app.editing_operation.0,
-        InputMode::EditingSource => &app.editing_operation.1,
-        InputMode::EditingDestination => &app.editing_operation.2,
-        InputMode::EditingType => match app.editing_operation.3 {
-            OperationType::Copy => "copy",
-            OperationType::Move => "move",
-        },
-        InputMode::Normal => "",
+    let current_value = app.field_value(app.input_mode);
+    let current_value = if current_value.is_empty() {
+        "(not set)".to_string()
+    } else {
+        current_value
     };
 
     let field_text = vec![
@@ -1089,7 +1437,7 @@ fn render_edit_popup(f: &mut Frame, app: &mut App, size: Rect) {
 
     f.render_widget(input_block, input_area);
 
-    let input_text = format!("{}", app.edit_buffer);
+    let input_text = app.edit_buffer.to_string();
     let input_widget = Paragraph::new(input_text)
         .block(Block::default().borders(Borders::NONE))
         .style(Style::default().fg(Color::White));
@@ -1103,11 +1451,27 @@ fn render_edit_popup(f: &mut Frame, app: &mut App, size: Rect) {
         f.set_cursor_position((cursor_x, cursor_y)); // CORRECTED: Pass as tuple
     }
 
-    let help_area = Rect::new(inner_area.x, inner_area.y + 9, inner_area.width, 3);
+    if let Some(error) = &app.edit_error {
+        let error_area = Rect::new(inner_area.x, inner_area.y + 9, inner_area.width, 1);
+        let error_widget = Paragraph::new(format!("✗ {}", error))
+            .style(Style::default().fg(Color::Red).add_modifier(Modifier::BOLD));
+        f.render_widget(error_widget, error_area);
+    }
+
+    let help_area = Rect::new(inner_area.x, inner_area.y + 10, inner_area.width, 2);
 
     let help_text = vec![
         Line::from("Tab/Shift+Tab: Next/Prev field | Enter: Save | Esc: Cancel"),
-        Line::from("↑/↓/Home/End: Move cursor | Backspace/Delete: Delete"),
+        Line::from(
+            if matches!(
+                app.input_mode,
+                InputMode::EditingSource | InputMode::EditingDestination
+            ) {
+                "Ctrl+O: Browse | ←/→/Home/End: Move cursor | Backspace/Delete"
+            } else {
+                "←/→/Home/End: Move cursor | Backspace/Delete: Delete"
+            },
+        ),
     ];
 
     let help_widget = Paragraph::new(help_text)
@@ -1115,5 +1479,186 @@ fn render_edit_popup(f: &mut Frame, app: &mut App, size: Rect) {
         .style(Style::default().fg(Color::Gray))
         .alignment(Alignment::Center);
 
-    f.render_widget(help_widget, help_area);
+    f.render_widget(help_widget, help_area.intersection(size));
+}
+
+fn render_file_browser(f: &mut Frame, app: &mut App, size: Rect) {
+    let Some(browser) = app.file_browser.as_mut() else {
+        return;
+    };
+
+    let popup_width = 76.min(size.width);
+    let popup_height = 24.min(size.height);
+    let popup_area = Rect::new(
+        (size.width - popup_width) / 2,
+        (size.height - popup_height) / 2,
+        popup_width,
+        popup_height,
+    );
+
+    f.render_widget(ratatui::widgets::Clear, popup_area);
+    let popup_block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(Color::Black))
+        .title(format!(
+            "Browse{}",
+            if browser.show_hidden {
+                " (showing hidden)"
+            } else {
+                ""
+            }
+        ));
+    f.render_widget(popup_block, popup_area);
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .margin(1)
+        .constraints([
+            Constraint::Length(2),
+            Constraint::Min(3),
+            Constraint::Length(1),
+            Constraint::Length(2),
+        ])
+        .split(popup_area);
+
+    let header = vec![
+        Line::from(Span::styled(
+            browser.current_dir.to_string_lossy().to_string(),
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        )),
+        Line::from(vec![
+            Span::raw("Filter: "),
+            Span::styled(browser.filter.clone(), Style::default().fg(Color::Cyan)),
+        ]),
+    ];
+    f.render_widget(Paragraph::new(header), chunks[0]);
+
+    let preview = browser.preview();
+    let items: Vec<ListItem> = match &browser.error {
+        Some(error) => vec![ListItem::new(Span::styled(
+            error.clone(),
+            Style::default().fg(Color::Red),
+        ))],
+        None => browser
+            .visible_entries()
+            .iter()
+            .map(|entry| {
+                if entry.is_dir {
+                    ListItem::new(Span::styled(
+                        format!("{}/", entry.name),
+                        Style::default().fg(Color::Blue),
+                    ))
+                } else {
+                    ListItem::new(format!("{}  ({})", entry.name, format_size(entry.size)))
+                }
+            })
+            .collect(),
+    };
+    let mut list_state = ListState::default();
+    list_state.select(Some(browser.selected));
+    let list = List::new(items).highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    f.render_stateful_widget(list, chunks[1], &mut list_state);
+
+    let preview_text = match preview {
+        Some(p) if p.truncated => format!(
+            "Size: at least {} in {}+ files",
+            format_size(p.bytes),
+            p.files
+        ),
+        Some(p) => format!("Size: {} in {} file(s)", format_size(p.bytes), p.files),
+        None => "No matching entries".to_string(),
+    };
+    f.render_widget(
+        Paragraph::new(preview_text).style(Style::default().fg(Color::Green)),
+        chunks[2],
+    );
+
+    let help_text = vec![
+        Line::from("↑/↓: Select | →: Open dir | ←: Parent | Tab: Complete | Ctrl+T: Hidden"),
+        Line::from("Type to filter | Enter: Choose (\".\" = this dir) | Esc: Cancel"),
+    ];
+    f.render_widget(
+        Paragraph::new(help_text)
+            .style(Style::default().fg(Color::Gray))
+            .alignment(Alignment::Center),
+        chunks[3],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::fs;
+    use std::path::Path;
+
+    fn app_with_operation(origin: &Path, destination: &Path) -> App {
+        let config = Config {
+            version: crate::schema::CURRENT_VERSION,
+            variables: Default::default(),
+            include: Vec::new(),
+            operations: vec![FileOperation {
+                name: "Docs".to_string(),
+                origin: origin.to_path_buf(),
+                destination: destination.to_path_buf(),
+                ..Default::default()
+            }],
+            global_rate_limit: RateLimit::default(),
+            free_space_margin: None,
+            files: Vec::new(),
+            fallback: false,
+        };
+        let mut app = App::new(config, "reports");
+        app.operations_state.select(Some(0));
+        app
+    }
+
+    // Opens the browser on the field `mode`, highlights `name` and picks it
+    fn browse_and_choose(app: &mut App, mode: InputMode, name: &str) {
+        app.start_editing();
+        while app.input_mode != mode {
+            app.next_edit_field();
+        }
+        app.open_file_browser();
+        let browser = app.file_browser.as_mut().unwrap();
+        browser.selected = browser
+            .visible_entries()
+            .iter()
+            .position(|e| e.name == name)
+            .unwrap();
+        app.choose_browser_entry();
+        assert!(app.file_browser.is_none());
+        assert!(app.save_edit());
+    }
+
+    #[test]
+    fn browser_entry_becomes_the_origin() {
+        let root = TempDir::new("ui");
+        fs::create_dir_all(root.join("photos")).unwrap();
+        fs::write(root.join("docs.txt"), "docs").unwrap();
+        let mut app = app_with_operation(&root, &root.join("backup"));
+
+        browse_and_choose(&mut app, InputMode::EditingSource, "docs.txt");
+        let chosen = root.canonicalize().unwrap().join("docs.txt");
+        assert_eq!(app.config.operations[0].origin, chosen);
+        assert_eq!(app.editing_operation.1, chosen.to_string_lossy());
+    }
+
+    #[test]
+    fn browser_entry_becomes_the_destination() {
+        let root = TempDir::new("ui");
+        fs::create_dir_all(root.join("backup")).unwrap();
+        let mut app = app_with_operation(&root.join("docs"), &root.join("backup/new"));
+
+        // The destination doesn't exist yet, so the browser opens on its
+        // parent and "." picks that
+        browse_and_choose(&mut app, InputMode::EditingDestination, ".");
+        assert_eq!(
+            app.config.operations[0].destination,
+            root.join("backup").canonicalize().unwrap()
+        );
+        assert_eq!(app.config.operations[0].origin, root.join("docs"));
+    }
 }
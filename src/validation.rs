@@ -1,15 +1,21 @@
+use crate::compression;
+use crate::config::Compression;
+use crate::crypto::{self, Key};
 use sha2::{Digest, Sha256};
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 
 pub fn calculate_sha256(file_path: &Path) -> anyhow::Result<String> {
-    let mut file = File::open(file_path)?;
+    sha256_of_reader(File::open(file_path)?)
+}
+
+pub fn sha256_of_reader(mut reader: impl Read) -> anyhow::Result<String> {
     let mut hasher = Sha256::new();
     let mut buffer = [0; 8192];
 
     loop {
-        let bytes_read = file.read(&mut buffer)?;
+        let bytes_read = reader.read(&mut buffer)?;
         if bytes_read == 0 {
             break;
         }
@@ -19,15 +25,28 @@ pub fn calculate_sha256(file_path: &Path) -> anyhow::Result<String> {
     Ok(format!("{:x}", hasher.finalize()))
 }
 
-pub fn verify_files_match(src: &Path, dst: &Path) -> anyhow::Result<bool> {
+/// Hashes `src` and what `dst` decodes to (decrypted, then decompressed),
+/// and returns the source hash if the two match. An encrypted `dst` must
+/// also carry the source hash as the one recorded at copy time.
+pub fn verify_files_match(
+    src: &Path,
+    dst: &Path,
+    compression: Option<Compression>,
+    key: Option<&Key>,
+) -> anyhow::Result<Option<String>> {
     if !src.exists() || !dst.exists() {
-        return Ok(false);
+        return Ok(None);
     }
 
     let src_hash = calculate_sha256(src)?;
-    let dst_hash = calculate_sha256(dst)?;
+    if let Some(key) = key
+        && crypto::recorded_sha256(dst, key)?.as_ref() != Some(&src_hash)
+    {
+        return Ok(None);
+    }
+    let dst_hash = sha256_of_reader(compression::open_decoded(dst, compression, key)?)?;
 
-    Ok(src_hash == dst_hash)
+    Ok((src_hash == dst_hash).then_some(src_hash))
 }
 
 pub fn verify_file_integrity(file_path: &Path, expected_hash: &str) -> anyhow::Result<bool> {
@@ -38,3 +57,49 @@ pub fn verify_file_integrity(file_path: &Path, expected_hash: &str) -> anyhow::R
     let actual_hash = calculate_sha256(file_path)?;
     Ok(actual_hash == expected_hash)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Encoder;
+    use crate::crypto::EncryptWriter;
+    use crate::test_util::TempDir;
+    use std::fs;
+    use std::io::Write;
+
+    // An encrypted, compressed copy of `data` that records `recorded` as
+    // the plaintext hash
+    fn sealed_copy(path: &Path, data: &[u8], key: &Key, recorded: Option<[u8; 32]>) {
+        let sealed = EncryptWriter::new(File::create(path).unwrap(), Some(key)).unwrap();
+        let mut writer = Encoder::new(sealed, Some(Compression::Zstd)).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap().finish(recorded).unwrap();
+    }
+
+    #[test]
+    fn encrypted_copy_must_record_the_source_hash() {
+        let dir = TempDir::new("validation");
+        let key_path = dir.join("key");
+        fs::write(&key_path, [7u8; 32]).unwrap();
+        let key = Key::load(&key_path).unwrap();
+        let source = dir.join("source.txt");
+        fs::write(&source, "the original content").unwrap();
+        let sha256: [u8; 32] = Sha256::digest(b"the original content").into();
+        let copy = dir.join("source.txt.zst.enc");
+        let verify = || verify_files_match(&source, &copy, Some(Compression::Zstd), Some(&key));
+
+        sealed_copy(&copy, b"the original content", &key, Some(sha256));
+        assert_eq!(verify().unwrap(), Some(calculate_sha256(&source).unwrap()));
+
+        // The content decrypts fine but the recorded hash is someone else's
+        let other: [u8; 32] = Sha256::digest(b"something else").into();
+        sealed_copy(&copy, b"the original content", &key, Some(other));
+        assert_eq!(verify().unwrap(), None);
+        sealed_copy(&copy, b"the original content", &key, None);
+        assert_eq!(verify().unwrap(), None);
+
+        // The recorded hash matches but the content doesn't
+        sealed_copy(&copy, b"the original contenT", &key, Some(sha256));
+        assert_eq!(verify().unwrap(), None);
+    }
+}
@@ -0,0 +1,451 @@
+use crate::config::{FileOperation, OperationType};
+use crate::file_ops::FileManager;
+use crate::paths;
+use crate::rate_limiter::GlobalLimiter;
+use chrono::Local;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+// A file that has been written and is waiting out the debounce period
+struct Pending {
+    operation: usize,
+    last_event: Instant,
+    closed: bool,
+    // The size at `last_event`
+    size: Option<u64>,
+}
+
+struct Watcher<'a> {
+    inotify: Inotify,
+    operations: &'a [FileOperation],
+    global_limiter: GlobalLimiter,
+    debounce: Duration,
+    // Every watched directory and the operation whose origin it is under
+    directories: HashMap<WatchDescriptor, (usize, PathBuf)>,
+    pending: HashMap<PathBuf, Pending>,
+}
+
+/// Watches the origin directory of every operation and copies or moves
+/// each file once it has been closed after writing and then left alone for
+/// `debounce`. Only files written after watching starts are transferred;
+/// run the operations once beforehand to bring destinations up to date.
+/// Runs until interrupted.
+pub fn watch(
+    operations: &[FileOperation],
+    global_limiter: GlobalLimiter,
+    debounce: Duration,
+) -> anyhow::Result<()> {
+    let mut watcher = Watcher {
+        inotify: Inotify::init()?,
+        operations,
+        global_limiter,
+        debounce,
+        directories: HashMap::new(),
+        pending: HashMap::new(),
+    };
+
+    let mut watched = 0;
+    for (idx, op) in operations.iter().enumerate() {
+        if !matches!(op.operation_type, OperationType::Copy | OperationType::Move) {
+            println!(
+                "Skipping '{}': watch mode only copies or moves files, not {:?}",
+                op.name, op.operation_type
+            );
+            continue;
+        }
+        if !op.origin.is_dir() {
+            println!(
+                "Skipping '{}': watch mode needs a directory origin, {} is not one",
+                op.name,
+                op.origin.display()
+            );
+            continue;
+        }
+        watcher.add_tree(idx, &op.origin, false)?;
+        println!(
+            "Watching {} for '{}' ({:?} to {})",
+            op.origin.display(),
+            op.name,
+            op.operation_type,
+            op.destination.display()
+        );
+        watched += 1;
+    }
+    if watched == 0 {
+        anyhow::bail!("no copy or move operation has a directory origin to watch");
+    }
+    println!("Files already in the origins are left alone; only new writes are transferred");
+
+    let mut buffer = [0u8; 4096];
+    loop {
+        watcher.read_events(&mut buffer)?;
+        watcher.transfer_settled_files();
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+impl Watcher<'_> {
+    // Watches `dir` and every directory below it. With `queue_existing`,
+    // files already inside are queued too; that is for directories moved
+    // or created while watching, whose contents produced no events of
+    // their own.
+    fn add_tree(&mut self, operation: usize, dir: &Path, queue_existing: bool) -> io::Result<()> {
+        let mask = WatchMask::CLOSE_WRITE
+            | WatchMask::MOVED_TO
+            | WatchMask::MOVED_FROM
+            | WatchMask::CREATE
+            | WatchMask::MODIFY;
+        for entry in WalkDir::new(dir).into_iter().flatten() {
+            if entry.file_type().is_dir() {
+                let wd = self.inotify.watches().add(entry.path(), mask)?;
+                self.directories
+                    .insert(wd, (operation, entry.path().to_path_buf()));
+            } else if queue_existing && entry.file_type().is_file() {
+                self.touch(operation, entry.path().to_path_buf(), true);
+            }
+        }
+        Ok(())
+    }
+
+    fn touch(&mut self, operation: usize, path: PathBuf, closed: bool) {
+        let size = std::fs::metadata(&path).ok().map(|m| m.len());
+        let pending = self.pending.entry(path).or_insert(Pending {
+            operation,
+            last_event: Instant::now(),
+            closed: false,
+            size: None,
+        });
+        pending.last_event = Instant::now();
+        // A write after close means someone reopened the file
+        pending.closed = closed;
+        pending.size = size;
+    }
+
+    // Stops watching `dir` and the directories below it, which have been
+    // moved away from where they were watched
+    fn remove_tree(&mut self, dir: &Path) {
+        let gone: Vec<WatchDescriptor> = self
+            .directories
+            .iter()
+            .filter(|(_, (_, path))| path.starts_with(dir))
+            .map(|(wd, _)| wd.clone())
+            .collect();
+        for wd in gone {
+            self.directories.remove(&wd);
+            // Fails if the kernel dropped the watch already
+            let _ = self.inotify.watches().remove(wd);
+        }
+    }
+
+    fn read_events(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        let events = match self.inotify.read_events(buffer) {
+            Ok(events) => events,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let mut new_directories = Vec::new();
+        let mut old_directories = Vec::new();
+        let mut touched = Vec::new();
+        for event in events {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                println!("WARNING: inotify queue overflowed; some files may have been missed");
+                continue;
+            }
+            // The kernel dropped the watch because its directory was deleted
+            if event.mask.contains(EventMask::IGNORED) {
+                self.directories.remove(&event.wd);
+                continue;
+            }
+            let (Some((operation, dir)), Some(name)) =
+                (self.directories.get(&event.wd), event.name)
+            else {
+                continue;
+            };
+            let path = dir.join(name);
+            if event.mask.contains(EventMask::ISDIR) {
+                if event
+                    .mask
+                    .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+                {
+                    new_directories.push((*operation, path));
+                } else if event.mask.contains(EventMask::MOVED_FROM) {
+                    old_directories.push(path);
+                }
+            } else if !is_temporary(name) {
+                let closed = event
+                    .mask
+                    .intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO);
+                if closed || event.mask.contains(EventMask::MODIFY) {
+                    touched.push((*operation, path, closed));
+                }
+            }
+        }
+
+        for (operation, path, closed) in touched {
+            self.touch(operation, path, closed);
+        }
+        // Before re-adding, so a directory moved within the origin is
+        // watched again at its new path
+        for dir in old_directories {
+            self.remove_tree(&dir);
+        }
+        for (operation, dir) in new_directories {
+            self.add_tree(operation, &dir, true)?;
+        }
+        Ok(())
+    }
+
+    fn transfer_settled_files(&mut self) {
+        for (operation, path) in self.settled_files(Instant::now()) {
+            self.transfer(operation, &path);
+        }
+    }
+
+    // Takes the files that have sat still for the debounce period as of
+    // `now` out of `pending`, and forgets the ones that have disappeared
+    fn settled_files(&mut self, now: Instant) -> Vec<(usize, PathBuf)> {
+        let mut ready = Vec::new();
+        let mut vanished = Vec::new();
+        for (path, pending) in self.pending.iter_mut() {
+            if now.duration_since(pending.last_event) < self.debounce {
+                continue;
+            }
+            let size = std::fs::metadata(path).ok().map(|m| m.len());
+            if size.is_none() {
+                // Renamed away (temporary files) or deleted; never settles
+                vanished.push(path.clone());
+            } else if !pending.closed {
+                continue;
+            } else if pending.size == size {
+                // The size also held still since the last event, in case a
+                // writer is appending without closing
+                ready.push(path.clone());
+            } else {
+                pending.size = size;
+                pending.last_event = now;
+            }
+        }
+
+        for path in vanished {
+            self.pending.remove(&path);
+        }
+        ready
+            .into_iter()
+            .filter_map(|path| Some((self.pending.remove(&path)?.operation, path)))
+            .collect()
+    }
+
+    fn transfer(&self, operation: usize, path: &Path) {
+        let op = &self.operations[operation];
+        let Ok(relative) = path.strip_prefix(&op.origin) else {
+            return;
+        };
+        // The destination is still a template; keep braces in the file's
+        // own name from being read as placeholders
+        let relative = paths::escape_template(&relative.to_string_lossy());
+        let file_op = FileOperation {
+            origin: path.to_path_buf(),
+            destination: op.destination.join(relative),
+            retention: None,
+            schedule: None,
+            ..op.clone()
+        };
+
+        let result = FileManager::execute_operation(&file_op, &self.global_limiter);
+        let stamp = Local::now().format("%Y-%m-%d %H:%M:%S");
+        if result.success {
+            println!(
+                "[{}] {:?} {} -> {} ({} bytes, verified)",
+                stamp,
+                op.operation_type,
+                path.display(),
+                result.destination,
+                result.total_size
+            );
+        } else {
+            println!(
+                "[{}] FAILED {} ({}): {}",
+                stamp,
+                path.display(),
+                op.name,
+                result.error_message.as_deref().unwrap_or("unknown error")
+            );
+        }
+    }
+}
+
+// Editor swap files and partial downloads get renamed into place when
+// complete, which shows up as a separate MOVED_TO event
+fn is_temporary(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.ends_with(".swp")
+        || name.ends_with(".part")
+        || name.ends_with(".crdownload")
+        || name.ends_with('~')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateLimit;
+    use crate::test_util::TempDir;
+    use std::fs;
+
+    const DEBOUNCE: Duration = Duration::from_secs(2);
+
+    fn watcher(operations: &[FileOperation]) -> Watcher<'_> {
+        Watcher {
+            inotify: Inotify::init().unwrap(),
+            operations,
+            global_limiter: GlobalLimiter::new(&RateLimit::default()),
+            debounce: DEBOUNCE,
+            directories: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn read(watcher: &mut Watcher) {
+        let mut buffer = [0u8; 4096];
+        watcher.read_events(&mut buffer).unwrap();
+    }
+
+    fn watched(watcher: &Watcher) -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = watcher
+            .directories
+            .values()
+            .map(|(_, path)| path.clone())
+            .collect();
+        dirs.sort();
+        dirs
+    }
+
+    #[test]
+    fn closed_file_settles_after_the_debounce() {
+        let dir = TempDir::new("watcher");
+        let mut watcher = watcher(&[]);
+        let file = dir.join("a.txt");
+        fs::write(&file, "hello").unwrap();
+        watcher.touch(0, file.clone(), true);
+
+        let start = Instant::now();
+        assert!(watcher.settled_files(start).is_empty());
+        assert!(watcher.settled_files(start + DEBOUNCE / 2).is_empty());
+        assert_eq!(watcher.settled_files(start + DEBOUNCE), [(0, file)]);
+        assert!(watcher.pending.is_empty());
+    }
+
+    #[test]
+    fn file_still_open_for_writing_does_not_settle() {
+        let dir = TempDir::new("watcher");
+        let mut watcher = watcher(&[]);
+        let file = dir.join("a.txt");
+        fs::write(&file, "hello").unwrap();
+        watcher.touch(0, file.clone(), true);
+        // Written again without closing
+        watcher.touch(0, file.clone(), false);
+
+        assert!(
+            watcher
+                .settled_files(Instant::now() + DEBOUNCE * 10)
+                .is_empty()
+        );
+        assert!(watcher.pending.contains_key(&file));
+    }
+
+    #[test]
+    fn growing_file_waits_another_debounce() {
+        let dir = TempDir::new("watcher");
+        let mut watcher = watcher(&[]);
+        let file = dir.join("a.txt");
+        fs::write(&file, "hello").unwrap();
+        watcher.touch(0, file.clone(), true);
+        // Appended to without an event reaching us
+        fs::write(&file, "hello, world").unwrap();
+
+        let settled = Instant::now() + DEBOUNCE;
+        assert!(watcher.settled_files(settled).is_empty());
+        assert!(watcher.settled_files(settled + DEBOUNCE / 2).is_empty());
+        assert_eq!(watcher.settled_files(settled + DEBOUNCE), [(0, file)]);
+    }
+
+    #[test]
+    fn vanished_file_is_dropped() {
+        let dir = TempDir::new("watcher");
+        let mut watcher = watcher(&[]);
+        let file = dir.join("a.txt");
+        fs::write(&file, "hello").unwrap();
+        watcher.touch(0, file.clone(), false);
+        fs::remove_file(&file).unwrap();
+
+        assert!(watcher.settled_files(Instant::now() + DEBOUNCE).is_empty());
+        assert!(watcher.pending.is_empty());
+    }
+
+    #[test]
+    fn events_queue_written_files_but_not_temporary_ones() {
+        let dir = TempDir::new("watcher");
+        let mut watcher = watcher(&[]);
+        watcher.add_tree(0, &dir, false).unwrap();
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        fs::write(dir.join("a.txt.part"), "hel").unwrap();
+        read(&mut watcher);
+
+        assert_eq!(watcher.pending.len(), 1);
+        assert!(watcher.pending[&dir.join("a.txt")].closed);
+    }
+
+    #[test]
+    fn deleted_directory_is_watched_again_when_recreated() {
+        let dir = TempDir::new("watcher");
+        let sub = dir.join("sub");
+        fs::create_dir(&sub).unwrap();
+        let mut watcher = watcher(&[]);
+        watcher.add_tree(0, &dir, false).unwrap();
+        assert_eq!(watched(&watcher), [dir.to_path_buf(), sub.clone()]);
+
+        fs::remove_dir(&sub).unwrap();
+        read(&mut watcher);
+        assert_eq!(watched(&watcher), [dir.to_path_buf()]);
+
+        fs::create_dir(&sub).unwrap();
+        read(&mut watcher);
+        assert_eq!(watched(&watcher), [dir.to_path_buf(), sub.clone()]);
+        fs::write(sub.join("b.txt"), "new").unwrap();
+        read(&mut watcher);
+        assert!(watcher.pending.contains_key(&sub.join("b.txt")));
+    }
+
+    #[test]
+    fn directory_moved_out_and_back_is_watched_again() {
+        let dir = TempDir::new("watcher");
+        let origin = dir.join("origin");
+        let sub = origin.join("sub");
+        fs::create_dir_all(sub.join("deeper")).unwrap();
+        let mut watcher = watcher(&[]);
+        watcher.add_tree(0, &origin, false).unwrap();
+
+        let outside = dir.join("elsewhere");
+        fs::rename(&sub, &outside).unwrap();
+        read(&mut watcher);
+        assert_eq!(watched(&watcher), vec![origin.clone()]);
+        // Writes outside the origin go unnoticed
+        fs::write(outside.join("deeper/c.txt"), "away").unwrap();
+        read(&mut watcher);
+        assert!(watcher.pending.is_empty());
+
+        fs::rename(&outside, &sub).unwrap();
+        read(&mut watcher);
+        assert_eq!(
+            watched(&watcher),
+            [origin.clone(), sub.clone(), sub.join("deeper")]
+        );
+        // Files carried in with the directory are queued
+        assert!(watcher.pending.contains_key(&sub.join("deeper/c.txt")));
+    }
+}